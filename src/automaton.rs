@@ -0,0 +1,164 @@
+use pyo3::prelude::*;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::error::{Result, TokengramsError};
+
+/// A deterministic finite automaton over tokens, restricting generation to the token sequences
+/// it allows. Generation starts in state zero and may only emit tokens with a transition out
+/// of the current state, ending once it reaches an accepting state. In an accepting state that
+/// longer sequences continue from, generation may stop or continue.
+///
+/// Transitions are stored in compressed sparse row form: the tokens leaving state `s` are
+/// `tokens[offsets[s]..offsets[s + 1]]`, in increasing order, with matching `targets`.
+#[pyclass(frozen)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenAutomaton {
+    offsets: Vec<usize>,
+    tokens: Vec<usize>,
+    targets: Vec<usize>,
+    accepting: Vec<bool>,
+}
+
+impl fmt::Display for TokenAutomaton {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "num_states={}, num_transitions={}",
+            self.num_states(),
+            self.tokens.len()
+        )
+    }
+}
+
+impl TokenAutomaton {
+    /// Builds an automaton from `(state, token, next_state)` transitions. The number of states
+    /// defaults to one more than the largest state mentioned.
+    pub fn new(
+        mut transitions: Vec<(usize, usize, usize)>,
+        accepting: &[usize],
+        num_states: Option<usize>,
+    ) -> Result<Self> {
+        let largest = transitions
+            .iter()
+            .flat_map(|&(state, _, next)| [state, next])
+            .chain(accepting.iter().copied())
+            .max();
+        let num_states = num_states.unwrap_or(largest.map_or(1, |s| s + 1));
+        if let Some(state) = largest.filter(|&s| s >= num_states) {
+            return Err(TokengramsError::InvalidArgument(format!(
+                "State {} is out of range for an automaton with {} states",
+                state, num_states
+            )));
+        }
+
+        transitions.sort_unstable();
+        transitions.dedup();
+        if let Some(pair) = transitions
+            .windows(2)
+            .find(|w| w[0].0 == w[1].0 && w[0].1 == w[1].1)
+        {
+            return Err(TokengramsError::InvalidArgument(format!(
+                "State {} has more than one transition on token {}",
+                pair[0].0, pair[0].1
+            )));
+        }
+
+        let mut automaton = TokenAutomaton {
+            offsets: vec![0; num_states.max(1) + 1],
+            tokens: Vec::with_capacity(transitions.len()),
+            targets: Vec::with_capacity(transitions.len()),
+            accepting: vec![false; num_states.max(1)],
+        };
+        for (state, token, next) in transitions {
+            automaton.offsets[state + 1] += 1;
+            automaton.tokens.push(token);
+            automaton.targets.push(next);
+        }
+        for state in 0..automaton.num_states() {
+            automaton.offsets[state + 1] += automaton.offsets[state];
+        }
+        for &state in accepting {
+            automaton.accepting[state] = true;
+        }
+        Ok(automaton)
+    }
+
+    /// Builds a trie accepting exactly the given sequences.
+    pub fn from_sequences(sequences: &[Vec<usize>]) -> Self {
+        let mut children: HashMap<(usize, usize), usize> = HashMap::new();
+        let mut accepting = Vec::with_capacity(sequences.len());
+        let mut num_states = 1;
+        for sequence in sequences {
+            let state = sequence.iter().fold(0, |state, &token| {
+                *children.entry((state, token)).or_insert_with(|| {
+                    num_states += 1;
+                    num_states - 1
+                })
+            });
+            accepting.push(state);
+        }
+
+        let transitions = children
+            .into_iter()
+            .map(|((state, token), next)| (state, token, next))
+            .collect();
+        Self::new(transitions, &accepting, Some(num_states))
+            .unwrap_or_else(|_| unreachable!("trie transitions are deterministic"))
+    }
+
+    pub fn num_states(&self) -> usize {
+        self.accepting.len()
+    }
+
+    /// Returns the tokens with a transition out of `state`, in increasing order.
+    pub fn allowed_tokens(&self, state: usize) -> &[usize] {
+        &self.tokens[self.offsets[state]..self.offsets[state + 1]]
+    }
+
+    pub fn next_state(&self, state: usize, token: usize) -> Option<usize> {
+        let start = self.offsets[state];
+        self.allowed_tokens(state)
+            .binary_search(&token)
+            .ok()
+            .map(|i| self.targets[start + i])
+    }
+
+    pub fn is_accepting(&self, state: usize) -> bool {
+        self.accepting[state]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trie_accepts_sequences() {
+        let automaton = TokenAutomaton::from_sequences(&[vec![1, 2], vec![1, 3, 4], vec![5]]);
+
+        assert_eq!(automaton.num_states(), 6);
+        assert_eq!(automaton.allowed_tokens(0), &[1, 5]);
+        let s1 = automaton.next_state(0, 1).unwrap();
+        assert_eq!(automaton.allowed_tokens(s1), &[2, 3]);
+        assert!(!automaton.is_accepting(s1));
+        assert!(automaton.is_accepting(automaton.next_state(s1, 2).unwrap()));
+        assert_eq!(automaton.next_state(0, 2), None);
+    }
+
+    #[test]
+    fn invalid_automata_are_rejected() {
+        // A loop over token 7 ending on token 8
+        let automaton = TokenAutomaton::new(vec![(0, 7, 0), (0, 8, 1)], &[1], None).unwrap();
+        assert_eq!(automaton.next_state(0, 7), Some(0));
+        assert_eq!(automaton.allowed_tokens(1), &[] as &[usize]);
+
+        for result in [
+            TokenAutomaton::new(vec![(0, 7, 1), (0, 7, 2)], &[], None),
+            TokenAutomaton::new(vec![(0, 7, 3)], &[], Some(2)),
+            TokenAutomaton::new(vec![], &[4], Some(2)),
+        ] {
+            assert!(matches!(result, Err(TokengramsError::InvalidArgument(_))));
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use crate::count_cache::CacheStats;
+use pyo3::prelude::*;
+
+#[pymethods]
+impl CacheStats {
+    #[getter]
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    #[getter]
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// The number of contexts in the cache.
+    #[getter]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    #[getter]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!("CacheStats({})", self)
+    }
+}
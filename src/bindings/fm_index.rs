@@ -0,0 +1,371 @@
+use crate::bindings::position_pages::{positions_array, PositionPages};
+use crate::bindings::sample_result::{check_output_flags, sample_results_into_py};
+use crate::error::Result;
+use crate::fm_index::{FmIndexHeader, FmIndexRs};
+use crate::metadata;
+use crate::query::OutOfVocab;
+use crate::sample::{SampleRequest, SampleResult};
+use crate::sampling_config::SamplingConfig;
+use pyo3::prelude::*;
+
+/// An FM-index exposes suffix table functionality over a compressed self-index, which replaces
+/// both the token file and the suffix table of a memmap index.
+#[pyclass]
+pub struct FmIndex {
+    index: Box<dyn FmIndexTrait + Send + Sync>,
+}
+
+/// This trait is non-generic for PyO3 compatibility. Implementing structs may cast data
+/// to other unsigned integer types.
+pub trait FmIndexTrait {
+    fn out_of_vocab(&self) -> OutOfVocab;
+    fn set_out_of_vocab(&mut self, policy: OutOfVocab);
+    fn sample_rate(&self) -> usize;
+    fn save(&self, index_path: String) -> Result<()>;
+    fn contains(&self, query: Vec<usize>) -> Result<bool>;
+    fn positions(
+        &self,
+        query: Vec<usize>,
+        offset: usize,
+        limit: Option<usize>,
+        sorted: bool,
+    ) -> Result<Vec<u64>>;
+    fn count(&self, query: Vec<usize>) -> Result<usize>;
+    fn count_next(&self, query: Vec<usize>) -> Result<Vec<usize>>;
+    fn batch_count_next(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<usize>>>;
+    fn sample_unsmoothed(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        num_samples: usize,
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<SampleResult>>;
+    fn sample_smoothed(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        num_samples: usize,
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<SampleResult>>;
+    fn batch_sample(
+        &self,
+        requests: Vec<SampleRequest<usize>>,
+        smoothed: bool,
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<Vec<SampleResult>>>;
+    fn decode_greedy(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        config: &SamplingConfig,
+    ) -> Result<(Vec<usize>, f64)>;
+    fn beam_search(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        beam_width: usize,
+        config: &SamplingConfig,
+    ) -> Result<Vec<(Vec<usize>, f64)>>;
+    fn get_smoothed_probs(&self, query: Vec<usize>) -> Result<Vec<f64>>;
+    fn batch_get_smoothed_probs(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<f64>>>;
+    fn estimate_deltas(&self, n: usize);
+}
+
+#[pymethods]
+impl FmIndex {
+    #[new]
+    pub fn new(py: Python<'_>, index_path: String) -> Result<Self> {
+        py.allow_threads(|| {
+            let header = FmIndexHeader::load(&index_path)?;
+            let index: Box<dyn FmIndexTrait + Send + Sync> = with_token_type!(header.token_width, T => {
+                Box::new(FmIndexRs::<T>::load(index_path)?)
+            });
+
+            Ok(FmIndex { index })
+        })
+    }
+
+    /// Build an FM-index over a token file and save it to `index_path`. Every
+    /// `sample_rate`-th suffix array entry is stored, so larger rates give smaller indexes and
+    /// slower `positions` queries.
+    #[staticmethod]
+    #[pyo3(signature = (token_path, index_path, vocab=u16::MAX as usize + 1, sample_rate=32, verbose=false, token_width=None))]
+    pub fn build(
+        py: Python<'_>,
+        token_path: String,
+        index_path: String,
+        vocab: usize,
+        sample_rate: usize,
+        verbose: bool,
+        token_width: Option<usize>,
+    ) -> Result<Self> {
+        py.allow_threads(|| {
+            let index: Box<dyn FmIndexTrait + Send + Sync> = with_token_type!(metadata::token_width(vocab, token_width)?, T => Box::new(
+                FmIndexRs::<T>::build(token_path, index_path, vocab, sample_rate, verbose)?,
+            ));
+
+            Ok(FmIndex { index })
+        })
+    }
+
+    pub fn save(&self, py: Python<'_>, index_path: String) -> Result<()> {
+        py.allow_threads(|| self.index.save(index_path))
+    }
+
+    /// How query tokens outside the vocabulary are handled: "error" raises
+    /// `TokenOutOfVocabError` and "miss" treats them as tokens that never occur.
+    #[getter]
+    pub fn out_of_vocab(&self) -> &'static str {
+        self.index.out_of_vocab().as_str()
+    }
+
+    #[setter]
+    pub fn set_out_of_vocab(&mut self, policy: &str) -> Result<()> {
+        self.index.set_out_of_vocab(policy.parse()?);
+        Ok(())
+    }
+
+    /// The distance between the text offsets of sampled suffix array entries.
+    #[getter]
+    pub fn sample_rate(&self) -> usize {
+        self.index.sample_rate()
+    }
+
+    pub fn contains(&self, py: Python<'_>, query: Vec<usize>) -> Result<bool> {
+        py.allow_threads(|| self.index.contains(query))
+    }
+
+    /// Returns the positions where `query` starts in the tokens as a numpy array, skipping the
+    /// first `offset` and keeping at most `limit`. Positions are in index order unless `sorted`,
+    /// in which case they are in corpus order and every page costs a pass over all matches.
+    #[pyo3(signature = (query, offset=0, limit=None, sorted=false))]
+    pub fn positions(
+        &self,
+        py: Python<'_>,
+        query: Vec<usize>,
+        offset: usize,
+        limit: Option<usize>,
+        sorted: bool,
+    ) -> PyResult<PyObject> {
+        let positions = py.allow_threads(|| self.index.positions(query, offset, limit, sorted))?;
+        positions_array(py, &positions)
+    }
+
+    /// Iterate over the positions where `query` starts in the tokens in numpy arrays of at most
+    /// `page_size` positions, fetching each page only when it is reached. In corpus order,
+    /// every position is fetched and sorted once with the first page.
+    #[pyo3(signature = (query, page_size=1 << 20, sorted=false))]
+    pub fn iter_positions(
+        slf: Py<Self>,
+        query: Vec<usize>,
+        page_size: usize,
+        sorted: bool,
+    ) -> Result<PositionPages> {
+        PositionPages::new(slf.into_any(), query, page_size, sorted)
+    }
+
+    pub fn count(&self, py: Python<'_>, query: Vec<usize>) -> Result<usize> {
+        py.allow_threads(|| self.index.count(query))
+    }
+
+    pub fn count_next(&self, py: Python<'_>, query: Vec<usize>) -> Result<Vec<usize>> {
+        py.allow_threads(|| self.index.count_next(query))
+    }
+
+    pub fn batch_count_next(
+        &self,
+        py: Python<'_>,
+        queries: Vec<Vec<usize>>,
+    ) -> Result<Vec<Vec<usize>>> {
+        py.allow_threads(|| self.index.batch_count_next(queries))
+    }
+
+    /// Autoregressively sample num_samples of k characters from an unsmoothed n-gram model,
+    /// backing off to shorter contexts where the (n - 1)-token context has no continuations.
+    /// With `return_orders`, also returns the order of the n-gram completed by each sampled
+    /// token. With `detailed`, returns a `SampleResult` with diagnostics for each sample
+    /// instead, and the two flags cannot be combined."""
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (
+        query,
+        n,
+        k,
+        num_samples,
+        seed=None,
+        config=None,
+        return_orders=false,
+        detailed=false,
+    ))]
+    pub fn sample_unsmoothed(
+        &self,
+        py: Python<'_>,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        num_samples: usize,
+        seed: Option<u64>,
+        config: Option<SamplingConfig>,
+        return_orders: bool,
+        detailed: bool,
+    ) -> Result<PyObject> {
+        check_output_flags(detailed, return_orders)?;
+        let results = py.allow_threads(|| {
+            self.index.sample_unsmoothed(
+                query,
+                n,
+                k,
+                num_samples,
+                seed,
+                &config.unwrap_or_default().with_diagnostics(detailed),
+            )
+        })?;
+        Ok(sample_results_into_py(py, results, detailed, return_orders))
+    }
+
+    /// Returns interpolated Kneser-Ney smoothed token probability distribution using all previous
+    /// tokens in the query.
+    pub fn get_smoothed_probs(&self, py: Python<'_>, query: Vec<usize>) -> Result<Vec<f64>> {
+        py.allow_threads(|| self.index.get_smoothed_probs(query))
+    }
+
+    /// Returns interpolated Kneser-Ney smoothed token probability distribution using all previous
+    /// tokens in the query.
+    pub fn batch_get_smoothed_probs(
+        &self,
+        py: Python<'_>,
+        queries: Vec<Vec<usize>>,
+    ) -> Result<Vec<Vec<f64>>> {
+        py.allow_threads(|| self.index.batch_get_smoothed_probs(queries))
+    }
+
+    /// Autoregressively sample num_samples of k characters from a Kneser-Ney smoothed n-gram model.
+    /// With `detailed`, returns a `SampleResult` with diagnostics for each sample.
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (query, n, k, num_samples, seed=None, config=None, detailed=false))]
+    pub fn sample_smoothed(
+        &self,
+        py: Python<'_>,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        num_samples: usize,
+        seed: Option<u64>,
+        config: Option<SamplingConfig>,
+        detailed: bool,
+    ) -> Result<PyObject> {
+        let results = py.allow_threads(|| {
+            self.index.sample_smoothed(
+                query,
+                n,
+                k,
+                num_samples,
+                seed,
+                &config.unwrap_or_default().with_diagnostics(detailed),
+            )
+        })?;
+        Ok(sample_results_into_py(py, results, detailed, false))
+    }
+
+    /// Sample from many prompts in one parallel job. Each request is a `(query, n, k,
+    /// num_samples)` tuple, and a list of samples is returned for each request, formatted as
+    /// by `sample_unsmoothed`. Samples are drawn from a Kneser-Ney smoothed n-gram model if
+    /// `smoothed`, and otherwise from an unsmoothed one. Given a seed, samples are numbered
+    /// consecutively across requests, so a lone request draws the same samples as the
+    /// single-prompt methods.
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (
+        requests,
+        smoothed=false,
+        seed=None,
+        config=None,
+        return_orders=false,
+        detailed=false,
+    ))]
+    pub fn batch_sample(
+        &self,
+        py: Python<'_>,
+        requests: Vec<(Vec<usize>, usize, usize, usize)>,
+        smoothed: bool,
+        seed: Option<u64>,
+        config: Option<SamplingConfig>,
+        return_orders: bool,
+        detailed: bool,
+    ) -> Result<Vec<PyObject>> {
+        check_output_flags(detailed, return_orders)?;
+        let requests = requests
+            .into_iter()
+            .map(|(query, n, k, num_samples)| SampleRequest {
+                query,
+                n,
+                k,
+                num_samples,
+            })
+            .collect();
+        let results = py.allow_threads(|| {
+            self.index.batch_sample(
+                requests,
+                smoothed,
+                seed,
+                &config.unwrap_or_default().with_diagnostics(detailed),
+            )
+        })?;
+        Ok(results
+            .into_iter()
+            .map(|results| sample_results_into_py(py, results, detailed, return_orders))
+            .collect())
+    }
+
+    /// Extend the query by up to k characters, picking the most likely token under a
+    /// Kneser-Ney smoothed n-gram model at each step. Returns the sequence and the sum of the
+    /// log-probabilities of its new tokens.
+    #[pyo3(signature = (query, n, k, config=None))]
+    pub fn decode_greedy(
+        &self,
+        py: Python<'_>,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        config: Option<SamplingConfig>,
+    ) -> Result<(Vec<usize>, f64)> {
+        py.allow_threads(|| {
+            self.index
+                .decode_greedy(query, n, k, &config.unwrap_or_default())
+        })
+    }
+
+    /// Beam search for the beam_width most likely extensions of the query by up to k
+    /// characters under a Kneser-Ney smoothed n-gram model. Returns (sequence, score) pairs in
+    /// decreasing order of score, the sum of the log-probabilities of the new tokens.
+    #[pyo3(signature = (query, n, k, beam_width=4, config=None))]
+    pub fn beam_search(
+        &self,
+        py: Python<'_>,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        beam_width: usize,
+        config: Option<SamplingConfig>,
+    ) -> Result<Vec<(Vec<usize>, f64)>> {
+        py.allow_threads(|| {
+            self.index
+                .beam_search(query, n, k, beam_width, &config.unwrap_or_default())
+        })
+    }
+
+    /// Warning: O(k**n) where k is vocabulary size, use with caution.
+    /// Improve smoothed model quality by replacing the default delta hyperparameters
+    /// for models of order n and below with improved estimates over the entire index.
+    /// <https://people.eecs.berkeley.edu/~klein/cs294-5/chen_goodman.pdf/>, page 16.
+    pub fn estimate_deltas(&self, py: Python<'_>, n: usize) {
+        py.allow_threads(|| {
+            self.index.estimate_deltas(n);
+        })
+    }
+}
@@ -1,5 +1,12 @@
+use crate::bindings::position_pages::{positions_array, PositionPages};
+use crate::bindings::sample_result::{check_output_flags, sample_results_into_py};
+use crate::count_cache::CacheStats;
+use crate::error::Result;
 use crate::in_memory_index::InMemoryIndexRs;
-use anyhow::Result;
+use crate::metadata::{self, resolve_token_type};
+use crate::query::{convert_tokens, OutOfVocab};
+use crate::sample::{SampleRequest, SampleResult};
+use crate::sampling_config::SamplingConfig;
 use pyo3::prelude::*;
 
 /// An in-memory index exposes suffix table functionality over text corpora small enough to fit in memory.
@@ -14,165 +21,496 @@ pub struct InMemoryIndex {
 pub trait InMemoryIndexTrait {
     fn save_text(&self, path: String) -> Result<()>;
     fn save_table(&self, path: String) -> Result<()>;
+    fn out_of_vocab(&self) -> OutOfVocab;
+    fn set_out_of_vocab(&mut self, policy: OutOfVocab);
     fn is_sorted(&self) -> bool;
-    fn contains(&self, query: Vec<usize>) -> bool;
-    fn positions(&self, query: Vec<usize>) -> Vec<u64>;
-    fn count(&self, query: Vec<usize>) -> usize;
-    fn count_next(&self, query: Vec<usize>) -> Vec<usize>;
-    fn batch_count_next(&self, queries: Vec<Vec<usize>>) -> Vec<Vec<usize>>;
+    fn save_cache(&self, path: String) -> Result<()>;
+    fn load_cache(&mut self, path: String) -> Result<()>;
+    fn count_cache_capacity(&self) -> usize;
+    fn set_count_cache_capacity(&mut self, capacity: usize);
+    fn count_cache_stats(&self) -> CacheStats;
+    fn warm_count_cache(&self, top_m: usize, max_len: usize);
+    fn contains(&self, query: Vec<usize>) -> Result<bool>;
+    fn positions(
+        &self,
+        query: Vec<usize>,
+        offset: usize,
+        limit: Option<usize>,
+        sorted: bool,
+    ) -> Result<Vec<u64>>;
+    fn sample_positions(&self, query: Vec<usize>, k: usize, seed: Option<u64>) -> Result<Vec<u64>>;
+    fn count(&self, query: Vec<usize>) -> Result<usize>;
+    fn count_next(&self, query: Vec<usize>) -> Result<Vec<usize>>;
+    fn approx_count_next(
+        &self,
+        query: Vec<usize>,
+        sample_size: usize,
+        seed: Option<u64>,
+    ) -> Result<(Vec<f64>, Vec<f64>)>;
+    fn batch_contains(&self, queries: Vec<Vec<usize>>) -> Result<Vec<bool>>;
+    fn batch_count(&self, queries: Vec<Vec<usize>>) -> Result<Vec<usize>>;
+    fn batch_positions(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<u64>>>;
+    fn batch_count_next(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<usize>>>;
     fn sample_unsmoothed(
         &self,
         query: Vec<usize>,
         n: usize,
         k: usize,
         num_samples: usize,
-    ) -> Result<Vec<Vec<usize>>>;
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<SampleResult>>;
     fn sample_smoothed(
-        &mut self,
+        &self,
         query: Vec<usize>,
         n: usize,
         k: usize,
         num_samples: usize,
-    ) -> Result<Vec<Vec<usize>>>;
-    fn get_smoothed_probs(&mut self, query: Vec<usize>) -> Vec<f64>;
-    fn batch_get_smoothed_probs(&mut self, queries: Vec<Vec<usize>>) -> Vec<Vec<f64>>;
-    fn estimate_deltas(&mut self, n: usize);
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<SampleResult>>;
+    fn batch_sample(
+        &self,
+        requests: Vec<SampleRequest<usize>>,
+        smoothed: bool,
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<Vec<SampleResult>>>;
+    fn decode_greedy(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        config: &SamplingConfig,
+    ) -> Result<(Vec<usize>, f64)>;
+    fn beam_search(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        beam_width: usize,
+        config: &SamplingConfig,
+    ) -> Result<Vec<(Vec<usize>, f64)>>;
+    fn get_smoothed_probs(&self, query: Vec<usize>) -> Result<Vec<f64>>;
+    fn batch_get_smoothed_probs(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<f64>>>;
+    fn estimate_deltas(&self, n: usize);
 }
 
 #[pymethods]
 impl InMemoryIndex {
     #[new]
-    #[pyo3(signature = (tokens, vocab=u16::MAX as usize + 1, verbose=false))]
-    pub fn new_py(_py: Python, tokens: Vec<usize>, vocab: usize, verbose: bool) -> Self {
-        let index: Box<dyn InMemoryIndexTrait + Send + Sync> = if vocab <= u16::MAX as usize + 1 {
-            let tokens: Vec<u16> = tokens.iter().map(|&x| x as u16).collect();
-            Box::new(InMemoryIndexRs::<u16>::new(tokens, Some(vocab), verbose))
-        } else {
-            let tokens: Vec<u32> = tokens.iter().map(|&x| x as u32).collect();
-            Box::new(InMemoryIndexRs::<u32>::new(tokens, Some(vocab), verbose))
-        };
+    #[pyo3(signature = (tokens, vocab=u16::MAX as usize + 1, verbose=false, token_width=None))]
+    pub fn new_py(
+        py: Python<'_>,
+        tokens: Vec<usize>,
+        vocab: usize,
+        verbose: bool,
+        token_width: Option<usize>,
+    ) -> Result<Self> {
+        py.allow_threads(|| {
+            let index: Box<dyn InMemoryIndexTrait + Send + Sync> = with_token_type!(metadata::token_width(vocab, token_width)?, T => {
+                let tokens: Vec<T> = convert_tokens(&tokens, vocab)?;
+                Box::new(InMemoryIndexRs::<T>::from_checked_tokens(tokens, vocab, verbose))
+            });
+
+            Ok(InMemoryIndex { index })
+        })
+    }
 
-        InMemoryIndex { index }
+    /// Builds a byte-level index over raw bytes, such as UTF-8 text, with a vocabulary of 256.
+    #[staticmethod]
+    #[pyo3(signature = (data, verbose=false))]
+    pub fn from_bytes(py: Python<'_>, data: &[u8], verbose: bool) -> Result<Self> {
+        py.allow_threads(|| {
+            Ok(InMemoryIndex {
+                index: Box::new(InMemoryIndexRs::<u8>::new(
+                    data.to_vec(),
+                    Some(u8::MAX as usize + 1),
+                    verbose,
+                )?),
+            })
+        })
     }
 
     #[staticmethod]
-    #[pyo3(signature = (path, token_limit=None, vocab=u16::MAX as usize + 1, verbose=false))]
+    #[pyo3(signature = (path, token_limit=None, vocab=u16::MAX as usize + 1, verbose=false, token_width=None))]
     pub fn from_token_file(
+        py: Python<'_>,
         path: String,
         token_limit: Option<usize>,
         vocab: usize,
         verbose: bool,
+        token_width: Option<usize>,
     ) -> Result<Self> {
-        if vocab <= u16::MAX as usize + 1 {
-            Ok(InMemoryIndex {
-                index: Box::new(InMemoryIndexRs::<u16>::from_token_file(
-                    path,
-                    token_limit,
-                    vocab,
-                    verbose,
-                )?),
-            })
-        } else {
-            Ok(InMemoryIndex {
-                index: Box::new(InMemoryIndexRs::<u32>::from_token_file(
-                    path,
-                    token_limit,
-                    vocab,
-                    verbose,
-                )?),
-            })
-        }
+        py.allow_threads(|| {
+            let index: Box<dyn InMemoryIndexTrait + Send + Sync> = with_token_type!(metadata::token_width(vocab, token_width)?, T => Box::new(
+                InMemoryIndexRs::<T>::from_token_file(path, token_limit, vocab, verbose)?,
+            ));
+
+            Ok(InMemoryIndex { index })
+        })
     }
 
     #[staticmethod]
-    #[pyo3(signature = (token_path, index_path, vocab=u16::MAX as usize + 1))]
-    pub fn from_disk(token_path: String, index_path: String, vocab: usize) -> Result<Self> {
-        if vocab <= u16::MAX as usize + 1 {
-            Ok(InMemoryIndex {
-                index: Box::new(InMemoryIndexRs::<u16>::from_disk(
-                    token_path, index_path, vocab,
-                )?),
-            })
-        } else {
-            Ok(InMemoryIndex {
-                index: Box::new(InMemoryIndexRs::<u32>::from_disk(
-                    token_path, index_path, vocab,
-                )?),
-            })
-        }
+    #[pyo3(signature = (token_path, index_path, vocab=None))]
+    pub fn from_disk(
+        py: Python<'_>,
+        token_path: String,
+        index_path: String,
+        vocab: Option<usize>,
+    ) -> Result<Self> {
+        py.allow_threads(|| {
+            let (token_width, vocab) = resolve_token_type(&index_path, vocab)?;
+            let index: Box<dyn InMemoryIndexTrait + Send + Sync> = with_token_type!(token_width, T => {
+                Box::new(InMemoryIndexRs::<T>::from_disk(token_path, index_path, vocab)?)
+            });
+
+            Ok(InMemoryIndex { index })
+        })
+    }
+
+    pub fn save_tokens(&self, py: Python<'_>, path: String) -> Result<()> {
+        py.allow_threads(|| self.index.save_text(path))
+    }
+
+    pub fn save_index(&self, py: Python<'_>, path: String) -> Result<()> {
+        py.allow_threads(|| self.index.save_table(path))
+    }
+
+    /// How query tokens outside the vocabulary are handled: "error" raises
+    /// `TokenOutOfVocabError` and "miss" treats them as tokens that never occur.
+    #[getter]
+    pub fn out_of_vocab(&self) -> &'static str {
+        self.index.out_of_vocab().as_str()
+    }
+
+    #[setter]
+    pub fn set_out_of_vocab(&mut self, policy: &str) -> Result<()> {
+        self.index.set_out_of_vocab(policy.parse()?);
+        Ok(())
+    }
+
+    pub fn is_sorted(&self, py: Python<'_>) -> bool {
+        py.allow_threads(|| self.index.is_sorted())
+    }
+
+    /// The maximum number of contexts whose `count_next` results are cached. Zero, the
+    /// default, disables the cache. Setting the capacity clears the cache and its statistics.
+    #[getter]
+    pub fn count_cache_capacity(&self) -> usize {
+        self.index.count_cache_capacity()
     }
 
-    pub fn save_tokens(&self, path: String) -> Result<()> {
-        self.index.save_text(path)
+    #[setter]
+    pub fn set_count_cache_capacity(&mut self, capacity: usize) {
+        self.index.set_count_cache_capacity(capacity);
     }
 
-    pub fn save_index(&self, path: String) -> Result<()> {
-        self.index.save_table(path)
+    /// Hit and miss statistics of the `count_next` cache.
+    pub fn count_cache_stats(&self) -> CacheStats {
+        self.index.count_cache_stats()
     }
 
-    pub fn is_sorted(&self) -> bool {
-        self.index.is_sorted()
+    /// Fill the `count_next` cache with the `top_m` most frequent contexts of at most `max_len`
+    /// tokens.
+    #[pyo3(signature = (top_m, max_len=2))]
+    pub fn warm_count_cache(&self, py: Python<'_>, top_m: usize, max_len: usize) {
+        py.allow_threads(|| self.index.warm_count_cache(top_m, max_len))
     }
 
-    pub fn contains(&self, query: Vec<usize>) -> bool {
-        self.index.contains(query)
+    pub fn contains(&self, py: Python<'_>, query: Vec<usize>) -> Result<bool> {
+        py.allow_threads(|| self.index.contains(query))
+    }
+
+    /// Returns the positions where `query` starts in the tokens as a numpy array, skipping the
+    /// first `offset` and keeping at most `limit`. Positions are in index order unless `sorted`,
+    /// in which case they are in corpus order and every page costs a pass over all matches.
+    #[pyo3(signature = (query, offset=0, limit=None, sorted=false))]
+    pub fn positions(
+        &self,
+        py: Python<'_>,
+        query: Vec<usize>,
+        offset: usize,
+        limit: Option<usize>,
+        sorted: bool,
+    ) -> PyResult<PyObject> {
+        let positions = py.allow_threads(|| self.index.positions(query, offset, limit, sorted))?;
+        positions_array(py, &positions)
     }
 
-    pub fn positions(&self, query: Vec<usize>) -> Vec<u64> {
-        self.index.positions(query).to_vec()
+    /// Iterate over the positions where `query` starts in the tokens in numpy arrays of at most
+    /// `page_size` positions, fetching each page only when it is reached. In corpus order,
+    /// every position is fetched and sorted once with the first page.
+    #[pyo3(signature = (query, page_size=1 << 20, sorted=false))]
+    pub fn iter_positions(
+        slf: Py<Self>,
+        query: Vec<usize>,
+        page_size: usize,
+        sorted: bool,
+    ) -> Result<PositionPages> {
+        PositionPages::new(slf.into_any(), query, page_size, sorted)
+    }
+
+    /// Returns `k` positions where `query` starts in the tokens as a numpy array in corpus order,
+    /// drawn uniformly at random without replacement, or every position if there are at most `k`.
+    #[pyo3(signature = (query, k, seed=None))]
+    pub fn sample_positions(
+        &self,
+        py: Python<'_>,
+        query: Vec<usize>,
+        k: usize,
+        seed: Option<u64>,
+    ) -> PyResult<PyObject> {
+        let positions = py.allow_threads(|| self.index.sample_positions(query, k, seed))?;
+        positions_array(py, &positions)
     }
 
-    pub fn count(&self, query: Vec<usize>) -> usize {
-        self.index.count(query)
+    pub fn count(&self, py: Python<'_>, query: Vec<usize>) -> Result<usize> {
+        py.allow_threads(|| self.index.count(query))
     }
 
-    pub fn count_next(&self, query: Vec<usize>) -> Vec<usize> {
-        self.index.count_next(query)
+    pub fn count_next(&self, py: Python<'_>, query: Vec<usize>) -> Result<Vec<usize>> {
+        py.allow_threads(|| self.index.count_next(query))
     }
 
-    pub fn batch_count_next(&self, queries: Vec<Vec<usize>>) -> Vec<Vec<usize>> {
-        self.index.batch_count_next(queries)
+    /// Estimate the occurrences of each token directly following `query` from `sample_size` of
+    /// the query's occurrences drawn uniformly at random, returning the estimates and their
+    /// standard errors.
+    #[pyo3(signature = (query, sample_size=1000, seed=None))]
+    pub fn approx_count_next(
+        &self,
+        py: Python<'_>,
+        query: Vec<usize>,
+        sample_size: usize,
+        seed: Option<u64>,
+    ) -> Result<(Vec<f64>, Vec<f64>)> {
+        py.allow_threads(|| self.index.approx_count_next(query, sample_size, seed))
+    }
+
+    /// Check whether each query occurs in the corpus. Queries are looked up in parallel in
+    /// sorted order, so that queries sharing a prefix share its search.
+    pub fn batch_contains(&self, py: Python<'_>, queries: Vec<Vec<usize>>) -> Result<Vec<bool>> {
+        py.allow_threads(|| self.index.batch_contains(queries))
+    }
+
+    /// Count the occurrences of each query in the corpus like `batch_contains`.
+    pub fn batch_count(&self, py: Python<'_>, queries: Vec<Vec<usize>>) -> Result<Vec<usize>> {
+        py.allow_threads(|| self.index.batch_count(queries))
+    }
+
+    /// Returns the unordered positions of each query in the corpus as numpy arrays, looked up
+    /// like `batch_contains`.
+    pub fn batch_positions(
+        &self,
+        py: Python<'_>,
+        queries: Vec<Vec<usize>>,
+    ) -> PyResult<Vec<PyObject>> {
+        let positions = py.allow_threads(|| self.index.batch_positions(queries))?;
+        positions
+            .iter()
+            .map(|positions| positions_array(py, positions))
+            .collect()
+    }
+
+    pub fn batch_count_next(
+        &self,
+        py: Python<'_>,
+        queries: Vec<Vec<usize>>,
+    ) -> Result<Vec<Vec<usize>>> {
+        py.allow_threads(|| self.index.batch_count_next(queries))
     }
 
-    /// Autoregressively sample num_samples of k characters from an unsmoothed n-gram model."""
+    /// Autoregressively sample num_samples of k characters from an unsmoothed n-gram model,
+    /// backing off to shorter contexts where the (n - 1)-token context has no continuations.
+    /// With `return_orders`, also returns the order of the n-gram completed by each sampled
+    /// token. With `detailed`, returns a `SampleResult` with diagnostics for each sample
+    /// instead, and the two flags cannot be combined."""
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (
+        query,
+        n,
+        k,
+        num_samples,
+        seed=None,
+        config=None,
+        return_orders=false,
+        detailed=false,
+    ))]
     pub fn sample_unsmoothed(
         &self,
+        py: Python<'_>,
         query: Vec<usize>,
         n: usize,
         k: usize,
         num_samples: usize,
-    ) -> Result<Vec<Vec<usize>>> {
-        self.index.sample_unsmoothed(query, n, k, num_samples)
+        seed: Option<u64>,
+        config: Option<SamplingConfig>,
+        return_orders: bool,
+        detailed: bool,
+    ) -> Result<PyObject> {
+        check_output_flags(detailed, return_orders)?;
+        let results = py.allow_threads(|| {
+            self.index.sample_unsmoothed(
+                query,
+                n,
+                k,
+                num_samples,
+                seed,
+                &config.unwrap_or_default().with_diagnostics(detailed),
+            )
+        })?;
+        Ok(sample_results_into_py(py, results, detailed, return_orders))
     }
 
     /// Returns interpolated Kneser-Ney smoothed token probability distribution using all previous
     /// tokens in the query.
-    pub fn get_smoothed_probs(&mut self, query: Vec<usize>) -> Vec<f64> {
-        self.index.get_smoothed_probs(query)
+    pub fn get_smoothed_probs(&self, py: Python<'_>, query: Vec<usize>) -> Result<Vec<f64>> {
+        py.allow_threads(|| self.index.get_smoothed_probs(query))
     }
 
     /// Returns interpolated Kneser-Ney smoothed token probability distribution using all previous
     /// tokens in the query.
-    pub fn batch_get_smoothed_probs(&mut self, queries: Vec<Vec<usize>>) -> Vec<Vec<f64>> {
-        self.index.batch_get_smoothed_probs(queries)
+    pub fn batch_get_smoothed_probs(
+        &self,
+        py: Python<'_>,
+        queries: Vec<Vec<usize>>,
+    ) -> Result<Vec<Vec<f64>>> {
+        py.allow_threads(|| self.index.batch_get_smoothed_probs(queries))
     }
 
     /// Autoregressively sample num_samples of k characters from a Kneser-Ney smoothed n-gram model.
+    /// With `detailed`, returns a `SampleResult` with diagnostics for each sample.
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (query, n, k, num_samples, seed=None, config=None, detailed=false))]
     pub fn sample_smoothed(
-        &mut self,
+        &self,
+        py: Python<'_>,
         query: Vec<usize>,
         n: usize,
         k: usize,
         num_samples: usize,
-    ) -> Result<Vec<Vec<usize>>> {
-        self.index.sample_smoothed(query, n, k, num_samples)
+        seed: Option<u64>,
+        config: Option<SamplingConfig>,
+        detailed: bool,
+    ) -> Result<PyObject> {
+        let results = py.allow_threads(|| {
+            self.index.sample_smoothed(
+                query,
+                n,
+                k,
+                num_samples,
+                seed,
+                &config.unwrap_or_default().with_diagnostics(detailed),
+            )
+        })?;
+        Ok(sample_results_into_py(py, results, detailed, false))
+    }
+
+    /// Sample from many prompts in one parallel job. Each request is a `(query, n, k,
+    /// num_samples)` tuple, and a list of samples is returned for each request, formatted as
+    /// by `sample_unsmoothed`. Samples are drawn from a Kneser-Ney smoothed n-gram model if
+    /// `smoothed`, and otherwise from an unsmoothed one. Given a seed, samples are numbered
+    /// consecutively across requests, so a lone request draws the same samples as the
+    /// single-prompt methods.
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (
+        requests,
+        smoothed=false,
+        seed=None,
+        config=None,
+        return_orders=false,
+        detailed=false,
+    ))]
+    pub fn batch_sample(
+        &self,
+        py: Python<'_>,
+        requests: Vec<(Vec<usize>, usize, usize, usize)>,
+        smoothed: bool,
+        seed: Option<u64>,
+        config: Option<SamplingConfig>,
+        return_orders: bool,
+        detailed: bool,
+    ) -> Result<Vec<PyObject>> {
+        check_output_flags(detailed, return_orders)?;
+        let requests = requests
+            .into_iter()
+            .map(|(query, n, k, num_samples)| SampleRequest {
+                query,
+                n,
+                k,
+                num_samples,
+            })
+            .collect();
+        let results = py.allow_threads(|| {
+            self.index.batch_sample(
+                requests,
+                smoothed,
+                seed,
+                &config.unwrap_or_default().with_diagnostics(detailed),
+            )
+        })?;
+        Ok(results
+            .into_iter()
+            .map(|results| sample_results_into_py(py, results, detailed, return_orders))
+            .collect())
+    }
+
+    /// Extend the query by up to k characters, picking the most likely token under a
+    /// Kneser-Ney smoothed n-gram model at each step. Returns the sequence and the sum of the
+    /// log-probabilities of its new tokens.
+    #[pyo3(signature = (query, n, k, config=None))]
+    pub fn decode_greedy(
+        &self,
+        py: Python<'_>,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        config: Option<SamplingConfig>,
+    ) -> Result<(Vec<usize>, f64)> {
+        py.allow_threads(|| {
+            self.index
+                .decode_greedy(query, n, k, &config.unwrap_or_default())
+        })
+    }
+
+    /// Beam search for the beam_width most likely extensions of the query by up to k
+    /// characters under a Kneser-Ney smoothed n-gram model. Returns (sequence, score) pairs in
+    /// decreasing order of score, the sum of the log-probabilities of the new tokens.
+    #[pyo3(signature = (query, n, k, beam_width=4, config=None))]
+    pub fn beam_search(
+        &self,
+        py: Python<'_>,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        beam_width: usize,
+        config: Option<SamplingConfig>,
+    ) -> Result<Vec<(Vec<usize>, f64)>> {
+        py.allow_threads(|| {
+            self.index
+                .beam_search(query, n, k, beam_width, &config.unwrap_or_default())
+        })
+    }
+
+    /// Save the smoothing statistics computed so far, such as those from `estimate_deltas`,
+    /// together with a fingerprint of the corpus.
+    pub fn save_cache(&self, py: Python<'_>, path: String) -> Result<()> {
+        py.allow_threads(|| self.index.save_cache(path))
+    }
+
+    /// Load smoothing statistics saved by `save_cache`, raising `InvalidMetadataError` if they
+    /// were computed from a different corpus.
+    pub fn load_cache(&mut self, py: Python<'_>, path: String) -> Result<()> {
+        py.allow_threads(|| self.index.load_cache(path))
     }
 
     /// Warning: O(k**n) where k is vocabulary size, use with caution.
     /// Improve smoothed model quality by replacing the default delta hyperparameters
     /// for models of order n and below with improved estimates over the entire index.
     /// <https://people.eecs.berkeley.edu/~klein/cs294-5/chen_goodman.pdf/>, page 16.
-    pub fn estimate_deltas(&mut self, n: usize) {
-        self.index.estimate_deltas(n);
+    pub fn estimate_deltas(&self, py: Python<'_>, n: usize) {
+        py.allow_threads(|| {
+            self.index.estimate_deltas(n);
+        })
     }
 }
@@ -0,0 +1,31 @@
+use crate::verify::IntegrityIssue;
+use pyo3::prelude::*;
+
+#[pymethods]
+impl IntegrityIssue {
+    /// One of "length_mismatch", "out_of_range", "duplicate", "unsorted",
+    /// "text_checksum_mismatch" or "table_checksum_mismatch".
+    #[getter]
+    pub fn kind(&self) -> &'static str {
+        self.kind.as_str()
+    }
+
+    #[getter]
+    pub fn rank(&self) -> Option<usize> {
+        self.rank
+    }
+
+    #[getter]
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    #[getter]
+    pub fn shard(&self) -> Option<usize> {
+        self.shard
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!("IntegrityIssue({})", self)
+    }
+}
@@ -1,3 +1,37 @@
+// pyo3 0.22 converts the errors of methods returning `PyResult` into `PyErr` again in the
+// wrappers it generates for them.
+#![allow(clippy::useless_conversion)]
+
+/// Evaluates `$body` with `$T` bound to the token type with the given width in bytes, returning
+/// an error from the enclosing function if the width is unsupported.
+macro_rules! with_token_type {
+    ($width:expr, $T:ident => $body:expr) => {
+        match $width {
+            1 => {
+                type $T = u8;
+                $body
+            }
+            2 => {
+                type $T = u16;
+                $body
+            }
+            4 => {
+                type $T = u32;
+                $body
+            }
+            width => return Err($crate::metadata::unsupported_token_width(width)),
+        }
+    };
+}
+
+pub mod cache_stats;
+pub mod fm_index;
 pub mod in_memory_index;
+pub mod integrity_issue;
 pub mod memmap_index;
+pub mod position_pages;
+pub mod sample_result;
+pub mod sampling_config;
 pub mod sharded_memmap_index;
+pub mod sparse_memmap_index;
+pub mod token_automaton;
@@ -0,0 +1,91 @@
+use crate::error::{Result, TokengramsError};
+use pyo3::buffer::PyBuffer;
+use pyo3::prelude::*;
+use pyo3::types::PySlice;
+
+/// Copies positions into a new numpy array of unsigned 64-bit integers, raising `ImportError`
+/// if numpy is not installed.
+pub fn positions_array(py: Python<'_>, positions: &[u64]) -> PyResult<PyObject> {
+    let array = py
+        .import_bound("numpy")?
+        .call_method1("empty", (positions.len(), "uint64"))?;
+    PyBuffer::<u64>::get_bound(&array)?.copy_from_slice(py, positions)?;
+    Ok(array.unbind())
+}
+
+/// An iterator over the positions of a query in pages of at most `page_size` positions. In
+/// index order each page is fetched from the index with `positions` when it is reached, while
+/// in corpus order every position is fetched and sorted once and pages are slices of the result.
+#[pyclass]
+pub struct PositionPages {
+    index: PyObject,
+    query: Vec<usize>,
+    offset: usize,
+    page_size: usize,
+    sorted: bool,
+    /// Every position in corpus order, fetched with the first sorted page.
+    sorted_positions: Option<PyObject>,
+    done: bool,
+}
+
+impl PositionPages {
+    pub fn new(index: PyObject, query: Vec<usize>, page_size: usize, sorted: bool) -> Result<Self> {
+        if page_size == 0 {
+            return Err(TokengramsError::InvalidArgument(
+                "Page size must be greater than zero".to_string(),
+            ));
+        }
+        Ok(PositionPages {
+            index,
+            query,
+            offset: 0,
+            page_size,
+            sorted,
+            sorted_positions: None,
+            done: false,
+        })
+    }
+}
+
+#[pymethods]
+impl PositionPages {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        if self.done {
+            return Ok(None);
+        }
+        let page = if self.sorted {
+            let positions = match &self.sorted_positions {
+                Some(positions) => positions,
+                None => self.sorted_positions.insert(self.index.call_method1(
+                    py,
+                    "positions",
+                    (self.query.clone(), 0, None::<usize>, true),
+                )?),
+            };
+            let end = self.offset.saturating_add(self.page_size);
+            let page = PySlice::new_bound(py, clamp_index(self.offset), clamp_index(end), 1);
+            positions.call_method1(py, "__getitem__", (page,))?
+        } else {
+            self.index.call_method1(
+                py,
+                "positions",
+                (self.query.clone(), self.offset, self.page_size, false),
+            )?
+        };
+        let len = page.bind(py).len()?;
+        self.offset += len;
+        self.done = len < self.page_size;
+        if self.done {
+            self.sorted_positions = None;
+        }
+        Ok((len > 0).then_some(page))
+    }
+}
+
+fn clamp_index(index: usize) -> isize {
+    index.min(isize::MAX as usize) as isize
+}
@@ -0,0 +1,78 @@
+use crate::error::{Result, TokengramsError};
+use crate::sample::SampleResult;
+use pyo3::prelude::*;
+
+#[pymethods]
+impl SampleResult {
+    /// The prompt followed by the sampled tokens.
+    #[getter]
+    pub fn tokens(&self) -> Vec<usize> {
+        self.tokens.clone()
+    }
+
+    /// Natural log-probability of each sampled token under the model, before decoding options.
+    #[getter]
+    pub fn log_probs(&self) -> Vec<f64> {
+        self.log_probs.clone()
+    }
+
+    /// Number of context tokens the distribution of each sampled token was conditioned on, the
+    /// longest suffix of the context followed by any token in the corpus.
+    #[getter]
+    pub fn context_lens(&self) -> Vec<usize> {
+        self.context_lens.clone()
+    }
+
+    /// Number of times each context was followed by any token in the corpus.
+    #[getter]
+    pub fn context_counts(&self) -> Vec<usize> {
+        self.context_counts.clone()
+    }
+
+    /// Entropy in nats of the model distribution each token was drawn from, before decoding
+    /// options.
+    #[getter]
+    pub fn entropies(&self) -> Vec<f64> {
+        self.entropies.clone()
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!("SampleResult({})", self)
+    }
+}
+
+/// Returns an error if both `detailed` and `return_orders` are set. Detailed results already
+/// carry the order of each sampled token as its context length plus one.
+pub fn check_output_flags(detailed: bool, return_orders: bool) -> Result<()> {
+    if detailed && return_orders {
+        return Err(TokengramsError::InvalidArgument(
+            "return_orders cannot be combined with detailed, whose context_lens give the orders"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Converts sample results to the return value of the Python sampling methods: the results
+/// themselves if `detailed`, and otherwise their token lists, paired with the order of the
+/// n-gram completed by each sampled token if `return_orders`. See `check_output_flags`.
+pub fn sample_results_into_py(
+    py: Python<'_>,
+    results: Vec<SampleResult>,
+    detailed: bool,
+    return_orders: bool,
+) -> PyObject {
+    if detailed {
+        return results.into_py(py);
+    }
+    let orders: Vec<Vec<usize>> = results
+        .iter()
+        .map(|result| result.context_lens.iter().map(|len| len + 1).collect())
+        .collect();
+    let tokens: Vec<Vec<usize>> = results.into_iter().map(|result| result.tokens).collect();
+    if return_orders {
+        (tokens, orders).into_py(py)
+    } else {
+        tokens.into_py(py)
+    }
+}
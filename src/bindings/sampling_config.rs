@@ -0,0 +1,81 @@
+use crate::automaton::TokenAutomaton;
+use crate::error::Result;
+use crate::sampling_config::SamplingConfig;
+use pyo3::prelude::*;
+use std::sync::Arc;
+
+#[pymethods]
+impl SamplingConfig {
+    #[new]
+    #[pyo3(signature = (
+        temperature=1.0,
+        top_k=None,
+        top_p=None,
+        min_count=0,
+        banned_tokens=Vec::new(),
+        stop_tokens=Vec::new(),
+        automaton=None,
+    ))]
+    pub fn new(
+        temperature: f64,
+        top_k: Option<usize>,
+        top_p: Option<f64>,
+        min_count: usize,
+        banned_tokens: Vec<usize>,
+        stop_tokens: Vec<usize>,
+        automaton: Option<TokenAutomaton>,
+    ) -> Result<Self> {
+        let config = SamplingConfig {
+            temperature,
+            top_k,
+            top_p,
+            min_count,
+            banned_tokens,
+            stop_tokens,
+            automaton: automaton.map(Arc::new),
+            diagnostics: false,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Zero always picks the most likely token.
+    #[getter]
+    pub fn temperature(&self) -> f64 {
+        self.temperature
+    }
+
+    #[getter]
+    pub fn top_k(&self) -> Option<usize> {
+        self.top_k
+    }
+
+    #[getter]
+    pub fn top_p(&self) -> Option<f64> {
+        self.top_p
+    }
+
+    #[getter]
+    pub fn min_count(&self) -> usize {
+        self.min_count
+    }
+
+    #[getter]
+    pub fn banned_tokens(&self) -> Vec<usize> {
+        self.banned_tokens.clone()
+    }
+
+    #[getter]
+    pub fn stop_tokens(&self) -> Vec<usize> {
+        self.stop_tokens.clone()
+    }
+
+    #[getter]
+    pub fn automaton(&self) -> Option<TokenAutomaton> {
+        self.automaton.as_deref().cloned()
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!("SamplingConfig({})", self)
+    }
+}
@@ -1,5 +1,13 @@
+use crate::bindings::position_pages::positions_array;
+use crate::bindings::sample_result::{check_output_flags, sample_results_into_py};
+use crate::count_cache::CacheStats;
+use crate::error::Result;
+use crate::metadata::{self, default_token_type, resolve_token_type};
+use crate::query::OutOfVocab;
+use crate::sample::{SampleRequest, SampleResult};
+use crate::sampling_config::SamplingConfig;
 use crate::sharded_memmap_index::ShardedMemmapIndexRs;
-use anyhow::Result;
+use crate::verify::IntegrityIssue;
 use pyo3::prelude::*;
 
 /// Expose suffix table functionality over text corpora too large to fit in memory.
@@ -11,119 +19,471 @@ pub struct ShardedMemmapIndex {
 /// This trait is non-generic for PyO3 compatibility. Implementing structs may cast data
 /// to other unsigned integer types.
 pub trait ShardedMemmapIndexTrait {
+    fn out_of_vocab(&self) -> OutOfVocab;
+    fn set_out_of_vocab(&mut self, policy: OutOfVocab);
     fn is_sorted(&self) -> bool;
-    fn contains(&self, query: Vec<usize>) -> bool;
-    fn count(&self, query: Vec<usize>) -> usize;
-    fn count_next(&self, query: Vec<usize>) -> Vec<usize>;
-    fn batch_count_next(&self, queries: Vec<Vec<usize>>) -> Vec<Vec<usize>>;
+    fn save_cache(&self, path: String) -> Result<()>;
+    fn load_cache(&mut self, path: String) -> Result<()>;
+    fn count_cache_capacity(&self) -> usize;
+    fn set_count_cache_capacity(&mut self, capacity: usize);
+    fn count_cache_stats(&self) -> CacheStats;
+    fn warm_count_cache(&self, top_m: usize, max_len: usize);
+    fn build_buckets(&mut self, depth: usize) -> Result<()>;
+    fn build_counts(&mut self) -> Result<()>;
+    fn verify(&self, sample: Option<usize>) -> Vec<IntegrityIssue>;
+    fn contains(&self, query: Vec<usize>) -> Result<bool>;
+    fn count(&self, query: Vec<usize>) -> Result<usize>;
+    fn count_next(&self, query: Vec<usize>) -> Result<Vec<usize>>;
+    fn approx_count_next(
+        &self,
+        query: Vec<usize>,
+        sample_size: usize,
+        seed: Option<u64>,
+    ) -> Result<(Vec<f64>, Vec<f64>)>;
+    fn batch_contains(&self, queries: Vec<Vec<usize>>) -> Result<Vec<bool>>;
+    fn batch_count(&self, queries: Vec<Vec<usize>>) -> Result<Vec<usize>>;
+    fn batch_positions(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<u64>>>;
+    fn batch_count_next(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<usize>>>;
     fn sample_unsmoothed(
         &self,
         query: Vec<usize>,
         n: usize,
         k: usize,
         num_samples: usize,
-    ) -> Result<Vec<Vec<usize>>>;
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<SampleResult>>;
     fn sample_smoothed(
-        &mut self,
+        &self,
         query: Vec<usize>,
         n: usize,
         k: usize,
         num_samples: usize,
-    ) -> Result<Vec<Vec<usize>>>;
-    fn get_smoothed_probs(&mut self, query: Vec<usize>) -> Vec<f64>;
-    fn batch_get_smoothed_probs(&mut self, queries: Vec<Vec<usize>>) -> Vec<Vec<f64>>;
-    fn estimate_deltas(&mut self, n: usize);
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<SampleResult>>;
+    fn batch_sample(
+        &self,
+        requests: Vec<SampleRequest<usize>>,
+        smoothed: bool,
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<Vec<SampleResult>>>;
+    fn decode_greedy(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        config: &SamplingConfig,
+    ) -> Result<(Vec<usize>, f64)>;
+    fn beam_search(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        beam_width: usize,
+        config: &SamplingConfig,
+    ) -> Result<Vec<(Vec<usize>, f64)>>;
+    fn get_smoothed_probs(&self, query: Vec<usize>) -> Result<Vec<f64>>;
+    fn batch_get_smoothed_probs(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<f64>>>;
+    fn estimate_deltas(&self, n: usize);
 }
 
 #[pymethods]
 impl ShardedMemmapIndex {
     #[new]
-    #[pyo3(signature = (paths, vocab=u16::MAX as usize + 1))]
-    pub fn new(_py: Python, paths: Vec<(String, String)>, vocab: usize) -> PyResult<Self> {
-        if vocab <= u16::MAX as usize + 1 {
-            Ok(ShardedMemmapIndex {
-                index: Box::new(ShardedMemmapIndexRs::<u16>::new(paths, vocab)?),
-            })
-        } else {
-            Ok(ShardedMemmapIndex {
-                index: Box::new(ShardedMemmapIndexRs::<u32>::new(paths, vocab)?),
-            })
-        }
+    #[pyo3(signature = (paths, vocab=None))]
+    pub fn new(py: Python<'_>, paths: Vec<(String, String)>, vocab: Option<usize>) -> Result<Self> {
+        py.allow_threads(|| {
+            let (token_width, vocab) = match paths.first() {
+                Some((_, table_path)) => resolve_token_type(table_path, vocab)?,
+                None => default_token_type(vocab),
+            };
+            let index: Box<dyn ShardedMemmapIndexTrait + Send + Sync> = with_token_type!(token_width, T => Box::new(ShardedMemmapIndexRs::<T>::new(paths, vocab)?));
+
+            Ok(ShardedMemmapIndex { index })
+        })
     }
 
     #[staticmethod]
-    #[pyo3(signature = (paths, vocab=u16::MAX as usize + 1, verbose=false))]
-    pub fn build(paths: Vec<(String, String)>, vocab: usize, verbose: bool) -> PyResult<Self> {
-        if vocab <= u16::MAX as usize + 1 {
-            Ok(ShardedMemmapIndex {
-                index: Box::new(ShardedMemmapIndexRs::<u16>::build(paths, vocab, verbose)?),
-            })
-        } else {
-            Ok(ShardedMemmapIndex {
-                index: Box::new(ShardedMemmapIndexRs::<u32>::build(paths, vocab, verbose)?),
+    #[pyo3(signature = (paths, vocab=u16::MAX as usize + 1, verbose=false, token_width=None))]
+    pub fn build(
+        py: Python<'_>,
+        paths: Vec<(String, String)>,
+        vocab: usize,
+        verbose: bool,
+        token_width: Option<usize>,
+    ) -> Result<Self> {
+        py.allow_threads(|| {
+            let index: Box<dyn ShardedMemmapIndexTrait + Send + Sync> = with_token_type!(metadata::token_width(vocab, token_width)?, T => Box::new(
+                ShardedMemmapIndexRs::<T>::build(paths, vocab, verbose)?,
+            ));
+
+            Ok(ShardedMemmapIndex { index })
+        })
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (token_path, output_prefix, shard_size, eod_token=None, vocab=u16::MAX as usize + 1, verbose=false, token_width=None))]
+    #[allow(clippy::too_many_arguments)]
+    pub fn build_from_token_file(
+        py: Python<'_>,
+        token_path: String,
+        output_prefix: String,
+        shard_size: usize,
+        eod_token: Option<usize>,
+        vocab: usize,
+        verbose: bool,
+        token_width: Option<usize>,
+    ) -> Result<Self> {
+        py.allow_threads(|| {
+            let index: Box<dyn ShardedMemmapIndexTrait + Send + Sync> = with_token_type!(metadata::token_width(vocab, token_width)?, T => Box::new(
+                ShardedMemmapIndexRs::<T>::build_from_token_file(
+                    token_path,
+                    output_prefix,
+                    shard_size,
+                    eod_token,
+                    vocab,
+                    verbose,
+                )?,
+            ));
+
+            Ok(ShardedMemmapIndex { index })
+        })
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (token_path, output_prefix, shard_size, eod_token=None, vocab=u16::MAX as usize + 1, token_width=None))]
+    pub fn split_token_file(
+        py: Python<'_>,
+        token_path: String,
+        output_prefix: String,
+        shard_size: usize,
+        eod_token: Option<usize>,
+        vocab: usize,
+        token_width: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        py.allow_threads(|| {
+            with_token_type!(metadata::token_width(vocab, token_width)?, T => {
+                ShardedMemmapIndexRs::<T>::split_token_file(
+                    token_path,
+                    output_prefix,
+                    shard_size,
+                    eod_token,
+                )
             })
-        }
+        })
+    }
+
+    /// How query tokens outside the vocabulary are handled: "error" raises
+    /// `TokenOutOfVocabError` and "miss" treats them as tokens that never occur.
+    #[getter]
+    pub fn out_of_vocab(&self) -> &'static str {
+        self.index.out_of_vocab().as_str()
+    }
+
+    #[setter]
+    pub fn set_out_of_vocab(&mut self, policy: &str) -> Result<()> {
+        self.index.set_out_of_vocab(policy.parse()?);
+        Ok(())
+    }
+
+    pub fn is_sorted(&self, py: Python<'_>) -> bool {
+        py.allow_threads(|| self.index.is_sorted())
+    }
+
+    /// The maximum number of contexts whose `count_next` results are cached. Zero, the
+    /// default, disables the cache. Setting the capacity clears the cache and its statistics.
+    #[getter]
+    pub fn count_cache_capacity(&self) -> usize {
+        self.index.count_cache_capacity()
+    }
+
+    #[setter]
+    pub fn set_count_cache_capacity(&mut self, capacity: usize) {
+        self.index.set_count_cache_capacity(capacity);
+    }
+
+    /// Hit and miss statistics of the `count_next` cache.
+    pub fn count_cache_stats(&self) -> CacheStats {
+        self.index.count_cache_stats()
+    }
+
+    /// Fill the `count_next` cache with the `top_m` most frequent contexts of at most `max_len`
+    /// tokens.
+    #[pyo3(signature = (top_m, max_len=2))]
+    pub fn warm_count_cache(&self, py: Python<'_>, top_m: usize, max_len: usize) {
+        py.allow_threads(|| self.index.warm_count_cache(top_m, max_len))
+    }
+
+    /// Build and save a lookup table for every shard, see `MemmapIndex.build_buckets`.
+    #[pyo3(signature = (depth=1))]
+    pub fn build_buckets(&mut self, py: Python<'_>, depth: usize) -> Result<()> {
+        py.allow_threads(|| self.index.build_buckets(depth))
     }
 
-    pub fn is_sorted(&self) -> bool {
-        self.index.is_sorted()
+    /// Build and save unigram and bigram counts for every shard, see `MemmapIndex.build_counts`.
+    pub fn build_counts(&mut self, py: Python<'_>) -> Result<()> {
+        py.allow_threads(|| self.index.build_counts())
     }
 
-    pub fn contains(&self, query: Vec<usize>) -> bool {
-        self.index.contains(query)
+    /// Check every shard's files, returning the first problem found in each failing shard.
+    #[pyo3(signature = (sample=None))]
+    pub fn verify(&self, py: Python<'_>, sample: Option<usize>) -> Vec<IntegrityIssue> {
+        py.allow_threads(|| self.index.verify(sample))
     }
 
-    pub fn count(&self, query: Vec<usize>) -> usize {
-        self.index.count(query)
+    pub fn contains(&self, py: Python<'_>, query: Vec<usize>) -> Result<bool> {
+        py.allow_threads(|| self.index.contains(query))
     }
 
-    pub fn count_next(&self, query: Vec<usize>) -> Vec<usize> {
-        self.index.count_next(query)
+    pub fn count(&self, py: Python<'_>, query: Vec<usize>) -> Result<usize> {
+        py.allow_threads(|| self.index.count(query))
     }
 
-    pub fn batch_count_next(&self, queries: Vec<Vec<usize>>) -> Vec<Vec<usize>> {
-        self.index.batch_count_next(queries)
+    pub fn count_next(&self, py: Python<'_>, query: Vec<usize>) -> Result<Vec<usize>> {
+        py.allow_threads(|| self.index.count_next(query))
     }
 
-    /// Autoregressively sample num_samples of k characters from an unsmoothed n-gram model."""
+    /// Estimate the occurrences of each token directly following `query` from `sample_size` of
+    /// the query's occurrences drawn uniformly at random, returning the estimates and their
+    /// standard errors.
+    #[pyo3(signature = (query, sample_size=1000, seed=None))]
+    pub fn approx_count_next(
+        &self,
+        py: Python<'_>,
+        query: Vec<usize>,
+        sample_size: usize,
+        seed: Option<u64>,
+    ) -> Result<(Vec<f64>, Vec<f64>)> {
+        py.allow_threads(|| self.index.approx_count_next(query, sample_size, seed))
+    }
+
+    /// Check whether each query occurs in the corpus. Queries are looked up in parallel in
+    /// sorted order, so that queries sharing a prefix share its search.
+    pub fn batch_contains(&self, py: Python<'_>, queries: Vec<Vec<usize>>) -> Result<Vec<bool>> {
+        py.allow_threads(|| self.index.batch_contains(queries))
+    }
+
+    /// Count the occurrences of each query in the corpus like `batch_contains`.
+    pub fn batch_count(&self, py: Python<'_>, queries: Vec<Vec<usize>>) -> Result<Vec<usize>> {
+        py.allow_threads(|| self.index.batch_count(queries))
+    }
+
+    /// Returns the unordered positions of each query in the corpus as numpy arrays, looked up
+    /// like `batch_contains`.
+    /// Positions are offsets into the concatenation of the shards' token files, in shard order.
+    pub fn batch_positions(
+        &self,
+        py: Python<'_>,
+        queries: Vec<Vec<usize>>,
+    ) -> PyResult<Vec<PyObject>> {
+        let positions = py.allow_threads(|| self.index.batch_positions(queries))?;
+        positions
+            .iter()
+            .map(|positions| positions_array(py, positions))
+            .collect()
+    }
+
+    pub fn batch_count_next(
+        &self,
+        py: Python<'_>,
+        queries: Vec<Vec<usize>>,
+    ) -> Result<Vec<Vec<usize>>> {
+        py.allow_threads(|| self.index.batch_count_next(queries))
+    }
+
+    /// Autoregressively sample num_samples of k characters from an unsmoothed n-gram model,
+    /// backing off to shorter contexts where the (n - 1)-token context has no continuations.
+    /// With `return_orders`, also returns the order of the n-gram completed by each sampled
+    /// token. With `detailed`, returns a `SampleResult` with diagnostics for each sample
+    /// instead, and the two flags cannot be combined."""
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (
+        query,
+        n,
+        k,
+        num_samples,
+        seed=None,
+        config=None,
+        return_orders=false,
+        detailed=false,
+    ))]
     pub fn sample_unsmoothed(
         &self,
+        py: Python<'_>,
         query: Vec<usize>,
         n: usize,
         k: usize,
         num_samples: usize,
-    ) -> Result<Vec<Vec<usize>>> {
-        self.index.sample_unsmoothed(query, n, k, num_samples)
+        seed: Option<u64>,
+        config: Option<SamplingConfig>,
+        return_orders: bool,
+        detailed: bool,
+    ) -> Result<PyObject> {
+        check_output_flags(detailed, return_orders)?;
+        let results = py.allow_threads(|| {
+            self.index.sample_unsmoothed(
+                query,
+                n,
+                k,
+                num_samples,
+                seed,
+                &config.unwrap_or_default().with_diagnostics(detailed),
+            )
+        })?;
+        Ok(sample_results_into_py(py, results, detailed, return_orders))
     }
 
     /// Returns interpolated Kneser-Ney smoothed token probability distribution using all previous
     /// tokens in the query.
-    pub fn get_smoothed_probs(&mut self, query: Vec<usize>) -> Vec<f64> {
-        self.index.get_smoothed_probs(query)
+    pub fn get_smoothed_probs(&self, py: Python<'_>, query: Vec<usize>) -> Result<Vec<f64>> {
+        py.allow_threads(|| self.index.get_smoothed_probs(query))
     }
 
     /// Returns interpolated Kneser-Ney smoothed token probability distribution using all previous
     /// tokens in the query.
-    pub fn batch_get_smoothed_probs(&mut self, queries: Vec<Vec<usize>>) -> Vec<Vec<f64>> {
-        self.index.batch_get_smoothed_probs(queries)
+    pub fn batch_get_smoothed_probs(
+        &self,
+        py: Python<'_>,
+        queries: Vec<Vec<usize>>,
+    ) -> Result<Vec<Vec<f64>>> {
+        py.allow_threads(|| self.index.batch_get_smoothed_probs(queries))
     }
 
     /// Autoregressively sample num_samples of k characters from a Kneser-Ney smoothed n-gram model.
+    /// With `detailed`, returns a `SampleResult` with diagnostics for each sample.
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (query, n, k, num_samples, seed=None, config=None, detailed=false))]
     pub fn sample_smoothed(
-        &mut self,
+        &self,
+        py: Python<'_>,
         query: Vec<usize>,
         n: usize,
         k: usize,
         num_samples: usize,
-    ) -> Result<Vec<Vec<usize>>> {
-        self.index.sample_smoothed(query, n, k, num_samples)
+        seed: Option<u64>,
+        config: Option<SamplingConfig>,
+        detailed: bool,
+    ) -> Result<PyObject> {
+        let results = py.allow_threads(|| {
+            self.index.sample_smoothed(
+                query,
+                n,
+                k,
+                num_samples,
+                seed,
+                &config.unwrap_or_default().with_diagnostics(detailed),
+            )
+        })?;
+        Ok(sample_results_into_py(py, results, detailed, false))
+    }
+
+    /// Sample from many prompts in one parallel job. Each request is a `(query, n, k,
+    /// num_samples)` tuple, and a list of samples is returned for each request, formatted as
+    /// by `sample_unsmoothed`. Samples are drawn from a Kneser-Ney smoothed n-gram model if
+    /// `smoothed`, and otherwise from an unsmoothed one. Given a seed, samples are numbered
+    /// consecutively across requests, so a lone request draws the same samples as the
+    /// single-prompt methods.
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(signature = (
+        requests,
+        smoothed=false,
+        seed=None,
+        config=None,
+        return_orders=false,
+        detailed=false,
+    ))]
+    pub fn batch_sample(
+        &self,
+        py: Python<'_>,
+        requests: Vec<(Vec<usize>, usize, usize, usize)>,
+        smoothed: bool,
+        seed: Option<u64>,
+        config: Option<SamplingConfig>,
+        return_orders: bool,
+        detailed: bool,
+    ) -> Result<Vec<PyObject>> {
+        check_output_flags(detailed, return_orders)?;
+        let requests = requests
+            .into_iter()
+            .map(|(query, n, k, num_samples)| SampleRequest {
+                query,
+                n,
+                k,
+                num_samples,
+            })
+            .collect();
+        let results = py.allow_threads(|| {
+            self.index.batch_sample(
+                requests,
+                smoothed,
+                seed,
+                &config.unwrap_or_default().with_diagnostics(detailed),
+            )
+        })?;
+        Ok(results
+            .into_iter()
+            .map(|results| sample_results_into_py(py, results, detailed, return_orders))
+            .collect())
+    }
+
+    /// Extend the query by up to k characters, picking the most likely token under a
+    /// Kneser-Ney smoothed n-gram model at each step. Returns the sequence and the sum of the
+    /// log-probabilities of its new tokens.
+    #[pyo3(signature = (query, n, k, config=None))]
+    pub fn decode_greedy(
+        &self,
+        py: Python<'_>,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        config: Option<SamplingConfig>,
+    ) -> Result<(Vec<usize>, f64)> {
+        py.allow_threads(|| {
+            self.index
+                .decode_greedy(query, n, k, &config.unwrap_or_default())
+        })
+    }
+
+    /// Beam search for the beam_width most likely extensions of the query by up to k
+    /// characters under a Kneser-Ney smoothed n-gram model. Returns (sequence, score) pairs in
+    /// decreasing order of score, the sum of the log-probabilities of the new tokens.
+    #[pyo3(signature = (query, n, k, beam_width=4, config=None))]
+    pub fn beam_search(
+        &self,
+        py: Python<'_>,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        beam_width: usize,
+        config: Option<SamplingConfig>,
+    ) -> Result<Vec<(Vec<usize>, f64)>> {
+        py.allow_threads(|| {
+            self.index
+                .beam_search(query, n, k, beam_width, &config.unwrap_or_default())
+        })
+    }
+
+    /// Save the smoothing statistics computed so far, such as those from `estimate_deltas`,
+    /// together with a fingerprint of the corpus.
+    pub fn save_cache(&self, py: Python<'_>, path: String) -> Result<()> {
+        py.allow_threads(|| self.index.save_cache(path))
+    }
+
+    /// Load smoothing statistics saved by `save_cache`, raising `InvalidMetadataError` if they
+    /// were computed from a different corpus.
+    pub fn load_cache(&mut self, py: Python<'_>, path: String) -> Result<()> {
+        py.allow_threads(|| self.index.load_cache(path))
     }
 
     /// Warning: O(k**n) where k is vocabulary size, use with caution.
     /// Improve smoothed model quality by replacing the default delta hyperparameters
     /// for models of order n and below with improved estimates over the entire index.
     /// <https://people.eecs.berkeley.edu/~klein/cs294-5/chen_goodman.pdf/>, page 16.
-    pub fn estimate_deltas(&mut self, n: usize) {
-        self.index.estimate_deltas(n);
+    pub fn estimate_deltas(&self, py: Python<'_>, n: usize) {
+        py.allow_threads(|| {
+            self.index.estimate_deltas(n);
+        })
     }
 }
@@ -0,0 +1,165 @@
+use crate::bindings::position_pages::{positions_array, PositionPages};
+use crate::error::Result;
+use crate::metadata::{self, resolve_token_type};
+use crate::query::OutOfVocab;
+use crate::sparse_memmap_index::SparseMemmapIndexRs;
+use pyo3::prelude::*;
+
+/// A sparse memmap index stores only every `step`-th suffix of a corpus, using a table `step`
+/// times smaller than a memmap index at the cost of slower queries.
+///
+/// Queries and `count_next` contexts shorter than `step`, including the empty context, cannot be
+/// answered from the sampled suffixes. They are rejected unless `allow_scan` is set, in which
+/// case each one costs a parallel scan of the whole corpus.
+#[pyclass]
+pub struct SparseMemmapIndex {
+    index: Box<dyn SparseMemmapIndexTrait + Send + Sync>,
+}
+
+/// This trait is non-generic for PyO3 compatibility. Implementing structs may cast data
+/// to other unsigned integer types.
+pub trait SparseMemmapIndexTrait {
+    fn out_of_vocab(&self) -> OutOfVocab;
+    fn set_out_of_vocab(&mut self, policy: OutOfVocab);
+    fn allow_scan(&self) -> bool;
+    fn set_allow_scan(&mut self, allow: bool);
+    fn step(&self) -> usize;
+    fn is_sorted(&self) -> bool;
+    fn contains(&self, query: Vec<usize>) -> Result<bool>;
+    fn positions(
+        &self,
+        query: Vec<usize>,
+        offset: usize,
+        limit: Option<usize>,
+        sorted: bool,
+    ) -> Result<Vec<u64>>;
+    fn count(&self, query: Vec<usize>) -> Result<usize>;
+    fn count_next(&self, query: Vec<usize>) -> Result<Vec<usize>>;
+    fn batch_count_next(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<usize>>>;
+}
+
+#[pymethods]
+impl SparseMemmapIndex {
+    #[new]
+    #[pyo3(signature = (text_path, table_path, vocab=None, step=None))]
+    pub fn new(
+        py: Python<'_>,
+        text_path: String,
+        table_path: String,
+        vocab: Option<usize>,
+        step: Option<usize>,
+    ) -> Result<Self> {
+        py.allow_threads(|| {
+            let (token_width, vocab) = resolve_token_type(&table_path, vocab)?;
+            let index: Box<dyn SparseMemmapIndexTrait + Send + Sync> = with_token_type!(token_width, T => Box::new(
+                SparseMemmapIndexRs::<T>::new(text_path, table_path, vocab, step)?,
+            ));
+
+            Ok(SparseMemmapIndex { index })
+        })
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (text_path, table_path, step, vocab=u16::MAX as usize + 1, verbose=false, token_width=None))]
+    pub fn build(
+        py: Python<'_>,
+        text_path: String,
+        table_path: String,
+        step: usize,
+        vocab: usize,
+        verbose: bool,
+        token_width: Option<usize>,
+    ) -> Result<Self> {
+        py.allow_threads(|| {
+            let index: Box<dyn SparseMemmapIndexTrait + Send + Sync> = with_token_type!(metadata::token_width(vocab, token_width)?, T => Box::new(
+                SparseMemmapIndexRs::<T>::build(text_path, table_path, step, vocab, verbose)?,
+            ));
+
+            Ok(SparseMemmapIndex { index })
+        })
+    }
+
+    /// How query tokens outside the vocabulary are handled: "error" raises
+    /// `TokenOutOfVocabError` and "miss" treats them as tokens that never occur.
+    #[getter]
+    pub fn out_of_vocab(&self) -> &'static str {
+        self.index.out_of_vocab().as_str()
+    }
+
+    #[setter]
+    pub fn set_out_of_vocab(&mut self, policy: &str) -> Result<()> {
+        self.index.set_out_of_vocab(policy.parse()?);
+        Ok(())
+    }
+
+    /// Whether queries shorter than `step` may fall back to a scan of the whole corpus. When
+    /// false, the default, such queries raise `ValueError`.
+    #[getter]
+    pub fn allow_scan(&self) -> bool {
+        self.index.allow_scan()
+    }
+
+    #[setter]
+    pub fn set_allow_scan(&mut self, allow: bool) {
+        self.index.set_allow_scan(allow);
+    }
+
+    /// The distance between the text offsets of sampled suffixes.
+    #[getter]
+    pub fn step(&self) -> usize {
+        self.index.step()
+    }
+
+    pub fn is_sorted(&self, py: Python<'_>) -> bool {
+        py.allow_threads(|| self.index.is_sorted())
+    }
+
+    pub fn contains(&self, py: Python<'_>, query: Vec<usize>) -> Result<bool> {
+        py.allow_threads(|| self.index.contains(query))
+    }
+
+    /// Returns the positions where `query` starts in the tokens as a numpy array, skipping the
+    /// first `offset` and keeping at most `limit`. Positions are in index order unless `sorted`,
+    /// in which case they are in corpus order and every page costs a pass over all matches.
+    #[pyo3(signature = (query, offset=0, limit=None, sorted=false))]
+    pub fn positions(
+        &self,
+        py: Python<'_>,
+        query: Vec<usize>,
+        offset: usize,
+        limit: Option<usize>,
+        sorted: bool,
+    ) -> PyResult<PyObject> {
+        let positions = py.allow_threads(|| self.index.positions(query, offset, limit, sorted))?;
+        positions_array(py, &positions)
+    }
+
+    /// Iterate over the positions where `query` starts in the tokens in numpy arrays of at most
+    /// `page_size` positions, fetching each page only when it is reached. In corpus order,
+    /// every position is fetched and sorted once with the first page.
+    #[pyo3(signature = (query, page_size=1 << 20, sorted=false))]
+    pub fn iter_positions(
+        slf: Py<Self>,
+        query: Vec<usize>,
+        page_size: usize,
+        sorted: bool,
+    ) -> Result<PositionPages> {
+        PositionPages::new(slf.into_any(), query, page_size, sorted)
+    }
+
+    pub fn count(&self, py: Python<'_>, query: Vec<usize>) -> Result<usize> {
+        py.allow_threads(|| self.index.count(query))
+    }
+
+    pub fn count_next(&self, py: Python<'_>, query: Vec<usize>) -> Result<Vec<usize>> {
+        py.allow_threads(|| self.index.count_next(query))
+    }
+
+    pub fn batch_count_next(
+        &self,
+        py: Python<'_>,
+        queries: Vec<Vec<usize>>,
+    ) -> Result<Vec<Vec<usize>>> {
+        py.allow_threads(|| self.index.batch_count_next(queries))
+    }
+}
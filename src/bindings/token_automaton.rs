@@ -0,0 +1,67 @@
+use crate::automaton::TokenAutomaton;
+use crate::error::{Result, TokengramsError};
+use pyo3::prelude::*;
+
+#[pymethods]
+impl TokenAutomaton {
+    /// Builds an automaton from `(state, token, next_state)` transitions, starting in state
+    /// zero. The number of states defaults to one more than the largest state mentioned.
+    #[new]
+    #[pyo3(signature = (transitions, accepting, num_states=None))]
+    pub fn py_new(
+        transitions: Vec<(usize, usize, usize)>,
+        accepting: Vec<usize>,
+        num_states: Option<usize>,
+    ) -> Result<Self> {
+        TokenAutomaton::new(transitions, &accepting, num_states)
+    }
+
+    /// Builds a trie accepting exactly the given token sequences.
+    #[staticmethod]
+    #[pyo3(name = "from_sequences")]
+    pub fn py_from_sequences(sequences: Vec<Vec<usize>>) -> Self {
+        TokenAutomaton::from_sequences(&sequences)
+    }
+
+    #[getter]
+    #[pyo3(name = "num_states")]
+    pub fn py_num_states(&self) -> usize {
+        self.num_states()
+    }
+
+    /// Returns the tokens with a transition out of `state`, in increasing order.
+    #[pyo3(name = "allowed_tokens")]
+    pub fn py_allowed_tokens(&self, state: usize) -> Result<Vec<usize>> {
+        self.check_state(state)?;
+        Ok(self.allowed_tokens(state).to_vec())
+    }
+
+    #[pyo3(name = "next_state")]
+    pub fn py_next_state(&self, state: usize, token: usize) -> Result<Option<usize>> {
+        self.check_state(state)?;
+        Ok(self.next_state(state, token))
+    }
+
+    #[pyo3(name = "is_accepting")]
+    pub fn py_is_accepting(&self, state: usize) -> Result<bool> {
+        self.check_state(state)?;
+        Ok(self.is_accepting(state))
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!("TokenAutomaton({})", self)
+    }
+}
+
+impl TokenAutomaton {
+    fn check_state(&self, state: usize) -> Result<()> {
+        if state >= self.num_states() {
+            return Err(TokengramsError::InvalidArgument(format!(
+                "State {} is out of range for an automaton with {} states",
+                state,
+                self.num_states()
+            )));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+use funty::Unsigned;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind};
+
+use crate::error::{Result, TokengramsError};
+use crate::metadata::IndexFingerprint;
+use crate::table_index::TableIndex;
+
+/// A lookup table from each prefix of `depth` tokens to the range of ranks whose suffixes start
+/// with it, stored in a sidecar file next to a suffix table.
+///
+/// Prefixes are encoded as base `vocab + 1` integers with digit `t + 1` for token `t`, and digit
+/// zero padding suffixes shorter than `depth`. This encoding orders prefixes the same way as the
+/// suffixes they start, so the table holds the rank of the first suffix of every distinct key and
+/// is searched in memory before touching the suffix table.
+///
+/// The sidecar also holds the fingerprint of the text the buckets were built from, so buckets
+/// left behind by a previous table at the same path are rejected.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BucketTable {
+    depth: usize,
+    vocab: usize,
+    /// Number of suffixes in the table the buckets were built from.
+    len: usize,
+    keys: Vec<u64>,
+    starts: Vec<u64>,
+}
+
+impl BucketTable {
+    /// Builds the buckets of a sorted suffix table in one parallel pass over its ranks.
+    pub fn build<E: Unsigned, I: TableIndex>(
+        text: &[E],
+        table: &[I],
+        vocab: usize,
+        depth: usize,
+    ) -> Result<Self> {
+        if depth == 0 || (vocab as u64 + 1).checked_pow(depth as u32).is_none() {
+            return Err(TokengramsError::InvalidArgument(format!(
+                "Cannot build buckets of depth {} over a vocabulary of size {}",
+                depth, vocab
+            )));
+        }
+
+        let mut buckets = BucketTable {
+            depth,
+            vocab,
+            len: table.len(),
+            keys: Vec::new(),
+            starts: Vec::new(),
+        };
+        let key = |rank: usize| buckets.encode(&text[table[rank].as_usize()..]);
+        let (keys, starts): (Vec<u64>, Vec<u64>) = (0..table.len())
+            .into_par_iter()
+            .filter_map(|rank| {
+                let k = key(rank);
+                (rank == 0 || key(rank - 1) != k).then_some((k, rank as u64))
+            })
+            .unzip();
+
+        buckets.keys = keys;
+        buckets.starts = starts;
+        Ok(buckets)
+    }
+
+    /// Returns the path of the bucket sidecar for a table file.
+    pub fn path(table_path: &str) -> String {
+        format!("{}.lut", table_path)
+    }
+
+    /// Saves the buckets next to a table over the text with the given fingerprint.
+    pub fn save(&self, table_path: &str, fingerprint: &IndexFingerprint) -> Result<()> {
+        let file = File::create(Self::path(table_path))?;
+        bincode::serialize_into(BufWriter::new(file), &(fingerprint, self))
+            .map_err(|e| TokengramsError::InvalidMetadata(e.to_string()))
+    }
+
+    /// Loads the buckets for a table over the text with the given fingerprint, returning
+    /// `None` if the table has no sidecar.
+    pub fn load(table_path: &str, fingerprint: &IndexFingerprint) -> Result<Option<Self>> {
+        let file = match File::open(Self::path(table_path)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let (built_from, buckets): (IndexFingerprint, BucketTable) =
+            bincode::deserialize_from(BufReader::new(file))
+                .map_err(|e| TokengramsError::InvalidMetadata(e.to_string()))?;
+        if built_from != *fingerprint {
+            return Err(TokengramsError::InvalidMetadata(
+                "Bucket table was built from a different text".to_string(),
+            ));
+        }
+        Ok(Some(buckets))
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Returns the range of ranks whose suffixes start with `query`, which is exact for queries
+    /// of at most `depth` tokens and otherwise contains every match.
+    pub fn range<E: Unsigned>(&self, query: &[E]) -> (usize, usize) {
+        let query = &query[..query.len().min(self.depth)];
+        if query.is_empty() {
+            return (0, self.len);
+        }
+        if query.iter().any(|t| t.as_usize() >= self.vocab) {
+            return (0, 0);
+        }
+
+        // Keys starting with the query lie between the query padded with zeros and its
+        // successor padded with zeros
+        let padding = (self.vocab as u64 + 1).pow((self.depth - query.len()) as u32);
+        let lo = self.encode(query);
+        let hi = lo + padding;
+        (self.rank_of(lo), self.rank_of(hi))
+    }
+
+    /// Returns the rank of the first suffix whose key is at least `key`.
+    fn rank_of(&self, key: u64) -> usize {
+        let i = self.keys.partition_point(|&k| k < key);
+        self.starts.get(i).map_or(self.len, |&start| start as usize)
+    }
+
+    fn encode<E: Unsigned>(&self, suffix: &[E]) -> u64 {
+        let base = self.vocab as u64 + 1;
+        (0..self.depth).fold(0, |key, i| {
+            key * base + suffix.get(i).map_or(0, |t| t.as_u64() + 1)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::SuffixTable;
+
+    #[test]
+    fn bucket_ranges_match_table() {
+        let text: Vec<u16> = (0..300u32).map(|i| ((i * 7 + i / 5) % 5) as u16).collect();
+        let table = SuffixTable::new(text.clone(), Some(6), false);
+
+        for depth in [1, 2, 3] {
+            let buckets =
+                BucketTable::build(table.get_text(), table.get_table(), 6, depth).unwrap();
+            for query in [
+                vec![0u16],
+                vec![4],
+                vec![5],
+                vec![1, 2],
+                vec![3, 3],
+                vec![0, 1, 2],
+            ] {
+                let (start, end) = buckets.range(&query);
+                let positions = &table.get_table()[start..end];
+                if query.len() <= depth {
+                    assert_eq!(positions, table.positions(&query), "depth {}", depth);
+                } else {
+                    assert!(table
+                        .positions(&query)
+                        .iter()
+                        .all(|p| positions.contains(p)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn buckets_narrow_table_searches() {
+        let text: Vec<u16> = (0..400u32)
+            .map(|i| ((i * i + i / 3) % 7 % 5) as u16)
+            .collect();
+        let plain = SuffixTable::new(text.clone(), Some(5), false);
+        let mut table = SuffixTable::new(text, Some(5), false);
+        table.set_buckets(Some(table.build_buckets(2).unwrap()));
+
+        for query in [
+            vec![],
+            vec![0],
+            vec![4],
+            vec![2, 2],
+            vec![1, 0, 3],
+            vec![3, 4, 4, 1],
+        ] {
+            assert_eq!(table.positions(&query), plain.positions(&query));
+            assert_eq!(table.contains(&query), plain.contains(&query));
+            assert_eq!(table.count_next(&query), plain.count_next(&query));
+        }
+        assert_eq!(table.count_ngrams(3), plain.count_ngrams(3));
+    }
+}
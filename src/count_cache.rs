@@ -0,0 +1,242 @@
+use funty::Unsigned;
+use lru::LruCache;
+use pyo3::prelude::*;
+use std::collections::BinaryHeap;
+use std::fmt;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Sparse counts of the tokens following each cached context.
+type Entries<T> = LruCache<Vec<T>, Vec<(T, usize)>>;
+
+/// Maximum number of independently locked shards in a cache.
+const MAX_SHARDS: usize = 16;
+
+/// Minimum capacity of a shard, so that small caches remain a single exact LRU cache.
+const MIN_SHARD_CAPACITY: usize = 64;
+
+/// A bounded, thread-safe LRU cache from contexts to the counts of the tokens following them,
+/// for the short contexts that sampling queries over and over. Counts are stored sparsely, so
+/// an entry costs memory proportional to the number of distinct continuations of its context.
+///
+/// Parallel sampling threads look up contexts concurrently, so the cache is split into up to
+/// `MAX_SHARDS` LRU caches chosen by the hash of the context, each behind its own lock and
+/// holding at least `MIN_SHARD_CAPACITY` contexts. Threads
+/// only contend when they look up contexts in the same shard, at the cost of evicting the
+/// least recently used entry of a shard rather than of the whole cache.
+///
+/// A cache with zero capacity is disabled and computes every query.
+pub struct CountCache<T: Unsigned> {
+    shards: Vec<Mutex<Entries<T>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Hit and miss statistics of a count cache.
+#[pyclass(frozen)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Number of contexts in the cache.
+    pub size: usize,
+    pub capacity: usize,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "hits={}, misses={}, size={}, capacity={}",
+            self.hits, self.misses, self.size, self.capacity
+        )
+    }
+}
+
+impl<T: Unsigned> Default for CountCache<T> {
+    fn default() -> Self {
+        CountCache::new(0)
+    }
+}
+
+impl<T: Unsigned> CountCache<T> {
+    /// Creates a cache holding at most `capacity` contexts, split evenly between its shards.
+    pub fn new(capacity: usize) -> Self {
+        let num_shards = (capacity / MIN_SHARD_CAPACITY).clamp(1, MAX_SHARDS);
+        let shards = (0..num_shards)
+            .filter_map(|i| {
+                let shard_capacity = capacity / num_shards + usize::from(i < capacity % num_shards);
+                NonZeroUsize::new(shard_capacity).map(|cap| Mutex::new(LruCache::new(cap)))
+            })
+            .collect();
+
+        CountCache {
+            shards,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().cap().get())
+            .sum()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (size, capacity) = self.shards.iter().fold((0, 0), |(size, capacity), shard| {
+            let entries = shard.lock().unwrap();
+            (size + entries.len(), capacity + entries.cap().get())
+        });
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size,
+            capacity,
+        }
+    }
+
+    /// Returns the shard holding `query`, or `None` if the cache is disabled.
+    fn shard(&self, query: &[T]) -> Option<&Mutex<Entries<T>>> {
+        if self.shards.is_empty() {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        query.hash(&mut hasher);
+        Some(&self.shards[hasher.finish() as usize % self.shards.len()])
+    }
+
+    /// Returns the cached counts of the tokens following `query`, computing and caching them
+    /// with `count_next` on a miss. The shard's lock is not held while counting.
+    pub fn get_or_insert_with(
+        &self,
+        query: &[T],
+        vocab: usize,
+        count_next: impl FnOnce() -> Vec<usize>,
+    ) -> Vec<usize> {
+        let Some(shard) = self.shard(query) else {
+            return count_next();
+        };
+        {
+            let mut entries = shard.lock().unwrap();
+            if let Some(sparse) = entries.get(query) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                let mut counts = vec![0; vocab];
+                for &(token, count) in sparse {
+                    counts[token.as_usize()] = count;
+                }
+                return counts;
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let counts = count_next();
+        let sparse = counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .filter_map(|(token, &count)| Some((T::try_from(token).ok()?, count)))
+            .collect();
+        shard.lock().unwrap().put(query.to_vec(), sparse);
+        counts
+    }
+
+    /// Fills the cache with the `top_m` most frequent contexts of at most `max_len` tokens,
+    /// including the empty context. Contexts are visited in decreasing order of frequency,
+    /// extending each visited context by its continuations, which are never more frequent.
+    pub fn warm(
+        &self,
+        top_m: usize,
+        max_len: usize,
+        vocab: usize,
+        count_next: impl Fn(&[T]) -> Vec<usize>,
+    ) {
+        let mut frontier: BinaryHeap<(usize, Vec<T>)> = BinaryHeap::new();
+        frontier.push((usize::MAX, Vec::new()));
+
+        for visited in 0..top_m {
+            let Some((_, context)) = frontier.pop() else {
+                break;
+            };
+            let counts = self.get_or_insert_with(&context, vocab, || count_next(&context));
+            if context.len() >= max_len {
+                continue;
+            }
+
+            for (token, &count) in counts.iter().enumerate().filter(|(_, &c)| c > 0) {
+                let Ok(token) = T::try_from(token) else {
+                    continue;
+                };
+                let mut extended = context.clone();
+                extended.push(token);
+                frontier.push((count, extended));
+            }
+
+            // Only the most frequent of the remaining candidates can still be visited
+            let remaining = top_m - visited - 1;
+            if frontier.len() > 2 * remaining.max(1) {
+                let mut candidates = std::mem::take(&mut frontier).into_sorted_vec();
+                candidates.drain(..candidates.len() - remaining);
+                frontier = candidates.into();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::SuffixTable;
+
+    #[test]
+    fn cache_hits_and_warming() {
+        let text: Vec<u16> = (0..500u32)
+            .map(|i| ((i * i + i / 3) % 7 % 4) as u16)
+            .collect();
+        let table = SuffixTable::new(text, Some(4), false);
+
+        let cache = CountCache::new(2);
+        for query in [vec![0u16], vec![0], vec![1, 2], vec![3], vec![0]] {
+            let counts = cache.get_or_insert_with(&query, 4, || table.count_next(&query));
+            assert_eq!(counts, table.count_next(&query));
+        }
+        // [0] is evicted by [1, 2] and [3] before it is queried for the third time
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.size), (1, 4, 2));
+
+        let cache = CountCache::new(10);
+        cache.warm(6, 1, 4, |query| table.count_next(query));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.size), (0, 5, 5));
+
+        cache.get_or_insert_with(&[3], 4, || unreachable!());
+        assert_eq!(cache.stats().hits, 1);
+    }
+
+    #[test]
+    fn sharded_cache_under_contention() {
+        use rayon::prelude::*;
+
+        let text: Vec<u16> = (0..2_000u32)
+            .map(|i| ((i * i + i / 3) % 13) as u16)
+            .collect();
+        let table = SuffixTable::new(text, Some(13), false);
+        let queries: Vec<Vec<u16>> = (0..13).map(|t| vec![t]).collect();
+
+        let cache = CountCache::new(1_100);
+        assert_eq!(cache.shards.len(), MAX_SHARDS);
+        assert_eq!(cache.capacity(), 1_100);
+        (0..10_000).into_par_iter().for_each(|i| {
+            let query = &queries[i % queries.len()];
+            let counts = cache.get_or_insert_with(query, 13, || table.count_next(query));
+            assert_eq!(counts, table.count_next(query));
+        });
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits + stats.misses, 10_000);
+        assert!(stats.size <= 13);
+    }
+}
@@ -0,0 +1,173 @@
+use funty::Unsigned;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind};
+
+use crate::error::{Result, TokengramsError};
+use crate::metadata::IndexFingerprint;
+use crate::table_index::TableIndex;
+
+/// Number of suffixes scanned by each task when building a count table.
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// A run of suffixes starting with the same token, followed by the same token if any.
+type Run = ((usize, Option<u64>), u64);
+
+/// Exact unigram counts and a sparse table of bigram counts, stored in a sidecar file next to a
+/// suffix table so that `count_next` never has to split the huge ranges of the shortest contexts.
+///
+/// Bigrams are stored in compressed sparse row form: the continuations of token `a` are
+/// `next[offsets[a]..offsets[a + 1]]`, in increasing order, with matching `counts`. The sidecar
+/// also holds the fingerprint of the text the counts were built from, so counts left behind by
+/// a previous table at the same path are rejected.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CountTable {
+    vocab: usize,
+    unigrams: Vec<u64>,
+    offsets: Vec<u64>,
+    next: Vec<u32>,
+    counts: Vec<u64>,
+}
+
+impl CountTable {
+    /// Builds the counts from a sorted suffix table, where each run of suffixes sharing their
+    /// first two tokens is one bigram.
+    pub fn build<E: Unsigned, I: TableIndex>(text: &[E], table: &[I], vocab: usize) -> Self {
+        Self::build_chunked(text, table, vocab, CHUNK_SIZE)
+    }
+
+    fn build_chunked<E: Unsigned, I: TableIndex>(
+        text: &[E],
+        table: &[I],
+        vocab: usize,
+        chunk_size: usize,
+    ) -> Self {
+        let first_two = |sufi: &I| {
+            let suffix = &text[sufi.as_usize()..];
+            (suffix[0].as_usize(), suffix.get(1).map(|t| t.as_u64()))
+        };
+
+        // Run-length encode each chunk of the table, then join runs split between chunks
+        let chunk_runs: Vec<Vec<Run>> = table
+            .par_chunks(chunk_size)
+            .map(|chunk| {
+                let mut runs: Vec<Run> = Vec::new();
+                for key in chunk.iter().map(first_two) {
+                    match runs.last_mut() {
+                        Some((last, count)) if *last == key => *count += 1,
+                        _ => runs.push((key, 1)),
+                    }
+                }
+                runs
+            })
+            .collect();
+
+        let mut counts = CountTable {
+            vocab,
+            unigrams: vec![0; vocab],
+            offsets: vec![0; vocab + 1],
+            next: Vec::new(),
+            counts: Vec::new(),
+        };
+        let mut last_key = None;
+        for ((a, b), count) in chunk_runs.into_iter().flatten() {
+            counts.unigrams[a] += count;
+            if let Some(b) = b {
+                if last_key == Some((a, b)) {
+                    *counts.counts.last_mut().unwrap() += count;
+                } else {
+                    counts.offsets[a + 1] += 1;
+                    counts.next.push(b as u32);
+                    counts.counts.push(count);
+                    last_key = Some((a, b));
+                }
+            }
+        }
+        for a in 0..vocab {
+            counts.offsets[a + 1] += counts.offsets[a];
+        }
+        counts
+    }
+
+    /// Returns the path of the count sidecar for a table file.
+    pub fn path(table_path: &str) -> String {
+        format!("{}.counts", table_path)
+    }
+
+    /// Saves the counts next to a table over the text with the given fingerprint.
+    pub fn save(&self, table_path: &str, fingerprint: &IndexFingerprint) -> Result<()> {
+        let file = File::create(Self::path(table_path))?;
+        bincode::serialize_into(BufWriter::new(file), &(fingerprint, self))
+            .map_err(|e| TokengramsError::InvalidMetadata(e.to_string()))
+    }
+
+    /// Loads the counts for a table over the text with the given fingerprint, returning `None`
+    /// if the table has no sidecar.
+    pub fn load(table_path: &str, fingerprint: &IndexFingerprint) -> Result<Option<Self>> {
+        let file = match File::open(Self::path(table_path)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let (built_from, counts): (IndexFingerprint, CountTable) =
+            bincode::deserialize_from(BufReader::new(file))
+                .map_err(|e| TokengramsError::InvalidMetadata(e.to_string()))?;
+        if built_from != *fingerprint {
+            return Err(TokengramsError::InvalidMetadata(
+                "Count table was built from a different text".to_string(),
+            ));
+        }
+        Ok(Some(counts))
+    }
+
+    /// Returns the counts of each token following `query`, or `None` if the query is too long
+    /// to be answered from the table.
+    pub fn count_next<E: Unsigned>(&self, query: &[E]) -> Option<Vec<usize>> {
+        match query {
+            [] => Some(self.unigrams.iter().map(|&c| c as usize).collect()),
+            [a] => {
+                let mut counts = vec![0; self.vocab];
+                let Some(row) = self.offsets.get(a.as_usize()..a.as_usize() + 2) else {
+                    return Some(counts);
+                };
+                let (start, end) = (row[0] as usize, row[1] as usize);
+                for (&b, &count) in self.next[start..end].iter().zip(&self.counts[start..end]) {
+                    counts[b as usize] = count as usize;
+                }
+                Some(counts)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::SuffixTable;
+
+    #[test]
+    fn counts_match_table() {
+        let text: Vec<u16> = (0..3_000u32)
+            .map(|i| ((i * i + 7 * i) % 13 % 6) as u16)
+            .collect();
+        let table = SuffixTable::new(text, Some(7), false);
+
+        // Small chunks split runs of equal bigrams between tasks
+        for chunk_size in [7, 1 << 20] {
+            let counts =
+                CountTable::build_chunked(table.get_text(), table.get_table(), 7, chunk_size);
+
+            assert_eq!(
+                counts.count_next::<u16>(&[]).unwrap(),
+                table.count_next(&[])
+            );
+            for a in 0..7 {
+                assert_eq!(counts.count_next(&[a]).unwrap(), table.count_next(&[a]));
+            }
+            assert_eq!(counts.count_next::<u16>(&[1, 2]), None);
+        }
+    }
+}
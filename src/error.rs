@@ -0,0 +1,151 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use rand::distributions::WeightedError;
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, TokengramsError>;
+
+/// Errors raised while building, loading or querying an index.
+#[derive(Debug, Error)]
+pub enum TokengramsError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("File size {size} is not a multiple of element size {element_size}")]
+    MisalignedFile { size: usize, element_size: usize },
+
+    #[error("Table length {table_len} does not match text length {text_len}")]
+    LengthMismatch { text_len: usize, table_len: usize },
+
+    #[error("Suffix table is not sorted")]
+    UnsortedTable,
+
+    #[error("Token {token} is out of range for vocabulary size {vocab}")]
+    TokenOutOfVocab { token: usize, vocab: usize },
+
+    #[error("Invalid index metadata: {0}")]
+    InvalidMetadata(String),
+
+    #[error("Cannot sample from an empty distribution")]
+    EmptyDistribution,
+
+    #[error("Invalid sampling weights: {0}")]
+    InvalidDistribution(WeightedError),
+
+    #[error("{0}")]
+    InvalidArgument(String),
+}
+
+impl From<WeightedError> for TokengramsError {
+    fn from(err: WeightedError) -> Self {
+        match err {
+            WeightedError::NoItem | WeightedError::AllWeightsZero => {
+                TokengramsError::EmptyDistribution
+            }
+            err => TokengramsError::InvalidDistribution(err),
+        }
+    }
+}
+
+/// Python exception classes raised in place of the corresponding `TokengramsError` variants.
+pub mod exceptions {
+    use pyo3::create_exception;
+    use pyo3::exceptions::PyException;
+
+    create_exception!(
+        tokengrams,
+        TokengramsError,
+        PyException,
+        "Base class for tokengrams errors."
+    );
+    create_exception!(
+        tokengrams,
+        MisalignedFileError,
+        TokengramsError,
+        "A token or table file size is not a multiple of its element size."
+    );
+    create_exception!(
+        tokengrams,
+        LengthMismatchError,
+        TokengramsError,
+        "A suffix table does not have one entry per token in its text."
+    );
+    create_exception!(
+        tokengrams,
+        UnsortedTableError,
+        TokengramsError,
+        "A suffix table is not lexicographically sorted."
+    );
+    create_exception!(
+        tokengrams,
+        TokenOutOfVocabError,
+        TokengramsError,
+        "A token is greater than or equal to the vocabulary size."
+    );
+    create_exception!(
+        tokengrams,
+        InvalidMetadataError,
+        TokengramsError,
+        "Index metadata is corrupt or does not match the files it describes."
+    );
+    create_exception!(
+        tokengrams,
+        EmptyDistributionError,
+        TokengramsError,
+        "A next token distribution has no nonzero weights to sample from."
+    );
+}
+
+impl From<TokengramsError> for PyErr {
+    fn from(err: TokengramsError) -> PyErr {
+        let msg = err.to_string();
+        match err {
+            TokengramsError::Io(err) => err.into(),
+            TokengramsError::MisalignedFile { .. } => exceptions::MisalignedFileError::new_err(msg),
+            TokengramsError::LengthMismatch { .. } => exceptions::LengthMismatchError::new_err(msg),
+            TokengramsError::UnsortedTable => exceptions::UnsortedTableError::new_err(msg),
+            TokengramsError::TokenOutOfVocab { .. } => {
+                exceptions::TokenOutOfVocabError::new_err(msg)
+            }
+            TokengramsError::InvalidMetadata(_) => exceptions::InvalidMetadataError::new_err(msg),
+            TokengramsError::EmptyDistribution => exceptions::EmptyDistributionError::new_err(msg),
+            TokengramsError::InvalidDistribution(_) | TokengramsError::InvalidArgument(_) => {
+                PyValueError::new_err(msg)
+            }
+        }
+    }
+}
+
+/// Register the exception classes on the Python module.
+pub fn add_exceptions(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add(
+        "TokengramsError",
+        py.get_type_bound::<exceptions::TokengramsError>(),
+    )?;
+    m.add(
+        "MisalignedFileError",
+        py.get_type_bound::<exceptions::MisalignedFileError>(),
+    )?;
+    m.add(
+        "LengthMismatchError",
+        py.get_type_bound::<exceptions::LengthMismatchError>(),
+    )?;
+    m.add(
+        "UnsortedTableError",
+        py.get_type_bound::<exceptions::UnsortedTableError>(),
+    )?;
+    m.add(
+        "TokenOutOfVocabError",
+        py.get_type_bound::<exceptions::TokenOutOfVocabError>(),
+    )?;
+    m.add(
+        "InvalidMetadataError",
+        py.get_type_bound::<exceptions::InvalidMetadataError>(),
+    )?;
+    m.add(
+        "EmptyDistributionError",
+        py.get_type_bound::<exceptions::EmptyDistributionError>(),
+    )?;
+    Ok(())
+}
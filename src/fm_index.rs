@@ -0,0 +1,818 @@
+use funty::Unsigned;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::bindings::fm_index::FmIndexTrait;
+use crate::error::{Result, TokengramsError};
+use crate::memmap_index::sort_thread_pool;
+use crate::metadata::MAGIC;
+use crate::mmap_slice::{MmapRange, MmapSlice, MmapSliceMut};
+use crate::par_quicksort::par_sort_unstable_by_key;
+use crate::query::{convert_context, convert_queries, convert_query, restore_prompt, OutOfVocab};
+use crate::sample::{KneserNeyCache, Sample, SampleRequest, SampleResult};
+use crate::sampling_config::SamplingConfig;
+use crate::util::{check_vocab, page_positions, transmute_slice};
+use crate::wavelet_matrix::{RankBitVec, WaveletMatrix};
+
+/// Version of the FM-index file layout, which differs from the metadata sidecar format.
+pub const FM_FORMAT_VERSION: u32 = 2;
+
+/// The header at the start of an FM-index file, read before the body to pick the token type.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FmIndexHeader {
+    pub magic: [u8; 8],
+    pub version: u32,
+    /// Size of each token in bytes.
+    pub token_width: usize,
+    pub vocab: usize,
+}
+
+impl FmIndexHeader {
+    /// Reads the header of an FM-index file.
+    pub fn load(path: &str) -> Result<Self> {
+        Self::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    fn read_from(reader: &mut impl Read) -> Result<Self> {
+        let header: FmIndexHeader = bincode::deserialize_from(reader)
+            .map_err(|e| TokengramsError::InvalidMetadata(e.to_string()))?;
+        if header.magic != MAGIC {
+            return Err(TokengramsError::InvalidMetadata(
+                "FM-index file has the wrong magic number".to_string(),
+            ));
+        }
+        if header.version != FM_FORMAT_VERSION {
+            return Err(TokengramsError::InvalidMetadata(format!(
+                "FM-index format version {} is not supported, rebuild the index with version {}",
+                header.version, FM_FORMAT_VERSION
+            )));
+        }
+        Ok(header)
+    }
+}
+
+/// The small fields of an FM-index, stored after the header. The arrays of the body follow
+/// them in the order of `FmIndexBody::arrays`, aligned to 8 bytes so they can be memory mapped.
+#[derive(Serialize, Deserialize)]
+struct FmIndexLayout {
+    counts_before: Vec<usize>,
+    zeros: Vec<usize>,
+    sample_rate: usize,
+    len: usize,
+    /// Length in words of each array.
+    array_lens: Vec<usize>,
+}
+
+/// The compressed representation of a text, with its arrays held in `W`.
+struct FmIndexBody<W> {
+    /// Burrows-Wheeler transform of the reversed text, with each token shifted up by one so
+    /// that zero marks the terminator.
+    bwt: WaveletMatrix<W>,
+    /// Number of symbols in the transform smaller than each symbol.
+    counts_before: Vec<usize>,
+    /// Marks the ranks whose suffix array entry is stored in `samples`.
+    sampled: RankBitVec<W>,
+    samples: W,
+    sample_rate: usize,
+    len: usize,
+}
+
+impl FmIndexBody<Vec<u64>> {
+    /// Builds the body from the reversed text and its suffix array, sampling every
+    /// `sample_rate`-th suffix array entry for position queries.
+    fn new<T: Unsigned>(reversed: &[T], table: &[u64], vocab: usize, sample_rate: usize) -> Self {
+        // The empty suffix sorts before every other suffix
+        let len = reversed.len();
+        let suffix_array = || std::iter::once(len as u64).chain(table.iter().copied());
+
+        let bwt: Vec<u32> = suffix_array()
+            .map(|i| match i {
+                0 => 0,
+                i => reversed[i as usize - 1].as_u32() + 1,
+            })
+            .collect();
+
+        let mut counts_before = vec![0; vocab + 2];
+        counts_before[1] = 1;
+        for token in reversed.iter() {
+            counts_before[token.as_usize() + 2] += 1;
+        }
+        for c in 1..counts_before.len() {
+            counts_before[c] += counts_before[c - 1];
+        }
+
+        let sampled =
+            RankBitVec::from_bits(suffix_array().map(|i| i.is_multiple_of(sample_rate as u64)));
+        let samples = suffix_array()
+            .filter(|i| i.is_multiple_of(sample_rate as u64))
+            .collect();
+
+        let bits = (u64::BITS - (vocab as u64).leading_zeros()) as usize;
+        FmIndexBody {
+            bwt: WaveletMatrix::new(bwt, bits),
+            counts_before,
+            sampled,
+            samples,
+            sample_rate,
+            len,
+        }
+    }
+}
+
+impl<W: Deref<Target = [u64]>> FmIndexBody<W> {
+    /// Returns the arrays of the body in file order: the words and block ranks of each wavelet
+    /// matrix level and of `sampled`, followed by `samples`.
+    fn arrays(&self) -> Vec<&[u64]> {
+        let mut arrays: Vec<&[u64]> = self
+            .bwt
+            .levels()
+            .iter()
+            .chain([&self.sampled])
+            .flat_map(|bitvec| bitvec.parts())
+            .collect();
+        arrays.push(&self.samples);
+        arrays
+    }
+
+    fn save(&self, header: &FmIndexHeader, index_path: &str) -> Result<()> {
+        let arrays = self.arrays();
+        let layout = FmIndexLayout {
+            counts_before: self.counts_before.clone(),
+            zeros: self.bwt.zeros().to_vec(),
+            sample_rate: self.sample_rate,
+            len: self.len,
+            array_lens: arrays.iter().map(|array| array.len()).collect(),
+        };
+        let to_err = |e: bincode::Error| TokengramsError::InvalidMetadata(e.to_string());
+        let prefix_len = bincode::serialized_size(header).map_err(to_err)?
+            + bincode::serialized_size(&layout).map_err(to_err)?;
+
+        let mut writer = BufWriter::new(File::create(index_path)?);
+        bincode::serialize_into(&mut writer, header).map_err(to_err)?;
+        bincode::serialize_into(&mut writer, &layout).map_err(to_err)?;
+        writer.write_all(&vec![
+            0;
+            (prefix_len.next_multiple_of(8) - prefix_len) as usize
+        ])?;
+        for array in arrays {
+            writer.write_all(transmute_slice::<u64, u8>(array))?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl FmIndexBody<MmapRange<u64>> {
+    /// Memory maps the arrays of a body saved with `save`.
+    fn load(file: &File, header: &FmIndexHeader, reader: &mut impl Read) -> Result<Self> {
+        let to_err = |e: bincode::Error| TokengramsError::InvalidMetadata(e.to_string());
+        let layout: FmIndexLayout = bincode::deserialize_from(reader).map_err(to_err)?;
+        let prefix_len = bincode::serialized_size(header).map_err(to_err)?
+            + bincode::serialized_size(&layout).map_err(to_err)?;
+
+        let words = Arc::new(MmapSlice::<u64>::new(file)?);
+        let mut start = prefix_len.div_ceil(8) as usize;
+        let mut arrays = Vec::with_capacity(layout.array_lens.len());
+        for len in &layout.array_lens {
+            let array = MmapRange::new(words.clone(), start, start + len).ok_or_else(|| {
+                TokengramsError::InvalidMetadata("FM-index file is truncated".to_string())
+            })?;
+            arrays.push(array);
+            start += len;
+        }
+        if arrays.len() != 2 * layout.zeros.len() + 3 {
+            return Err(TokengramsError::InvalidMetadata(format!(
+                "FM-index file has {} arrays, expected {}",
+                arrays.len(),
+                2 * layout.zeros.len() + 3
+            )));
+        }
+
+        let mut arrays = arrays.into_iter();
+        let mut bitvec = || {
+            let words = arrays.next().unwrap();
+            RankBitVec::from_parts(words, arrays.next().unwrap())
+        };
+        let levels = (0..layout.zeros.len()).map(|_| bitvec()).collect();
+        let sampled = bitvec();
+
+        Ok(FmIndexBody {
+            bwt: WaveletMatrix::from_parts(levels, layout.zeros, layout.len + 1),
+            counts_before: layout.counts_before,
+            sampled,
+            samples: arrays.next().unwrap(),
+            sample_rate: layout.sample_rate,
+            len: layout.len,
+        })
+    }
+}
+
+/// An FM-index answers the same queries as a suffix table from a compressed self-index that
+/// replaces both the text and the table.
+///
+/// The index is built over the reversed text, so that the backward search of the reversed
+/// query extends the query one token at a time from the left. This lets `count_next` read the
+/// counts of every continuation directly off the wavelet matrix. Positions are recovered by
+/// walking the LF mapping to the nearest sampled suffix array entry, taking at most
+/// `sample_rate` steps per occurrence.
+///
+/// Indices opened from disk memory map their arrays, while `new` holds them in memory.
+pub struct FmIndexRs<T: Unsigned, W = MmapRange<u64>> {
+    body: FmIndexBody<W>,
+    vocab: usize,
+    cache: KneserNeyCache,
+    out_of_vocab: OutOfVocab,
+    _token_type: std::marker::PhantomData<T>,
+}
+
+#[cfg(test)]
+impl<T: Unsigned> FmIndexRs<T, Vec<u64>> {
+    /// Builds an FM-index over the tokens in memory, sampling every `sample_rate`-th suffix
+    /// array entry for position queries.
+    pub fn new(tokens: &[T], vocab: usize, sample_rate: usize, verbose: bool) -> Result<Self> {
+        check_sample_rate(sample_rate)?;
+        check_vocab(tokens, vocab)?;
+
+        let reversed: Vec<T> = tokens.iter().rev().copied().collect();
+        let start = Instant::now();
+        let table = crate::table::SuffixTable::new(reversed, Some(vocab), verbose);
+        if verbose {
+            println!("Built suffix array in {:?}", start.elapsed());
+        }
+        let (reversed, table) = table.into_parts();
+
+        Ok(FmIndexRs {
+            body: FmIndexBody::new(&reversed, &table, vocab, sample_rate),
+            vocab,
+            cache: KneserNeyCache::default(),
+            out_of_vocab: OutOfVocab::default(),
+            _token_type: std::marker::PhantomData,
+        })
+    }
+}
+
+impl<T: Unsigned> FmIndexRs<T> {
+    /// Builds an FM-index over a token file, saves it to `index_path` and opens it.
+    ///
+    /// The reversed tokens and their suffix array are written to temporary files next to the
+    /// index and memory mapped while sorting. The Burrows-Wheeler transform is then built in
+    /// memory, so peak memory is about 8 bytes per token for the two copies of the transform
+    /// held while building the wavelet matrix, plus the finished index.
+    pub fn build(
+        token_path: String,
+        index_path: String,
+        vocab: usize,
+        sample_rate: usize,
+        verbose: bool,
+    ) -> Result<Self> {
+        check_sample_rate(sample_rate)?;
+        let reversed_path = format!("{}.rev.tmp", index_path);
+        let table_path = format!("{}.sa.tmp", index_path);
+
+        let built = Self::build_body(
+            &token_path,
+            &reversed_path,
+            &table_path,
+            vocab,
+            sample_rate,
+            verbose,
+        )
+        .and_then(|body| body.save(&Self::header(vocab), &index_path));
+        for path in [reversed_path, table_path] {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        built?;
+
+        Self::load(index_path)
+    }
+
+    fn build_body(
+        token_path: &str,
+        reversed_path: &str,
+        table_path: &str,
+        vocab: usize,
+        sample_rate: usize,
+        verbose: bool,
+    ) -> Result<FmIndexBody<Vec<u64>>> {
+        let tokens = MmapSlice::<T>::new(&File::open(token_path)?)?;
+        check_vocab(&tokens, vocab)?;
+
+        let mut writer = BufWriter::new(File::create(reversed_path)?);
+        for chunk in tokens.rchunks(1 << 20) {
+            let chunk: Vec<T> = chunk.iter().rev().copied().collect();
+            writer.write_all(transmute_slice::<T, u8>(&chunk))?;
+        }
+        writer.flush()?;
+        drop(writer);
+        let reversed = MmapSlice::<T>::new(&File::open(reversed_path)?)?;
+
+        let table_file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(table_path)?;
+        table_file.set_len((reversed.len() * std::mem::size_of::<u64>()) as u64)?;
+        let mut table = MmapSliceMut::<u64>::new(&table_file)?;
+        table
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, x)| *x = i as u64);
+
+        let start = Instant::now();
+        sort_thread_pool(reversed.len()).install(|| {
+            par_sort_unstable_by_key(table.as_slice_mut(), |&i| &reversed[i as usize..], verbose)
+        });
+        if verbose {
+            println!("Built suffix array in {:?}", start.elapsed());
+        }
+
+        Ok(FmIndexBody::new(&reversed, &table, vocab, sample_rate))
+    }
+
+    /// Opens an FM-index saved with `save`, memory mapping its arrays.
+    pub fn load(index_path: String) -> Result<Self> {
+        let file = File::open(&index_path)?;
+        let mut reader = BufReader::new(&file);
+        let header = FmIndexHeader::read_from(&mut reader)?;
+        if header.token_width != std::mem::size_of::<T>() {
+            return Err(TokengramsError::InvalidMetadata(format!(
+                "Index was built with {}-byte tokens but opened with {}-byte tokens",
+                header.token_width,
+                std::mem::size_of::<T>()
+            )));
+        }
+        let body = FmIndexBody::load(&file, &header, &mut reader)?;
+
+        Ok(FmIndexRs {
+            body,
+            vocab: header.vocab,
+            cache: KneserNeyCache::default(),
+            out_of_vocab: OutOfVocab::default(),
+            _token_type: std::marker::PhantomData,
+        })
+    }
+}
+
+fn check_sample_rate(sample_rate: usize) -> Result<()> {
+    if sample_rate == 0 {
+        return Err(TokengramsError::InvalidArgument(
+            "Sample rate must be greater than zero".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+impl<T: Unsigned, W: Deref<Target = [u64]> + Sync> FmIndexRs<T, W> {
+    fn header(vocab: usize) -> FmIndexHeader {
+        FmIndexHeader {
+            magic: MAGIC,
+            version: FM_FORMAT_VERSION,
+            token_width: std::mem::size_of::<T>(),
+            vocab,
+        }
+    }
+
+    /// Saves the index to `index_path`. The file is written beside it and then moved into
+    /// place, so an index can be saved over the file it was opened from.
+    pub fn save(&self, index_path: &str) -> Result<()> {
+        let tmp_path = format!("{}.tmp", index_path);
+        self.body.save(&Self::header(self.vocab), &tmp_path)?;
+        Ok(std::fs::rename(tmp_path, index_path)?)
+    }
+
+    pub fn sample_rate(&self) -> usize {
+        self.body.sample_rate
+    }
+
+    /// Returns the range of ranks whose suffixes of the reversed text start with the reversed
+    /// query.
+    fn range(&self, query: &[T]) -> (usize, usize) {
+        let (mut start, mut end) = (0, self.body.bwt.len());
+        for token in query {
+            if start >= end {
+                break;
+            }
+            (start, end) = self.extend(token.as_u32() + 1, start, end);
+        }
+        (start, end)
+    }
+
+    /// Extends the query matched by a range of ranks with one more token on its right.
+    fn extend(&self, symbol: u32, start: usize, end: usize) -> (usize, usize) {
+        let before = self.body.counts_before[symbol as usize];
+        (
+            before + self.body.bwt.rank(symbol, start),
+            before + self.body.bwt.rank(symbol, end),
+        )
+    }
+
+    /// Returns the offset in the reversed text of the suffix with the given rank.
+    fn locate(&self, mut rank: usize) -> u64 {
+        let mut steps = 0;
+        while !self.body.sampled.get(rank) {
+            let symbol = self.body.bwt.access(rank);
+            (rank, _) = self.extend(symbol, rank, rank + 1);
+            steps += 1;
+        }
+        self.body.samples[self.body.sampled.rank1(rank)] + steps
+    }
+
+    /// Returns true if and only if `query` is in text.
+    pub fn contains(&self, query: &[T]) -> bool {
+        !query.is_empty() && self.count(query) > 0
+    }
+
+    /// Returns the number of occurrences of `query` in text.
+    pub fn count(&self, query: &[T]) -> usize {
+        if query.is_empty() {
+            return 0;
+        }
+        let (start, end) = self.range(query);
+        end.saturating_sub(start)
+    }
+
+    /// Returns an unordered list of positions where `query` starts in text.
+    pub fn positions(&self, query: &[T]) -> Vec<u64> {
+        if query.is_empty() {
+            return Vec::new();
+        }
+        let (start, end) = self.range(query);
+        if start >= end {
+            return Vec::new();
+        }
+        let end_offset = (self.body.len - query.len()) as u64;
+        (start..end)
+            .into_par_iter()
+            .map(|rank| end_offset - self.locate(rank))
+            .collect()
+    }
+
+    /// Returns the page of positions where `query` starts in text that skips `offset` positions
+    /// and keeps at most `limit`, in rank order or, if `sorted`, in increasing order. Unsorted
+    /// pages only locate the occurrences on the page.
+    pub fn positions_page(
+        &self,
+        query: &[T],
+        offset: usize,
+        limit: Option<usize>,
+        sorted: bool,
+    ) -> Vec<u64> {
+        if sorted {
+            return page_positions(self.positions(query), offset, limit, true);
+        }
+        if query.is_empty() {
+            return Vec::new();
+        }
+        let (start, end) = self.range(query);
+        let count = end.saturating_sub(start);
+        let page_end = limit
+            .map_or(count, |limit| offset.saturating_add(limit))
+            .min(count);
+        let end_offset = (self.body.len - query.len()) as u64;
+        (start + offset.min(page_end)..start + page_end)
+            .into_par_iter()
+            .map(|rank| end_offset - self.locate(rank))
+            .collect()
+    }
+
+    /// Count occurrences of each token directly following the query sequence.
+    pub fn count_next(&self, query: &[T]) -> Vec<usize> {
+        let mut counts = vec![0; self.vocab];
+        let (start, end) = self.range(query);
+        self.body
+            .bwt
+            .for_each_symbol(start, end, &mut |symbol, count| {
+                if symbol > 0 {
+                    counts[symbol as usize - 1] += count;
+                }
+            });
+        counts
+    }
+
+    // count_ngrams helper method.
+    fn recurse_count_ngrams(
+        &self,
+        start: usize,
+        end: usize,
+        n: usize,
+        target_n: usize,
+        count_map: &mut HashMap<usize, usize>,
+    ) {
+        let mut symbols = Vec::new();
+        self.body
+            .bwt
+            .for_each_symbol(start, end, &mut |symbol, _| symbols.push(symbol));
+
+        for symbol in symbols.into_iter().filter(|&s| s > 0) {
+            let (start, end) = self.extend(symbol, start, end);
+            if n < target_n {
+                self.recurse_count_ngrams(start, end, n + 1, target_n, count_map);
+            } else {
+                *count_map.entry(end - start).or_insert(0) += 1;
+            }
+        }
+    }
+
+    fn convert_query(&self, query: &[usize]) -> Result<Option<Vec<T>>> {
+        convert_query(query, self.vocab, self.out_of_vocab)
+    }
+}
+
+impl<T: Unsigned, W: Deref<Target = [u64]> + Send + Sync> Sample<T> for FmIndexRs<T, W> {
+    fn get_cache(&self) -> &KneserNeyCache {
+        &self.cache
+    }
+
+    fn count_next_slice(&self, query: &[T]) -> Vec<usize> {
+        self.count_next(query)
+    }
+
+    fn count_ngrams(&self, n: usize) -> HashMap<usize, usize> {
+        let mut count_map = HashMap::new();
+        self.recurse_count_ngrams(0, self.body.bwt.len(), 1, n, &mut count_map);
+        count_map
+    }
+}
+
+impl<T: Unsigned, W: Deref<Target = [u64]> + Send + Sync> FmIndexTrait for FmIndexRs<T, W> {
+    fn out_of_vocab(&self) -> OutOfVocab {
+        self.out_of_vocab
+    }
+
+    fn set_out_of_vocab(&mut self, policy: OutOfVocab) {
+        self.out_of_vocab = policy;
+    }
+
+    fn sample_rate(&self) -> usize {
+        self.sample_rate()
+    }
+
+    fn save(&self, index_path: String) -> Result<()> {
+        self.save(&index_path)
+    }
+
+    fn contains(&self, query: Vec<usize>) -> Result<bool> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => self.contains(&query),
+            None => false,
+        })
+    }
+
+    fn positions(
+        &self,
+        query: Vec<usize>,
+        offset: usize,
+        limit: Option<usize>,
+        sorted: bool,
+    ) -> Result<Vec<u64>> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => self.positions_page(&query, offset, limit, sorted),
+            None => Vec::new(),
+        })
+    }
+
+    fn count(&self, query: Vec<usize>) -> Result<usize> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => self.count(&query),
+            None => 0,
+        })
+    }
+
+    fn count_next(&self, query: Vec<usize>) -> Result<Vec<usize>> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => self.count_next(&query),
+            None => vec![0; self.vocab],
+        })
+    }
+
+    fn batch_count_next(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<usize>>> {
+        let queries = convert_queries::<T>(&queries, self.vocab, self.out_of_vocab)?;
+        Ok(queries
+            .into_par_iter()
+            .map(|query| match query {
+                Some(query) => self.count_next(&query),
+                None => vec![0; self.vocab],
+            })
+            .collect())
+    }
+
+    fn sample_smoothed(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        num_samples: usize,
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<SampleResult>> {
+        let context: Vec<T> = convert_context(&query, self.vocab, self.out_of_vocab)?;
+
+        let traces =
+            <Self as Sample<T>>::sample_smoothed(self, &context, n, k, num_samples, seed, config)?;
+        Ok(SampleResult::restore(&query, context.len(), traces))
+    }
+
+    fn batch_sample(
+        &self,
+        requests: Vec<SampleRequest<usize>>,
+        smoothed: bool,
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<Vec<SampleResult>>> {
+        let contexts = requests
+            .iter()
+            .map(|request| request.convert(self.vocab, self.out_of_vocab))
+            .collect::<Result<Vec<_>>>()?;
+
+        let traces = <Self as Sample<T>>::batch_sample(self, &contexts, smoothed, seed, config)?;
+        Ok(SampleResult::restore_batch(&requests, &contexts, traces))
+    }
+
+    fn decode_greedy(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        config: &SamplingConfig,
+    ) -> Result<(Vec<usize>, f64)> {
+        let context: Vec<T> = convert_context(&query, self.vocab, self.out_of_vocab)?;
+
+        let (sequence, score) = <Self as Sample<T>>::decode_greedy(self, &context, n, k, config)?;
+        let mut restored = restore_prompt(&query, context.len(), vec![sequence]);
+        Ok((restored.swap_remove(0), score))
+    }
+
+    fn beam_search(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        beam_width: usize,
+        config: &SamplingConfig,
+    ) -> Result<Vec<(Vec<usize>, f64)>> {
+        let context: Vec<T> = convert_context(&query, self.vocab, self.out_of_vocab)?;
+
+        let (sequences, scores): (Vec<_>, Vec<_>) =
+            <Self as Sample<T>>::beam_search(self, &context, n, k, beam_width, config)?
+                .into_iter()
+                .unzip();
+        Ok(restore_prompt(&query, context.len(), sequences)
+            .into_iter()
+            .zip(scores)
+            .collect())
+    }
+
+    fn sample_unsmoothed(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        num_samples: usize,
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<SampleResult>> {
+        let context: Vec<T> = convert_context(&query, self.vocab, self.out_of_vocab)?;
+
+        let traces = <Self as Sample<T>>::sample_unsmoothed(
+            self,
+            &context,
+            n,
+            k,
+            num_samples,
+            seed,
+            config,
+        )?;
+        Ok(SampleResult::restore(&query, context.len(), traces))
+    }
+
+    fn get_smoothed_probs(&self, query: Vec<usize>) -> Result<Vec<f64>> {
+        let context: Vec<T> = convert_context(&query, self.vocab, self.out_of_vocab)?;
+
+        Ok(<Self as Sample<T>>::get_smoothed_probs(self, &context))
+    }
+
+    fn batch_get_smoothed_probs(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<f64>>> {
+        let contexts: Vec<Vec<T>> = queries
+            .iter()
+            .map(|query| convert_context(query, self.vocab, self.out_of_vocab))
+            .collect::<Result<_>>()?;
+
+        Ok(<Self as Sample<T>>::batch_get_smoothed_probs(
+            self, &contexts,
+        ))
+    }
+
+    fn estimate_deltas(&self, n: usize) {
+        <Self as Sample<T>>::estimate_deltas(self, n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::SuffixTable;
+
+    #[test]
+    fn fm_index_matches_suffix_table() {
+        let text: Vec<u16> = (0..700u32)
+            .map(|i| ((i * i + 5 * i) % 11 % 5) as u16)
+            .collect();
+        let table = SuffixTable::new(text.clone(), Some(6), false);
+
+        for sample_rate in [1, 4, 32] {
+            let index = FmIndexRs::new(&text, 6, sample_rate, false).unwrap();
+
+            for query in [
+                vec![],
+                vec![0],
+                vec![4],
+                vec![5],
+                vec![1, 2],
+                vec![0, 0, 1],
+                vec![2, 3, 0, 1],
+                vec![1, 0, 0, 1, 2, 3],
+            ] {
+                let mut expected = table.positions(&query).to_vec();
+                let mut positions = index.positions(&query);
+                expected.sort();
+                positions.sort();
+
+                assert_eq!(
+                    positions, expected,
+                    "rate {} query {:?}",
+                    sample_rate, query
+                );
+                assert_eq!(index.contains(&query), table.contains(&query));
+                assert_eq!(index.count_next(&query), table.count_next(&query));
+            }
+            for n in 1..4 {
+                assert_eq!(
+                    Sample::count_ngrams(&index, n),
+                    table.count_ngrams(n),
+                    "n {}",
+                    n
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let text: Vec<u8> = b"the cat sat on the mat".to_vec();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens.fm").display().to_string();
+
+        let index = FmIndexRs::new(&text, 256, 3, false).unwrap();
+        index.save(&path).unwrap();
+        assert_eq!(FmIndexHeader::load(&path).unwrap().token_width, 1);
+
+        let loaded = FmIndexRs::<u8>::load(path.clone()).unwrap();
+        assert_eq!(loaded.body.len, text.len());
+        assert_eq!(loaded.count(b"the"), 2);
+        assert_eq!(loaded.positions(b"at"), index.positions(b"at"));
+        assert!(matches!(
+            FmIndexRs::<u16>::load(path.clone()),
+            Err(TokengramsError::InvalidMetadata(_))
+        ));
+
+        // Saving over the mapped file replaces it rather than truncating it
+        loaded.save(&path).unwrap();
+        assert_eq!(loaded.count_next(b"the "), index.count_next(b"the "));
+    }
+
+    #[test]
+    fn build_from_token_file() {
+        let text: Vec<u16> = (0..700u32).map(|i| ((i * i + 5 * i) % 11) as u16).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let token_path = dir.path().join("tokens.bin").display().to_string();
+        let index_path = dir.path().join("tokens.fm").display().to_string();
+        std::fs::write(&token_path, transmute_slice::<u16, u8>(&text)).unwrap();
+
+        let built =
+            FmIndexRs::<u16>::build(token_path.clone(), index_path.clone(), 11, 8, false).unwrap();
+        let expected = FmIndexRs::new(&text, 11, 8, false).unwrap();
+        for query in [vec![0], vec![3, 8], vec![6, 0, 6]] {
+            let mut positions = built.positions(&query);
+            let mut expected_positions = expected.positions(&query);
+            positions.sort();
+            expected_positions.sort();
+
+            assert_eq!(positions, expected_positions);
+            assert_eq!(built.count_next(&query), expected.count_next(&query));
+        }
+
+        // The temporary reversed text and suffix array are removed
+        assert!(!std::path::Path::new(&format!("{}.rev.tmp", index_path)).exists());
+        assert!(!std::path::Path::new(&format!("{}.sa.tmp", index_path)).exists());
+    }
+}
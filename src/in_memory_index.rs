@@ -1,6 +1,4 @@
-use anyhow::Result;
 use funty::Unsigned;
-use pyo3::prelude::*;
 use rayon::prelude::*;
 use std::collections::HashMap;
 use std::fmt::Debug;
@@ -8,27 +6,49 @@ use std::fs::{File, OpenOptions};
 use std::io::Read;
 
 use crate::bindings::in_memory_index::InMemoryIndexTrait;
+use crate::count_cache::{CacheStats, CountCache};
+use crate::error::{Result, TokengramsError};
+use crate::metadata::{
+    remove_stale_sidecars, resolve_table_width, IndexFingerprint, IndexMetadata,
+};
 use crate::mmap_slice::MmapSliceMut;
-use crate::sample::{KneserNeyCache, Sample};
+use crate::query::{convert_context, convert_queries, convert_query, restore_prompt, OutOfVocab};
+use crate::sample::{KneserNeyCache, Sample, SampleRequest, SampleResult};
+use crate::sampling_config::SamplingConfig;
 use crate::table::SuffixTable;
-use crate::util::transmute_slice;
+use crate::table_index::{table_width, TableIndex, U40};
+use crate::util::{check_sample_size, check_vocab, seeded_rng};
 
 /// An in-memory index exposes suffix table functionality over text corpora small enough to fit in memory.
 pub struct InMemoryIndexRs<T: Unsigned> {
     table: SuffixTable<Box<[T]>, Box<[u64]>>,
     cache: KneserNeyCache,
+    count_cache: CountCache<T>,
+    out_of_vocab: OutOfVocab,
 }
 
 impl<T: Unsigned + Debug> InMemoryIndexRs<T> {
-    pub fn new(tokens: Vec<T>, vocab: Option<usize>, verbose: bool) -> Self {
+    pub fn new(tokens: Vec<T>, vocab: Option<usize>, verbose: bool) -> Result<Self> {
         let vocab = vocab.unwrap_or(u16::MAX as usize + 1);
+        check_vocab(&tokens, vocab)?;
+        Ok(Self::from_checked_tokens(tokens, vocab, verbose))
+    }
 
+    /// Builds an index over tokens already known to be less than `vocab`, such as those
+    /// converted with `query::convert_tokens`.
+    pub(crate) fn from_checked_tokens(
+        tokens: impl Into<Box<[T]>>,
+        vocab: usize,
+        verbose: bool,
+    ) -> Self {
         let table = SuffixTable::new(tokens, Some(vocab), verbose);
         debug_assert!(table.is_sorted());
 
         InMemoryIndexRs {
             table,
             cache: KneserNeyCache::default(),
+            count_cache: CountCache::default(),
+            out_of_vocab: OutOfVocab::default(),
         }
     }
 
@@ -37,41 +57,50 @@ impl<T: Unsigned + Debug> InMemoryIndexRs<T> {
         token_limit: Option<usize>,
         vocab: usize,
         verbose: bool,
-    ) -> PyResult<Self> {
-        let mut buffer = Vec::new();
-        let mut file = File::open(&path)?;
-
-        if let Some(max_tokens) = token_limit {
-            // Limit on the number of tokens to consider is provided
-            let max_bytes = max_tokens * std::mem::size_of::<T>();
-            file.take(max_bytes as u64).read_to_end(&mut buffer)?;
-        } else {
-            file.read_to_end(&mut buffer)?;
-        };
+    ) -> Result<Self> {
+        let tokens = Self::read_file_prefix::<T>(&path, token_limit)?;
+        check_vocab(&tokens, vocab)?;
+        Ok(Self::from_checked_tokens(tokens, vocab, verbose))
+    }
 
-        let tokens = transmute_slice::<u8, T>(buffer.as_slice());
-        let table = SuffixTable::new(tokens, Some(vocab), verbose);
-        debug_assert!(table.is_sorted());
+    /// Counts the tokens following `query` without consulting the count cache.
+    fn count_next_uncached(&self, query: &[T]) -> Vec<usize> {
+        self.table.count_next(query)
+    }
 
-        Ok(InMemoryIndexRs {
-            table,
-            cache: KneserNeyCache::default(),
-        })
+    fn fingerprint(&self) -> IndexFingerprint {
+        IndexFingerprint::new(self.table.get_text(), self.table.vocab())
+    }
+
+    fn convert_query(&self, query: &[usize]) -> Result<Option<Vec<T>>> {
+        convert_query(query, self.table.vocab(), self.out_of_vocab)
     }
 
-    fn read_file_to_boxed_slice<E: Unsigned>(path: &str) -> Result<Box<[E]>> {
+    fn read_file_to_boxed_slice<E: Copy>(path: &str) -> Result<Box<[E]>> {
+        Self::read_file_prefix(path, None)
+    }
+
+    /// Reads at most `limit` elements from the start of a file, returning an error unless the
+    /// whole file is a whole number of elements.
+    fn read_file_prefix<E: Copy>(path: &str, limit: Option<usize>) -> Result<Box<[E]>> {
         let mut file = File::open(path)?;
         let file_len = file.metadata()?.len() as usize;
 
         // Ensure file size is a multiple of size of E
-        if file_len % std::mem::size_of::<T>() != 0 {
-            anyhow::bail!("File size is not a multiple of element size");
+        if !file_len.is_multiple_of(std::mem::size_of::<E>()) {
+            return Err(TokengramsError::MisalignedFile {
+                size: file_len,
+                element_size: std::mem::size_of::<E>(),
+            });
         }
 
-        let num_elements = file_len / std::mem::size_of::<E>();
+        let num_elements = (file_len / std::mem::size_of::<E>()).min(limit.unwrap_or(usize::MAX));
         let mut vec: Vec<E> = Vec::with_capacity(num_elements);
         unsafe {
-            let buf = std::slice::from_raw_parts_mut(vec.as_mut_ptr() as *mut u8, file_len);
+            let buf = std::slice::from_raw_parts_mut(
+                vec.as_mut_ptr() as *mut u8,
+                num_elements * std::mem::size_of::<E>(),
+            );
             file.read_exact(buf)?;
             vec.set_len(num_elements);
         }
@@ -79,16 +108,39 @@ impl<T: Unsigned + Debug> InMemoryIndexRs<T> {
         Ok(vec.into_boxed_slice())
     }
 
-    pub fn from_disk(text_path: String, table_path: String, vocab: usize) -> PyResult<Self> {
+    fn read_table<I: TableIndex>(path: &str) -> Result<Box<[u64]>> {
+        let table = Self::read_file_to_boxed_slice::<I>(path)?;
+        Ok(table.par_iter().map(|i| i.to_u64()).collect())
+    }
+
+    pub fn from_disk(text_path: String, table_path: String, vocab: usize) -> Result<Self> {
         let text = Self::read_file_to_boxed_slice::<T>(&text_path)?;
-        let table = Self::read_file_to_boxed_slice::<u64>(&table_path)?;
+        let metadata = IndexMetadata::load(&table_path)?;
+
+        // Compact tables are widened to u64 entries in memory
+        let table = match resolve_table_width(&table_path, text.len(), metadata.as_ref())? {
+            4 => Self::read_table::<u32>(&table_path)?,
+            5 => Self::read_table::<U40>(&table_path)?,
+            8 => Self::read_file_to_boxed_slice::<u64>(&table_path)?,
+            width => {
+                return Err(TokengramsError::InvalidMetadata(format!(
+                    "Unsupported table width {}",
+                    width
+                )))
+            }
+        };
 
-        let suffix_table = SuffixTable::from_parts(text, table, Some(vocab));
-        debug_assert!(suffix_table.is_sorted());
+        let suffix_table = SuffixTable::from_parts(text, table, Some(vocab))?;
+        match metadata {
+            Some(metadata) => metadata.validate::<T>(suffix_table.len(), 1)?,
+            None => suffix_table.check_sorted()?,
+        }
 
         Ok(InMemoryIndexRs {
             table: suffix_table,
             cache: KneserNeyCache::default(),
+            count_cache: CountCache::default(),
+            out_of_vocab: OutOfVocab::default(),
         })
     }
 
@@ -96,11 +148,12 @@ impl<T: Unsigned + Debug> InMemoryIndexRs<T> {
         let text = self.table.get_text();
         let file = OpenOptions::new()
             .create(true)
+            .truncate(false)
             .read(true)
             .write(true)
             .open(&path)?;
 
-        let file_len = text.len() * std::mem::size_of::<T>();
+        let file_len = std::mem::size_of_val(text);
         file.set_len(file_len as u64)?;
 
         let mut mmap = MmapSliceMut::<T>::new(&file)?;
@@ -110,21 +163,34 @@ impl<T: Unsigned + Debug> InMemoryIndexRs<T> {
         Ok(())
     }
 
+    /// Saves the table using the narrowest entries able to index the text.
     pub fn save_table(&self, path: String) -> Result<()> {
+        match table_width(self.table.len()) {
+            4 => self.save_table_as::<u32>(&path),
+            5 => self.save_table_as::<U40>(&path),
+            _ => self.save_table_as::<u64>(&path),
+        }
+    }
+
+    fn save_table_as<I: TableIndex>(&self, path: &str) -> Result<()> {
         let table = self.table.get_table();
         let file = OpenOptions::new()
             .create(true)
+            .truncate(false)
             .read(true)
             .write(true)
-            .open(&path)?;
+            .open(path)?;
 
-        file.set_len((table.len() * 8) as u64)?;
+        file.set_len((table.len() * std::mem::size_of::<I>()) as u64)?;
 
-        let mut mmap = MmapSliceMut::<u64>::new(&file)?;
-        mmap.copy_from_slice(table);
+        let mut mmap = MmapSliceMut::<I>::new(&file)?;
+        mmap.par_iter_mut()
+            .zip(table.par_iter())
+            .for_each(|(dst, &src)| *dst = I::from_u64(src));
         mmap.flush()?;
 
-        Ok(())
+        IndexMetadata::new(self.table.get_text(), &mmap, self.table.vocab()).save(path)?;
+        remove_stale_sidecars(path)
     }
 }
 
@@ -133,12 +199,11 @@ impl<T: Unsigned> Sample<T> for InMemoryIndexRs<T> {
         &self.cache
     }
 
-    fn get_mut_cache(&mut self) -> &mut KneserNeyCache {
-        &mut self.cache
-    }
-
     fn count_next_slice(&self, query: &[T]) -> Vec<usize> {
-        self.table.count_next(query)
+        self.count_cache
+            .get_or_insert_with(query, self.table.vocab(), || {
+                self.count_next_uncached(query)
+            })
     }
 
     fn count_ngrams(&self, n: usize) -> HashMap<usize, usize> {
@@ -155,75 +220,205 @@ impl<T: Unsigned> InMemoryIndexTrait for InMemoryIndexRs<T> {
         self.save_text(text_path)
     }
 
+    fn out_of_vocab(&self) -> OutOfVocab {
+        self.out_of_vocab
+    }
+
+    fn set_out_of_vocab(&mut self, policy: OutOfVocab) {
+        self.out_of_vocab = policy;
+    }
+
+    fn save_cache(&self, path: String) -> Result<()> {
+        self.cache.save(&path, vec![self.fingerprint()])
+    }
+
+    fn load_cache(&mut self, path: String) -> Result<()> {
+        self.cache = KneserNeyCache::load(&path, &[self.fingerprint()])?;
+        Ok(())
+    }
+
     fn is_sorted(&self) -> bool {
         self.table.is_sorted()
     }
 
-    fn contains(&self, query: Vec<usize>) -> bool {
-        let query: Vec<T> = query
-            .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
-        self.table.contains(&query)
+    fn count_cache_capacity(&self) -> usize {
+        self.count_cache.capacity()
     }
 
-    fn positions(&self, query: Vec<usize>) -> Vec<u64> {
-        let query: Vec<T> = query
-            .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
-        self.table.positions(&query).to_vec()
+    fn set_count_cache_capacity(&mut self, capacity: usize) {
+        self.count_cache = CountCache::new(capacity);
     }
 
-    fn count(&self, query: Vec<usize>) -> usize {
-        let query: Vec<T> = query
+    fn count_cache_stats(&self) -> CacheStats {
+        self.count_cache.stats()
+    }
+
+    fn warm_count_cache(&self, top_m: usize, max_len: usize) {
+        self.count_cache
+            .warm(top_m, max_len, self.table.vocab(), |query| {
+                self.count_next_uncached(query)
+            });
+    }
+
+    fn contains(&self, query: Vec<usize>) -> Result<bool> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => self.table.contains(&query),
+            None => false,
+        })
+    }
+
+    fn positions(
+        &self,
+        query: Vec<usize>,
+        offset: usize,
+        limit: Option<usize>,
+        sorted: bool,
+    ) -> Result<Vec<u64>> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => self.table.positions_page(&query, offset, limit, sorted),
+            None => Vec::new(),
+        })
+    }
+
+    fn sample_positions(&self, query: Vec<usize>, k: usize, seed: Option<u64>) -> Result<Vec<u64>> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => self
+                .table
+                .sample_positions(&query, k, &mut seeded_rng(seed)),
+            None => Vec::new(),
+        })
+    }
+
+    fn count(&self, query: Vec<usize>) -> Result<usize> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => self.table.positions(&query).len(),
+            None => 0,
+        })
+    }
+
+    fn count_next(&self, query: Vec<usize>) -> Result<Vec<usize>> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => self.count_next_slice(&query),
+            None => vec![0; self.table.vocab()],
+        })
+    }
+
+    fn approx_count_next(
+        &self,
+        query: Vec<usize>,
+        sample_size: usize,
+        seed: Option<u64>,
+    ) -> Result<(Vec<f64>, Vec<f64>)> {
+        check_sample_size(sample_size)?;
+        let mut rng = seeded_rng(seed);
+        Ok(match self.convert_query(&query)? {
+            Some(query) => self.table.approx_count_next(&query, sample_size, &mut rng),
+            None => (vec![0.0; self.table.vocab()], vec![0.0; self.table.vocab()]),
+        })
+    }
+
+    fn batch_contains(&self, queries: Vec<Vec<usize>>) -> Result<Vec<bool>> {
+        Ok(self
+            .batch_count(queries)?
+            .into_iter()
+            .map(|count| count > 0)
+            .collect())
+    }
+
+    fn batch_count(&self, queries: Vec<Vec<usize>>) -> Result<Vec<usize>> {
+        let queries = convert_queries::<T>(&queries, self.table.vocab(), self.out_of_vocab)?;
+        Ok(self
+            .table
+            .batch_positions(&queries)
             .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
-        self.table.positions(&query).len()
+            .map(|positions| positions.len())
+            .collect())
     }
 
-    fn count_next(&self, query: Vec<usize>) -> Vec<usize> {
-        let query: Vec<T> = query
+    fn batch_positions(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<u64>>> {
+        let queries = convert_queries::<T>(&queries, self.table.vocab(), self.out_of_vocab)?;
+        Ok(self
+            .table
+            .batch_positions(&queries)
             .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
-        self.table.count_next(&query)
+            .map(|positions| positions.to_vec())
+            .collect())
     }
 
-    fn batch_count_next(&self, queries: Vec<Vec<usize>>) -> Vec<Vec<usize>> {
-        queries
+    fn batch_count_next(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<usize>>> {
+        let queries = convert_queries::<T>(&queries, self.table.vocab(), self.out_of_vocab)?;
+        Ok(queries
             .into_par_iter()
-            .map(|query| self.count_next(query))
-            .collect()
+            .map(|query| match query {
+                Some(query) => self.count_next_slice(&query),
+                None => vec![0; self.table.vocab()],
+            })
+            .collect())
     }
 
     fn sample_smoothed(
-        &mut self,
+        &self,
         query: Vec<usize>,
         n: usize,
         k: usize,
         num_samples: usize,
-    ) -> Result<Vec<Vec<usize>>> {
-        let query: Vec<T> = query
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<SampleResult>> {
+        let context: Vec<T> = convert_context(&query, self.table.vocab(), self.out_of_vocab)?;
+
+        let traces =
+            <Self as Sample<T>>::sample_smoothed(self, &context, n, k, num_samples, seed, config)?;
+        Ok(SampleResult::restore(&query, context.len(), traces))
+    }
+
+    fn batch_sample(
+        &self,
+        requests: Vec<SampleRequest<usize>>,
+        smoothed: bool,
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<Vec<SampleResult>>> {
+        let contexts = requests
             .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
+            .map(|request| request.convert(self.table.vocab(), self.out_of_vocab))
+            .collect::<Result<Vec<_>>>()?;
+
+        let traces = <Self as Sample<T>>::batch_sample(self, &contexts, smoothed, seed, config)?;
+        Ok(SampleResult::restore_batch(&requests, &contexts, traces))
+    }
+
+    fn decode_greedy(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        config: &SamplingConfig,
+    ) -> Result<(Vec<usize>, f64)> {
+        let context: Vec<T> = convert_context(&query, self.table.vocab(), self.out_of_vocab)?;
+
+        let (sequence, score) = <Self as Sample<T>>::decode_greedy(self, &context, n, k, config)?;
+        let mut restored = restore_prompt(&query, context.len(), vec![sequence]);
+        Ok((restored.swap_remove(0), score))
+    }
 
-        let samples_batch = <Self as Sample<T>>::sample_smoothed(self, &query, n, k, num_samples)?;
-        Ok(samples_batch
+    fn beam_search(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        beam_width: usize,
+        config: &SamplingConfig,
+    ) -> Result<Vec<(Vec<usize>, f64)>> {
+        let context: Vec<T> = convert_context(&query, self.table.vocab(), self.out_of_vocab)?;
+
+        let (sequences, scores): (Vec<_>, Vec<_>) =
+            <Self as Sample<T>>::beam_search(self, &context, n, k, beam_width, config)?
+                .into_iter()
+                .unzip();
+        Ok(restore_prompt(&query, context.len(), sequences)
             .into_iter()
-            .map(|samples| {
-                samples
-                    .into_iter()
-                    .filter_map(|sample| {
-                        match TryInto::<usize>::try_into(sample) {
-                            Ok(value) => Some(value),
-                            Err(_) => None, // Silently skip values that can't be converted
-                        }
-                    })
-                    .collect::<Vec<usize>>()
-            })
+            .zip(scores)
             .collect())
     }
 
@@ -233,63 +428,55 @@ impl<T: Unsigned> InMemoryIndexTrait for InMemoryIndexRs<T> {
         n: usize,
         k: usize,
         num_samples: usize,
-    ) -> Result<Vec<Vec<usize>>> {
-        let query: Vec<T> = query
-            .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
-
-        let samples_batch =
-            <Self as Sample<T>>::sample_unsmoothed(self, &query, n, k, num_samples)?;
-        Ok(samples_batch
-            .into_iter()
-            .map(|samples| {
-                samples
-                    .into_iter()
-                    .filter_map(|sample| {
-                        match TryInto::<usize>::try_into(sample) {
-                            Ok(value) => Some(value),
-                            Err(_) => None, // Silently skip values that can't be converted
-                        }
-                    })
-                    .collect::<Vec<usize>>()
-            })
-            .collect())
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<SampleResult>> {
+        let context: Vec<T> = convert_context(&query, self.table.vocab(), self.out_of_vocab)?;
+
+        let traces = <Self as Sample<T>>::sample_unsmoothed(
+            self,
+            &context,
+            n,
+            k,
+            num_samples,
+            seed,
+            config,
+        )?;
+        Ok(SampleResult::restore(&query, context.len(), traces))
     }
 
-    fn get_smoothed_probs(&mut self, query: Vec<usize>) -> Vec<f64> {
-        let query: Vec<T> = query
-            .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
+    fn get_smoothed_probs(&self, query: Vec<usize>) -> Result<Vec<f64>> {
+        let context: Vec<T> = convert_context(&query, self.table.vocab(), self.out_of_vocab)?;
 
-        <Self as Sample<T>>::get_smoothed_probs(self, &query)
+        Ok(<Self as Sample<T>>::get_smoothed_probs(self, &context))
     }
 
-    fn batch_get_smoothed_probs(&mut self, queries: Vec<Vec<usize>>) -> Vec<Vec<f64>> {
-        let queries: Vec<Vec<T>> = queries
-            .into_iter()
-            .map(|query| {
-                query
-                    .iter()
-                    .filter_map(|&item| T::try_from(item).ok())
-                    .collect()
-            })
-            .collect();
-        <Self as Sample<T>>::batch_get_smoothed_probs(self, &queries)
+    fn batch_get_smoothed_probs(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<f64>>> {
+        let contexts: Vec<Vec<T>> = queries
+            .iter()
+            .map(|query| convert_context(query, self.table.vocab(), self.out_of_vocab))
+            .collect::<Result<_>>()?;
+
+        Ok(<Self as Sample<T>>::batch_get_smoothed_probs(
+            self, &contexts,
+        ))
     }
 
-    fn estimate_deltas(&mut self, n: usize) {
+    fn estimate_deltas(&self, n: usize) {
         <Self as Sample<T>>::estimate_deltas(self, n)
     }
 }
 
-
 #[cfg(test)]
 pub mod tests {
     use super::*;
-    use utf16_literal::utf16;
+    use crate::automaton::TokenAutomaton;
+    use crate::bucket_table::BucketTable;
+    use crate::count_table::CountTable;
+    use crate::memmap_index::MemmapIndexRs;
     use crate::table::SuffixTable;
+    use std::sync::Arc;
+    use utf16_literal::utf16;
 
     fn sais(text: &str) -> SuffixTable {
         SuffixTable::new(text.encode_utf16().collect::<Vec<_>>(), None, false)
@@ -302,51 +489,268 @@ pub mod tests {
     #[test]
     fn sample_unsmoothed_empty_query_exists() {
         let s = utf16!("aaa");
-        let index: Box<dyn Sample<u16>> = Box::new(InMemoryIndexRs::new(s.to_vec(), None, false));
+        let index: Box<dyn Sample<u16>> =
+            Box::new(InMemoryIndexRs::new(s.to_vec(), None, false).unwrap());
 
-        let seqs = index.sample_unsmoothed(&[], 3, 10, 1).unwrap();
+        let seqs = index
+            .sample_unsmoothed(&[], 3, 10, 1, None, &SamplingConfig::default())
+            .unwrap();
 
-        assert_eq!(*seqs[0].last().unwrap(), s[0]);
+        assert_eq!(*seqs[0].tokens.last().unwrap(), s[0]);
     }
 
     #[test]
     fn sample_unsmoothed_u16_exists() {
         let s = utf16!("aaaa");
         let a = &s[0..1];
-        let index: Box<dyn Sample<u16>> = Box::new(InMemoryIndexRs::new(s.to_vec(), None, false));
+        let index: Box<dyn Sample<u16>> =
+            Box::new(InMemoryIndexRs::new(s.to_vec(), None, false).unwrap());
 
-        let seqs = index.sample_unsmoothed(a, 3, 10, 1).unwrap();
+        let seqs = index
+            .sample_unsmoothed(a, 3, 10, 1, None, &SamplingConfig::default())
+            .unwrap();
 
-        assert_eq!(*seqs[0].last().unwrap(), a[0]);
+        assert_eq!(*seqs[0].tokens.last().unwrap(), a[0]);
     }
 
     #[test]
     fn sample_unsmoothed_u32_exists() {
         let s: Vec<u32> = "aaaa".encode_utf16().map(|c| c as u32).collect();
         let u32_vocab = Some(u16::MAX as usize + 2);
-        let index: Box<dyn Sample<u32>> = Box::new(InMemoryIndexRs::<u32>::new(s.clone(), u32_vocab, false));
+        let index: Box<dyn Sample<u32>> =
+            Box::new(InMemoryIndexRs::<u32>::new(s.clone(), u32_vocab, false).unwrap());
 
-        let seqs = index.sample_unsmoothed(&s[0..1], 3, 10, 1).unwrap();
+        let seqs = index
+            .sample_unsmoothed(&s[0..1], 3, 10, 1, None, &SamplingConfig::default())
+            .unwrap();
 
-        assert_eq!(*seqs[0].last().unwrap(), s[0]);
+        assert_eq!(*seqs[0].tokens.last().unwrap(), s[0]);
     }
 
     #[test]
     fn sample_unsmoothed_usize_exists() {
         let s = utf16_as_usize("aaaa");
-        let index: Box<dyn InMemoryIndexTrait> = Box::new(InMemoryIndexRs::new(s.to_vec(), None, false));
+        let index: Box<dyn InMemoryIndexTrait> =
+            Box::new(InMemoryIndexRs::new(s.to_vec(), None, false).unwrap());
+
+        let seqs = index
+            .sample_unsmoothed(s[0..1].to_vec(), 3, 10, 1, None, &SamplingConfig::default())
+            .unwrap();
+
+        assert_eq!(*seqs[0].tokens.last().unwrap(), s[0]);
+    }
+
+    #[test]
+    fn new_out_of_vocab_fails() {
+        let result = InMemoryIndexRs::<u16>::new(vec![0, 1, 5], Some(4), false);
+
+        assert!(matches!(
+            result,
+            Err(TokengramsError::TokenOutOfVocab { token: 5, vocab: 4 })
+        ));
+    }
+
+    #[test]
+    fn sample_unsmoothed_unseen_context_backs_off() {
+        let s = utf16!("abc");
+        let index = InMemoryIndexRs::new(s.to_vec(), None, false).unwrap();
+
+        // "c" ends the corpus, so the bigram and trigram contexts ending in it are unseen
+        let traces =
+            Sample::sample_unsmoothed(&index, &s[..], 3, 4, 8, None, &SamplingConfig::default())
+                .unwrap();
+        for trace in &traces {
+            let seq = &trace.tokens;
+            assert_eq!(seq.len(), 7);
+            assert_eq!(trace.context_lens.len(), 4);
+            assert_eq!(trace.context_lens[0], 0);
+            for (i, order) in trace.context_lens.iter().map(|len| len + 1).enumerate() {
+                let context = &seq[3 + i + 1 - order..3 + i];
+                assert!(order <= 3);
+                assert!(index
+                    .table
+                    .contains(&[context, &seq[3 + i..3 + i + 1]].concat()));
+                if order < 3 {
+                    assert_eq!(
+                        index
+                            .table
+                            .count_next(&seq[3 + i - order..3 + i])
+                            .iter()
+                            .sum::<usize>(),
+                        0
+                    );
+                }
+            }
+        }
+
+        let banned_all = SamplingConfig {
+            banned_tokens: s.iter().map(|&t| t as usize).collect(),
+            ..Default::default()
+        };
+        let result = Sample::sample_unsmoothed(&index, &s[..1], 2, 1, 1, None, &banned_all);
+        assert!(matches!(result, Err(TokengramsError::EmptyDistribution)));
+    }
+
+    #[test]
+    fn sampling_rejects_zero_order() {
+        let s = utf16!("abc");
+        let index: Box<dyn Sample<u16>> =
+            Box::new(InMemoryIndexRs::new(s.to_vec(), None, false).unwrap());
+        let config = SamplingConfig::default();
+        let requests = [SampleRequest {
+            query: s[..1].to_vec(),
+            n: 0,
+            k: 2,
+            num_samples: 1,
+        }];
+
+        for result in [
+            index.sample_unsmoothed(&s[..1], 0, 2, 1, None, &config),
+            index.sample_smoothed(&s[..1], 0, 2, 1, None, &config),
+        ] {
+            assert!(matches!(result, Err(TokengramsError::InvalidArgument(_))));
+        }
+        for smoothed in [false, true] {
+            assert!(matches!(
+                index.batch_sample(&requests, smoothed, None, &config),
+                Err(TokengramsError::InvalidArgument(_))
+            ));
+        }
+        assert!(matches!(
+            index.beam_search(&s[..1], 0, 2, 1, &config),
+            Err(TokengramsError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn count_out_of_vocab_query() {
+        let s = utf16_as_usize("aaab");
+        let mut index =
+            InMemoryIndexRs::<u16>::new(s.iter().map(|&t| t as u16).collect(), Some(200), false)
+                .unwrap();
+        let query = vec![s[0], 70_000, s[3]];
+
+        assert!(matches!(
+            index.count(query.clone()),
+            Err(TokengramsError::TokenOutOfVocab { token: 70_000, .. })
+        ));
+
+        index.set_out_of_vocab(OutOfVocab::Miss);
+        assert_eq!(index.count(query.clone()).unwrap(), 0);
+        assert_eq!(index.count_next(query).unwrap(), vec![0; 200]);
+    }
+
+    #[test]
+    fn byte_level_index() {
+        let index = InMemoryIndexRs::<u8>::new(b"abracadabra".to_vec(), Some(256), false).unwrap();
+
+        assert_eq!(
+            index.count(b"abra".map(|b| b as usize).to_vec()).unwrap(),
+            2
+        );
+        assert_eq!(
+            index.count_next(vec![b'r' as usize]).unwrap()[b'a' as usize],
+            2
+        );
+        assert!(matches!(
+            index.count(vec![256]),
+            Err(TokengramsError::TokenOutOfVocab { token: 256, .. })
+        ));
+    }
+
+    #[test]
+    fn count_next_uses_count_cache() {
+        let mut index = InMemoryIndexRs::new(utf16!("abcabd").to_vec(), None, false).unwrap();
+        index.set_count_cache_capacity(8);
+        let query = vec![b'a' as usize];
+
+        let counts = InMemoryIndexTrait::count_next(&index, query.clone()).unwrap();
+        let batch = InMemoryIndexTrait::batch_count_next(&index, vec![query; 2]).unwrap();
+        assert_eq!(batch, vec![counts.clone(), counts]);
+        let stats = index.count_cache_stats();
+        assert_eq!((stats.misses, stats.hits, stats.size), (1, 2, 1));
+    }
 
-        let seqs = index.sample_unsmoothed(s[0..1].to_vec(), 3, 10, 1).unwrap();
+    #[test]
+    fn misaligned_token_files_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens.bin").display().to_string();
+        std::fs::write(&path, [1, 0, 2, 0, 3]).unwrap();
+
+        assert!(matches!(
+            InMemoryIndexRs::<u16>::from_token_file(path.clone(), None, 1 << 16, false),
+            Err(TokengramsError::MisalignedFile {
+                size: 5,
+                element_size: 2
+            })
+        ));
+        let index = InMemoryIndexRs::<u8>::from_token_file(path.clone(), Some(2), 256, false);
+        assert_eq!(index.unwrap().table.get_text(), [1, 0]);
+    }
+
+    #[test]
+    fn save_compact_table() {
+        let tokens: Vec<u16> = utf16!("aabbccabccba").to_vec();
+        let index = InMemoryIndexRs::new(tokens.clone(), None, false).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let text_path = dir.path().join("tokens.bin").display().to_string();
+        let table_path = dir.path().join("tokens.idx").display().to_string();
+
+        index.save_text(text_path.clone()).unwrap();
+        index.save_table(table_path.clone()).unwrap();
+        assert_eq!(
+            std::fs::metadata(&table_path).unwrap().len(),
+            tokens.len() as u64 * 4
+        );
+
+        let loaded =
+            InMemoryIndexRs::<u16>::from_disk(text_path.clone(), table_path.clone(), 1 << 16)
+                .unwrap();
+        assert_eq!(loaded.table.get_table(), index.table.get_table());
+    }
 
-        assert_eq!(*seqs[0].last().unwrap(), s[0]);
+    #[test]
+    fn stale_sidecars_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let text_path = dir.path().join("tokens.bin").display().to_string();
+        let table_path = dir.path().join("tokens.idx").display().to_string();
+        let counts_path = CountTable::path(&table_path);
+        let stale_path = format!("{}.stale", counts_path);
+
+        let old = InMemoryIndexRs::new(utf16!("abcabd").to_vec(), None, false).unwrap();
+        old.save_text(text_path.clone()).unwrap();
+        old.save_table(table_path.clone()).unwrap();
+        let mut index =
+            MemmapIndexRs::<u16>::new(text_path.clone(), table_path.clone(), 1 << 16).unwrap();
+        index.build_buckets(1).unwrap();
+        index.build_counts().unwrap();
+        std::fs::copy(&counts_path, &stale_path).unwrap();
+
+        // Saving a table over another text removes the sidecars of the old table
+        let new = InMemoryIndexRs::new(utf16!("bcdbca").to_vec(), None, false).unwrap();
+        new.save_text(text_path.clone()).unwrap();
+        new.save_table(table_path.clone()).unwrap();
+        assert!(!std::path::Path::new(&BucketTable::path(&table_path)).exists());
+        assert!(!std::path::Path::new(&counts_path).exists());
+
+        // and sidecars restored from the old table are rejected
+        std::fs::rename(&stale_path, &counts_path).unwrap();
+        assert!(matches!(
+            MemmapIndexRs::<u16>::new(text_path.clone(), table_path.clone(), 1 << 16),
+            Err(TokengramsError::InvalidMetadata(_))
+        ));
     }
 
     #[test]
     fn sample_smoothed_exists() {
         let s = utf16!("aabbccabccba");
-        let mut index: Box<dyn Sample<u16>> = Box::new(InMemoryIndexRs::new(s.to_vec(), None, false));
+        let index: Box<dyn Sample<u16>> =
+            Box::new(InMemoryIndexRs::new(s.to_vec(), None, false).unwrap());
 
-        let tokens = &index.sample_smoothed(&s[0..1], 3, 10, 1).unwrap()[0];
+        let tokens = &index
+            .sample_smoothed(&s[0..1], 3, 10, 1, None, &SamplingConfig::default())
+            .unwrap()[0]
+            .tokens;
 
         assert_eq!(tokens.len(), 11);
     }
@@ -354,13 +758,356 @@ pub mod tests {
     #[test]
     fn sample_smoothed_empty_query_exists() {
         let s: Vec<u16> = "aabbccabccba".encode_utf16().collect();
-        let mut index: Box<dyn Sample<u16>> = Box::new(InMemoryIndexRs::new(s, None, false));
+        let index: Box<dyn Sample<u16>> = Box::new(InMemoryIndexRs::new(s, None, false).unwrap());
 
-        let tokens = &index.sample_smoothed(&[], 1, 10, 10).unwrap()[0];
+        let tokens = &index
+            .sample_smoothed(&[], 1, 10, 10, None, &SamplingConfig::default())
+            .unwrap()[0]
+            .tokens;
 
         assert_eq!(tokens.len(), 10);
     }
 
+    #[test]
+    fn seeded_samples_are_reproducible() {
+        let s: Vec<u16> = "the cat sat on the mat and the rat ate the hat"
+            .encode_utf16()
+            .collect();
+        let index: Box<dyn Sample<u16>> = Box::new(InMemoryIndexRs::new(s, None, false).unwrap());
+        let sample = |threads: usize, seed: u64| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            pool.install(|| {
+                (
+                    index
+                        .sample_unsmoothed(&[], 3, 20, 16, Some(seed), &SamplingConfig::default())
+                        .unwrap(),
+                    index
+                        .sample_smoothed(&[], 3, 20, 16, Some(seed), &SamplingConfig::default())
+                        .unwrap(),
+                )
+            })
+        };
+
+        let (unsmoothed, smoothed) = sample(4, 7);
+        assert_eq!(sample(1, 7), (unsmoothed.clone(), smoothed.clone()));
+        assert_ne!(sample(4, 8), (unsmoothed.clone(), smoothed));
+        // Each sample draws from its own stream
+        assert!(unsmoothed.iter().any(|seq| *seq != unsmoothed[0]));
+    }
+
+    #[test]
+    fn batch_sample_matches_single_prompt_sampling() {
+        let s: Vec<u16> = "the cat sat on the mat and the rat ate the hat"
+            .encode_utf16()
+            .collect();
+        let index: Box<dyn Sample<u16>> =
+            Box::new(InMemoryIndexRs::new(s.clone(), None, false).unwrap());
+        let config = SamplingConfig::default();
+        let request = |query: &[u16], n, k, num_samples| SampleRequest {
+            query: query.to_vec(),
+            n,
+            k,
+            num_samples,
+        };
+        let requests = [
+            request(&s[..3], 3, 5, 3),
+            request(&[], 2, 10, 0),
+            request(&s[3..5], 4, 8, 2),
+        ];
+
+        for smoothed in [false, true] {
+            let single = |request: &SampleRequest<u16>, seed| {
+                let (query, n, k, num_samples) =
+                    (&request.query, request.n, request.k, request.num_samples);
+                if smoothed {
+                    index.sample_smoothed(query, n, k, num_samples, Some(seed), &config)
+                } else {
+                    index.sample_unsmoothed(query, n, k, num_samples, Some(seed), &config)
+                }
+                .unwrap()
+            };
+
+            // Samples are numbered across requests, so the third request starts at seed 5 + 3
+            let batch = index
+                .batch_sample(&requests, smoothed, Some(5), &config)
+                .unwrap();
+            assert_eq!(batch.len(), 3);
+            assert_eq!(batch[0], single(&requests[0], 5));
+            assert!(batch[1].is_empty());
+            assert_eq!(batch[2], single(&requests[2], 8));
+            for (request, traces) in requests.iter().zip(&batch) {
+                for trace in traces {
+                    assert_eq!(trace.tokens[..request.query.len()], request.query);
+                    assert!(trace.tokens.len() <= request.query.len() + request.k);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn samples_carry_diagnostics() {
+        let s: Vec<u16> = "aaab".encode_utf16().collect();
+        let index: Box<dyn Sample<u16>> =
+            Box::new(InMemoryIndexRs::new(s.clone(), None, false).unwrap());
+        let config = SamplingConfig::default().with_diagnostics(true);
+
+        // "a" is followed by "a" twice and "b" once
+        let trace = &index
+            .sample_unsmoothed(&s[..1], 2, 1, 1, Some(0), &config)
+            .unwrap()[0];
+        let p = if trace.tokens[1] == s[0] { 2.0 } else { 1.0 } / 3.0;
+        let entropy = -(2.0f64 / 3.0 * (2.0f64 / 3.0).ln() + 1.0 / 3.0 * (1.0f64 / 3.0).ln());
+        assert!((trace.log_probs[0] - f64::ln(p)).abs() < 1e-12);
+        assert!((trace.entropies[0] - entropy).abs() < 1e-12);
+        assert_eq!((trace.context_lens[0], trace.context_counts[0]), (1, 3));
+
+        let trace = &index
+            .sample_smoothed(&s[..2], 2, 3, 1, Some(0), &config)
+            .unwrap()[0];
+        assert_eq!(trace.log_probs.len(), trace.tokens.len() - 2);
+        for (i, &log_prob) in trace.log_probs.iter().enumerate() {
+            let context = &trace.tokens[i + 1..i + 2];
+            let probs = index.get_smoothed_probs(context);
+            let total: f64 = probs.iter().sum();
+            let token = trace.tokens[i + 2] as usize;
+            assert!((log_prob - (probs[token] / total).ln()).abs() < 1e-12);
+            // "b" ends the corpus, so the distribution after it backs off to the empty context
+            let context_len = if context[0] == s[3] { 0 } else { 1 };
+            assert_eq!(trace.context_lens[i], context_len);
+            assert_eq!(
+                trace.context_counts[i],
+                index
+                    .count_next_slice(&context[1 - context_len..])
+                    .iter()
+                    .sum::<usize>()
+            );
+            assert!(trace.entropies[i] > 0.0);
+        }
+
+        // Without diagnostics, samples only carry their context lengths and counts
+        let trace = &index
+            .sample_smoothed(&s[3..], 2, 1, 1, Some(0), &SamplingConfig::default())
+            .unwrap()[0];
+        assert!(trace.log_probs.is_empty() && trace.entropies.is_empty());
+        assert_eq!((trace.context_lens[0], trace.context_counts[0]), (0, 4));
+    }
+
+    #[test]
+    fn sampling_config_applies_to_both_paths() {
+        let s: Vec<u16> = "abcabdabcabe".encode_utf16().collect();
+        let [a, b, c, d, e] = [s[0], s[1], s[2], s[5], s[11]];
+        let index: Box<dyn Sample<u16>> = Box::new(InMemoryIndexRs::new(s, None, false).unwrap());
+
+        // Generation ends early after a stop token, which is kept
+        let config = SamplingConfig {
+            stop_tokens: vec![b as usize],
+            ..Default::default()
+        };
+        for seq in index
+            .sample_unsmoothed(&[a], 2, 10, 8, Some(0), &config)
+            .unwrap()
+            .into_iter()
+            .chain(
+                index
+                    .sample_smoothed(&[a], 2, 10, 8, Some(0), &config)
+                    .unwrap(),
+            )
+        {
+            let end = seq.tokens.iter().position(|&t| t == b).unwrap();
+            assert_eq!(end, seq.tokens.len() - 1);
+        }
+
+        // Only tokens seen at least twice after "ab" can be drawn, and "c" is the most common
+        let config = SamplingConfig {
+            min_count: 2,
+            ..Default::default()
+        };
+        for seq in index
+            .sample_unsmoothed(&[a, b], 3, 1, 8, Some(0), &config)
+            .unwrap()
+            .into_iter()
+            .chain(
+                index
+                    .sample_smoothed(&[a, b], 3, 1, 8, Some(0), &config)
+                    .unwrap(),
+            )
+        {
+            assert_eq!(seq.tokens, vec![a, b, c]);
+        }
+        // "e" ends the text, so the minimum count applies to the context smoothing backs off to
+        let config = SamplingConfig {
+            min_count: 1,
+            ..Default::default()
+        };
+        assert!(index
+            .sample_smoothed(&[e], 2, 3, 2, Some(0), &config)
+            .is_ok());
+        assert!(index.beam_search(&[e], 2, 3, 2, &config).is_ok());
+
+        let config = SamplingConfig {
+            temperature: 0.0,
+            banned_tokens: vec![c as usize],
+            ..Default::default()
+        };
+        assert_eq!(
+            index
+                .sample_unsmoothed(&[a, b], 3, 1, 1, None, &config)
+                .unwrap()[0]
+                .tokens,
+            vec![a, b, d]
+        );
+
+        let invalid = SamplingConfig {
+            top_p: Some(2.0),
+            ..Default::default()
+        };
+        assert!(matches!(
+            index.sample_smoothed(&[a], 2, 1, 1, None, &invalid),
+            Err(TokengramsError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn beam_search_finds_most_likely_continuations() {
+        let text: Vec<u16> = (0..200u32)
+            .map(|i| ((i * i + i / 3) % 7 % 4) as u16)
+            .collect();
+        let index: Box<dyn Sample<u16>> =
+            Box::new(InMemoryIndexRs::new(text, Some(4), false).unwrap());
+        let config = SamplingConfig::default();
+        let query = [1u16, 2];
+
+        // A beam as wide as the vocabulary keeps every first token, so the search is exact
+        let log_prob = |context: &[u16], token: u16| {
+            index.get_smoothed_probs(&context[context.len() - 2..])[token as usize].ln()
+        };
+        let mut expected: Vec<(Vec<u16>, f64)> = (0..4u16)
+            .flat_map(|a| (0..4u16).map(move |b| (a, b)))
+            .map(|(a, b)| {
+                let score = log_prob(&query, a) + log_prob(&[query[1], a], b);
+                (vec![1, 2, a, b], score)
+            })
+            .collect();
+        expected.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        let beams = index.beam_search(&query, 3, 2, 4, &config).unwrap();
+        assert_eq!(beams.len(), 4);
+        for ((sequence, score), (expected_sequence, expected_score)) in beams.iter().zip(&expected)
+        {
+            assert_eq!(sequence, expected_sequence);
+            assert!((score - expected_score).abs() < 1e-9);
+        }
+
+        let (greedy, score) = index.decode_greedy(&query, 3, 5, &config).unwrap();
+        assert_eq!(
+            (greedy.clone(), score),
+            index.beam_search(&query, 3, 5, 1, &config).unwrap()[0]
+        );
+        for i in query.len()..greedy.len() {
+            let probs = index.get_smoothed_probs(&greedy[i - 2..i]);
+            let best = probs.iter().copied().fold(0.0, f64::max);
+            assert_eq!(probs[greedy[i] as usize], best);
+        }
+
+        // Beams end after a stop token
+        let stop = SamplingConfig {
+            stop_tokens: vec![greedy[2] as usize],
+            ..Default::default()
+        };
+        let (stopped, _) = index.decode_greedy(&query, 3, 5, &stop).unwrap();
+        assert_eq!(stopped, greedy[..3]);
+
+        assert!(matches!(
+            index.beam_search(&query, 3, 2, 0, &config),
+            Err(TokengramsError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn automaton_constrains_generation() {
+        let s: Vec<u16> = "abcabdabcabe".encode_utf16().collect();
+        let [a, b, c, d, e] = [s[0], s[1], s[2], s[5], s[11]];
+        let index: Box<dyn Sample<u16>> = Box::new(InMemoryIndexRs::new(s, None, false).unwrap());
+
+        // "ea" never occurs, so its continuation backs off to a shorter context
+        let allowed = [vec![c, a], vec![d], vec![e, a, b]];
+        let config = SamplingConfig {
+            automaton: Some(Arc::new(TokenAutomaton::from_sequences(
+                &allowed
+                    .iter()
+                    .map(|seq| seq.iter().map(|&t| t as usize).collect())
+                    .collect::<Vec<_>>(),
+            ))),
+            ..Default::default()
+        };
+        for seq in index
+            .sample_unsmoothed(&[a, b], 3, 10, 16, Some(0), &config)
+            .unwrap()
+            .into_iter()
+            .chain(
+                index
+                    .sample_smoothed(&[a, b], 3, 10, 16, Some(0), &config)
+                    .unwrap(),
+            )
+        {
+            assert!(allowed.contains(&seq.tokens[2..].to_vec()));
+        }
+
+        let mut beams: Vec<Vec<u16>> = index
+            .beam_search(&[a, b], 3, 10, 3, &config)
+            .unwrap()
+            .into_iter()
+            .map(|(seq, _)| seq[2..].to_vec())
+            .collect();
+        beams.sort();
+        let mut expected = allowed.to_vec();
+        expected.sort();
+        assert_eq!(beams, expected);
+    }
+
+    #[test]
+    fn automaton_allows_prefix_sequences() {
+        let s: Vec<u16> = "abcabdabcabe".encode_utf16().collect();
+        let [a, b, c] = [s[0], s[1], s[2]];
+        let index: Box<dyn Sample<u16>> = Box::new(InMemoryIndexRs::new(s, None, false).unwrap());
+
+        // "ab" is followed by "c" twice, which continues it, and by "d" or "e", which end it
+        let allowed = [vec![a, b], vec![a, b, c]];
+        let config = SamplingConfig {
+            automaton: Some(Arc::new(TokenAutomaton::from_sequences(&[
+                vec![a as usize, b as usize],
+                vec![a as usize, b as usize, c as usize],
+            ]))),
+            ..Default::default()
+        };
+        let mut samples: Vec<Vec<u16>> = index
+            .sample_unsmoothed(&[c], 3, 10, 32, Some(0), &config)
+            .unwrap()
+            .into_iter()
+            .chain(
+                index
+                    .sample_smoothed(&[c], 3, 10, 32, Some(0), &config)
+                    .unwrap(),
+            )
+            .map(|trace| trace.tokens[1..].to_vec())
+            .collect();
+        samples.sort();
+        samples.dedup();
+        assert_eq!(samples, allowed);
+
+        let mut beams: Vec<Vec<u16>> = index
+            .beam_search(&[c], 3, 10, 2, &config)
+            .unwrap()
+            .into_iter()
+            .map(|(seq, _)| seq[1..].to_vec())
+            .collect();
+        beams.sort();
+        assert_eq!(beams, allowed);
+    }
+
     #[test]
     fn smoothed_probs_exists() {
         let tokens = "aaaaaaaabc".to_string();
@@ -376,7 +1123,8 @@ pub mod tests {
             .collect::<Vec<f64>>();
 
         // Get smoothed probs for query
-        let mut index: Box<dyn Sample<u16>> = Box::new(InMemoryIndexRs::new(tokens_vec, None, false));
+        let index: Box<dyn Sample<u16>> =
+            Box::new(InMemoryIndexRs::new(tokens_vec, None, false).unwrap());
         let smoothed_probs = index.get_smoothed_probs(&query);
 
         // Compare unsmoothed and smoothed probabilities
@@ -392,4 +1140,56 @@ pub mod tests {
         assert!(smoothed_probs[a] > 0.1);
         assert!(smoothed_probs[c] < 1.0);
     }
+
+    #[test]
+    fn concurrent_smoothed_probs() {
+        let tokens: Vec<u16> = "abracadabra cadabra abra".encode_utf16().collect();
+        let index: Box<dyn Sample<u16>> =
+            Box::new(InMemoryIndexRs::new(tokens.clone(), None, false).unwrap());
+        let queries: Vec<&[u16]> = (0..tokens.len() - 2).map(|i| &tokens[i..i + 2]).collect();
+
+        // Every task races to fill the shared cache on first use
+        let probs: Vec<Vec<f64>> = queries
+            .par_iter()
+            .map(|query| {
+                index.estimate_deltas(2);
+                index.get_smoothed_probs(query)
+            })
+            .collect();
+
+        let fresh: Box<dyn Sample<u16>> =
+            Box::new(InMemoryIndexRs::new(tokens.clone(), None, false).unwrap());
+        fresh.estimate_deltas(2);
+        for (query, probs) in queries.iter().zip(probs) {
+            assert_eq!(probs, fresh.get_smoothed_probs(query));
+        }
+    }
+
+    #[test]
+    fn save_and_load_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kn.cache").display().to_string();
+        let tokens: Vec<u16> = "abracadabra cadabra abra".encode_utf16().collect();
+        let query = utf16_as_usize("ab");
+
+        let index = InMemoryIndexRs::new(tokens.clone(), None, false).unwrap();
+        Sample::estimate_deltas(&index, 3);
+        let probs = InMemoryIndexTrait::get_smoothed_probs(&index, query.clone()).unwrap();
+        index.save_cache(path.clone()).unwrap();
+
+        let mut loaded = InMemoryIndexRs::new(tokens.clone(), None, false).unwrap();
+        loaded.load_cache(path.clone()).unwrap();
+        // The trigram delta is only known from the saved estimate
+        assert_eq!(
+            InMemoryIndexTrait::get_smoothed_probs(&loaded, query).unwrap(),
+            probs
+        );
+
+        // A cache computed from another corpus is rejected
+        let mut other = InMemoryIndexRs::new(tokens[1..].to_vec(), None, false).unwrap();
+        assert!(matches!(
+            other.load_cache(path.clone()),
+            Err(TokengramsError::InvalidMetadata(_))
+        ));
+    }
 }
@@ -1,25 +1,57 @@
+pub mod error;
 pub mod mmap_slice;
+pub use automaton::TokenAutomaton;
+pub use bindings::fm_index::FmIndex;
 pub use bindings::in_memory_index::InMemoryIndex;
 pub use bindings::memmap_index::MemmapIndex;
+pub use bindings::position_pages::PositionPages;
 pub use bindings::sharded_memmap_index::ShardedMemmapIndex;
+pub use bindings::sparse_memmap_index::SparseMemmapIndex;
+pub use count_cache::CacheStats;
+pub use error::TokengramsError;
+pub use sample::SampleResult;
+pub use sampling_config::SamplingConfig;
 pub use table::SuffixTable;
+pub use verify::IntegrityIssue;
 
 /// Python bindings
 use pyo3::prelude::*;
 
+mod automaton;
 mod bindings;
+mod bucket_table;
+mod count_cache;
+mod count_table;
+mod fm_index;
 mod in_memory_index;
 mod memmap_index;
+pub mod metadata;
 mod par_quicksort;
+mod query;
 mod sample;
+mod sampling_config;
 mod sharded_memmap_index;
+mod sparse_memmap_index;
+mod sparse_table;
 mod table;
+pub mod table_index;
 mod util;
+mod verify;
+mod wavelet_matrix;
 
 #[pymodule]
 fn tokengrams(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<InMemoryIndex>()?;
     m.add_class::<MemmapIndex>()?;
     m.add_class::<ShardedMemmapIndex>()?;
+    m.add_class::<SparseMemmapIndex>()?;
+    m.add_class::<FmIndex>()?;
+    m.add_class::<IntegrityIssue>()?;
+    m.add_class::<CacheStats>()?;
+    m.add_class::<PositionPages>()?;
+    m.add_class::<SamplingConfig>()?;
+    m.add_class::<SampleResult>()?;
+    m.add_class::<TokenAutomaton>()?;
+    error::add_exceptions(m)?;
     Ok(())
 }
@@ -1,40 +1,127 @@
-use anyhow::Result;
 use funty::Unsigned;
+use rand::Rng;
 use rayon::prelude::*;
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 use std::time::Instant;
 
 use crate::bindings::memmap_index::MemmapIndexTrait;
+use crate::bucket_table::BucketTable;
+use crate::count_cache::{CacheStats, CountCache};
+use crate::count_table::CountTable;
+use crate::error::{Result, TokengramsError};
+use crate::metadata::{
+    remove_stale_sidecars, resolve_table_width, IndexFingerprint, IndexMetadata,
+};
 use crate::mmap_slice::{MmapSlice, MmapSliceMut};
 use crate::par_quicksort::par_sort_unstable_by_key;
-use crate::sample::{KneserNeyCache, Sample};
+use crate::query::{convert_context, convert_queries, convert_query, restore_prompt, OutOfVocab};
+use crate::sample::{KneserNeyCache, Sample, SampleRequest, SampleResult};
+use crate::sampling_config::SamplingConfig;
 use crate::table::SuffixTable;
+use crate::table_index::{table_width, TableIndex, U40};
+use crate::util::{check_sample_size, check_vocab, seeded_rng};
+use crate::verify::{verify, IntegrityIssue};
+
+/// A suffix table whose entry width is chosen at build time from the length of its text.
+enum MmapTable<T: Unsigned> {
+    U32(SuffixTable<MmapSlice<T>, MmapSlice<u32>>),
+    U40(SuffixTable<MmapSlice<T>, MmapSlice<U40>>),
+    U64(SuffixTable<MmapSlice<T>, MmapSlice<u64>>),
+}
+
+/// Evaluates `$body` with `$table` bound to the suffix table, whatever its entry width.
+macro_rules! with_table {
+    ($mmap_table:expr, $table:ident => $body:expr) => {
+        match $mmap_table {
+            MmapTable::U32($table) => $body,
+            MmapTable::U40($table) => $body,
+            MmapTable::U64($table) => $body,
+        }
+    };
+}
+
+impl<T: Unsigned> MmapTable<T> {
+    fn open(text: MmapSlice<T>, table_file: &File, width: usize, vocab: usize) -> Result<Self> {
+        Ok(match width {
+            4 => MmapTable::U32(SuffixTable::from_parts(
+                text,
+                MmapSlice::new(table_file)?,
+                Some(vocab),
+            )?),
+            5 => MmapTable::U40(SuffixTable::from_parts(
+                text,
+                MmapSlice::new(table_file)?,
+                Some(vocab),
+            )?),
+            8 => MmapTable::U64(SuffixTable::from_parts(
+                text,
+                MmapSlice::new(table_file)?,
+                Some(vocab),
+            )?),
+            width => {
+                return Err(TokengramsError::InvalidMetadata(format!(
+                    "Unsupported table width {}",
+                    width
+                )))
+            }
+        })
+    }
+
+    fn vocab(&self) -> usize {
+        with_table!(self, table => table.vocab())
+    }
+
+    fn len(&self) -> usize {
+        with_table!(self, table => table.len())
+    }
+}
 
 /// A memmap index exposes suffix table functionality over text corpora too large to fit in memory.
 pub struct MemmapIndexRs<T: Unsigned> {
-    table: SuffixTable<MmapSlice<T>, MmapSlice<u64>>,
+    table: MmapTable<T>,
+    table_path: String,
+    metadata: Option<IndexMetadata>,
+    counts: Option<CountTable>,
     cache: KneserNeyCache,
+    count_cache: CountCache<T>,
+    out_of_vocab: OutOfVocab,
 }
 
 impl<T: Unsigned> MemmapIndexRs<T> {
+    /// Opens a prebuilt index. Tables built with metadata are checked against it, while tables
+    /// without metadata fall back to an O(n) check that the table is sorted.
     pub fn new(text_path: String, table_path: String, vocab: usize) -> Result<Self> {
-        let text_file = File::open(&text_path)?;
+        let text = MmapSlice::<T>::new(&File::open(&text_path)?)?;
         let table_file = File::open(&table_path)?;
 
-        let table = SuffixTable::from_parts(
-            MmapSlice::<T>::new(&text_file)?,
-            MmapSlice::new(&table_file)?,
-            Some(vocab),
-        );
-        assert!(table.is_sorted());
+        let metadata = IndexMetadata::load(&table_path)?;
+        if let Some(metadata) = &metadata {
+            metadata.validate::<T>(text.len(), 1)?;
+        }
+        let width = resolve_table_width(&table_path, text.len(), metadata.as_ref())?;
+        let table = MmapTable::open(text, &table_file, width, vocab)?;
+        if metadata.is_none() {
+            with_table!(&table, table => table.check_sorted())?;
+        }
 
-        Ok(MemmapIndexRs {
+        let mut index = MemmapIndexRs {
             table,
+            table_path,
+            metadata,
+            counts: None,
             cache: KneserNeyCache::default(),
-        })
+            count_cache: CountCache::default(),
+            out_of_vocab: OutOfVocab::default(),
+        };
+        let fingerprint = index.fingerprint();
+        let buckets = BucketTable::load(&index.table_path, &fingerprint)?;
+        with_table!(&mut index.table, table => table.set_buckets(buckets));
+        index.counts = CountTable::load(&index.table_path, &fingerprint)?;
+        Ok(index)
     }
 
+    /// Builds an index over a token file, using the narrowest table entries able to index it.
     pub fn build(
         text_path: String,
         table_path: String,
@@ -42,27 +129,58 @@ impl<T: Unsigned> MemmapIndexRs<T> {
         verbose: bool,
     ) -> Result<Self> {
         // Memory map the text as read-only
-        let text_mmap = MmapSlice::<T>::new(&File::open(&text_path).unwrap()).unwrap();
+        let text_mmap = MmapSlice::<T>::new(&File::open(&text_path)?)?;
+        check_vocab(&text_mmap, vocab)?;
 
         let table_file = OpenOptions::new()
             .create(true)
+            .truncate(false)
             .read(true)
             .write(true)
             .open(&table_path)?;
 
+        let table = match table_width(text_mmap.len()) {
+            4 => MmapTable::U32(Self::build_table(text_mmap, &table_file, vocab, verbose)?),
+            5 => MmapTable::U40(Self::build_table(text_mmap, &table_file, vocab, verbose)?),
+            _ => MmapTable::U64(Self::build_table(text_mmap, &table_file, vocab, verbose)?),
+        };
+
+        let metadata = with_table!(&table, table => {
+            IndexMetadata::new(table.get_text(), table.get_table(), vocab)
+        });
+        metadata.save(&table_path)?;
+        remove_stale_sidecars(&table_path)?;
+
+        Ok(MemmapIndexRs {
+            table,
+            table_path,
+            metadata: Some(metadata),
+            counts: None,
+            cache: KneserNeyCache::default(),
+            count_cache: CountCache::default(),
+            out_of_vocab: OutOfVocab::default(),
+        })
+    }
+
+    fn build_table<I: TableIndex>(
+        text_mmap: MmapSlice<T>,
+        table_file: &File,
+        vocab: usize,
+        verbose: bool,
+    ) -> Result<SuffixTable<MmapSlice<T>, MmapSlice<I>>> {
         // Allocate space on disk for the table
-        let table_size = text_mmap.len() * 8;
+        let table_size = text_mmap.len() * std::mem::size_of::<I>();
         table_file.set_len(table_size as u64)?;
 
         if verbose {
             println!("Writing indices to disk...");
         }
         let start = Instant::now();
-        let mut table_mmap = MmapSliceMut::<u64>::new(&table_file)?;
+        let mut table_mmap = MmapSliceMut::<I>::new(table_file)?;
         table_mmap
             .iter_mut()
             .enumerate()
-            .for_each(|(i, x)| *x = i as u64);
+            .for_each(|(i, x)| *x = I::from_u64(i as u64));
 
         assert_eq!(table_mmap.len(), text_mmap.len());
         if verbose {
@@ -70,42 +188,173 @@ impl<T: Unsigned> MemmapIndexRs<T> {
         }
         let start = Instant::now();
 
-        // TODO: Be even smarter about this? We may need to take into account the number of CPUs
-        // available as well. These magic numbers were tuned on a server with 48 physical cores.
-        // Empirically we start getting stack overflows between 5B and 10B tokens when using the
-        // default stack size of 2MB. We scale the stack size as log2(n) * 8MB to avoid this.
-        let scale = (text_mmap.len() as f64) / 5e9; // 5B tokens
-        let stack_size = scale.log2().max(1.0) * 8e6; // 8MB
-
-        rayon::ThreadPoolBuilder::new()
-            .stack_size(stack_size as usize)
-            .build()
-            .unwrap()
-            .install(|| {
-                // Sort the indices by the suffixes they point to.
-                // The unstable algorithm is critical for avoiding out-of-memory errors, since it does
-                // not allocate any more memory than the input and output slices.
-                println!("Sorting indices...");
-                par_sort_unstable_by_key(
-                    table_mmap.as_slice_mut(),
-                    |&i| &text_mmap[i as usize..],
-                    verbose,
-                );
-            });
+        sort_thread_pool(text_mmap.len()).install(|| {
+            // Sort the indices by the suffixes they point to.
+            // The unstable algorithm is critical for avoiding out-of-memory errors, since it does
+            // not allocate any more memory than the input and output slices.
+            println!("Sorting indices...");
+            par_sort_unstable_by_key(
+                table_mmap.as_slice_mut(),
+                |&i| &text_mmap[i.as_usize()..],
+                verbose,
+            );
+        });
         if verbose {
             println!("Time elapsed: {:?}", start.elapsed());
         }
 
         // Re-open the table as read-only
-        let table_mmap = MmapSlice::new(&table_file)?;
-        let table = SuffixTable::from_parts(text_mmap, table_mmap, Some(vocab));
+        let table_mmap = MmapSlice::new(table_file)?;
+        let table = SuffixTable::from_parts(text_mmap, table_mmap, Some(vocab))?;
         debug_assert!(table.is_sorted());
+        Ok(table)
+    }
 
-        Ok(MemmapIndexRs {
-            table,
-            cache: KneserNeyCache::default(),
+    /// Checks a text and table pair that may not open as an index, for example because their
+    /// lengths differ. See `verify::verify` for the checks performed.
+    pub fn verify_files(
+        text_path: String,
+        table_path: String,
+        sample: Option<usize>,
+    ) -> Result<Option<IntegrityIssue>> {
+        let text = MmapSlice::<T>::new(&File::open(&text_path)?)?;
+        let table_file = File::open(&table_path)?;
+        let metadata = IndexMetadata::load(&table_path)?;
+
+        let width = match resolve_table_width(&table_path, text.len(), metadata.as_ref()) {
+            Ok(width) => width,
+            Err(TokengramsError::LengthMismatch { .. }) => std::mem::size_of::<u64>(),
+            Err(e) => return Err(e),
+        };
+        let metadata = metadata.as_ref();
+        Ok(match width {
+            4 => verify(
+                &text,
+                &MmapSlice::<u32>::new(&table_file)?,
+                metadata,
+                sample,
+            ),
+            5 => verify(
+                &text,
+                &MmapSlice::<U40>::new(&table_file)?,
+                metadata,
+                sample,
+            ),
+            _ => verify(
+                &text,
+                &MmapSlice::<u64>::new(&table_file)?,
+                metadata,
+                sample,
+            ),
+        })
+    }
+
+    /// Builds a lookup table from the first `depth` tokens of each suffix to its range of ranks
+    /// and saves it next to the table, where it is picked up whenever the index is opened.
+    pub fn build_buckets(&mut self, depth: usize) -> Result<()> {
+        let buckets = with_table!(&self.table, table => table.build_buckets(depth))?;
+        buckets.save(&self.table_path, &self.fingerprint())?;
+        with_table!(&mut self.table, table => table.set_buckets(Some(buckets)));
+        Ok(())
+    }
+
+    /// Builds exact unigram and bigram counts and saves them next to the table, where they are
+    /// picked up whenever the index is opened and answer `count_next` for contexts of at most
+    /// one token.
+    pub fn build_counts(&mut self) -> Result<()> {
+        let counts = with_table!(&self.table, table => {
+            CountTable::build(table.get_text(), table.get_table(), table.vocab())
+        });
+        counts.save(&self.table_path, &self.fingerprint())?;
+        self.counts = Some(counts);
+        Ok(())
+    }
+
+    pub fn contains_slice(&self, query: &[T]) -> bool {
+        with_table!(&self.table, table => table.contains(query))
+    }
+
+    pub fn count_slice(&self, query: &[T]) -> usize {
+        with_table!(&self.table, table => table.positions(query).len())
+    }
+
+    /// Fingerprints the text from its metadata, or by checksumming it if it has none.
+    pub fn fingerprint(&self) -> IndexFingerprint {
+        match &self.metadata {
+            Some(metadata) => IndexFingerprint::from_metadata(metadata),
+            None => with_table!(&self.table, table => {
+                IndexFingerprint::new(table.get_text(), table.vocab())
+            }),
+        }
+    }
+
+    /// Returns the number of tokens in the text.
+    pub fn text_len(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Counts each of a batch of converted queries, counting zero for missing queries.
+    pub fn batch_count_slices(&self, queries: &[Option<Vec<T>>]) -> Vec<usize> {
+        with_table!(&self.table, table => {
+            table.batch_positions(queries).iter().map(|positions| positions.len()).collect()
+        })
+    }
+
+    /// Returns the positions of each of a batch of converted queries, with none for missing
+    /// queries.
+    pub fn batch_positions_slices(&self, queries: &[Option<Vec<T>>]) -> Vec<Vec<u64>> {
+        with_table!(&self.table, table => {
+            table
+                .batch_positions(queries)
+                .iter()
+                .map(|positions| positions.iter().map(|sufi| sufi.to_u64()).collect())
+                .collect()
         })
     }
+
+    /// Returns the number of suffixes starting with `query`, which is the length of the text
+    /// for an empty query.
+    pub fn range_len(&self, query: &[T]) -> usize {
+        let (start, end) = with_table!(&self.table, table => table.boundaries(query));
+        end - start
+    }
+
+    /// See `SuffixTable::approx_count_next`.
+    pub fn approx_count_next_slice<R: Rng>(
+        &self,
+        query: &[T],
+        sample_size: usize,
+        rng: &mut R,
+    ) -> (Vec<f64>, Vec<f64>) {
+        with_table!(&self.table, table => table.approx_count_next(query, sample_size, rng))
+    }
+
+    /// Counts the tokens following `query` without consulting the count cache.
+    fn count_next_uncached(&self, query: &[T]) -> Vec<usize> {
+        if let Some(counts) = self.counts.as_ref().and_then(|c| c.count_next(query)) {
+            return counts;
+        }
+        with_table!(&self.table, table => table.count_next(query))
+    }
+
+    fn convert_query(&self, query: &[usize]) -> Result<Option<Vec<T>>> {
+        convert_query(query, self.table.vocab(), self.out_of_vocab)
+    }
+}
+
+/// Returns a thread pool with enough stack to sort the suffixes of a text of `len` tokens.
+pub(crate) fn sort_thread_pool(len: usize) -> rayon::ThreadPool {
+    // TODO: Be even smarter about this? We may need to take into account the number of CPUs
+    // available as well. These magic numbers were tuned on a server with 48 physical cores.
+    // Empirically we start getting stack overflows between 5B and 10B tokens when using the
+    // default stack size of 2MB. We scale the stack size as log2(n) * 8MB to avoid this.
+    let scale = (len as f64) / 5e9; // 5B tokens
+    let stack_size = scale.log2().max(1.0) * 8e6; // 8MB
+
+    rayon::ThreadPoolBuilder::new()
+        .stack_size(stack_size as usize)
+        .build()
+        .unwrap()
 }
 
 impl<T: Unsigned> Sample<T> for MemmapIndexRs<T> {
@@ -113,16 +362,15 @@ impl<T: Unsigned> Sample<T> for MemmapIndexRs<T> {
         &self.cache
     }
 
-    fn get_mut_cache(&mut self) -> &mut KneserNeyCache {
-        &mut self.cache
-    }
-
     fn count_next_slice(&self, query: &[T]) -> Vec<usize> {
-        self.table.count_next(query)
+        self.count_cache
+            .get_or_insert_with(query, self.table.vocab(), || {
+                self.count_next_uncached(query)
+            })
     }
 
     fn count_ngrams(&self, n: usize) -> HashMap<usize, usize> {
-        self.table.count_ngrams(n)
+        with_table!(&self.table, table => table.count_ngrams(n))
     }
 }
 
@@ -130,75 +378,222 @@ impl<T> MemmapIndexTrait for MemmapIndexRs<T>
 where
     T: Unsigned,
 {
-    fn positions(&self, query: Vec<usize>) -> Vec<u64> {
-        let query: Vec<T> = query
-            .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
-        self.table.positions(&query).to_vec()
+    fn out_of_vocab(&self) -> OutOfVocab {
+        self.out_of_vocab
+    }
+
+    fn set_out_of_vocab(&mut self, policy: OutOfVocab) {
+        self.out_of_vocab = policy;
+    }
+
+    fn save_cache(&self, path: String) -> Result<()> {
+        self.cache.save(&path, vec![self.fingerprint()])
+    }
+
+    fn load_cache(&mut self, path: String) -> Result<()> {
+        self.cache = KneserNeyCache::load(&path, &[self.fingerprint()])?;
+        Ok(())
+    }
+
+    fn positions(
+        &self,
+        query: Vec<usize>,
+        offset: usize,
+        limit: Option<usize>,
+        sorted: bool,
+    ) -> Result<Vec<u64>> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => with_table!(&self.table, table => {
+                table.positions_page(&query, offset, limit, sorted)
+            }),
+            None => Vec::new(),
+        })
+    }
+
+    fn sample_positions(&self, query: Vec<usize>, k: usize, seed: Option<u64>) -> Result<Vec<u64>> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => with_table!(&self.table, table => {
+                table.sample_positions(&query, k, &mut seeded_rng(seed))
+            }),
+            None => Vec::new(),
+        })
     }
 
     fn is_sorted(&self) -> bool {
-        self.table.is_sorted()
+        with_table!(&self.table, table => table.is_sorted())
     }
 
-    fn contains(&self, query: Vec<usize>) -> bool {
-        let query: Vec<T> = query
-            .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
-        self.table.contains(&query)
+    fn count_cache_capacity(&self) -> usize {
+        self.count_cache.capacity()
     }
 
-    fn count(&self, query: Vec<usize>) -> usize {
-        let query: Vec<T> = query
-            .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
-        self.table.positions(&query).len()
+    fn set_count_cache_capacity(&mut self, capacity: usize) {
+        self.count_cache = CountCache::new(capacity);
     }
 
-    fn count_next(&self, query: Vec<usize>) -> Vec<usize> {
-        let query: Vec<T> = query
-            .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
-        self.table.count_next(&query)
+    fn count_cache_stats(&self) -> CacheStats {
+        self.count_cache.stats()
+    }
+
+    fn warm_count_cache(&self, top_m: usize, max_len: usize) {
+        self.count_cache
+            .warm(top_m, max_len, self.table.vocab(), |query| {
+                self.count_next_uncached(query)
+            });
+    }
+
+    fn bucket_depth(&self) -> Option<usize> {
+        with_table!(&self.table, table => table.buckets().map(|b| b.depth()))
+    }
+
+    fn build_buckets(&mut self, depth: usize) -> Result<()> {
+        self.build_buckets(depth)
+    }
+
+    fn has_counts(&self) -> bool {
+        self.counts.is_some()
     }
 
-    fn batch_count_next(&self, queries: Vec<Vec<usize>>) -> Vec<Vec<usize>> {
-        queries
+    fn build_counts(&mut self) -> Result<()> {
+        self.build_counts()
+    }
+
+    fn verify(&self, sample: Option<usize>) -> Option<IntegrityIssue> {
+        with_table!(&self.table, table => verify(
+            table.get_text(),
+            table.get_table(),
+            self.metadata.as_ref(),
+            sample,
+        ))
+    }
+
+    fn contains(&self, query: Vec<usize>) -> Result<bool> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => self.contains_slice(&query),
+            None => false,
+        })
+    }
+
+    fn count(&self, query: Vec<usize>) -> Result<usize> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => self.count_slice(&query),
+            None => 0,
+        })
+    }
+
+    fn count_next(&self, query: Vec<usize>) -> Result<Vec<usize>> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => self.count_next_slice(&query),
+            None => vec![0; self.table.vocab()],
+        })
+    }
+
+    fn approx_count_next(
+        &self,
+        query: Vec<usize>,
+        sample_size: usize,
+        seed: Option<u64>,
+    ) -> Result<(Vec<f64>, Vec<f64>)> {
+        check_sample_size(sample_size)?;
+        let mut rng = seeded_rng(seed);
+        Ok(match self.convert_query(&query)? {
+            Some(query) => self.approx_count_next_slice(&query, sample_size, &mut rng),
+            None => (vec![0.0; self.table.vocab()], vec![0.0; self.table.vocab()]),
+        })
+    }
+
+    fn batch_contains(&self, queries: Vec<Vec<usize>>) -> Result<Vec<bool>> {
+        Ok(self
+            .batch_count(queries)?
+            .into_iter()
+            .map(|count| count > 0)
+            .collect())
+    }
+
+    fn batch_count(&self, queries: Vec<Vec<usize>>) -> Result<Vec<usize>> {
+        let queries = convert_queries::<T>(&queries, self.table.vocab(), self.out_of_vocab)?;
+        Ok(self.batch_count_slices(&queries))
+    }
+
+    fn batch_positions(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<u64>>> {
+        let queries = convert_queries::<T>(&queries, self.table.vocab(), self.out_of_vocab)?;
+        Ok(self.batch_positions_slices(&queries))
+    }
+
+    fn batch_count_next(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<usize>>> {
+        let queries = convert_queries::<T>(&queries, self.table.vocab(), self.out_of_vocab)?;
+        Ok(queries
             .into_par_iter()
-            .map(|query| self.count_next(query))
-            .collect()
+            .map(|query| match query {
+                Some(query) => self.count_next_slice(&query),
+                None => vec![0; self.table.vocab()],
+            })
+            .collect())
     }
 
     fn sample_smoothed(
-        &mut self,
+        &self,
         query: Vec<usize>,
         n: usize,
         k: usize,
         num_samples: usize,
-    ) -> Result<Vec<Vec<usize>>> {
-        let query: Vec<T> = query
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<SampleResult>> {
+        let context: Vec<T> = convert_context(&query, self.table.vocab(), self.out_of_vocab)?;
+
+        let traces =
+            <Self as Sample<T>>::sample_smoothed(self, &context, n, k, num_samples, seed, config)?;
+        Ok(SampleResult::restore(&query, context.len(), traces))
+    }
+
+    fn batch_sample(
+        &self,
+        requests: Vec<SampleRequest<usize>>,
+        smoothed: bool,
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<Vec<SampleResult>>> {
+        let contexts = requests
             .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
+            .map(|request| request.convert(self.table.vocab(), self.out_of_vocab))
+            .collect::<Result<Vec<_>>>()?;
+
+        let traces = <Self as Sample<T>>::batch_sample(self, &contexts, smoothed, seed, config)?;
+        Ok(SampleResult::restore_batch(&requests, &contexts, traces))
+    }
 
-        let samples_batch = <Self as Sample<T>>::sample_smoothed(self, &query, n, k, num_samples)?;
-        Ok(samples_batch
+    fn decode_greedy(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        config: &SamplingConfig,
+    ) -> Result<(Vec<usize>, f64)> {
+        let context: Vec<T> = convert_context(&query, self.table.vocab(), self.out_of_vocab)?;
+
+        let (sequence, score) = <Self as Sample<T>>::decode_greedy(self, &context, n, k, config)?;
+        let mut restored = restore_prompt(&query, context.len(), vec![sequence]);
+        Ok((restored.swap_remove(0), score))
+    }
+
+    fn beam_search(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        beam_width: usize,
+        config: &SamplingConfig,
+    ) -> Result<Vec<(Vec<usize>, f64)>> {
+        let context: Vec<T> = convert_context(&query, self.table.vocab(), self.out_of_vocab)?;
+
+        let (sequences, scores): (Vec<_>, Vec<_>) =
+            <Self as Sample<T>>::beam_search(self, &context, n, k, beam_width, config)?
+                .into_iter()
+                .unzip();
+        Ok(restore_prompt(&query, context.len(), sequences)
             .into_iter()
-            .map(|samples| {
-                samples
-                    .into_iter()
-                    .filter_map(|sample| {
-                        match TryInto::<usize>::try_into(sample) {
-                            Ok(value) => Some(value),
-                            Err(_) => None, // Silently skip values that can't be converted
-                        }
-                    })
-                    .collect::<Vec<usize>>()
-            })
+            .zip(scores)
             .collect())
     }
 
@@ -208,53 +603,41 @@ where
         n: usize,
         k: usize,
         num_samples: usize,
-    ) -> Result<Vec<Vec<usize>>> {
-        let query: Vec<T> = query
-            .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
-
-        let samples_batch =
-            <Self as Sample<T>>::sample_unsmoothed(self, &query, n, k, num_samples)?;
-        Ok(samples_batch
-            .into_iter()
-            .map(|samples| {
-                samples
-                    .into_iter()
-                    .filter_map(|sample| {
-                        match TryInto::<usize>::try_into(sample) {
-                            Ok(value) => Some(value),
-                            Err(_) => None, // Silently skip values that can't be converted
-                        }
-                    })
-                    .collect::<Vec<usize>>()
-            })
-            .collect())
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<SampleResult>> {
+        let context: Vec<T> = convert_context(&query, self.table.vocab(), self.out_of_vocab)?;
+
+        let traces = <Self as Sample<T>>::sample_unsmoothed(
+            self,
+            &context,
+            n,
+            k,
+            num_samples,
+            seed,
+            config,
+        )?;
+        Ok(SampleResult::restore(&query, context.len(), traces))
     }
 
-    fn get_smoothed_probs(&mut self, query: Vec<usize>) -> Vec<f64> {
-        let query: Vec<T> = query
-            .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
+    fn get_smoothed_probs(&self, query: Vec<usize>) -> Result<Vec<f64>> {
+        let context: Vec<T> = convert_context(&query, self.table.vocab(), self.out_of_vocab)?;
 
-        <Self as Sample<T>>::get_smoothed_probs(self, &query)
+        Ok(<Self as Sample<T>>::get_smoothed_probs(self, &context))
     }
 
-    fn batch_get_smoothed_probs(&mut self, queries: Vec<Vec<usize>>) -> Vec<Vec<f64>> {
-        let queries: Vec<Vec<T>> = queries
-            .into_iter()
-            .map(|query| {
-                query
-                    .iter()
-                    .filter_map(|&item| T::try_from(item).ok())
-                    .collect()
-            })
-            .collect();
-        <Self as Sample<T>>::batch_get_smoothed_probs(self, &queries)
+    fn batch_get_smoothed_probs(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<f64>>> {
+        let contexts: Vec<Vec<T>> = queries
+            .iter()
+            .map(|query| convert_context(query, self.table.vocab(), self.out_of_vocab))
+            .collect::<Result<_>>()?;
+
+        Ok(<Self as Sample<T>>::batch_get_smoothed_probs(
+            self, &contexts,
+        ))
     }
 
-    fn estimate_deltas(&mut self, n: usize) {
+    fn estimate_deltas(&self, n: usize) {
         <Self as Sample<T>>::estimate_deltas(self, n)
     }
 }
@@ -0,0 +1,276 @@
+use funty::Unsigned;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind};
+
+use crate::bucket_table::BucketTable;
+use crate::count_table::CountTable;
+use crate::error::{Result, TokengramsError};
+use crate::table_index::infer_table_width;
+use crate::util::checksum;
+
+pub const MAGIC: [u8; 8] = *b"TKGRAMS\0";
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Metadata describing a text and suffix table pair, stored in a sidecar file next to the table.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IndexMetadata {
+    pub magic: [u8; 8],
+    pub version: u32,
+    /// Size of each token in bytes.
+    pub token_width: usize,
+    pub vocab: usize,
+    /// Number of tokens in the text, which is also the number of suffixes in the table.
+    pub len: usize,
+    /// Checksum of the text's bytes, see `util::checksum`.
+    pub text_checksum: u64,
+    /// Checksum of the suffix table's bytes.
+    pub table_checksum: u64,
+    /// Size of each suffix table entry in bytes.
+    pub table_width: usize,
+    /// Distance between the text offsets of sampled suffixes, or 1 if the table holds every
+    /// suffix.
+    pub step: usize,
+    /// Version of tokengrams that built the table.
+    pub built_by: String,
+}
+
+impl IndexMetadata {
+    pub fn new<T: Unsigned, U>(text: &[T], table: &[U], vocab: usize) -> Self {
+        IndexMetadata {
+            magic: MAGIC,
+            version: FORMAT_VERSION,
+            token_width: std::mem::size_of::<T>(),
+            vocab,
+            len: text.len(),
+            text_checksum: checksum(text),
+            table_checksum: checksum(table),
+            table_width: std::mem::size_of::<U>(),
+            step: 1,
+            built_by: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// Returns the path of the metadata sidecar for a table file.
+    pub fn path(table_path: &str) -> String {
+        format!("{}.meta", table_path)
+    }
+
+    pub fn save(&self, table_path: &str) -> Result<()> {
+        let file = File::create(Self::path(table_path))?;
+        bincode::serialize_into(BufWriter::new(file), self)
+            .map_err(|e| TokengramsError::InvalidMetadata(e.to_string()))
+    }
+
+    /// Loads the metadata for a table file, returning `None` if the table has no sidecar.
+    pub fn load(table_path: &str) -> Result<Option<Self>> {
+        let file = match File::open(Self::path(table_path)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let metadata: IndexMetadata = bincode::deserialize_from(BufReader::new(file))
+            .map_err(|e| TokengramsError::InvalidMetadata(e.to_string()))?;
+        if metadata.magic != MAGIC {
+            return Err(TokengramsError::InvalidMetadata(
+                "Metadata file has the wrong magic number".to_string(),
+            ));
+        }
+        if metadata.version > FORMAT_VERSION {
+            return Err(TokengramsError::InvalidMetadata(format!(
+                "Index format version {} is newer than the supported version {}",
+                metadata.version, FORMAT_VERSION
+            )));
+        }
+        Ok(Some(metadata))
+    }
+
+    /// Checks that the metadata describes a text of `text_len` tokens of type `T`, indexed by a
+    /// table sampled every `step` suffixes.
+    pub fn validate<T: Unsigned>(&self, text_len: usize, step: usize) -> Result<()> {
+        if self.token_width != std::mem::size_of::<T>() {
+            return Err(TokengramsError::InvalidMetadata(format!(
+                "Index was built with {}-byte tokens but opened with {}-byte tokens",
+                self.token_width,
+                std::mem::size_of::<T>()
+            )));
+        }
+        if self.step != step {
+            return Err(TokengramsError::InvalidMetadata(format!(
+                "Index was built with a sampling step of {} but opened with a step of {}",
+                self.step, step
+            )));
+        }
+        if self.len != text_len {
+            return Err(TokengramsError::LengthMismatch {
+                text_len,
+                table_len: self.len,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Identifies the text an index was built over, so that statistics saved from one index are
+/// only loaded into an index over the same text.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexFingerprint {
+    pub token_width: usize,
+    pub vocab: usize,
+    pub len: usize,
+    /// Checksum of the text's bytes, see `util::checksum`.
+    pub text_checksum: u64,
+}
+
+impl IndexFingerprint {
+    /// Fingerprints a text by checksumming it.
+    pub fn new<T: Unsigned>(text: &[T], vocab: usize) -> Self {
+        IndexFingerprint {
+            token_width: std::mem::size_of::<T>(),
+            vocab,
+            len: text.len(),
+            text_checksum: checksum(text),
+        }
+    }
+
+    /// Reads the fingerprint of a text from its index metadata without checksumming it again.
+    pub fn from_metadata(metadata: &IndexMetadata) -> Self {
+        IndexFingerprint {
+            token_width: metadata.token_width,
+            vocab: metadata.vocab,
+            len: metadata.len,
+            text_checksum: metadata.text_checksum,
+        }
+    }
+}
+
+/// Removes the bucket and count sidecars of a previous table at `table_path`, which no longer
+/// describe a table written there.
+pub fn remove_stale_sidecars(table_path: &str) -> Result<()> {
+    for path in [BucketTable::path(table_path), CountTable::path(table_path)] {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Returns the token width in bytes and the vocabulary size to open a table with. These are read
+/// from the table's metadata when present and otherwise inferred from the vocabulary size.
+pub fn resolve_token_type(table_path: &str, vocab: Option<usize>) -> Result<(usize, usize)> {
+    match IndexMetadata::load(table_path)? {
+        Some(metadata) => match vocab {
+            Some(vocab) if vocab != metadata.vocab => {
+                Err(TokengramsError::InvalidMetadata(format!(
+                    "Index was built with vocabulary size {} but opened with {}",
+                    metadata.vocab, vocab
+                )))
+            }
+            _ => Ok((metadata.token_width, metadata.vocab)),
+        },
+        None => Ok(default_token_type(vocab)),
+    }
+}
+
+/// Returns the entry width in bytes of a table file over a text of `text_len` tokens. This is
+/// read from the table's metadata when present and otherwise inferred from the file size.
+pub fn resolve_table_width(
+    table_path: &str,
+    text_len: usize,
+    metadata: Option<&IndexMetadata>,
+) -> Result<usize> {
+    match metadata {
+        Some(metadata) => Ok(metadata.table_width),
+        None => infer_table_width(std::fs::metadata(table_path)?.len() as usize, text_len),
+    }
+}
+
+/// Returns the token width and vocabulary size for tables without metadata, defaulting to a
+/// vocabulary of 2^16 tokens.
+pub fn default_token_type(vocab: Option<usize>) -> (usize, usize) {
+    let vocab = vocab.unwrap_or(u16::MAX as usize + 1);
+    (inferred_token_width(vocab), vocab)
+}
+
+/// Returns the token width in bytes used to read a corpus with a vocabulary size. Corpora are
+/// read as u16 unless the vocabulary needs u32 tokens, so u8 corpora must request a width of 1.
+pub fn token_width(vocab: usize, requested: Option<usize>) -> Result<usize> {
+    match requested {
+        None => Ok(inferred_token_width(vocab)),
+        Some(width @ (1 | 2 | 4)) if vocab > 1 << (8 * width) => {
+            Err(TokengramsError::InvalidArgument(format!(
+                "Vocabulary size {} does not fit in {}-byte tokens",
+                vocab, width
+            )))
+        }
+        Some(width @ (1 | 2 | 4)) => Ok(width),
+        Some(width) => Err(TokengramsError::InvalidArgument(format!(
+            "Unsupported token width {}, expected 1, 2 or 4",
+            width
+        ))),
+    }
+}
+
+fn inferred_token_width(vocab: usize) -> usize {
+    if vocab <= u16::MAX as usize + 1 {
+        2
+    } else {
+        4
+    }
+}
+
+pub fn unsupported_token_width(width: usize) -> TokengramsError {
+    TokengramsError::InvalidMetadata(format!("Unsupported token width {}", width))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let table_path = dir.path().join("tokens.idx").display().to_string();
+        let text: Vec<u32> = vec![1, 2, 3, 70_000];
+
+        assert_eq!(IndexMetadata::load(&table_path).unwrap(), None);
+        assert_eq!(
+            resolve_token_type(&table_path, Some(100_000)).unwrap(),
+            (4, 100_000)
+        );
+
+        let table: Vec<u64> = vec![0, 1, 2, 3];
+        let metadata = IndexMetadata::new(&text, &table, 100_000);
+        metadata.save(&table_path).unwrap();
+
+        let loaded = IndexMetadata::load(&table_path).unwrap().unwrap();
+        assert_eq!(loaded, metadata);
+        assert!(loaded.validate::<u32>(text.len(), 1).is_ok());
+        assert!(matches!(
+            loaded.validate::<u16>(text.len(), 1),
+            Err(TokengramsError::InvalidMetadata(_))
+        ));
+        assert_eq!(resolve_token_type(&table_path, None).unwrap(), (4, 100_000));
+        assert!(resolve_token_type(&table_path, Some(1 << 16)).is_err());
+    }
+
+    #[test]
+    fn token_width_defaults_to_u16() {
+        assert_eq!(token_width(200, None).unwrap(), 2);
+        assert_eq!(token_width(1 << 16, None).unwrap(), 2);
+        assert_eq!(token_width((1 << 16) + 1, None).unwrap(), 4);
+        assert_eq!(default_token_type(Some(200)), (2, 200));
+
+        assert_eq!(token_width(200, Some(1)).unwrap(), 1);
+        assert!(matches!(
+            token_width(300, Some(1)),
+            Err(TokengramsError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            token_width(200, Some(3)),
+            Err(TokengramsError::InvalidArgument(_))
+        ));
+    }
+}
@@ -1,24 +1,25 @@
-use funty::Unsigned;
+use crate::error::{Result, TokengramsError};
 use memmap2::{Mmap, MmapAsRawDesc, MmapMut};
 use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
 
-/// An immutable memory-mapped slice of unsigned integers
-pub struct MmapSlice<T: Unsigned> {
+/// An immutable memory-mapped slice of plain-old-data values, such as tokens or table entries
+pub struct MmapSlice<T: Copy> {
     mmap: Mmap,
     _element_type: PhantomData<T>,
 }
 
-impl<T: Unsigned> MmapSlice<T> {
-    pub fn new<F: MmapAsRawDesc>(file: F) -> std::io::Result<Self> {
+impl<T: Copy> MmapSlice<T> {
+    pub fn new<F: MmapAsRawDesc>(file: F) -> Result<Self> {
         let raw = unsafe { Mmap::map(file)? };
 
         // Sanity check that the file size is a multiple of the element size.
-        if raw.len() % std::mem::size_of::<T>() != 0 {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "File size is not a multiple of element size",
-            ))
+        if !raw.len().is_multiple_of(std::mem::size_of::<T>()) {
+            Err(TokengramsError::MisalignedFile {
+                size: raw.len(),
+                element_size: std::mem::size_of::<T>(),
+            })
         } else {
             Ok(MmapSlice {
                 mmap: raw,
@@ -32,12 +33,16 @@ impl<T: Unsigned> MmapSlice<T> {
         self.mmap.len() / std::mem::size_of::<T>()
     }
 
-    pub fn as_slice<'a>(&'a self) -> &'a [T] {
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_slice(&self) -> &[T] {
         unsafe { std::slice::from_raw_parts(self.mmap.as_ptr() as *const T, self.len()) }
     }
 }
 
-impl<T: Unsigned> Deref for MmapSlice<T> {
+impl<T: Copy> Deref for MmapSlice<T> {
     type Target = [T];
 
     fn deref(&self) -> &[T] {
@@ -45,14 +50,14 @@ impl<T: Unsigned> Deref for MmapSlice<T> {
     }
 }
 
-/// A mutable memory-mapped slice of unsigned integers
-pub struct MmapSliceMut<T: Unsigned> {
+/// A mutable memory-mapped slice of plain-old-data values
+pub struct MmapSliceMut<T: Copy> {
     mmap: MmapMut,
     _element_type: PhantomData<T>,
 }
 
-impl<T: Unsigned> MmapSliceMut<T> {
-    pub fn new<F: MmapAsRawDesc>(file: F) -> std::io::Result<Self> {
+impl<T: Copy> MmapSliceMut<T> {
+    pub fn new<F: MmapAsRawDesc>(file: F) -> Result<Self> {
         let raw = unsafe { MmapMut::map_mut(file)? };
 
         Ok(MmapSliceMut {
@@ -65,27 +70,31 @@ impl<T: Unsigned> MmapSliceMut<T> {
         self.mmap.len() / std::mem::size_of::<T>()
     }
 
-    pub fn as_slice<'a>(&'a self) -> &'a [T] {
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_slice(&self) -> &[T] {
         unsafe { std::slice::from_raw_parts(self.mmap.as_ptr() as *const T, self.len()) }
     }
 
-    pub fn as_slice_mut<'a>(&'a mut self) -> &'a mut [T] {
+    pub fn as_slice_mut(&mut self) -> &mut [T] {
         unsafe { std::slice::from_raw_parts_mut(self.mmap.as_mut_ptr() as *mut T, self.len()) }
     }
 
-    pub fn into_read_only(self) -> std::io::Result<MmapSlice<T>> {
+    pub fn into_read_only(self) -> Result<MmapSlice<T>> {
         Ok(MmapSlice {
             mmap: self.mmap.make_read_only()?,
             _element_type: PhantomData,
         })
     }
 
-    pub fn flush(&self) -> std::io::Result<()> {
-        self.mmap.flush()
+    pub fn flush(&self) -> Result<()> {
+        Ok(self.mmap.flush()?)
     }
 }
 
-impl<T: Unsigned> Deref for MmapSliceMut<T> {
+impl<T: Copy> Deref for MmapSliceMut<T> {
     type Target = [T];
 
     fn deref(&self) -> &[T] {
@@ -93,8 +102,30 @@ impl<T: Unsigned> Deref for MmapSliceMut<T> {
     }
 }
 
-impl<T: Unsigned> DerefMut for MmapSliceMut<T> {
+impl<T: Copy> DerefMut for MmapSliceMut<T> {
     fn deref_mut(&mut self) -> &mut [T] {
         self.as_slice_mut()
     }
 }
+
+/// A range of a shared memory-mapped slice, letting one file hold several mapped arrays.
+pub struct MmapRange<T: Copy> {
+    mmap: Arc<MmapSlice<T>>,
+    start: usize,
+    end: usize,
+}
+
+impl<T: Copy> MmapRange<T> {
+    /// Returns the range `[start, end)` of the slice, or `None` if it is out of bounds.
+    pub fn new(mmap: Arc<MmapSlice<T>>, start: usize, end: usize) -> Option<Self> {
+        (start <= end && end <= mmap.len()).then_some(MmapRange { mmap, start, end })
+    }
+}
+
+impl<T: Copy> Deref for MmapRange<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        &self.mmap[self.start..self.end]
+    }
+}
@@ -0,0 +1,167 @@
+use funty::Unsigned;
+use std::str::FromStr;
+
+use crate::error::{Result, TokengramsError};
+
+/// How an index treats query tokens that are not in its vocabulary.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutOfVocab {
+    /// Fail the query with `TokengramsError::TokenOutOfVocab`.
+    #[default]
+    Error,
+    /// Treat the token as one that never occurs in the corpus, so any n-gram containing it
+    /// has a count of zero.
+    Miss,
+}
+
+impl FromStr for OutOfVocab {
+    type Err = TokengramsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "error" => Ok(OutOfVocab::Error),
+            "miss" => Ok(OutOfVocab::Miss),
+            _ => Err(TokengramsError::InvalidArgument(format!(
+                "Unknown out of vocabulary policy '{}', expected 'error' or 'miss'",
+                s
+            ))),
+        }
+    }
+}
+
+impl OutOfVocab {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutOfVocab::Error => "error",
+            OutOfVocab::Miss => "miss",
+        }
+    }
+}
+
+/// Converts query tokens to the index's token type.
+///
+/// Returns `None` if the query contains an out of vocabulary token and the policy is
+/// `OutOfVocab::Miss`, in which case the query can't occur in the corpus.
+pub fn convert_query<T: Unsigned>(
+    query: &[usize],
+    vocab: usize,
+    policy: OutOfVocab,
+) -> Result<Option<Vec<T>>> {
+    match (last_out_of_vocab::<T>(query, vocab), policy) {
+        (None, _) => Ok(Some(query.iter().map(|&t| convert_token(t)).collect())),
+        (Some(i), OutOfVocab::Error) => Err(TokengramsError::TokenOutOfVocab {
+            token: query[i],
+            vocab: vocab_limit::<T>(vocab),
+        }),
+        (Some(_), OutOfVocab::Miss) => Ok(None),
+    }
+}
+
+/// Converts a batch of queries with `convert_query`.
+pub fn convert_queries<T: Unsigned>(
+    queries: &[Vec<usize>],
+    vocab: usize,
+    policy: OutOfVocab,
+) -> Result<Vec<Option<Vec<T>>>> {
+    queries
+        .iter()
+        .map(|query| convert_query(query, vocab, policy))
+        .collect()
+}
+
+/// Converts a context for smoothed language modeling or sampling.
+///
+/// Under `OutOfVocab::Miss` the context is cut after its last out of vocabulary token. Any
+/// longer context has a count of zero, so Kneser-Ney smoothing and unsmoothed sampling back
+/// off to this suffix exactly as they would with the token in place.
+pub fn convert_context<T: Unsigned>(
+    query: &[usize],
+    vocab: usize,
+    policy: OutOfVocab,
+) -> Result<Vec<T>> {
+    match (last_out_of_vocab::<T>(query, vocab), policy) {
+        (None, _) => Ok(query.iter().map(|&t| convert_token(t)).collect()),
+        (Some(i), OutOfVocab::Error) => Err(TokengramsError::TokenOutOfVocab {
+            token: query[i],
+            vocab: vocab_limit::<T>(vocab),
+        }),
+        (Some(i), OutOfVocab::Miss) => {
+            Ok(query[i + 1..].iter().map(|&t| convert_token(t)).collect())
+        }
+    }
+}
+
+/// Converts a corpus to the index's token type, returning an error for any token outside the
+/// vocabulary.
+pub fn convert_tokens<T: Unsigned>(tokens: &[usize], vocab: usize) -> Result<Vec<T>> {
+    convert_context(tokens, vocab, OutOfVocab::Error)
+}
+
+/// Converts sampled sequences back to `usize` tokens, replacing the converted context at the
+/// start of each sequence with the original prompt.
+pub fn restore_prompt<T: Unsigned>(
+    prompt: &[usize],
+    context_len: usize,
+    samples: Vec<Vec<T>>,
+) -> Vec<Vec<usize>> {
+    samples
+        .into_iter()
+        .map(|sample| {
+            prompt
+                .iter()
+                .copied()
+                .chain(sample[context_len..].iter().map(|t| t.as_usize()))
+                .collect()
+        })
+        .collect()
+}
+
+/// The effective vocabulary size: the declared vocab capped by the range of the token type.
+fn vocab_limit<T: Unsigned>(vocab: usize) -> usize {
+    vocab.min(T::MAX.as_usize().saturating_add(1))
+}
+
+fn last_out_of_vocab<T: Unsigned>(query: &[usize], vocab: usize) -> Option<usize> {
+    let limit = vocab_limit::<T>(vocab);
+    query.iter().rposition(|&t| t >= limit)
+}
+
+fn convert_token<T: Unsigned>(token: usize) -> T {
+    T::try_from(token).unwrap_or_else(|_| unreachable!("token range checked"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_query_out_of_range() {
+        let query = vec![1, 70_000, 2];
+
+        assert!(matches!(
+            convert_query::<u16>(&query, 1 << 20, OutOfVocab::Error),
+            Err(TokengramsError::TokenOutOfVocab {
+                token: 70_000,
+                vocab: 65_536
+            })
+        ));
+        assert_eq!(
+            convert_query::<u16>(&query, 1 << 20, OutOfVocab::Miss).unwrap(),
+            None
+        );
+        assert_eq!(
+            convert_query::<u32>(&query, 1 << 20, OutOfVocab::Error).unwrap(),
+            Some(vec![1, 70_000, 2])
+        );
+    }
+
+    #[test]
+    fn convert_context_keeps_suffix() {
+        let query = vec![1, 9, 2, 3];
+
+        assert_eq!(
+            convert_context::<u16>(&query, 8, OutOfVocab::Miss).unwrap(),
+            vec![2, 3]
+        );
+    }
+}
@@ -1,18 +1,273 @@
-use anyhow::Result;
+use crate::error::{Result, TokengramsError};
+use crate::metadata::{IndexFingerprint, FORMAT_VERSION, MAGIC};
+use crate::query::{convert_context, OutOfVocab};
+use crate::sampling_config::SamplingConfig;
+use crate::util::seeded_rng;
 use funty::Unsigned;
-use rand::distributions::{Distribution, WeightedIndex};
-use rand::thread_rng;
+use pyo3::prelude::*;
+use rand::RngCore;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
 use std::ops::Mul;
+use std::sync::{OnceLock, RwLock};
 
-#[derive(Clone, Deserialize, Serialize, Default)]
+/// Smoothing statistics computed once per index and shared by every smoothed query. The cache
+/// is filled lazily through shared references, so smoothed queries can run concurrently.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(from = "KneserNeyCacheData", into = "KneserNeyCacheData")]
 pub struct KneserNeyCache {
+    unigram_probs: OnceLock<Vec<f64>>,
+    n_delta: RwLock<HashMap<usize, f64>>,
+}
+
+/// The serialized form of a `KneserNeyCache`.
+#[derive(Deserialize, Serialize)]
+struct KneserNeyCacheData {
     unigram_probs: Option<Vec<f64>>,
     n_delta: HashMap<usize, f64>,
 }
 
+impl Clone for KneserNeyCache {
+    fn clone(&self) -> Self {
+        KneserNeyCacheData {
+            unigram_probs: self.unigram_probs.get().cloned(),
+            n_delta: self.n_delta.read().unwrap().clone(),
+        }
+        .into()
+    }
+}
+
+/// A cache saved to disk together with the fingerprints of the texts it was computed from, one
+/// per shard.
+#[derive(Deserialize, Serialize)]
+struct SavedKneserNeyCache {
+    magic: [u8; 8],
+    version: u32,
+    fingerprints: Vec<IndexFingerprint>,
+    cache: KneserNeyCache,
+}
+
+impl KneserNeyCache {
+    pub fn save(&self, path: &str, fingerprints: Vec<IndexFingerprint>) -> Result<()> {
+        let saved = SavedKneserNeyCache {
+            magic: MAGIC,
+            version: FORMAT_VERSION,
+            fingerprints,
+            cache: self.clone(),
+        };
+        let file = File::create(path)?;
+        bincode::serialize_into(BufWriter::new(file), &saved)
+            .map_err(|e| TokengramsError::InvalidMetadata(e.to_string()))
+    }
+
+    /// Loads a saved cache, returning an error unless it was computed from texts with the
+    /// given fingerprints.
+    pub fn load(path: &str, fingerprints: &[IndexFingerprint]) -> Result<Self> {
+        let file = File::open(path)?;
+        let saved: SavedKneserNeyCache = bincode::deserialize_from(BufReader::new(file))
+            .map_err(|e| TokengramsError::InvalidMetadata(e.to_string()))?;
+        if saved.magic != MAGIC || saved.version > FORMAT_VERSION {
+            return Err(TokengramsError::InvalidMetadata(
+                "File is not a supported smoothing cache".to_string(),
+            ));
+        }
+        if saved.fingerprints != fingerprints {
+            return Err(TokengramsError::InvalidMetadata(
+                "Smoothing cache was computed from a different corpus".to_string(),
+            ));
+        }
+        Ok(saved.cache)
+    }
+}
+
+impl From<KneserNeyCacheData> for KneserNeyCache {
+    fn from(data: KneserNeyCacheData) -> Self {
+        let unigram_probs = OnceLock::new();
+        if let Some(probs) = data.unigram_probs {
+            let _ = unigram_probs.set(probs);
+        }
+        KneserNeyCache {
+            unigram_probs,
+            n_delta: RwLock::new(data.n_delta),
+        }
+    }
+}
+
+impl From<KneserNeyCache> for KneserNeyCacheData {
+    fn from(cache: KneserNeyCache) -> Self {
+        KneserNeyCacheData {
+            unigram_probs: cache.unigram_probs.into_inner(),
+            n_delta: cache.n_delta.into_inner().unwrap(),
+        }
+    }
+}
+
+/// A sampled sequence with diagnostics for each sampled token, stored column by column.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SampleTrace<T> {
+    /// The prompt followed by the sampled tokens.
+    pub tokens: Vec<T>,
+    /// Natural log-probability of each sampled token under the model, before decoding options.
+    /// Only recorded with `SamplingConfig::diagnostics`.
+    pub log_probs: Vec<f64>,
+    /// Number of context tokens the distribution of each sampled token was conditioned on, the
+    /// longest suffix of the context followed by any token in the corpus.
+    pub context_lens: Vec<usize>,
+    /// Number of times the context was followed by any token in the corpus.
+    pub context_counts: Vec<usize>,
+    /// Entropy in nats of the model distribution each token was drawn from, before decoding
+    /// options. Only recorded with `SamplingConfig::diagnostics`.
+    pub entropies: Vec<f64>,
+}
+
+impl<T: Unsigned> SampleTrace<T> {
+    fn new(query: &[T], k: usize) -> Self {
+        SampleTrace {
+            tokens: query.to_vec(),
+            log_probs: Vec::with_capacity(k),
+            context_lens: Vec::with_capacity(k),
+            context_counts: Vec::with_capacity(k),
+            entropies: Vec::with_capacity(k),
+        }
+    }
+
+    /// Appends a token drawn from a model distribution conditioned on `context_len` tokens that
+    /// followed the context `context_count` times. Given `weights`, the unnormalized
+    /// distribution, also records the log-probability of the token and the entropy.
+    fn push(
+        &mut self,
+        token: usize,
+        weights: Option<&[f64]>,
+        context_len: usize,
+        context_count: usize,
+    ) {
+        self.tokens.push(to_token(token));
+        self.context_lens.push(context_len);
+        self.context_counts.push(context_count);
+
+        if let Some(weights) = weights {
+            let total: f64 = weights.iter().sum();
+            let entropy = -weights
+                .iter()
+                .filter(|&&w| w > 0.0)
+                .map(|&w| (w / total) * (w / total).ln())
+                .sum::<f64>();
+            self.log_probs.push((weights[token] / total).ln());
+            self.entropies.push(entropy);
+        }
+    }
+}
+
+/// A prompt to sample from as part of a batch, with its own n-gram order, maximum number of new
+/// tokens and number of samples.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleRequest<T> {
+    pub query: Vec<T>,
+    pub n: usize,
+    pub k: usize,
+    pub num_samples: usize,
+}
+
+impl SampleRequest<usize> {
+    /// Converts the prompt of the request as a sampling context.
+    pub fn convert<T: Unsigned>(
+        &self,
+        vocab: usize,
+        policy: OutOfVocab,
+    ) -> Result<SampleRequest<T>> {
+        Ok(SampleRequest {
+            query: convert_context(&self.query, vocab, policy)?,
+            n: self.n,
+            k: self.k,
+            num_samples: self.num_samples,
+        })
+    }
+}
+
+/// A sampled sequence with diagnostics for each sampled token, in the tokens of the original
+/// prompt.
+#[pyclass(frozen)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SampleResult {
+    pub tokens: Vec<usize>,
+    pub log_probs: Vec<f64>,
+    pub context_lens: Vec<usize>,
+    pub context_counts: Vec<usize>,
+    pub entropies: Vec<f64>,
+}
+
+impl SampleResult {
+    /// Converts the traces of samples from a converted context, replacing the context at the
+    /// start of each sequence with the original prompt.
+    pub fn restore<T: Unsigned>(
+        prompt: &[usize],
+        context_len: usize,
+        traces: Vec<SampleTrace<T>>,
+    ) -> Vec<Self> {
+        traces
+            .into_iter()
+            .map(|trace| SampleResult {
+                tokens: prompt
+                    .iter()
+                    .copied()
+                    .chain(trace.tokens[context_len..].iter().map(|t| t.as_usize()))
+                    .collect(),
+                log_probs: trace.log_probs,
+                context_lens: trace.context_lens,
+                context_counts: trace.context_counts,
+                entropies: trace.entropies,
+            })
+            .collect()
+    }
+
+    /// Converts the traces of a batch of requests, restoring the original prompt of each.
+    pub fn restore_batch<T: Unsigned>(
+        requests: &[SampleRequest<usize>],
+        contexts: &[SampleRequest<T>],
+        traces: Vec<Vec<SampleTrace<T>>>,
+    ) -> Vec<Vec<Self>> {
+        requests
+            .iter()
+            .zip(contexts)
+            .zip(traces)
+            .map(|((request, context), traces)| {
+                Self::restore(&request.query, context.query.len(), traces)
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for SampleResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "tokens={:?}, log_probs={:?}, context_lens={:?}, context_counts={:?}, entropies={:?}",
+            self.tokens, self.log_probs, self.context_lens, self.context_counts, self.entropies
+        )
+    }
+}
+
+/// Returns the random number generator for the sample at `index` of a batch, independent of
+/// the thread that draws it.
+fn sample_rng(seed: Option<u64>, index: usize) -> impl RngCore {
+    seeded_rng(seed.map(|seed| seed.wrapping_add(index as u64)))
+}
+
+/// Returns an error unless an n-gram order is positive, since sampling conditions on the
+/// previous `n - 1` tokens.
+fn check_order(n: usize) -> Result<()> {
+    if n == 0 {
+        return Err(TokengramsError::InvalidArgument(
+            "n must be at least 1".to_string(),
+        ));
+    }
+    Ok(())
+}
+
 pub trait Sample<T: Unsigned>: Send + Sync {
     fn count_next_slice(&self, query: &[T]) -> Vec<usize>;
 
@@ -22,58 +277,149 @@ pub trait Sample<T: Unsigned>: Send + Sync {
 
     fn get_cache(&self) -> &KneserNeyCache;
 
-    fn get_mut_cache(&mut self) -> &mut KneserNeyCache;
-
-    /// Autoregressively sample num_samples of k characters from an unsmoothed n-gram model."""
+    /// Autoregressively sample num_samples of up to k characters from an unsmoothed n-gram model.
+    /// Given a seed, sample i draws from its own stream seeded with seed + i, so the samples
+    /// do not depend on the number of threads."""
     fn sample_unsmoothed(
         &self,
         query: &[T],
         n: usize,
         k: usize,
         num_samples: usize,
-    ) -> Result<Vec<Vec<T>>> {
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<SampleTrace<T>>> {
+        config.validate()?;
+        check_order(n)?;
+
         (0..num_samples)
             .into_par_iter()
-            .map(|_| self.sample(query, n, k))
+            .map(|i| self.sample(query, n, k, config, &mut sample_rng(seed, i)))
             .collect()
     }
 
-    //// Autoregressively sample a sequence of k characters from an unsmoothed n-gram model."""
-    fn sample(&self, query: &[T], n: usize, k: usize) -> Result<Vec<T>> {
-        let mut rng = thread_rng();
-        let mut sequence = Vec::from(query);
+    //// Autoregressively sample a sequence of up to k characters from an unsmoothed n-gram model,
+    /// stopping early after a stop token."""
+    fn sample(
+        &self,
+        query: &[T],
+        n: usize,
+        k: usize,
+        config: &SamplingConfig,
+        rng: &mut dyn RngCore,
+    ) -> Result<SampleTrace<T>> {
+        let mut trace = SampleTrace::new(query, k);
+        let mut state = 0;
 
         for _ in 0..k {
             // look at the previous (n - 1) characters to predict the n-gram completion
-            let start = sequence.len().saturating_sub(n - 1);
-            let prev = &sequence[start..];
+            let start = trace.tokens.len().saturating_sub(n - 1);
+            let Some(token) = self.backoff_choose(&mut trace, start, config, state, rng)? else {
+                break;
+            };
+            state = config.advance(state, token);
+            if config.ends(token, state) {
+                break;
+            }
+        }
 
+        Ok(trace)
+    }
+
+    /// Draws the next token of `trace` from the longest suffix of its tokens from `start` with
+    /// continuations left by the decoding options, backing off stupid-backoff style to shorter
+    /// suffixes down to the empty context. Returns `None` if the sequence stops instead.
+    fn backoff_choose(
+        &self,
+        trace: &mut SampleTrace<T>,
+        start: usize,
+        config: &SamplingConfig,
+        state: usize,
+        rng: &mut dyn RngCore,
+    ) -> Result<Option<usize>> {
+        for start in start..=trace.tokens.len() {
+            let prev = &trace.tokens[start..];
             let counts = self.count_next_slice(prev);
-            let dist = WeightedIndex::new(&counts)?;
-            let sampled_index: T = dist
-                .sample(&mut rng)
-                .try_into()
-                .unwrap_or_else(|_| panic!("Sampled token > T::MAX"));
+            let weights: Vec<f64> = counts.iter().map(|&count| count as f64).collect();
+            let diagnostics = config.diagnostics.then(|| weights.clone());
+            match config.choose(weights, Some(&counts), state, rng) {
+                Ok(Some(token)) => {
+                    let context_len = prev.len();
+                    trace.push(
+                        token,
+                        diagnostics.as_deref(),
+                        context_len,
+                        counts.iter().sum(),
+                    );
+                    return Ok(Some(token));
+                }
+                Ok(None) => return Ok(None),
+                Err(TokengramsError::EmptyDistribution) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(TokengramsError::EmptyDistribution)
+    }
+
+    /// Sample from many prompts in one parallel job, from a Kneser-Ney smoothed n-gram model if
+    /// `smoothed` and otherwise from an unsmoothed one. Returns the samples of each request in
+    /// order. Given a seed, samples are numbered consecutively across requests and seeded as in
+    /// `sample_unsmoothed`, so a lone request draws the same samples as the single-prompt
+    /// methods.
+    fn batch_sample(
+        &self,
+        requests: &[SampleRequest<T>],
+        smoothed: bool,
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<Vec<SampleTrace<T>>>> {
+        config.validate()?;
+        for request in requests {
+            check_order(request.n)?;
+        }
+        if smoothed {
+            self.estimate_deltas(1);
+        }
 
-            sequence.push(sampled_index);
+        // offsets[r] is the number of samples drawn for the requests before request r
+        let mut offsets = Vec::with_capacity(requests.len() + 1);
+        offsets.push(0);
+        for request in requests {
+            offsets.push(offsets[offsets.len() - 1] + request.num_samples);
         }
 
-        Ok(sequence)
+        let traces: Vec<SampleTrace<T>> = (0..offsets[requests.len()])
+            .into_par_iter()
+            .map(|i| {
+                let request = &requests[offsets.partition_point(|&offset| offset <= i) - 1];
+                let (query, n, k) = (&request.query, request.n, request.k);
+                let mut rng = sample_rng(seed, i);
+                if smoothed {
+                    self.kn_sample(query, n, k, config, &mut rng)
+                } else {
+                    self.sample(query, n, k, config, &mut rng)
+                }
+            })
+            .collect::<Result<_>>()?;
+
+        let mut traces = traces.into_iter();
+        Ok(requests
+            .iter()
+            .map(|request| traces.by_ref().take(request.num_samples).collect())
+            .collect())
     }
 
     /// Returns interpolated Kneser-Ney smoothed token probability distribution using all previous
     /// tokens in the query.
-    fn get_smoothed_probs(&mut self, query: &[T]) -> Vec<f64> {
+    fn get_smoothed_probs(&self, query: &[T]) -> Vec<f64> {
         self.estimate_deltas(1);
-        self.compute_smoothed_unigram_probs();
         self.smoothed_probs(query)
     }
 
     /// Returns interpolated Kneser-Ney smoothed token probability distribution using all previous
     /// tokens in the query.
-    fn batch_get_smoothed_probs(&mut self, queries: &[Vec<T>]) -> Vec<Vec<f64>> {
+    fn batch_get_smoothed_probs(&self, queries: &[Vec<T>]) -> Vec<Vec<f64>> {
         self.estimate_deltas(1);
-        self.compute_smoothed_unigram_probs();
 
         queries
             .into_par_iter()
@@ -81,20 +427,24 @@ pub trait Sample<T: Unsigned>: Send + Sync {
             .collect()
     }
 
-    /// Autoregressively sample num_samples of k characters from a Kneser-Ney smoothed n-gram model.
+    /// Autoregressively sample num_samples of up to k characters from a Kneser-Ney smoothed
+    /// n-gram model, seeding each sample as in `sample_unsmoothed`.
     fn sample_smoothed(
-        &mut self,
+        &self,
         query: &[T],
         n: usize,
         k: usize,
         num_samples: usize,
-    ) -> Result<Vec<Vec<T>>> {
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<SampleTrace<T>>> {
+        config.validate()?;
+        check_order(n)?;
         self.estimate_deltas(1);
-        self.compute_smoothed_unigram_probs();
 
         (0..num_samples)
             .into_par_iter()
-            .map(|_| self.kn_sample(query, n, k))
+            .map(|i| self.kn_sample(query, n, k, config, &mut sample_rng(seed, i)))
             .collect()
     }
 
@@ -103,20 +453,26 @@ pub trait Sample<T: Unsigned>: Send + Sync {
     /// "On structuring probabilistic dependences in stochastic language modelling", page 25,
     /// doi:10.1006/csla.1994.1001
     fn smoothed_probs(&self, query: &[T]) -> Vec<f64> {
-        let p_continuations = if query.is_empty() {
-            self.get_cached_smoothed_unigram_probs().to_vec()
+        self.smoothed_probs_in_context(query).0
+    }
+
+    /// Returns the smoothed distribution of `smoothed_probs` together with the length of the
+    /// longest suffix of the query followed by any token in the corpus, which the distribution
+    /// is conditioned on, and the number of times that suffix was followed by a token.
+    fn smoothed_probs_in_context(&self, query: &[T]) -> (Vec<f64>, usize, usize) {
+        let lower_order = if query.is_empty() {
+            (self.get_smoothed_unigram_probs().to_vec(), 0, 0)
         } else {
-            self.smoothed_probs(&query[1..])
+            self.smoothed_probs_in_context(&query[1..])
         };
 
-        let counts = self.count_next_slice(&query);
-        let suffix_count_recip = {
-            let suffix_count: usize = counts.iter().sum();
-            if suffix_count == 0 {
-                return p_continuations;
-            }
-            1.0 / suffix_count as f64
-        };
+        let counts = self.count_next_slice(query);
+        let suffix_count: usize = counts.iter().sum();
+        if suffix_count == 0 {
+            return lower_order;
+        }
+        let suffix_count_recip = 1.0 / suffix_count as f64;
+        let p_continuations = lower_order.0;
 
         let (gt_zero_count, eq_one_count) = get_occurrence_counts(&counts);
         let used_suffix_count = gt_zero_count as f64;
@@ -142,37 +498,170 @@ pub trait Sample<T: Unsigned>: Send + Sync {
                     + lambda.mul(p_continuation);
                 probs.push(prob);
             });
-        probs
+        (probs, query.len(), suffix_count)
+    }
+
+    /// Returns the counts the minimum count of the config filters a smoothed distribution by:
+    /// those of the `context_len` token suffix of `prev` the distribution was conditioned on,
+    /// or none if that suffix was never followed by a token.
+    fn min_count_counts(
+        &self,
+        prev: &[T],
+        context_len: usize,
+        context_count: usize,
+        config: &SamplingConfig,
+    ) -> Option<Vec<usize>> {
+        (config.needs_counts() && context_count > 0)
+            .then(|| self.count_next_slice(&prev[prev.len() - context_len..]))
     }
 
-    /// Autoregressively sample k characters from a Kneser-Ney smoothed n-gram model.
-    fn kn_sample(&self, query: &[T], n: usize, k: usize) -> Result<Vec<T>> {
-        let mut rng = thread_rng();
-        let mut sequence = Vec::from(query);
+    /// Autoregressively sample up to k characters from a Kneser-Ney smoothed n-gram model,
+    /// stopping early after a stop token.
+    fn kn_sample(
+        &self,
+        query: &[T],
+        n: usize,
+        k: usize,
+        config: &SamplingConfig,
+        rng: &mut dyn RngCore,
+    ) -> Result<SampleTrace<T>> {
+        let mut trace = SampleTrace::new(query, k);
+        let mut state = 0;
 
         for _ in 0..k {
-            let start = sequence.len().saturating_sub(n - 1);
-            let prev = &sequence[start..];
-            let probs = self.smoothed_probs(prev);
-            let dist = WeightedIndex::new(&probs)?;
-            let sampled_index: T = dist
-                .sample(&mut rng)
-                .try_into()
-                .unwrap_or_else(|_| panic!("Sampled token > usize::MAX"));
+            let start = trace.tokens.len().saturating_sub(n - 1);
+            let prev = &trace.tokens[start..];
+            let (probs, context_len, context_count) = self.smoothed_probs_in_context(prev);
+            let counts = self.min_count_counts(prev, context_len, context_count, config);
+            let diagnostics = config.diagnostics.then(|| probs.clone());
+            let Some(token) = config.choose(probs, counts.as_deref(), state, rng)? else {
+                break;
+            };
 
-            sequence.push(sampled_index);
+            trace.push(token, diagnostics.as_deref(), context_len, context_count);
+            state = config.advance(state, token);
+            if config.ends(token, state) {
+                break;
+            }
         }
 
-        Ok(sequence)
+        Ok(trace)
+    }
+
+    /// Extend the query by up to k characters, picking the most likely token under a
+    /// Kneser-Ney smoothed n-gram model at each step. Returns the sequence and the sum of the
+    /// log-probabilities of its new tokens.
+    fn decode_greedy(
+        &self,
+        query: &[T],
+        n: usize,
+        k: usize,
+        config: &SamplingConfig,
+    ) -> Result<(Vec<T>, f64)> {
+        let mut beams = self.beam_search(query, n, k, 1, config)?;
+        Ok(beams.swap_remove(0))
+    }
+
+    /// Beam search for the beam_width most likely extensions of the query by up to k
+    /// characters under a Kneser-Ney smoothed n-gram model, scored by the sum of the
+    /// log-probabilities of their new tokens. Banned tokens, the minimum count and the
+    /// automaton of the config are applied to each distribution, and a beam ends after a stop
+    /// token or once the automaton accepts it. A beam the automaton accepts with transitions
+    /// left also competes with its continuations as an ended beam, scored by the probability
+    /// of the tokens the automaton disallows. Returns the beams in decreasing order of score.
+    fn beam_search(
+        &self,
+        query: &[T],
+        n: usize,
+        k: usize,
+        beam_width: usize,
+        config: &SamplingConfig,
+    ) -> Result<Vec<(Vec<T>, f64)>> {
+        config.validate()?;
+        check_order(n)?;
+        if beam_width == 0 {
+            return Err(TokengramsError::InvalidArgument(
+                "beam_width must be at least 1".to_string(),
+            ));
+        }
+        self.estimate_deltas(1);
+
+        let mut beams = vec![Beam {
+            sequence: query.to_vec(),
+            score: 0.0,
+            state: 0,
+            ended: false,
+        }];
+        for _ in 0..k {
+            if beams.iter().all(|beam| beam.ended) {
+                break;
+            }
+
+            let mut candidates: Vec<Beam<T>> = beams
+                .par_iter()
+                .flat_map_iter(|beam| {
+                    if beam.ended {
+                        return vec![beam.clone()];
+                    }
+                    let start = beam.sequence.len().saturating_sub(n - 1);
+                    let prev = &beam.sequence[start..];
+                    let (mut probs, context_len, context_count) =
+                        self.smoothed_probs_in_context(prev);
+                    let counts = self.min_count_counts(prev, context_len, context_count, config);
+                    let stop_weight = config.stop_weight(&probs, beam.state);
+                    config.mask(&mut probs, counts.as_deref(), beam.state);
+
+                    let stopped = stop_weight.filter(|&w| w > 0.0).map(|w| Beam {
+                        score: beam.score + w.ln(),
+                        ended: true,
+                        ..beam.clone()
+                    });
+                    top_tokens(&probs, beam_width)
+                        .into_iter()
+                        .map(|token| {
+                            let mut sequence = beam.sequence.clone();
+                            sequence.push(to_token(token));
+                            let state = config.advance(beam.state, token);
+                            Beam {
+                                sequence,
+                                score: beam.score + probs[token].ln(),
+                                state,
+                                ended: config.ends(token, state),
+                            }
+                        })
+                        .chain(stopped)
+                        .collect()
+                })
+                .collect();
+            if candidates.is_empty() {
+                return Err(TokengramsError::EmptyDistribution);
+            }
+
+            candidates.sort_by(|a, b| {
+                b.score
+                    .total_cmp(&a.score)
+                    .then_with(|| a.sequence.cmp(&b.sequence))
+            });
+            candidates.truncate(beam_width);
+            beams = candidates;
+        }
+
+        Ok(beams
+            .into_iter()
+            .map(|beam| (beam.sequence, beam.score))
+            .collect())
     }
 
     /// Warning: O(k**n) where k is vocabulary size, use with caution.
     /// Improve smoothed model quality by replacing the default delta hyperparameters
     /// for models of order n and below with improved estimates over the entire index.
     /// https://people.eecs.berkeley.edu/~klein/cs294-5/chen_goodman.pdf, page 16."""
-    fn estimate_deltas(&mut self, n: usize) {
+    ///
+    /// Deltas are computed without holding the cache lock, so concurrent calls may estimate
+    /// the same delta twice, but always store the same value.
+    fn estimate_deltas(&self, n: usize) {
         for i in 1..n + 1 {
-            if self.get_cache().n_delta.contains_key(&i) {
+            if self.get_cache().n_delta.read().unwrap().contains_key(&i) {
                 continue;
             }
 
@@ -187,20 +676,34 @@ pub trait Sample<T: Unsigned>: Send + Sync {
                 n1 / (n1 + n2.mul(2.))
             };
 
-            self.get_mut_cache().n_delta.insert(i, delta);
+            self.get_cache()
+                .n_delta
+                .write()
+                .unwrap()
+                .entry(i)
+                .or_insert(delta);
         }
     }
 
     fn get_cached_delta(&self, n: usize) -> f64 {
-        *self.get_cache().n_delta.get(&n).unwrap_or(&0.5)
+        *self
+            .get_cache()
+            .n_delta
+            .read()
+            .unwrap()
+            .get(&n)
+            .unwrap_or(&0.5)
     }
 
-    /// Returns unigram probabilities with additive smoothing applied.
-    fn compute_smoothed_unigram_probs(&mut self) {
-        if let Some(_) = &self.get_cache().unigram_probs {
-            return;
-        }
+    /// Returns unigram probabilities with additive smoothing applied, computing them on first
+    /// use.
+    fn get_smoothed_unigram_probs(&self) -> &[f64] {
+        self.get_cache()
+            .unigram_probs
+            .get_or_init(|| self.compute_smoothed_unigram_probs())
+    }
 
+    fn compute_smoothed_unigram_probs(&self) -> Vec<f64> {
         let eps = 1e-9;
 
         // Count the number of unique bigrams that end with each token
@@ -208,17 +711,39 @@ pub trait Sample<T: Unsigned>: Send + Sync {
 
         let total_count: usize = counts.iter().sum();
         let adjusted_total_count = total_count as f64 + eps.mul(counts.len() as f64);
-        let unigram_probs: Vec<f64> = counts
+        counts
             .iter()
             .map(|&count| (count as f64 + eps) / adjusted_total_count)
-            .collect();
-
-        self.get_mut_cache().unigram_probs = Some(unigram_probs);
+            .collect()
     }
+}
 
-    fn get_cached_smoothed_unigram_probs(&self) -> &[f64] {
-        self.get_cache().unigram_probs.as_ref().unwrap()
+/// A partial sequence in beam search, with the automaton state it leaves.
+#[derive(Clone)]
+struct Beam<T> {
+    sequence: Vec<T>,
+    score: f64,
+    state: usize,
+    ended: bool,
+}
+
+/// Returns the tokens with the `k` largest positive weights, breaking ties in favour of the
+/// smallest token.
+fn top_tokens(weights: &[f64], k: usize) -> Vec<usize> {
+    let mut tokens: Vec<usize> = (0..weights.len()).filter(|&t| weights[t] > 0.0).collect();
+    let by_weight = |a: &usize, b: &usize| weights[*b].total_cmp(&weights[*a]).then(a.cmp(b));
+    if tokens.len() > k {
+        tokens.select_nth_unstable_by(k, by_weight);
+        tokens.truncate(k);
     }
+    tokens.sort_unstable_by(by_weight);
+    tokens
+}
+
+fn to_token<T: Unsigned>(token: usize) -> T {
+    token
+        .try_into()
+        .unwrap_or_else(|_| panic!("Sampled token > T::MAX"))
 }
 
 fn get_occurrence_counts(slice: &[usize]) -> (usize, usize) {
@@ -0,0 +1,392 @@
+use pyo3::prelude::*;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::RngCore;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::automaton::TokenAutomaton;
+use crate::error::{Result, TokengramsError};
+
+/// Decoding options applied to each next-token distribution when sampling, in both the smoothed
+/// and unsmoothed paths.
+///
+/// Banned tokens and tokens seen fewer than `min_count` times after the context are removed
+/// first. The remaining weights are then sharpened or flattened by `temperature`, truncated to
+/// the `top_k` most likely tokens, and truncated again to the smallest set of most likely tokens
+/// holding at least `top_p` of the remaining mass. A sequence ends early once it emits one of
+/// the `stop_tokens`, which is kept at its end.
+///
+/// With an `automaton`, each step may only emit tokens allowed by the automaton's current
+/// state. A sequence the automaton accepts ends there if no transition leaves its state, and
+/// otherwise may end in place of its next token, weighted by the tokens the automaton
+/// disallows.
+#[pyclass(frozen)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SamplingConfig {
+    /// Exponent `1 / temperature` applied to the weights. Zero always picks the most likely
+    /// token, breaking ties in favour of the smallest token.
+    pub temperature: f64,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f64>,
+    pub min_count: usize,
+    pub banned_tokens: Vec<usize>,
+    pub stop_tokens: Vec<usize>,
+    pub automaton: Option<Arc<TokenAutomaton>>,
+    /// Whether samples record the log-probability of each token and the entropy of the
+    /// distribution it was drawn from. Set from the `detailed` argument of the sampling
+    /// methods, since both cost a pass over the vocabulary per token.
+    pub diagnostics: bool,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        SamplingConfig {
+            temperature: 1.0,
+            top_k: None,
+            top_p: None,
+            min_count: 0,
+            banned_tokens: Vec::new(),
+            stop_tokens: Vec::new(),
+            automaton: None,
+            diagnostics: false,
+        }
+    }
+}
+
+impl fmt::Display for SamplingConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "temperature={}, top_k={:?}, top_p={:?}, min_count={}, banned_tokens={:?}, \
+             stop_tokens={:?}, automaton={}",
+            self.temperature,
+            self.top_k,
+            self.top_p,
+            self.min_count,
+            self.banned_tokens,
+            self.stop_tokens,
+            match &self.automaton {
+                Some(automaton) => format!("TokenAutomaton({})", automaton),
+                None => "None".to_string(),
+            }
+        )
+    }
+}
+
+impl SamplingConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.temperature.is_finite() && self.temperature >= 0.0) {
+            return Err(TokengramsError::InvalidArgument(format!(
+                "temperature must be a non-negative number, got {}",
+                self.temperature
+            )));
+        }
+        if self.top_k == Some(0) {
+            return Err(TokengramsError::InvalidArgument(
+                "top_k must be at least 1".to_string(),
+            ));
+        }
+        if let Some(top_p) = self.top_p {
+            if !(top_p > 0.0 && top_p <= 1.0) {
+                return Err(TokengramsError::InvalidArgument(format!(
+                    "top_p must be in (0, 1], got {}",
+                    top_p
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the config with diagnostics recorded if `diagnostics`.
+    pub fn with_diagnostics(self, diagnostics: bool) -> Self {
+        SamplingConfig {
+            diagnostics,
+            ..self
+        }
+    }
+
+    /// Whether `choose` needs the counts of each token following the context.
+    pub fn needs_counts(&self) -> bool {
+        self.min_count > 0
+    }
+
+    /// Returns the automaton state after emitting `token` in `state`, which is always zero
+    /// without an automaton.
+    pub fn advance(&self, state: usize, token: usize) -> usize {
+        match &self.automaton {
+            Some(automaton) => automaton
+                .next_state(state, token)
+                .unwrap_or_else(|| unreachable!("disallowed tokens are masked")),
+            None => state,
+        }
+    }
+
+    /// Whether a sequence ends after emitting `token`, leaving the automaton in `state`. An
+    /// accepting state that other sequences continue from does not end the sequence, which
+    /// may instead stop in `choose`.
+    pub fn ends(&self, token: usize, state: usize) -> bool {
+        self.stop_tokens.contains(&token)
+            || self.automaton.as_ref().is_some_and(|automaton| {
+                automaton.is_accepting(state) && automaton.allowed_tokens(state).is_empty()
+            })
+    }
+
+    /// Returns the weight of ending the sequence in automaton `state` instead of emitting a
+    /// token from `weights`, if the automaton accepts the sequence there. This is the weight
+    /// of the tokens the automaton disallows, any of which would leave the accepted sequence.
+    pub fn stop_weight(&self, weights: &[f64], state: usize) -> Option<f64> {
+        let automaton = self
+            .automaton
+            .as_ref()
+            .filter(|automaton| automaton.is_accepting(state))?;
+        let allowed: f64 = automaton
+            .allowed_tokens(state)
+            .iter()
+            .filter_map(|&token| weights.get(token))
+            .sum();
+        Some((weights.iter().sum::<f64>() - allowed).max(0.0))
+    }
+
+    /// Draws a token from `weights` after applying the decoding options in automaton `state`,
+    /// or returns `None` if the sequence stops there, see `stop_weight`. `counts` holds the
+    /// number of times each token follows the context, and is only read when `needs_counts`.
+    pub fn choose(
+        &self,
+        mut weights: Vec<f64>,
+        counts: Option<&[usize]>,
+        state: usize,
+        rng: &mut dyn RngCore,
+    ) -> Result<Option<usize>> {
+        let vocab = weights.len();
+        let stop_weight = self.stop_weight(&weights, state);
+        self.mask(&mut weights, counts, state);
+        // Stopping competes with the remaining tokens as one more choice
+        weights.extend(stop_weight);
+
+        let token = if self.temperature == 0.0 {
+            argmax(&weights).ok_or(TokengramsError::EmptyDistribution)?
+        } else {
+            if self.temperature != 1.0 {
+                apply_temperature(&mut weights, self.temperature);
+            }
+            if self.top_k.is_some() || self.top_p.is_some() {
+                self.truncate(&mut weights);
+            }
+            WeightedIndex::new(&weights)?.sample(rng)
+        };
+        Ok((token < vocab).then_some(token))
+    }
+
+    /// Zeroes the weights of banned tokens, of tokens the automaton disallows in `state` and,
+    /// given `counts`, of tokens seen fewer than `min_count` times after the context.
+    pub fn mask(&self, weights: &mut [f64], counts: Option<&[usize]>, state: usize) {
+        if let Some(automaton) = &self.automaton {
+            let mut allowed = vec![0.0; weights.len()];
+            for &token in automaton.allowed_tokens(state) {
+                if let Some(&weight) = weights.get(token) {
+                    allowed[token] = weight;
+                }
+            }
+            weights.copy_from_slice(&allowed);
+        }
+        for &token in &self.banned_tokens {
+            if let Some(weight) = weights.get_mut(token) {
+                *weight = 0.0;
+            }
+        }
+        if let Some(counts) = counts.filter(|_| self.needs_counts()) {
+            for (weight, &count) in weights.iter_mut().zip(counts) {
+                if count < self.min_count {
+                    *weight = 0.0;
+                }
+            }
+        }
+    }
+
+    /// Zeroes every weight outside the `top_k` and then `top_p` most likely tokens.
+    fn truncate(&self, weights: &mut [f64]) {
+        let mut order: Vec<usize> = (0..weights.len()).filter(|&i| weights[i] > 0.0).collect();
+        order.sort_unstable_by(|&a, &b| weights[b].total_cmp(&weights[a]).then(a.cmp(&b)));
+
+        let mut keep = order.len().min(self.top_k.unwrap_or(usize::MAX));
+        if let Some(top_p) = self.top_p {
+            let total: f64 = order[..keep].iter().map(|&i| weights[i]).sum();
+            let mut mass = 0.0;
+            if let Some(last) = order[..keep].iter().position(|&i| {
+                mass += weights[i];
+                mass >= top_p * total
+            }) {
+                keep = last + 1;
+            }
+        }
+        for &i in &order[keep..] {
+            weights[i] = 0.0;
+        }
+    }
+}
+
+/// Raises each positive weight to the power `1 / temperature`, in log space and relative to the
+/// largest weight so that low temperatures neither underflow nor overflow.
+fn apply_temperature(weights: &mut [f64], temperature: f64) {
+    let max = weights.iter().copied().fold(0.0, f64::max);
+    if max <= 0.0 {
+        return;
+    }
+    for weight in weights.iter_mut().filter(|w| **w > 0.0) {
+        *weight = ((weight.ln() - max.ln()) / temperature).exp();
+    }
+}
+
+/// Returns the smallest index of the largest positive weight.
+fn argmax(weights: &[f64]) -> Option<usize> {
+    weights
+        .iter()
+        .enumerate()
+        .filter(|(_, &w)| w > 0.0)
+        .fold(None, |best: Option<(usize, f64)>, (i, &w)| match best {
+            Some((_, best_w)) if best_w >= w => best,
+            _ => Some((i, w)),
+        })
+        .map(|(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::seeded_rng;
+
+    fn draws(config: &SamplingConfig, weights: &[f64], counts: &[usize]) -> Vec<usize> {
+        let mut rng = seeded_rng(Some(0));
+        let mut seen: Vec<usize> = (0..200)
+            .map(|_| {
+                config
+                    .choose(weights.to_vec(), Some(counts), 0, &mut rng)
+                    .unwrap()
+                    .unwrap()
+            })
+            .collect();
+        seen.sort();
+        seen.dedup();
+        seen
+    }
+
+    #[test]
+    fn decoding_options_restrict_tokens() {
+        let weights = [5.0, 0.0, 3.0, 1.0, 1.0];
+        let counts = [5, 0, 3, 1, 1];
+        let config = |f: fn(&mut SamplingConfig)| {
+            let mut config = SamplingConfig::default();
+            f(&mut config);
+            config
+        };
+
+        assert_eq!(
+            draws(&SamplingConfig::default(), &weights, &counts),
+            vec![0, 2, 3, 4]
+        );
+        assert_eq!(
+            draws(&config(|c| c.banned_tokens = vec![0, 9]), &weights, &counts),
+            vec![2, 3, 4]
+        );
+        assert_eq!(
+            draws(&config(|c| c.min_count = 2), &weights, &counts),
+            vec![0, 2]
+        );
+        assert_eq!(
+            draws(&config(|c| c.top_k = Some(2)), &weights, &counts),
+            vec![0, 2]
+        );
+        // 5 / 10 falls short of 0.6 of the mass, 8 / 10 does not
+        assert_eq!(
+            draws(&config(|c| c.top_p = Some(0.6)), &weights, &counts),
+            vec![0, 2]
+        );
+        assert_eq!(
+            draws(&config(|c| c.temperature = 0.0), &weights, &counts),
+            vec![0]
+        );
+        // Low temperatures concentrate the mass without underflowing
+        assert_eq!(
+            draws(
+                &config(|c| c.temperature = 1e-3),
+                &[1e-300, 2e-300],
+                &[1, 1]
+            ),
+            vec![1]
+        );
+
+        let automaton = SamplingConfig {
+            automaton: Some(Arc::new(TokenAutomaton::from_sequences(&[
+                vec![1, 3],
+                vec![4],
+            ]))),
+            ..Default::default()
+        };
+        assert_eq!(draws(&automaton, &weights, &counts), vec![4]);
+
+        let banned_all = config(|c| c.banned_tokens = vec![0, 2, 3, 4]);
+        assert!(matches!(
+            banned_all.choose(weights.to_vec(), None, 0, &mut seeded_rng(None)),
+            Err(TokengramsError::EmptyDistribution)
+        ));
+    }
+
+    #[test]
+    fn accepted_prefixes_may_stop_or_continue() {
+        let automaton = TokenAutomaton::from_sequences(&[vec![1, 2], vec![1, 2, 3]]);
+        let prefix = automaton
+            .next_state(automaton.next_state(0, 1).unwrap(), 2)
+            .unwrap();
+        let full = automaton.next_state(prefix, 3).unwrap();
+        let config = SamplingConfig {
+            automaton: Some(Arc::new(automaton)),
+            ..Default::default()
+        };
+        assert!(!config.ends(2, prefix));
+        assert!(config.ends(3, full));
+
+        // Stopping takes the weight of every token but 3, and competes with 3
+        let weights = [5.0, 0.0, 3.0, 1.0, 1.0];
+        assert_eq!(config.stop_weight(&weights, prefix), Some(9.0));
+        assert_eq!(config.stop_weight(&weights, 0), None);
+        let mut rng = seeded_rng(Some(0));
+        let mut seen: Vec<Option<usize>> = (0..200)
+            .map(|_| {
+                config
+                    .choose(weights.to_vec(), None, prefix, &mut rng)
+                    .unwrap()
+            })
+            .collect();
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen, vec![None, Some(3)]);
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        for config in [
+            SamplingConfig {
+                temperature: -1.0,
+                ..Default::default()
+            },
+            SamplingConfig {
+                top_k: Some(0),
+                ..Default::default()
+            },
+            SamplingConfig {
+                top_p: Some(0.0),
+                ..Default::default()
+            },
+            SamplingConfig {
+                top_p: Some(1.5),
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                config.validate(),
+                Err(TokengramsError::InvalidArgument(_))
+            ));
+        }
+        assert!(SamplingConfig::default().validate().is_ok());
+    }
+}
@@ -1,17 +1,29 @@
-use anyhow::Result;
 use funty::Unsigned;
-use pyo3::prelude::*;
+use rayon::prelude::*;
 use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
 
 use crate::bindings::memmap_index::MemmapIndexTrait;
 use crate::bindings::sharded_memmap_index::ShardedMemmapIndexTrait;
+use crate::count_cache::{CacheStats, CountCache};
+use crate::error::{Result, TokengramsError};
 use crate::memmap_index::MemmapIndexRs;
-use crate::sample::{KneserNeyCache, Sample};
+use crate::metadata::IndexFingerprint;
+use crate::mmap_slice::MmapSlice;
+use crate::query::{convert_context, convert_queries, convert_query, restore_prompt, OutOfVocab};
+use crate::sample::{KneserNeyCache, Sample, SampleRequest, SampleResult};
+use crate::sampling_config::SamplingConfig;
+use crate::util::{check_sample_size, seeded_rng, transmute_slice};
+use crate::verify::IntegrityIssue;
 
 /// Expose suffix table functionality over text corpora too large to fit in memory.
 pub struct ShardedMemmapIndexRs<T: Unsigned> {
     shards: Vec<MemmapIndexRs<T>>,
     cache: KneserNeyCache,
+    count_cache: CountCache<T>,
+    vocab: usize,
+    out_of_vocab: OutOfVocab,
 }
 
 impl<T: Unsigned> Sample<T> for ShardedMemmapIndexRs<T> {
@@ -19,19 +31,9 @@ impl<T: Unsigned> Sample<T> for ShardedMemmapIndexRs<T> {
         &self.cache
     }
 
-    fn get_mut_cache(&mut self) -> &mut KneserNeyCache {
-        &mut self.cache
-    }
-
     fn count_next_slice(&self, query: &[T]) -> Vec<usize> {
-        let counts = self
-            .shards
-            .iter()
-            .map(|shard| shard.count_next_slice(query))
-            .collect::<Vec<_>>();
-        (0..counts[0].len())
-            .map(|i| counts.iter().map(|count| count[i]).sum())
-            .collect()
+        self.count_cache
+            .get_or_insert_with(query, self.vocab, || self.count_next_uncached(query))
     }
 
     fn count_ngrams(&self, n: usize) -> HashMap<usize, usize> {
@@ -48,83 +50,322 @@ impl<T: Unsigned> Sample<T> for ShardedMemmapIndexRs<T> {
 }
 
 impl<T: Unsigned> ShardedMemmapIndexRs<T> {
-    pub fn new(paths: Vec<(String, String)>, vocab: usize) -> PyResult<Self> {
-        let shards: Vec<MemmapIndexRs<T>> = paths
+    pub fn new(paths: Vec<(String, String)>, vocab: usize) -> Result<Self> {
+        let shards = paths
             .into_iter()
-            .map(|(text_path, table_path)| {
-                MemmapIndexRs::new(text_path, table_path, vocab).unwrap()
-            })
-            .collect();
+            .map(|(text_path, table_path)| MemmapIndexRs::new(text_path, table_path, vocab))
+            .collect::<Result<Vec<_>>>()?;
 
         Ok(ShardedMemmapIndexRs {
             shards,
             cache: KneserNeyCache::default(),
+            count_cache: CountCache::default(),
+            vocab,
+            out_of_vocab: OutOfVocab::default(),
         })
     }
 
-    pub fn build(paths: Vec<(String, String)>, vocab: usize, verbose: bool) -> PyResult<Self> {
-        let shards: Vec<MemmapIndexRs<T>> = paths
+    pub fn build(paths: Vec<(String, String)>, vocab: usize, verbose: bool) -> Result<Self> {
+        let shards = paths
             .into_iter()
             .map(|(token_paths, index_paths)| {
-                MemmapIndexRs::build(token_paths, index_paths, vocab, verbose).unwrap()
+                MemmapIndexRs::build(token_paths, index_paths, vocab, verbose)
             })
-            .collect();
+            .collect::<Result<Vec<_>>>()?;
 
         Ok(ShardedMemmapIndexRs {
             shards,
             cache: KneserNeyCache::default(),
+            count_cache: CountCache::default(),
+            vocab,
+            out_of_vocab: OutOfVocab::default(),
         })
     }
+
+    /// Split a single token file into shards of roughly `shard_size` tokens and build a suffix
+    /// table for each. When `eod_token` is provided every shard except possibly the last ends
+    /// with it, so no document is split across shards.
+    pub fn build_from_token_file(
+        token_path: String,
+        output_prefix: String,
+        shard_size: usize,
+        eod_token: Option<usize>,
+        vocab: usize,
+        verbose: bool,
+    ) -> Result<Self> {
+        let paths = Self::split_token_file(token_path, output_prefix, shard_size, eod_token)?;
+        Self::build(paths, vocab, verbose)
+    }
+
+    /// Counts the tokens following `query` without consulting the count cache.
+    fn count_next_uncached(&self, query: &[T]) -> Vec<usize> {
+        self.shards
+            .iter()
+            .fold(vec![0; self.vocab], |mut acc, shard| {
+                for (total, count) in acc.iter_mut().zip(shard.count_next_slice(query)) {
+                    *total += count;
+                }
+                acc
+            })
+    }
+
+    fn fingerprints(&self) -> Vec<IndexFingerprint> {
+        self.shards
+            .iter()
+            .map(|shard| shard.fingerprint())
+            .collect()
+    }
+
+    fn convert_query(&self, query: &[usize]) -> Result<Option<Vec<T>>> {
+        convert_query(query, self.vocab, self.out_of_vocab)
+    }
+
+    /// Write the shards of a token file to `{output_prefix}-{i:05}.bin`, returning the
+    /// (token file, index file) path pair for each shard.
+    pub fn split_token_file(
+        token_path: String,
+        output_prefix: String,
+        shard_size: usize,
+        eod_token: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        if shard_size == 0 {
+            return Err(TokengramsError::InvalidArgument(
+                "Shard size must be greater than zero".to_string(),
+            ));
+        }
+        let eod_token = eod_token
+            .map(|token| {
+                T::try_from(token).map_err(|_| TokengramsError::TokenOutOfVocab {
+                    token,
+                    vocab: T::MAX.as_usize() + 1,
+                })
+            })
+            .transpose()?;
+
+        let tokens = MmapSlice::<T>::new(&File::open(&token_path)?)?;
+        let bounds = shard_bounds(&tokens, shard_size, eod_token);
+
+        bounds
+            .into_iter()
+            .enumerate()
+            .map(|(i, (start, end))| {
+                let text_path = format!("{}-{:05}.bin", output_prefix, i);
+                let table_path = format!("{}-{:05}.idx", output_prefix, i);
+
+                let mut file = File::create(&text_path)?;
+                file.write_all(transmute_slice::<T, u8>(&tokens[start..end]))?;
+                file.flush()?;
+
+                Ok((text_path, table_path))
+            })
+            .collect()
+    }
+}
+
+/// Compute [start, end) token ranges of roughly `shard_size` tokens. With an EOD token each
+/// shard is cut after the last EOD within its target size, or after the first EOD beyond it
+/// when a single document is longer than `shard_size`.
+fn shard_bounds<T: Unsigned>(
+    tokens: &[T],
+    shard_size: usize,
+    eod_token: Option<T>,
+) -> Vec<(usize, usize)> {
+    let mut bounds = Vec::new();
+    let mut start = 0;
+
+    while start < tokens.len() {
+        let target = (start + shard_size).min(tokens.len());
+        let end = match eod_token {
+            Some(_) if target == tokens.len() => target,
+            Some(eod) => match tokens[start..target].iter().rposition(|&t| t == eod) {
+                Some(i) => start + i + 1,
+                None => tokens[target..]
+                    .iter()
+                    .position(|&t| t == eod)
+                    .map_or(tokens.len(), |i| target + i + 1),
+            },
+            None => target,
+        };
+
+        bounds.push((start, end));
+        start = end;
+    }
+    bounds
 }
 
 impl<T: Unsigned> ShardedMemmapIndexTrait for ShardedMemmapIndexRs<T> {
+    fn out_of_vocab(&self) -> OutOfVocab {
+        self.out_of_vocab
+    }
+
+    fn set_out_of_vocab(&mut self, policy: OutOfVocab) {
+        self.out_of_vocab = policy;
+    }
+
+    fn save_cache(&self, path: String) -> Result<()> {
+        self.cache.save(&path, self.fingerprints())
+    }
+
+    fn load_cache(&mut self, path: String) -> Result<()> {
+        self.cache = KneserNeyCache::load(&path, &self.fingerprints())?;
+        Ok(())
+    }
+
     fn is_sorted(&self) -> bool {
         self.shards.iter().all(|shard| shard.is_sorted())
     }
 
-    fn contains(&self, query: Vec<usize>) -> bool {
+    fn count_cache_capacity(&self) -> usize {
+        self.count_cache.capacity()
+    }
+
+    fn set_count_cache_capacity(&mut self, capacity: usize) {
+        self.count_cache = CountCache::new(capacity);
+    }
+
+    fn count_cache_stats(&self) -> CacheStats {
+        self.count_cache.stats()
+    }
+
+    fn warm_count_cache(&self, top_m: usize, max_len: usize) {
+        self.count_cache.warm(top_m, max_len, self.vocab, |query| {
+            self.count_next_uncached(query)
+        });
+    }
+
+    fn build_buckets(&mut self, depth: usize) -> Result<()> {
         self.shards
-            .iter()
-            .any(|shard| shard.contains(query.clone()))
+            .iter_mut()
+            .try_for_each(|shard| shard.build_buckets(depth))
     }
 
-    fn count(&self, query: Vec<usize>) -> usize {
+    fn build_counts(&mut self) -> Result<()> {
         self.shards
-            .iter()
-            .map(|shard| shard.count(query.clone()))
-            .sum()
+            .iter_mut()
+            .try_for_each(|shard| shard.build_counts())
     }
 
-    fn count_next(&self, query: Vec<usize>) -> Vec<usize> {
-        let query: Vec<T> = query
+    fn verify(&self, sample: Option<usize>) -> Vec<IntegrityIssue> {
+        self.shards
             .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
+            .enumerate()
+            .filter_map(|(i, shard)| shard.verify(sample).map(|issue| issue.with_shard(i)))
+            .collect()
+    }
+
+    fn contains(&self, query: Vec<usize>) -> Result<bool> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => self.shards.iter().any(|shard| shard.contains_slice(&query)),
+            None => false,
+        })
+    }
+
+    fn count(&self, query: Vec<usize>) -> Result<usize> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => self
+                .shards
+                .iter()
+                .map(|shard| shard.count_slice(&query))
+                .sum(),
+            None => 0,
+        })
+    }
 
-        let counts = self
+    fn count_next(&self, query: Vec<usize>) -> Result<Vec<usize>> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => self.count_next_slice(&query),
+            None => vec![0; self.vocab],
+        })
+    }
+
+    /// Splits the sample between shards in proportion to the query's occurrences in each, then
+    /// sums the estimates. Shards are sampled independently, so their variances add.
+    fn approx_count_next(
+        &self,
+        query: Vec<usize>,
+        sample_size: usize,
+        seed: Option<u64>,
+    ) -> Result<(Vec<f64>, Vec<f64>)> {
+        check_sample_size(sample_size)?;
+        let mut estimates = vec![0.0; self.vocab];
+        let mut variances = vec![0.0; self.vocab];
+        let Some(query) = self.convert_query(&query)? else {
+            return Ok((estimates, variances));
+        };
+
+        let populations: Vec<usize> = self
             .shards
             .iter()
-            .map(|shard| shard.count_next_slice(&query))
-            .collect::<Vec<_>>();
-        (0..counts[0].len())
-            .map(|i| counts.iter().map(|count| count[i]).sum())
-            .collect()
+            .map(|shard| shard.range_len(&query))
+            .collect();
+        let total: usize = populations.iter().sum();
+        let mut rng = seeded_rng(seed);
+
+        for (shard, &population) in self.shards.iter().zip(&populations) {
+            if population == 0 {
+                continue;
+            }
+            let shard_sample_size = (sample_size * population).div_ceil(total);
+            let (shard_estimates, shard_errors) =
+                shard.approx_count_next_slice(&query, shard_sample_size, &mut rng);
+            for (i, (estimate, error)) in shard_estimates.into_iter().zip(shard_errors).enumerate()
+            {
+                estimates[i] += estimate;
+                variances[i] += error * error;
+            }
+        }
+
+        let errors = variances.into_iter().map(f64::sqrt).collect();
+        Ok((estimates, errors))
+    }
+
+    fn batch_contains(&self, queries: Vec<Vec<usize>>) -> Result<Vec<bool>> {
+        Ok(self
+            .batch_count(queries)?
+            .into_iter()
+            .map(|count| count > 0)
+            .collect())
     }
 
-    fn batch_count_next(&self, queries: Vec<Vec<usize>>) -> Vec<Vec<usize>> {
-        let batch_counts = self
+    fn batch_count(&self, queries: Vec<Vec<usize>>) -> Result<Vec<usize>> {
+        let queries = convert_queries::<T>(&queries, self.vocab, self.out_of_vocab)?;
+        Ok(self
             .shards
             .iter()
-            .map(|shard| shard.batch_count_next(queries.clone()))
-            .collect::<Vec<_>>();
-
-        (0..queries.len())
-            .map(|i| {
-                (0..batch_counts[0][i].len())
-                    .map(|j| batch_counts.iter().map(|count| count[i][j]).sum())
-                    .collect()
+            .fold(vec![0; queries.len()], |mut acc, shard| {
+                for (total, count) in acc.iter_mut().zip(shard.batch_count_slices(&queries)) {
+                    *total += count;
+                }
+                acc
+            }))
+    }
+
+    /// Positions are offsets into the concatenation of the shards' token files, in shard order.
+    fn batch_positions(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<u64>>> {
+        let queries = convert_queries::<T>(&queries, self.vocab, self.out_of_vocab)?;
+        let mut positions = vec![Vec::new(); queries.len()];
+        let mut shard_start = 0;
+        for shard in &self.shards {
+            for (all, shard_positions) in positions
+                .iter_mut()
+                .zip(shard.batch_positions_slices(&queries))
+            {
+                all.extend(shard_positions.into_iter().map(|p| p + shard_start));
+            }
+            shard_start += shard.text_len() as u64;
+        }
+        Ok(positions)
+    }
+
+    fn batch_count_next(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<usize>>> {
+        let queries = convert_queries::<T>(&queries, self.vocab, self.out_of_vocab)?;
+        Ok(queries
+            .into_par_iter()
+            .map(|query| match query {
+                Some(query) => self.count_next_slice(&query),
+                None => vec![0; self.vocab],
             })
-            .collect()
+            .collect())
     }
 
     /// Autoregressively sample num_samples of k characters from an unsmoothed n-gram model."""
@@ -134,82 +375,108 @@ impl<T: Unsigned> ShardedMemmapIndexTrait for ShardedMemmapIndexRs<T> {
         n: usize,
         k: usize,
         num_samples: usize,
-    ) -> Result<Vec<Vec<usize>>> {
-        let query: Vec<T> = query
-            .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<SampleResult>> {
+        let context: Vec<T> = convert_context(&query, self.vocab, self.out_of_vocab)?;
 
-        let samples_batch =
-            <Self as Sample<T>>::sample_unsmoothed(self, &query, n, k, num_samples)?;
-        Ok(samples_batch
-            .into_iter()
-            .map(|samples| {
-                samples
-                    .into_iter()
-                    .filter_map(|sample| {
-                        match TryInto::<usize>::try_into(sample) {
-                            Ok(value) => Some(value),
-                            Err(_) => None, // Silently skip values that can't be converted
-                        }
-                    })
-                    .collect::<Vec<usize>>()
-            })
-            .collect())
+        let traces = <Self as Sample<T>>::sample_unsmoothed(
+            self,
+            &context,
+            n,
+            k,
+            num_samples,
+            seed,
+            config,
+        )?;
+        Ok(SampleResult::restore(&query, context.len(), traces))
     }
 
     /// Returns interpolated Kneser-Ney smoothed token probability distribution using all previous
     /// tokens in the query.
-    fn get_smoothed_probs(&mut self, query: Vec<usize>) -> Vec<f64> {
-        let query: Vec<T> = query
-            .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
-        <Self as Sample<T>>::get_smoothed_probs(self, &query)
+    fn get_smoothed_probs(&self, query: Vec<usize>) -> Result<Vec<f64>> {
+        let context: Vec<T> = convert_context(&query, self.vocab, self.out_of_vocab)?;
+
+        Ok(<Self as Sample<T>>::get_smoothed_probs(self, &context))
     }
 
     /// Returns interpolated Kneser-Ney smoothed token probability distribution using all previous
     /// tokens in the query.
-    fn batch_get_smoothed_probs(&mut self, queries: Vec<Vec<usize>>) -> Vec<Vec<f64>> {
-        let queries: Vec<Vec<T>> = queries
-            .into_iter()
-            .map(|query| {
-                query
-                    .iter()
-                    .filter_map(|&item| T::try_from(item).ok())
-                    .collect()
-            })
-            .collect();
-        <Self as Sample<T>>::batch_get_smoothed_probs(self, &queries)
+    fn batch_get_smoothed_probs(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<f64>>> {
+        let contexts: Vec<Vec<T>> = queries
+            .iter()
+            .map(|query| convert_context(query, self.vocab, self.out_of_vocab))
+            .collect::<Result<_>>()?;
+
+        Ok(<Self as Sample<T>>::batch_get_smoothed_probs(
+            self, &contexts,
+        ))
     }
 
     /// Autoregressively sample num_samples of k characters from a Kneser-Ney smoothed n-gram model.
     fn sample_smoothed(
-        &mut self,
+        &self,
         query: Vec<usize>,
         n: usize,
         k: usize,
         num_samples: usize,
-    ) -> Result<Vec<Vec<usize>>> {
-        let query: Vec<T> = query
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<SampleResult>> {
+        let context: Vec<T> = convert_context(&query, self.vocab, self.out_of_vocab)?;
+
+        let traces =
+            <Self as Sample<T>>::sample_smoothed(self, &context, n, k, num_samples, seed, config)?;
+        Ok(SampleResult::restore(&query, context.len(), traces))
+    }
+
+    fn batch_sample(
+        &self,
+        requests: Vec<SampleRequest<usize>>,
+        smoothed: bool,
+        seed: Option<u64>,
+        config: &SamplingConfig,
+    ) -> Result<Vec<Vec<SampleResult>>> {
+        let contexts = requests
             .iter()
-            .filter_map(|&item| T::try_from(item).ok())
-            .collect();
+            .map(|request| request.convert(self.vocab, self.out_of_vocab))
+            .collect::<Result<Vec<_>>>()?;
+
+        let traces = <Self as Sample<T>>::batch_sample(self, &contexts, smoothed, seed, config)?;
+        Ok(SampleResult::restore_batch(&requests, &contexts, traces))
+    }
+
+    fn decode_greedy(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        config: &SamplingConfig,
+    ) -> Result<(Vec<usize>, f64)> {
+        let context: Vec<T> = convert_context(&query, self.vocab, self.out_of_vocab)?;
+
+        let (sequence, score) = <Self as Sample<T>>::decode_greedy(self, &context, n, k, config)?;
+        let mut restored = restore_prompt(&query, context.len(), vec![sequence]);
+        Ok((restored.swap_remove(0), score))
+    }
 
-        let samples_batch = <Self as Sample<T>>::sample_smoothed(self, &query, n, k, num_samples)?;
-        Ok(samples_batch
+    fn beam_search(
+        &self,
+        query: Vec<usize>,
+        n: usize,
+        k: usize,
+        beam_width: usize,
+        config: &SamplingConfig,
+    ) -> Result<Vec<(Vec<usize>, f64)>> {
+        let context: Vec<T> = convert_context(&query, self.vocab, self.out_of_vocab)?;
+
+        let (sequences, scores): (Vec<_>, Vec<_>) =
+            <Self as Sample<T>>::beam_search(self, &context, n, k, beam_width, config)?
+                .into_iter()
+                .unzip();
+        Ok(restore_prompt(&query, context.len(), sequences)
             .into_iter()
-            .map(|samples| {
-                samples
-                    .into_iter()
-                    .filter_map(|sample| {
-                        match TryInto::<usize>::try_into(sample) {
-                            Ok(value) => Some(value),
-                            Err(_) => None, // Silently skip values that can't be converted
-                        }
-                    })
-                    .collect::<Vec<usize>>()
-            })
+            .zip(scores)
             .collect())
     }
 
@@ -217,7 +484,50 @@ impl<T: Unsigned> ShardedMemmapIndexTrait for ShardedMemmapIndexRs<T> {
     /// Improve smoothed model quality by replacing the default delta hyperparameters
     /// for models of order n and below with improved estimates over the entire index.
     /// <https://people.eecs.berkeley.edu/~klein/cs294-5/chen_goodman.pdf/>, page 16.
-    fn estimate_deltas(&mut self, n: usize) {
+    fn estimate_deltas(&self, n: usize) {
         <Self as Sample<T>>::estimate_deltas(self, n);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shard_bounds_split_on_eod() {
+        let tokens: Vec<u16> = vec![1, 2, 0, 3, 4, 5, 0, 6, 0, 7, 8];
+
+        let bounds = shard_bounds(&tokens, 4, Some(0));
+        assert_eq!(bounds, vec![(0, 3), (3, 7), (7, 11)]);
+
+        // Without an EOD token shards are cut at exactly `shard_size` tokens
+        let bounds = shard_bounds(&tokens, 4, None);
+        assert_eq!(bounds, vec![(0, 4), (4, 8), (8, 11)]);
+    }
+
+    #[test]
+    fn split_token_file_preserves_counts() {
+        let tokens: Vec<u16> = (0..1_000).map(|i| [1, 2, 3, 0][i % 4]).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path().join("tokens");
+        let token_path = format!("{}.bin", prefix.display());
+        std::fs::write(&token_path, transmute_slice::<u16, u8>(&tokens)).unwrap();
+
+        let paths = ShardedMemmapIndexRs::<u16>::split_token_file(
+            token_path.clone(),
+            prefix.display().to_string(),
+            150,
+            Some(0),
+        )
+        .unwrap();
+        assert_eq!(paths.len(), 7);
+        for (text_path, _) in &paths {
+            let shard = std::fs::read(text_path).unwrap();
+            assert_eq!(transmute_slice::<u8, u16>(&shard).last(), Some(&0));
+        }
+
+        let index = ShardedMemmapIndexRs::<u16>::build(paths.clone(), 4, false).unwrap();
+        assert_eq!(index.count(vec![1, 2, 3]).unwrap(), 250);
+        assert_eq!(index.count(vec![0]).unwrap(), 250);
+    }
+}
@@ -0,0 +1,351 @@
+use funty::Unsigned;
+use rayon::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::time::Instant;
+
+use crate::bindings::sparse_memmap_index::SparseMemmapIndexTrait;
+use crate::error::{Result, TokengramsError};
+use crate::memmap_index::sort_thread_pool;
+use crate::metadata::IndexMetadata;
+use crate::mmap_slice::{MmapSlice, MmapSliceMut};
+use crate::query::{convert_queries, convert_query, OutOfVocab};
+use crate::sparse_table::{build_sparse_table, sparse_table_len, SparseSuffixTable};
+use crate::table_index::{infer_table_width, table_width, TableIndex, U40};
+use crate::util::{check_vocab, page_positions};
+
+/// A sparse suffix table whose entry width is chosen at build time from the length of its text.
+enum SparseMmapTable<T: Unsigned> {
+    U32(SparseSuffixTable<MmapSlice<T>, MmapSlice<u32>>),
+    U40(SparseSuffixTable<MmapSlice<T>, MmapSlice<U40>>),
+    U64(SparseSuffixTable<MmapSlice<T>, MmapSlice<u64>>),
+}
+
+/// Evaluates `$body` with `$table` bound to the sparse table, whatever its entry width.
+macro_rules! with_table {
+    ($mmap_table:expr, $table:ident => $body:expr) => {
+        match $mmap_table {
+            SparseMmapTable::U32($table) => $body,
+            SparseMmapTable::U40($table) => $body,
+            SparseMmapTable::U64($table) => $body,
+        }
+    };
+}
+
+/// A memmap index over a suffix table holding only every `step`-th suffix, trading query time
+/// for a table `step` times smaller than a `MemmapIndex`'s. Queries and contexts shorter than the
+/// step, including the empty context, are rejected unless `allow_scan` is set, since answering
+/// them takes an `O(n)` scan of the text.
+pub struct SparseMemmapIndexRs<T: Unsigned> {
+    table: SparseMmapTable<T>,
+    out_of_vocab: OutOfVocab,
+    /// Whether queries shorter than the step may fall back to a scan of the whole text.
+    allow_scan: bool,
+}
+
+impl<T: Unsigned> SparseMmapTable<T> {
+    fn vocab(&self) -> usize {
+        with_table!(self, table => table.vocab())
+    }
+
+    fn step(&self) -> usize {
+        with_table!(self, table => table.step())
+    }
+
+    fn scans_text(&self, query_len: usize) -> bool {
+        with_table!(self, table => table.scans_text(query_len))
+    }
+}
+
+impl<T: Unsigned> SparseMemmapIndexRs<T> {
+    /// Opens a prebuilt sparse index. The sampling step is read from the table's metadata, or
+    /// must be given for tables without metadata, which are then checked to be sorted.
+    pub fn new(
+        text_path: String,
+        table_path: String,
+        vocab: usize,
+        step: Option<usize>,
+    ) -> Result<Self> {
+        let text = MmapSlice::<T>::new(&File::open(&text_path)?)?;
+        let table_file = File::open(&table_path)?;
+
+        let metadata = IndexMetadata::load(&table_path)?;
+        let (step, width) = match (&metadata, step) {
+            (Some(metadata), step) => {
+                let step = step.unwrap_or(metadata.step);
+                metadata.validate::<T>(text.len(), step)?;
+                (step, metadata.table_width)
+            }
+            (None, Some(step)) if step > 0 => {
+                let table_size = table_file.metadata()?.len() as usize;
+                let width = infer_table_width(table_size, sparse_table_len(text.len(), step))?;
+                (step, width)
+            }
+            (None, _) => {
+                return Err(TokengramsError::InvalidArgument(
+                    "The sampling step of a sparse table without metadata must be given"
+                        .to_string(),
+                ))
+            }
+        };
+
+        let table = match width {
+            4 => SparseMmapTable::U32(SparseSuffixTable::from_parts(
+                text,
+                MmapSlice::new(&table_file)?,
+                step,
+                vocab,
+            )?),
+            5 => SparseMmapTable::U40(SparseSuffixTable::from_parts(
+                text,
+                MmapSlice::new(&table_file)?,
+                step,
+                vocab,
+            )?),
+            8 => SparseMmapTable::U64(SparseSuffixTable::from_parts(
+                text,
+                MmapSlice::new(&table_file)?,
+                step,
+                vocab,
+            )?),
+            width => {
+                return Err(TokengramsError::InvalidMetadata(format!(
+                    "Unsupported table width {}",
+                    width
+                )))
+            }
+        };
+        if metadata.is_none() {
+            with_table!(&table, table => table.check_sorted())?;
+        }
+
+        Ok(SparseMemmapIndexRs {
+            table,
+            out_of_vocab: OutOfVocab::default(),
+            allow_scan: false,
+        })
+    }
+
+    /// Builds a sparse index over a token file, sampling every `step`-th suffix.
+    pub fn build(
+        text_path: String,
+        table_path: String,
+        step: usize,
+        vocab: usize,
+        verbose: bool,
+    ) -> Result<Self> {
+        if step == 0 {
+            return Err(TokengramsError::InvalidArgument(
+                "Sampling step must be greater than zero".to_string(),
+            ));
+        }
+        let text_mmap = MmapSlice::<T>::new(&File::open(&text_path)?)?;
+        check_vocab(&text_mmap, vocab)?;
+
+        let table_file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&table_path)?;
+
+        let table = match table_width(text_mmap.len()) {
+            4 => SparseMmapTable::U32(Self::build_table(
+                text_mmap,
+                &table_file,
+                step,
+                vocab,
+                verbose,
+            )?),
+            5 => SparseMmapTable::U40(Self::build_table(
+                text_mmap,
+                &table_file,
+                step,
+                vocab,
+                verbose,
+            )?),
+            _ => SparseMmapTable::U64(Self::build_table(
+                text_mmap,
+                &table_file,
+                step,
+                vocab,
+                verbose,
+            )?),
+        };
+
+        with_table!(&table, table => IndexMetadata {
+            step,
+            ..IndexMetadata::new(table.get_text(), table.get_table(), vocab)
+        })
+        .save(&table_path)?;
+
+        Ok(SparseMemmapIndexRs {
+            table,
+            out_of_vocab: OutOfVocab::default(),
+            allow_scan: false,
+        })
+    }
+
+    fn build_table<I: TableIndex>(
+        text_mmap: MmapSlice<T>,
+        table_file: &File,
+        step: usize,
+        vocab: usize,
+        verbose: bool,
+    ) -> Result<SparseSuffixTable<MmapSlice<T>, MmapSlice<I>>> {
+        let len = sparse_table_len(text_mmap.len(), step);
+        table_file.set_len((len * std::mem::size_of::<I>()) as u64)?;
+
+        if verbose {
+            println!("Sorting {} sampled suffixes...", len);
+        }
+        let start = Instant::now();
+        let mut table_mmap = MmapSliceMut::<I>::new(table_file)?;
+        sort_thread_pool(text_mmap.len())
+            .install(|| build_sparse_table(&text_mmap, table_mmap.as_slice_mut(), step, verbose));
+        if verbose {
+            println!("Time elapsed: {:?}", start.elapsed());
+        }
+
+        let table =
+            SparseSuffixTable::from_parts(text_mmap, table_mmap.into_read_only()?, step, vocab)?;
+        debug_assert!(table.is_sorted());
+        Ok(table)
+    }
+
+    fn convert_query(&self, query: &[usize]) -> Result<Option<Vec<T>>> {
+        self.check_scan(query)?;
+        convert_query(query, self.table.vocab(), self.out_of_vocab)
+    }
+
+    /// Returns an error if answering `query` would scan the whole text and scans are not
+    /// allowed.
+    fn check_scan(&self, query: &[usize]) -> Result<()> {
+        if self.allow_scan || !self.table.scans_text(query.len()) {
+            return Ok(());
+        }
+        Err(TokengramsError::InvalidArgument(format!(
+            "Query of {} tokens is shorter than the sampling step {} and would scan the whole \
+             corpus; set allow_scan to permit this",
+            query.len(),
+            self.table.step()
+        )))
+    }
+}
+
+impl<T: Unsigned> SparseMemmapIndexTrait for SparseMemmapIndexRs<T> {
+    fn out_of_vocab(&self) -> OutOfVocab {
+        self.out_of_vocab
+    }
+
+    fn set_out_of_vocab(&mut self, policy: OutOfVocab) {
+        self.out_of_vocab = policy;
+    }
+
+    fn allow_scan(&self) -> bool {
+        self.allow_scan
+    }
+
+    fn set_allow_scan(&mut self, allow: bool) {
+        self.allow_scan = allow;
+    }
+
+    fn step(&self) -> usize {
+        self.table.step()
+    }
+
+    fn is_sorted(&self) -> bool {
+        with_table!(&self.table, table => table.is_sorted())
+    }
+
+    fn contains(&self, query: Vec<usize>) -> Result<bool> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => with_table!(&self.table, table => table.contains(&query)),
+            None => false,
+        })
+    }
+
+    fn positions(
+        &self,
+        query: Vec<usize>,
+        offset: usize,
+        limit: Option<usize>,
+        sorted: bool,
+    ) -> Result<Vec<u64>> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => {
+                let positions = with_table!(&self.table, table => table.positions(&query));
+                page_positions(positions, offset, limit, sorted)
+            }
+            None => Vec::new(),
+        })
+    }
+
+    fn count(&self, query: Vec<usize>) -> Result<usize> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => with_table!(&self.table, table => table.count(&query)),
+            None => 0,
+        })
+    }
+
+    fn count_next(&self, query: Vec<usize>) -> Result<Vec<usize>> {
+        Ok(match self.convert_query(&query)? {
+            Some(query) => with_table!(&self.table, table => table.count_next(&query)),
+            None => vec![0; self.table.vocab()],
+        })
+    }
+
+    fn batch_count_next(&self, queries: Vec<Vec<usize>>) -> Result<Vec<Vec<usize>>> {
+        queries
+            .iter()
+            .try_for_each(|query| self.check_scan(query))?;
+        let queries = convert_queries::<T>(&queries, self.table.vocab(), self.out_of_vocab)?;
+        Ok(queries
+            .into_par_iter()
+            .map(|query| match query {
+                Some(query) => with_table!(&self.table, table => table.count_next(&query)),
+                None => vec![0; self.table.vocab()],
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::transmute_slice;
+
+    #[test]
+    fn build_and_reopen() {
+        let tokens: Vec<u16> = (0..1_000).map(|i| [1, 2, 3, 0, 2][i % 5]).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let text_path = dir.path().join("tokens.bin").display().to_string();
+        let table_path = dir.path().join("tokens.idx").display().to_string();
+        std::fs::write(&text_path, transmute_slice::<u16, u8>(&tokens)).unwrap();
+
+        let mut index =
+            SparseMemmapIndexRs::<u16>::build(text_path.clone(), table_path.clone(), 4, 4, false)
+                .unwrap();
+        assert_eq!(std::fs::metadata(&table_path).unwrap().len(), 250 * 4);
+        assert_eq!(index.count(vec![1, 2, 3, 0, 2, 1]).unwrap(), 199);
+
+        // Queries shorter than the step would scan the whole text unless scans are allowed
+        assert!(matches!(
+            index.count(vec![2]),
+            Err(TokengramsError::InvalidArgument(_))
+        ));
+        assert!(index
+            .batch_count_next(vec![vec![1, 2, 3, 0], vec![]])
+            .is_err());
+        index.set_allow_scan(true);
+        assert_eq!(index.count(vec![2]).unwrap(), 400);
+        assert_eq!(index.count(vec![1, 2, 3, 0, 2, 1]).unwrap(), 199);
+        assert_eq!(index.count_next(vec![2, 3]).unwrap(), vec![200, 0, 0, 0]);
+
+        let mut index =
+            SparseMemmapIndexRs::<u16>::new(text_path.clone(), table_path.clone(), 4, None)
+                .unwrap();
+        assert_eq!(index.step(), 4);
+        index.set_allow_scan(true);
+        assert_eq!(index.count(vec![3, 0, 2]).unwrap(), 200);
+    }
+}
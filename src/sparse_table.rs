@@ -0,0 +1,265 @@
+use crate::error::{Result, TokengramsError};
+use crate::par_quicksort::par_sort_unstable_by_key;
+use crate::table::binary_search;
+use crate::table_index::TableIndex;
+use funty::Unsigned;
+use rayon::prelude::*;
+use std::ops::Deref;
+
+/// A sparse suffix table stores only the suffixes starting at multiples of `step`, shrinking
+/// the table by a factor of `step` at the cost of extra work per query.
+///
+/// An occurrence of a query at position `p` is found through the first sampled suffix at or
+/// after `p`, which starts `j = (step - p % step) % step` tokens into the query. Queries of at
+/// least `step` tokens are therefore answered with `step` binary searches over the table, each
+/// followed by a scan over the suffixes sharing the query's tail. Shorter queries can also
+/// occur entirely between two sampled suffixes, so they fall back to an `O(n)` parallel scan of
+/// the text, see `scans_text`.
+pub struct SparseSuffixTable<T, U> {
+    text: T,
+    table: U,
+    step: usize,
+    vocab: usize,
+}
+
+/// Creates a sorted table of every `step`-th suffix of `text` in `O((n / step) log n)` time.
+pub fn build_sparse_table<E: Unsigned, I: TableIndex>(
+    text: &[E],
+    table: &mut [I],
+    step: usize,
+    verbose: bool,
+) {
+    table
+        .par_iter_mut()
+        .enumerate()
+        .for_each(|(i, x)| *x = I::from_u64((i * step) as u64));
+    par_sort_unstable_by_key(table, |&i| &text[i.as_usize()..], verbose);
+}
+
+/// Returns the number of sampled suffixes in a text of `len` tokens.
+pub fn sparse_table_len(len: usize, step: usize) -> usize {
+    len.div_ceil(step)
+}
+
+impl<T, U, E, I> SparseSuffixTable<T, U>
+where
+    E: Unsigned,
+    I: TableIndex,
+    T: Deref<Target = [E]> + Sync,
+    U: Deref<Target = [I]> + Sync,
+{
+    /// Creates a sparse suffix table from a text and a sorted table of every `step`-th suffix.
+    pub fn from_parts(text: T, table: U, step: usize, vocab: usize) -> Result<Self> {
+        if step == 0 {
+            return Err(TokengramsError::InvalidArgument(
+                "Sampling step must be greater than zero".to_string(),
+            ));
+        }
+        if sparse_table_len(text.len(), step) != table.len() {
+            return Err(TokengramsError::LengthMismatch {
+                text_len: text.len(),
+                table_len: table.len(),
+            });
+        }
+
+        Ok(SparseSuffixTable {
+            text,
+            table,
+            step,
+            vocab,
+        })
+    }
+
+    pub fn step(&self) -> usize {
+        self.step
+    }
+
+    pub fn vocab(&self) -> usize {
+        self.vocab
+    }
+
+    pub fn get_text(&self) -> &[E] {
+        &self.text
+    }
+
+    pub fn get_table(&self) -> &[I] {
+        &self.table
+    }
+
+    /// Returns true if queries of `query_len` tokens, or `count_next` contexts of that length,
+    /// are answered with a scan of the whole text rather than searches of the table.
+    pub fn scans_text(&self, query_len: usize) -> bool {
+        query_len < self.step
+    }
+
+    /// Checks if the sampled suffixes are lexicographically sorted.
+    pub fn is_sorted(&self) -> bool {
+        self.table
+            .par_windows(2)
+            .all(|pair| self.text[pair[0].as_usize()..] <= self.text[pair[1].as_usize()..])
+    }
+
+    /// Returns an error if the sampled suffixes are not lexicographically sorted.
+    pub fn check_sorted(&self) -> Result<()> {
+        if self.is_sorted() {
+            Ok(())
+        } else {
+            Err(TokengramsError::UnsortedTable)
+        }
+    }
+
+    /// Returns true if and only if `query` is in text.
+    pub fn contains(&self, query: &[E]) -> bool {
+        if self.scans_text(query.len()) {
+            return self.count(query) > 0;
+        }
+        (0..self.step)
+            .into_par_iter()
+            .any(|j| self.sampled_matches(query, j).next().is_some())
+    }
+
+    /// Returns the number of occurrences of `query` in text.
+    pub fn count(&self, query: &[E]) -> usize {
+        self.positions(query).len()
+    }
+
+    /// Returns an unordered list of positions where `query` starts in `text`.
+    pub fn positions(&self, query: &[E]) -> Vec<u64> {
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        let mut positions: Vec<u64> = (0..self.step.min(query.len()))
+            .into_par_iter()
+            .flat_map_iter(|j| self.sampled_matches(query, j))
+            .collect();
+        if self.scans_text(query.len()) {
+            positions.extend(self.scan_unsampled(query));
+        }
+        positions
+    }
+
+    /// Count occurrences of each token directly following the query sequence.
+    pub fn count_next(&self, query: &[E]) -> Vec<usize> {
+        if query.is_empty() {
+            return self
+                .text
+                .par_chunks(1 << 20)
+                .map(|chunk| {
+                    let mut counts = vec![0; self.vocab];
+                    chunk.iter().for_each(|t| counts[t.as_usize()] += 1);
+                    counts
+                })
+                .reduce(
+                    || vec![0; self.vocab],
+                    |mut a, b| {
+                        a.iter_mut().zip(b).for_each(|(x, y)| *x += y);
+                        a
+                    },
+                );
+        }
+
+        let mut counts = vec![0; self.vocab];
+        for p in self.positions(query) {
+            if let Some(t) = self.text.get(p as usize + query.len()) {
+                counts[t.as_usize()] += 1;
+            }
+        }
+        counts
+    }
+
+    /// Returns the occurrences of `query` whose first sampled suffix starts `j` tokens in.
+    fn sampled_matches<'a>(&'a self, query: &'a [E], j: usize) -> impl Iterator<Item = u64> + 'a {
+        let (head, tail) = query.split_at(j);
+        let (start, end) = self.boundaries(tail);
+
+        self.table[start..end].iter().filter_map(move |&sufi| {
+            let s = sufi.as_usize();
+            (s >= j && &self.text[s - j..s] == head).then(|| (s - j) as u64)
+        })
+    }
+
+    /// Scans the text for occurrences of a query shorter than `step` that do not reach a
+    /// sampled suffix.
+    fn scan_unsampled(&self, query: &[E]) -> Vec<u64> {
+        let n = self.text.len();
+        if query.len() > n {
+            return Vec::new();
+        }
+
+        (0..=n - query.len())
+            .into_par_iter()
+            .filter(|&p| {
+                let j = (self.step - p % self.step) % self.step;
+                (j >= query.len() || p + j >= n) && self.text[p..].starts_with(query)
+            })
+            .map(|p| p as u64)
+            .collect()
+    }
+
+    /// Determine start and end `table` indices of sampled suffixes that start with `query`.
+    fn boundaries(&self, query: &[E]) -> (usize, usize) {
+        let start = binary_search(&self.table, |&sufi| query <= &self.text[sufi.as_usize()..]);
+        let end = start
+            + binary_search(&self.table[start..], |&sufi| {
+                !self.text[sufi.as_usize()..].starts_with(query)
+            });
+        (start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::SuffixTable;
+
+    fn sparse(text: &[u16], step: usize) -> SparseSuffixTable<Box<[u16]>, Box<[u64]>> {
+        let mut table = vec![0u64; sparse_table_len(text.len(), step)];
+        build_sparse_table(text, &mut table, step, false);
+        SparseSuffixTable::from_parts(text.into(), table.into(), step, 8).unwrap()
+    }
+
+    #[test]
+    fn sparse_matches_full_table() {
+        let text: Vec<u16> = (0..500u32)
+            .map(|i| ((i * i + 3 * i) % 7 % 4) as u16)
+            .collect();
+        let full = SuffixTable::new(text.clone(), Some(8), false);
+
+        for step in [1, 2, 3, 5] {
+            let table = sparse(&text, step);
+            assert!(table.is_sorted());
+
+            for query in [
+                vec![],
+                vec![0],
+                vec![3],
+                vec![1, 2],
+                vec![0, 0, 1],
+                vec![2, 3, 0, 1],
+                vec![1, 0, 0, 1, 2, 3],
+                vec![7],
+            ] {
+                let mut expected = full.positions(&query).to_vec();
+                let mut positions = table.positions(&query);
+                expected.sort();
+                positions.sort();
+
+                assert_eq!(positions, expected, "step {} query {:?}", step, query);
+                assert_eq!(table.contains(&query), full.contains(&query));
+                assert_eq!(table.count_next(&query), full.count_next(&query));
+            }
+        }
+    }
+
+    #[test]
+    fn from_parts_length_mismatch() {
+        let text: Box<[u16]> = vec![0, 1, 2, 3, 4].into();
+        let table: Box<[u64]> = vec![0, 2].into();
+
+        assert!(matches!(
+            SparseSuffixTable::from_parts(text, table, 2, 8),
+            Err(TokengramsError::LengthMismatch { .. })
+        ));
+    }
+}
@@ -1,11 +1,20 @@
 extern crate utf16_literal;
 
+use crate::bucket_table::BucketTable;
+use crate::error::{Result, TokengramsError};
 use crate::par_quicksort::par_sort_unstable_by_key;
+use crate::table_index::TableIndex;
+use crate::util::page_positions;
 use funty::Unsigned;
+use rand::seq::index::sample;
+use rand::Rng;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::{fmt, ops::Deref, u64};
+use std::{fmt, ops::Deref};
+
+/// Number of sorted queries searched one after another by each task in a batch.
+const BATCH_CHUNK_SIZE: usize = 256;
 
 /// A suffix table is a sequence of lexicographically sorted suffixes.
 /// The table supports n-gram statistics computation and language modeling over text corpora.
@@ -14,6 +23,8 @@ pub struct SuffixTable<T = Box<[u16]>, U = Box<[u64]>> {
     text: T,
     table: U,
     vocab: usize,
+    /// Optional lookup table narrowing searches to the suffixes sharing a query's first tokens.
+    buckets: Option<BucketTable>,
 }
 
 /// Method for vanilla in-memory suffix tables
@@ -42,19 +53,34 @@ impl<T: Unsigned> SuffixTable<Box<[T]>, Box<[u64]>> {
             text,
             table: table.into(),
             vocab,
+            buckets: None,
         }
     }
 }
 
-impl<T, U, E> SuffixTable<T, U>
+impl<T, U, E, I> SuffixTable<T, U>
 where
     E: Unsigned,
+    I: TableIndex,
     T: Deref<Target = [E]> + Sync,
-    U: Deref<Target = [u64]> + Sync,
+    U: Deref<Target = [I]> + Sync,
 {
-    pub fn from_parts(text: T, table: U, vocab: Option<usize>) -> Self {
+    /// Creates a suffix table from a text and a table of sorted suffix indices into it.
+    pub fn from_parts(text: T, table: U, vocab: Option<usize>) -> Result<Self> {
+        if text.len() != table.len() {
+            return Err(TokengramsError::LengthMismatch {
+                text_len: text.len(),
+                table_len: table.len(),
+            });
+        }
+
         let vocab = vocab.unwrap_or(u16::MAX as usize + 1);
-        SuffixTable { text, table, vocab }
+        Ok(SuffixTable {
+            text,
+            table,
+            vocab,
+            buckets: None,
+        })
     }
 
     /// Consumes the suffix table and returns the underlying text and table.
@@ -71,6 +97,37 @@ where
         self.table.len()
     }
 
+    /// Returns the vocabulary size, which bounds the token values in the text.
+    pub fn vocab(&self) -> usize {
+        self.vocab
+    }
+
+    pub fn buckets(&self) -> Option<&BucketTable> {
+        self.buckets.as_ref()
+    }
+
+    /// Sets the lookup table used to narrow searches, which must have been built from this table.
+    pub fn set_buckets(&mut self, buckets: Option<BucketTable>) {
+        self.buckets = buckets;
+    }
+
+    /// Builds a lookup table over the first `depth` tokens of each suffix.
+    pub fn build_buckets(&self, depth: usize) -> Result<BucketTable> {
+        BucketTable::build(&self.text, &self.table, self.vocab, depth)
+    }
+
+    /// Returns the range of ranks that may hold suffixes starting with `query`, and whether
+    /// every suffix in it does.
+    fn search_range(&self, query: &[E]) -> (usize, usize, bool) {
+        match &self.buckets {
+            Some(buckets) => {
+                let (start, end) = buckets.range(query);
+                (start, end, query.len() <= buckets.depth())
+            }
+            None => (0, self.table.len(), query.is_empty()),
+        }
+    }
+
     /// Returns `true` iff `self.len() == 0`.
     #[inline]
     #[allow(dead_code)]
@@ -82,14 +139,23 @@ where
     pub fn is_sorted(&self) -> bool {
         self.table
             .par_windows(2)
-            .all(|pair| self.text[pair[0] as usize..] <= self.text[pair[1] as usize..])
+            .all(|pair| self.text[pair[0].as_usize()..] <= self.text[pair[1].as_usize()..])
+    }
+
+    /// Returns an error if the suffix table is not lexicographically sorted.
+    pub fn check_sorted(&self) -> Result<()> {
+        if self.is_sorted() {
+            Ok(())
+        } else {
+            Err(TokengramsError::UnsortedTable)
+        }
     }
 
     /// Returns the suffix at index `i`.
     #[inline]
     #[allow(dead_code)]
     pub fn suffix(&self, i: usize) -> &[E] {
-        &self.text[self.table[i] as usize..]
+        &self.text[self.table[i].as_usize()..]
     }
 
     /// Returns true if and only if `query` is in text.
@@ -114,16 +180,21 @@ where
     /// ```
     #[allow(dead_code)]
     pub fn contains(&self, query: &[E]) -> bool {
-        !query.is_empty()
-            && self
-                .table
-                .binary_search_by(|&sufi| {
-                    self.text[sufi as usize..]
-                        .iter()
-                        .take(query.len())
-                        .cmp(query.iter())
-                })
-                .is_ok()
+        if query.is_empty() {
+            return false;
+        }
+        let (start, end, exact) = self.search_range(query);
+        if exact {
+            return start < end;
+        }
+        self.table[start..end]
+            .binary_search_by(|&sufi| {
+                self.text[sufi.as_usize()..]
+                    .iter()
+                    .take(query.len())
+                    .cmp(query.iter())
+            })
+            .is_ok()
     }
 
     /// Returns an unordered list of positions where `query` starts in `text`.
@@ -150,57 +221,143 @@ where
     /// assert_eq!(sa.positions(utf16!("quick")), &[4, 29]);
     /// ```
     #[allow(dead_code)]
-    pub fn positions(&self, query: &[E]) -> &[u64] {
-        // We can quickly decide whether the query won't match at all if
-        // it's outside the range of suffixes.
-        if self.text.is_empty()
-            || query.is_empty()
-            || (query < self.suffix(0) && !self.suffix(0).starts_with(query))
-            || query > self.suffix(self.len() - 1)
-        {
+    pub fn positions(&self, query: &[E]) -> &[I] {
+        if query.is_empty() {
             return &[];
         }
+        let (start, end) = self.boundaries(query);
+        &self.table[start..end]
+    }
 
-        // The below is pretty close to the algorithm on Wikipedia:
-        //
-        //     http://en.wikipedia.org/wiki/Suffix_array#Applications
-        //
-        // The key difference is that after we find the start index, we look
-        // for the end by finding the first occurrence that doesn't start
-        // with `query`. That becomes our upper bound.
-        let start = binary_search(&self.table, |&sufi| query <= &self.text[sufi as usize..]);
-        let end = start
-            + binary_search(&self.table[start..], |&sufi| {
-                !self.text[sufi as usize..].starts_with(query)
-            });
+    /// Returns the positions of each query like `positions`, or none for missing queries.
+    ///
+    /// Queries are searched in parallel in sorted order, so consecutive queries in a task tend
+    /// to share prefixes. Each search starts from the range of the prefix it shares with the
+    /// previous query and narrows it one token at a time.
+    pub fn batch_positions(&self, queries: &[Option<Vec<E>>]) -> Vec<&[I]> {
+        let mut order: Vec<usize> = (0..queries.len())
+            .filter(|&i| queries[i].as_ref().is_some_and(|query| !query.is_empty()))
+            .collect();
+        order.par_sort_by(|&a, &b| queries[a].cmp(&queries[b]));
+
+        let ranges: Vec<(usize, (usize, usize))> = order
+            .par_chunks(BATCH_CHUNK_SIZE)
+            .flat_map_iter(|chunk| {
+                // The ranges of each prefix of the previous query, by prefix length
+                let mut prefix_ranges = vec![(0, self.table.len())];
+                let mut previous: &[E] = &[];
+                chunk
+                    .iter()
+                    .map(|&i| {
+                        let query = queries[i].as_deref().unwrap_or_default();
+                        let shared = previous
+                            .iter()
+                            .zip(query)
+                            .take_while(|(a, b)| a == b)
+                            .count();
+                        prefix_ranges.truncate(shared + 1);
+                        for depth in shared..query.len() {
+                            let range = match &self.buckets {
+                                Some(buckets) if depth < buckets.depth() => {
+                                    buckets.range(&query[..depth + 1])
+                                }
+                                _ => {
+                                    let (start, end) = prefix_ranges[depth];
+                                    self.narrow(start, end, depth, query[depth])
+                                }
+                            };
+                            prefix_ranges.push(range);
+                        }
+                        previous = query;
+                        (i, prefix_ranges[query.len()])
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        let mut positions: Vec<&[I]> = vec![&[]; queries.len()];
+        for (i, (start, end)) in ranges {
+            positions[i] = &self.table[start..end];
+        }
+        positions
+    }
 
-        // Whoops. If start is somehow greater than end, then we've got
-        // nothing.
-        if start > end {
-            &[]
-        } else {
-            &self.table[start..end]
+    /// Narrows a range of suffixes sharing their first `depth` tokens to those continuing
+    /// with `token`.
+    fn narrow(&self, start: usize, end: usize, depth: usize, token: E) -> (usize, usize) {
+        let range = &self.table[start..end];
+        let next = |sufi: &I| self.text.get(sufi.as_usize() + depth);
+        let lo = binary_search(range, |sufi| next(sufi) >= Some(&token));
+        let hi = lo + binary_search(&range[lo..], |sufi| next(sufi) > Some(&token));
+        (start + lo, start + hi)
+    }
+
+    /// Returns the page of positions where `query` starts in `text` that skips `offset`
+    /// positions and keeps at most `limit`, in table order or, if `sorted`, in increasing order.
+    /// Unsorted pages are copied directly from the table without visiting the other matches.
+    pub fn positions_page(
+        &self,
+        query: &[E],
+        offset: usize,
+        limit: Option<usize>,
+        sorted: bool,
+    ) -> Vec<u64> {
+        let positions = self.positions(query);
+        if sorted {
+            let positions = positions.iter().map(|sufi| sufi.to_u64()).collect();
+            return page_positions(positions, offset, limit, true);
         }
+        let end = limit
+            .map_or(positions.len(), |limit| offset.saturating_add(limit))
+            .min(positions.len());
+        positions[offset.min(end)..end]
+            .iter()
+            .map(|sufi| sufi.to_u64())
+            .collect()
+    }
+
+    /// Returns `k` positions where `query` starts in `text`, drawn uniformly without
+    /// replacement, in increasing order. Returns every position if there are at most `k`.
+    pub fn sample_positions<R: Rng>(&self, query: &[E], k: usize, rng: &mut R) -> Vec<u64> {
+        let positions = self.positions(query);
+        let mut sampled: Vec<u64> = sample(rng, positions.len(), k.min(positions.len()))
+            .into_iter()
+            .map(|i| positions[i].to_u64())
+            .collect();
+        sampled.sort_unstable();
+        sampled
     }
 
     /// Determine start and end `table` indices of items that start with `query`.
-    fn boundaries(&self, query: &[E]) -> (usize, usize) {
+    pub(crate) fn boundaries(&self, query: &[E]) -> (usize, usize) {
         if self.text.is_empty() || query.is_empty() {
             return (0, self.table.len());
         }
+        let (range_start, range_end, exact) = self.search_range(query);
+        if exact {
+            return (range_start, range_end);
+        }
         if (query < self.suffix(0) && !self.suffix(0).starts_with(query))
             || query > self.suffix(self.len() - 1)
         {
             return (0, 0);
         }
 
-        let start = binary_search(&self.table, |&sufi| query <= &self.text[sufi as usize..]);
+        // The below is pretty close to the algorithm on Wikipedia:
+        //
+        //     http://en.wikipedia.org/wiki/Suffix_array#Applications
+        //
+        // The key difference is that after we find the start index, we look
+        // for the end by finding the first occurrence that doesn't start
+        // with `query`. That becomes our upper bound.
+        let table = &self.table[range_start..range_end];
+        let start = binary_search(table, |&sufi| query <= &self.text[sufi.as_usize()..]);
         let end = start
-            + binary_search(&self.table[start..], |&sufi| {
-                !self.text[sufi as usize..].starts_with(query)
+            + binary_search(&table[start..], |&sufi| {
+                !self.text[sufi.as_usize()..].starts_with(query)
             });
 
-        (start, end)
+        (range_start + start, range_start + end)
     }
 
     /// Determine start and end indices of items that start with `query` in the `table` range.
@@ -220,11 +377,11 @@ where
         }
 
         let start = binary_search(&self.table[range_start..range_end], |&sufi| {
-            query <= &self.text[sufi as usize..]
+            query <= &self.text[sufi.as_usize()..]
         });
         let end = start
             + binary_search(&self.table[range_start + start..range_end], |&sufi| {
-                !self.text[sufi as usize..].starts_with(query)
+                !self.text[sufi.as_usize()..].starts_with(query)
             });
 
         if start > end {
@@ -243,6 +400,30 @@ where
         counts
     }
 
+    /// Estimates the counts of each token following `query` from `sample_size` of its
+    /// occurrences, drawn uniformly without replacement from its range of the table. Returns the
+    /// estimates and their standard errors, which are exact and zero when the sample covers
+    /// every occurrence.
+    pub fn approx_count_next<R: Rng>(
+        &self,
+        query: &[E],
+        sample_size: usize,
+        rng: &mut R,
+    ) -> (Vec<f64>, Vec<f64>) {
+        let (start, end) = self.boundaries(query);
+        let population = end - start;
+        let sample_size = sample_size.min(population);
+
+        let mut counts = vec![0; self.vocab];
+        for i in sample(rng, population, sample_size) {
+            let sufi = self.table[start + i].as_usize();
+            if let Some(token) = self.text.get(sufi + query.len()) {
+                counts[token.as_usize()] += 1;
+            }
+        }
+        estimate_counts(&counts, population, sample_size)
+    }
+
     // count_next helper method.
     fn recurse_count_next(
         &self,
@@ -340,7 +521,7 @@ where
             .collect()
     }
 
-    pub fn get_table(&self) -> &[u64] {
+    pub fn get_table(&self) -> &[I] {
         &self.table
     }
 
@@ -360,13 +541,38 @@ impl fmt::Debug for SuffixTable {
     }
 }
 
+/// Scales the counts of each token in a simple random sample of `sample_size` items out of
+/// `population` to estimates of the counts in the population, returning the estimates and their
+/// standard errors with the finite population correction.
+pub(crate) fn estimate_counts(
+    counts: &[usize],
+    population: usize,
+    sample_size: usize,
+) -> (Vec<f64>, Vec<f64>) {
+    if sample_size == 0 || sample_size == population {
+        return (
+            counts.iter().map(|&c| c as f64).collect(),
+            vec![0.0; counts.len()],
+        );
+    }
+
+    let (n, s) = (population as f64, sample_size as f64);
+    let fpc = (n - s) / (n - 1.0);
+    counts
+        .iter()
+        .map(|&c| {
+            let p = c as f64 / s;
+            (n * p, n * (p * (1.0 - p) / s * fpc).sqrt())
+        })
+        .unzip()
+}
+
 /// Binary search to find first element such that `pred(T) == true`.
 ///
 /// Assumes that if `pred(xs[i]) == true` then `pred(xs[i+1]) == true`.
 ///
 /// If all elements yield `pred(T) == false`, then `xs.len()` is returned.
-#[allow(dead_code)]
-fn binary_search<T, F>(xs: &[T], mut pred: F) -> usize
+pub(crate) fn binary_search<T, F>(xs: &[T], mut pred: F) -> usize
 where
     F: FnMut(&T) -> bool,
 {
@@ -415,6 +621,20 @@ mod tests {
         assert_eq!(1, sa.count_next(query)[b_index]);
     }
 
+    #[test]
+    fn from_parts_length_mismatch() {
+        let text: Box<[u16]> = utf16!("aaab").to_vec().into();
+        let table: Box<[u64]> = vec![0, 1, 2].into();
+
+        assert!(matches!(
+            SuffixTable::from_parts(text, table, None),
+            Err(TokengramsError::LengthMismatch {
+                text_len: 4,
+                table_len: 3
+            })
+        ));
+    }
+
     #[test]
     fn batch_count_next_exists() {
         let sa = sais("aaab");
@@ -427,4 +647,82 @@ mod tests {
         assert_eq!(2, sa.batch_count_next(&queries)[0][a_index]);
         assert_eq!(1, sa.batch_count_next(&queries)[0][b_index]);
     }
+
+    #[test]
+    fn approx_count_next_estimates() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let text: Vec<u16> = (0..2_000u32).map(|i| (i * i % 7 % 3) as u16).collect();
+        let sa = SuffixTable::new(text, Some(3), false);
+        let exact = sa.count_next(&[1]);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        // A sample as large as the population is exact
+        let (estimates, errors) = sa.approx_count_next(&[1], 100_000, &mut rng);
+        assert_eq!(
+            estimates,
+            exact.iter().map(|&c| c as f64).collect::<Vec<_>>()
+        );
+        assert!(errors.iter().all(|&e| e == 0.0));
+
+        let (estimates, errors) = sa.approx_count_next(&[1], 200, &mut rng);
+        let population: usize = exact.iter().sum();
+        assert!((estimates.iter().sum::<f64>() - population as f64).abs() < 1e-6);
+        for ((&estimate, &error), &count) in estimates.iter().zip(&errors).zip(&exact) {
+            assert!((estimate - count as f64).abs() <= 5.0 * error);
+        }
+    }
+
+    #[test]
+    fn positions_pages_and_samples() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let sa = sais("abracadabra abracadabra");
+        let query = utf16!("a");
+        let mut all = sa.positions(query).to_vec();
+
+        let pages: Vec<u64> = (0..all.len())
+            .step_by(3)
+            .flat_map(|offset| sa.positions_page(query, offset, Some(3), false))
+            .collect();
+        assert_eq!(pages, all);
+
+        all.sort();
+        assert_eq!(sa.positions_page(query, 2, Some(4), true), &all[2..6]);
+
+        let sampled = sa.sample_positions(query, 4, &mut StdRng::seed_from_u64(0));
+        assert_eq!(sampled.len(), 4);
+        assert!(sampled.windows(2).all(|w| w[0] < w[1]));
+        assert!(sampled.iter().all(|p| all.contains(p)));
+        assert_eq!(
+            sa.sample_positions(query, 100, &mut StdRng::seed_from_u64(0)),
+            all
+        );
+    }
+
+    #[test]
+    fn batch_positions_match_positions() {
+        let text: Vec<u16> = (0..1_000u32)
+            .map(|i| ((i * i + i / 7) % 11 % 5) as u16)
+            .collect();
+        let mut sa = SuffixTable::new(text, Some(5), false);
+        let queries: Vec<Option<Vec<u16>>> = (0..600u32)
+            .map(|i| match i % 50 {
+                0 => None,
+                1 => Some(vec![]),
+                _ => Some((0..i % 6).map(|j| ((i * 3 + j * j) % 5) as u16).collect()),
+            })
+            .collect();
+
+        for depth in [None, Some(2)] {
+            sa.set_buckets(depth.map(|depth| sa.build_buckets(depth).unwrap()));
+            let batch = sa.batch_positions(&queries);
+            for (query, positions) in queries.iter().zip(batch) {
+                let expected = query
+                    .as_deref()
+                    .map_or(&[][..], |query| sa.positions(query));
+                assert_eq!(positions, expected, "query {:?}", query);
+            }
+        }
+    }
 }
@@ -0,0 +1,112 @@
+use crate::error::{Result, TokengramsError};
+
+/// An integer type used to store suffix table entries. Narrower types shrink the table for
+/// texts short enough that every offset fits.
+pub trait TableIndex: Copy + Send + Sync + 'static {
+    fn from_u64(i: u64) -> Self;
+    fn to_u64(self) -> u64;
+
+    #[inline]
+    fn as_usize(self) -> usize {
+        self.to_u64() as usize
+    }
+}
+
+impl TableIndex for u32 {
+    #[inline]
+    fn from_u64(i: u64) -> Self {
+        debug_assert!(i <= u32::MAX as u64);
+        i as u32
+    }
+
+    #[inline]
+    fn to_u64(self) -> u64 {
+        self as u64
+    }
+}
+
+impl TableIndex for u64 {
+    #[inline]
+    fn from_u64(i: u64) -> Self {
+        i
+    }
+
+    #[inline]
+    fn to_u64(self) -> u64 {
+        self
+    }
+}
+
+/// A packed little-endian 40-bit unsigned integer, covering texts of up to 2^40 tokens.
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct U40([u8; 5]);
+
+impl TableIndex for U40 {
+    #[inline]
+    fn from_u64(i: u64) -> Self {
+        debug_assert!(i < 1 << 40);
+        let bytes = i.to_le_bytes();
+        U40([bytes[0], bytes[1], bytes[2], bytes[3], bytes[4]])
+    }
+
+    #[inline]
+    fn to_u64(self) -> u64 {
+        let [a, b, c, d, e] = self.0;
+        u64::from_le_bytes([a, b, c, d, e, 0, 0, 0])
+    }
+}
+
+/// Returns the narrowest table entry width in bytes able to index a text of `len` tokens.
+pub fn table_width(len: usize) -> usize {
+    let len = len as u64;
+    if len <= 1 << 32 {
+        4
+    } else if len <= 1 << 40 {
+        5
+    } else {
+        8
+    }
+}
+
+/// Infers the entry width of a table file of `table_size` bytes built over a text of
+/// `text_len` tokens, for tables without metadata.
+pub fn infer_table_width(table_size: usize, text_len: usize) -> Result<usize> {
+    if text_len == 0 {
+        return Ok(table_width(0));
+    }
+    match table_size / text_len {
+        width @ (4 | 5 | 8) if table_size.is_multiple_of(text_len) => Ok(width),
+        _ => Err(TokengramsError::LengthMismatch {
+            text_len,
+            table_len: table_size / std::mem::size_of::<u64>(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn u40_roundtrip() {
+        for i in [0, 1, 255, 1 << 32, (1 << 40) - 1] {
+            assert_eq!(U40::from_u64(i).to_u64(), i);
+        }
+        assert_eq!(std::mem::size_of::<U40>(), 5);
+        assert_eq!(std::mem::align_of::<U40>(), 1);
+    }
+
+    #[test]
+    fn table_width_from_len() {
+        assert_eq!(table_width(1_000), 4);
+        assert_eq!(table_width(1 << 32), 4);
+        assert_eq!(table_width((1 << 32) + 1), 5);
+        assert_eq!(table_width((1 << 40) + 1), 8);
+
+        assert_eq!(infer_table_width(40, 10).unwrap(), 4);
+        assert_eq!(infer_table_width(50, 10).unwrap(), 5);
+        assert_eq!(infer_table_width(80, 10).unwrap(), 8);
+        assert!(infer_table_width(60, 10).is_err());
+    }
+}
@@ -1,9 +1,92 @@
+use crate::error::{Result, TokengramsError};
+use funty::Unsigned;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rayon::prelude::*;
+
+/// Return an error for the first token that is not less than `vocab`.
+pub fn check_vocab<T: Unsigned>(tokens: &[T], vocab: usize) -> Result<()> {
+    match tokens
+        .par_iter()
+        .find_first(|token| token.as_usize() >= vocab)
+    {
+        Some(token) => Err(TokengramsError::TokenOutOfVocab {
+            token: token.as_usize(),
+            vocab,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Returns an error unless a sample size is positive.
+pub fn check_sample_size(sample_size: usize) -> Result<()> {
+    if sample_size == 0 {
+        return Err(TokengramsError::InvalidArgument(
+            "Sample size must be greater than zero".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns a random number generator seeded with `seed`, or from system entropy when no seed is
+/// given.
+pub fn seeded_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+/// Returns the page of `positions` that skips `offset` positions and keeps at most `limit`,
+/// taken from the positions in increasing order if `sorted` and in their given order otherwise.
+/// Sorted pages only fully sort the positions up to the end of the page.
+pub fn page_positions(
+    mut positions: Vec<u64>,
+    offset: usize,
+    limit: Option<usize>,
+    sorted: bool,
+) -> Vec<u64> {
+    let end = limit
+        .map_or(positions.len(), |limit| offset.saturating_add(limit))
+        .min(positions.len());
+    if sorted {
+        if end == 0 {
+            return Vec::new();
+        }
+        if end < positions.len() {
+            positions.select_nth_unstable(end - 1);
+            positions.truncate(end);
+        }
+        positions.par_sort_unstable();
+    }
+    positions.truncate(end);
+    positions.drain(..offset.min(end));
+    positions
+}
+
+/// Checksum the bytes of a slice. The data is split into fixed-size chunks that are hashed in
+/// parallel with 64-bit FNV-1a, and the chunk hashes are then hashed in order, so the result
+/// doesn't depend on the number of threads.
+pub fn checksum<T>(data: &[T]) -> u64 {
+    const CHUNK_SIZE: usize = 1 << 24;
+
+    let bytes = transmute_slice::<T, u8>(data);
+    let chunk_hashes: Vec<u64> = bytes.par_chunks(CHUNK_SIZE).map(fnv1a).collect();
+    fnv1a(transmute_slice::<u64, u8>(&chunk_hashes))
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
 /// Return a zero-copy view of the given slice with the given type.
 /// The resulting view has the same lifetime as the provided slice.
 #[inline]
-pub fn transmute_slice<'a, T, U>(slice: &'a [T]) -> &'a [U] {
+pub fn transmute_slice<T, U>(slice: &[T]) -> &[U] {
     // SAFETY: We use floor division to ensure that we can't read past the end of the slice.
-    let new_len = (slice.len() * std::mem::size_of::<T>()) / std::mem::size_of::<U>();
+    let new_len = std::mem::size_of_val(slice) / std::mem::size_of::<U>();
     unsafe { std::slice::from_raw_parts(slice.as_ptr() as *const U, new_len) }
 }
 
@@ -36,4 +119,21 @@ mod tests {
         test_transmute!(bytes, u64);
         test_transmute!(bytes, u128);
     }
+
+    #[test]
+    fn test_page_positions() {
+        let positions = vec![9, 2, 7, 4, 0, 5];
+
+        assert_eq!(
+            page_positions(positions.clone(), 1, Some(3), false),
+            [2, 7, 4]
+        );
+        assert_eq!(
+            page_positions(positions.clone(), 1, Some(3), true),
+            [2, 4, 5]
+        );
+        assert_eq!(page_positions(positions.clone(), 4, None, true), [7, 9]);
+        assert_eq!(page_positions(positions.clone(), 0, Some(0), true), []);
+        assert!(page_positions(positions, 10, Some(2), false).is_empty());
+    }
 }
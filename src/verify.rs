@@ -0,0 +1,278 @@
+use funty::Unsigned;
+use pyo3::prelude::*;
+use rayon::prelude::*;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::metadata::IndexMetadata;
+use crate::table_index::TableIndex;
+use crate::util::checksum;
+
+/// The kind of problem found by an integrity check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IssueKind {
+    /// The text and table, or the text and its metadata, have different lengths.
+    LengthMismatch,
+    /// A table entry points past the end of the text.
+    OutOfRange,
+    /// A table entry repeats an offset stored at a lower rank, so the table is not a
+    /// permutation of the text's offsets.
+    Duplicate,
+    /// The suffix at a rank is greater than the suffix at the next rank.
+    Unsorted,
+    /// The text does not match the checksum stored in its metadata.
+    TextChecksumMismatch,
+    /// The table does not match the checksum stored in its metadata.
+    TableChecksumMismatch,
+}
+
+impl IssueKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IssueKind::LengthMismatch => "length_mismatch",
+            IssueKind::OutOfRange => "out_of_range",
+            IssueKind::Duplicate => "duplicate",
+            IssueKind::Unsorted => "unsorted",
+            IssueKind::TextChecksumMismatch => "text_checksum_mismatch",
+            IssueKind::TableChecksumMismatch => "table_checksum_mismatch",
+        }
+    }
+}
+
+/// The first problem found in a text and suffix table pair.
+#[pyclass(frozen)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IntegrityIssue {
+    pub kind: IssueKind,
+    /// Rank of the offending table entry.
+    pub rank: Option<usize>,
+    /// Text offset stored at `rank`.
+    pub offset: Option<u64>,
+    /// Index of the shard containing the issue, for sharded indices.
+    pub shard: Option<usize>,
+}
+
+impl IntegrityIssue {
+    fn new(kind: IssueKind, rank: Option<usize>, offset: Option<u64>) -> Self {
+        IntegrityIssue {
+            kind,
+            rank,
+            offset,
+            shard: None,
+        }
+    }
+
+    pub fn with_shard(self, shard: usize) -> Self {
+        IntegrityIssue {
+            shard: Some(shard),
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for IntegrityIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind.as_str())?;
+        if let Some(shard) = self.shard {
+            write!(f, " in shard {}", shard)?;
+        }
+        if let Some(rank) = self.rank {
+            write!(f, " at rank {}", rank)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " (offset {})", offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks a text and suffix table pair, returning the first problem found.
+///
+/// A full check confirms the table is a sorted permutation of `0..text.len()` and, when
+/// metadata is given, that both files match their stored checksums. A sampled check only
+/// inspects `sample` evenly spaced ranks, checking each entry is in range and sorted relative
+/// to the next one, and skips the permutation and checksum checks.
+pub fn verify<T: Unsigned, I: TableIndex>(
+    text: &[T],
+    table: &[I],
+    metadata: Option<&IndexMetadata>,
+    sample: Option<usize>,
+) -> Option<IntegrityIssue> {
+    let expected_len = metadata.map_or(text.len(), |metadata| metadata.len);
+    if table.len() != text.len() || text.len() != expected_len {
+        return Some(IntegrityIssue::new(IssueKind::LengthMismatch, None, None));
+    }
+
+    match sample {
+        Some(sample) => verify_sampled(text, table, sample),
+        None => verify_full(text, table, metadata),
+    }
+}
+
+fn verify_sampled<T: Unsigned, I: TableIndex>(
+    text: &[T],
+    table: &[I],
+    sample: usize,
+) -> Option<IntegrityIssue> {
+    let n = table.len();
+    let sample = sample.min(n);
+
+    (0..sample)
+        .map(|i| (i as u128 * n as u128 / sample as u128) as usize)
+        .find_map(|rank| {
+            check_range(text, table, rank).or_else(|| {
+                (rank + 1 < n)
+                    .then(|| {
+                        check_range(text, table, rank + 1)
+                            .or_else(|| check_order(text, table, rank))
+                    })
+                    .flatten()
+            })
+        })
+}
+
+fn verify_full<T: Unsigned, I: TableIndex>(
+    text: &[T],
+    table: &[I],
+    metadata: Option<&IndexMetadata>,
+) -> Option<IntegrityIssue> {
+    let n = table.len();
+
+    if let Some(rank) = table.par_iter().position_first(|i| i.to_u64() >= n as u64) {
+        return Some(IntegrityIssue::new(
+            IssueKind::OutOfRange,
+            Some(rank),
+            Some(table[rank].to_u64()),
+        ));
+    }
+
+    // All entries are in range, so the table is a permutation iff it has no duplicates. Detect
+    // duplicates in parallel and only fall back to a sequential pass to locate the first one.
+    let seen: Vec<AtomicU64> = (0..n.div_ceil(64)).map(|_| AtomicU64::new(0)).collect();
+    let has_duplicate = table.par_iter().any(|i| {
+        let i = i.to_u64();
+        let bit = 1 << (i % 64);
+        seen[i as usize / 64].fetch_or(bit, Ordering::Relaxed) & bit != 0
+    });
+    if has_duplicate {
+        let mut seen = vec![0u64; n.div_ceil(64)];
+        let rank = table.iter().position(|i| {
+            let i = i.to_u64();
+            let bit = 1 << (i % 64);
+            let duplicate = seen[i as usize / 64] & bit != 0;
+            seen[i as usize / 64] |= bit;
+            duplicate
+        })?;
+        return Some(IntegrityIssue::new(
+            IssueKind::Duplicate,
+            Some(rank),
+            Some(table[rank].to_u64()),
+        ));
+    }
+
+    if let Some(rank) = table
+        .par_windows(2)
+        .position_first(|pair| text[pair[0].as_usize()..] > text[pair[1].as_usize()..])
+    {
+        return Some(IntegrityIssue::new(
+            IssueKind::Unsorted,
+            Some(rank),
+            Some(table[rank].to_u64()),
+        ));
+    }
+
+    let metadata = metadata?;
+    if checksum(text) != metadata.text_checksum {
+        return Some(IntegrityIssue::new(
+            IssueKind::TextChecksumMismatch,
+            None,
+            None,
+        ));
+    }
+    if checksum(table) != metadata.table_checksum {
+        return Some(IntegrityIssue::new(
+            IssueKind::TableChecksumMismatch,
+            None,
+            None,
+        ));
+    }
+    None
+}
+
+fn check_range<T, I: TableIndex>(text: &[T], table: &[I], rank: usize) -> Option<IntegrityIssue> {
+    let offset = table[rank].to_u64();
+    (offset >= text.len() as u64)
+        .then(|| IntegrityIssue::new(IssueKind::OutOfRange, Some(rank), Some(offset)))
+}
+
+fn check_order<T: Unsigned, I: TableIndex>(
+    text: &[T],
+    table: &[I],
+    rank: usize,
+) -> Option<IntegrityIssue> {
+    let (a, b) = (table[rank].as_usize(), table[rank + 1].as_usize());
+    (text[a..] > text[b..])
+        .then(|| IntegrityIssue::new(IssueKind::Unsorted, Some(rank), Some(table[rank].to_u64())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::SuffixTable;
+
+    fn kind(issue: Option<IntegrityIssue>) -> Option<(IssueKind, Option<usize>)> {
+        issue.map(|issue| (issue.kind, issue.rank))
+    }
+
+    #[test]
+    fn verify_reports_first_issue() {
+        let text: Vec<u16> = vec![3, 1, 2, 1, 2, 0];
+        let table = SuffixTable::<Box<[u16]>, Box<[u64]>>::new(text.clone(), Some(4), false);
+        let table = table.get_table().to_vec();
+        let metadata = IndexMetadata::new(&text, &table, 4);
+
+        assert_eq!(verify(&text, &table, Some(&metadata), None), None);
+        assert_eq!(verify(&text, &table, Some(&metadata), Some(3)), None);
+
+        assert_eq!(
+            kind(verify(&text, &table[1..], None, None)),
+            Some((IssueKind::LengthMismatch, None))
+        );
+
+        let mut bad = table.clone();
+        bad[2] = 6;
+        assert_eq!(
+            kind(verify(&text, &bad, None, None)),
+            Some((IssueKind::OutOfRange, Some(2)))
+        );
+
+        let mut bad = table.clone();
+        bad[4] = bad[1];
+        assert_eq!(
+            kind(verify(&text, &bad, None, None)),
+            Some((IssueKind::Duplicate, Some(4)))
+        );
+
+        let mut bad = table.clone();
+        bad.swap(0, 5);
+        assert_eq!(
+            kind(verify(&text, &bad, None, None)),
+            Some((IssueKind::Unsorted, Some(0)))
+        );
+        assert_eq!(
+            kind(verify(&text, &bad, None, Some(1))),
+            Some((IssueKind::Unsorted, Some(0)))
+        );
+
+        let stale = IndexMetadata {
+            table_checksum: metadata.table_checksum ^ 1,
+            ..metadata.clone()
+        };
+        assert_eq!(
+            kind(verify(&text, &table, Some(&stale), None)),
+            Some((IssueKind::TableChecksumMismatch, None))
+        );
+        // Sampled checks skip the checksums
+        assert_eq!(verify(&text, &table, Some(&stale), Some(6)), None);
+    }
+}
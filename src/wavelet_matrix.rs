@@ -0,0 +1,228 @@
+use std::ops::Deref;
+
+const WORDS_PER_BLOCK: usize = 8;
+
+/// A bitvector supporting constant time rank queries, using one cumulative count per 512 bits.
+/// The words and counts are held in memory or in any other `u64` storage, such as a memory map.
+#[derive(Clone, Debug, Default)]
+pub struct RankBitVec<W = Vec<u64>> {
+    words: W,
+    block_ranks: W,
+}
+
+impl RankBitVec {
+    pub fn from_bits(bits: impl Iterator<Item = bool>) -> Self {
+        let mut words = Vec::new();
+        for (i, bit) in bits.enumerate() {
+            if i.is_multiple_of(64) {
+                words.push(0);
+            }
+            words[i / 64] |= (bit as u64) << (i % 64);
+        }
+
+        let mut block_ranks = Vec::with_capacity(words.len().div_ceil(WORDS_PER_BLOCK) + 1);
+        let mut rank = 0;
+        for block in words.chunks(WORDS_PER_BLOCK) {
+            block_ranks.push(rank);
+            rank += block.iter().map(|w| w.count_ones() as u64).sum::<u64>();
+        }
+        block_ranks.push(rank);
+
+        RankBitVec { words, block_ranks }
+    }
+}
+
+impl<W: Deref<Target = [u64]>> RankBitVec<W> {
+    /// Creates a bitvector from the words and block counts of another bitvector, see `parts`.
+    pub fn from_parts(words: W, block_ranks: W) -> Self {
+        RankBitVec { words, block_ranks }
+    }
+
+    /// Returns the words and block counts backing the bitvector.
+    pub fn parts(&self) -> [&[u64]; 2] {
+        [&self.words, &self.block_ranks]
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        self.words[i / 64] >> (i % 64) & 1 == 1
+    }
+
+    /// Returns the number of set bits in `[0, i)`.
+    pub fn rank1(&self, i: usize) -> usize {
+        let word = i / 64;
+        let block = word / WORDS_PER_BLOCK;
+        let mut rank = self.block_ranks[block] as usize;
+        for w in &self.words[block * WORDS_PER_BLOCK..word] {
+            rank += w.count_ones() as usize;
+        }
+        if !i.is_multiple_of(64) {
+            rank += (self.words[word] & ((1 << (i % 64)) - 1)).count_ones() as usize;
+        }
+        rank
+    }
+
+    /// Returns the number of unset bits in `[0, i)`.
+    pub fn rank0(&self, i: usize) -> usize {
+        i - self.rank1(i)
+    }
+}
+
+/// A wavelet matrix over a sequence of integer symbols, supporting access, rank and the
+/// enumeration of the distinct symbols in a range in time logarithmic in the alphabet size.
+///
+/// See Claude, Navarro and Ordóñez, "The wavelet matrix", Information Systems 47 (2015).
+#[derive(Clone, Debug, Default)]
+pub struct WaveletMatrix<W = Vec<u64>> {
+    levels: Vec<RankBitVec<W>>,
+    zeros: Vec<usize>,
+    len: usize,
+}
+
+impl WaveletMatrix {
+    /// Builds a wavelet matrix over symbols less than `2^bits`. Besides the finished matrix
+    /// this holds two copies of the symbols in memory at a time.
+    pub fn new(mut symbols: Vec<u32>, bits: usize) -> Self {
+        let len = symbols.len();
+        let mut levels = Vec::with_capacity(bits);
+        let mut zeros = Vec::with_capacity(bits);
+
+        for level in 0..bits {
+            let shift = bits - 1 - level;
+            let bitvec = RankBitVec::from_bits(symbols.iter().map(|&s| s >> shift & 1 == 1));
+            zeros.push(bitvec.rank0(len));
+            levels.push(bitvec);
+
+            // Stably partition the symbols by the current bit for the next level
+            let mut next = Vec::with_capacity(len);
+            next.extend(symbols.iter().filter(|&&s| s >> shift & 1 == 0));
+            next.extend(symbols.iter().filter(|&&s| s >> shift & 1 == 1));
+            symbols = next;
+        }
+
+        WaveletMatrix { levels, zeros, len }
+    }
+}
+
+impl<W: Deref<Target = [u64]>> WaveletMatrix<W> {
+    /// Creates a wavelet matrix from the levels and zero counts of another matrix, see
+    /// `levels` and `zeros`.
+    pub fn from_parts(levels: Vec<RankBitVec<W>>, zeros: Vec<usize>, len: usize) -> Self {
+        WaveletMatrix { levels, zeros, len }
+    }
+
+    pub fn levels(&self) -> &[RankBitVec<W>] {
+        &self.levels
+    }
+
+    /// Returns the number of unset bits in each level.
+    pub fn zeros(&self) -> &[usize] {
+        &self.zeros
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the symbol at position `i`.
+    pub fn access(&self, mut i: usize) -> u32 {
+        let mut symbol = 0;
+        for (level, bitvec) in self.levels.iter().enumerate() {
+            let bit = bitvec.get(i);
+            symbol = symbol << 1 | bit as u32;
+            i = if bit {
+                self.zeros[level] + bitvec.rank1(i)
+            } else {
+                bitvec.rank0(i)
+            };
+        }
+        symbol
+    }
+
+    /// Returns the number of occurrences of `symbol` in `[0, i)`.
+    pub fn rank(&self, symbol: u32, mut i: usize) -> usize {
+        let bits = self.levels.len();
+        let mut start = 0;
+        for (level, bitvec) in self.levels.iter().enumerate() {
+            if symbol >> (bits - 1 - level) & 1 == 1 {
+                start = self.zeros[level] + bitvec.rank1(start);
+                i = self.zeros[level] + bitvec.rank1(i);
+            } else {
+                start = bitvec.rank0(start);
+                i = bitvec.rank0(i);
+            }
+        }
+        i - start
+    }
+
+    /// Calls `f(symbol, count)` for each distinct symbol in `[start, end)`.
+    pub fn for_each_symbol(&self, start: usize, end: usize, f: &mut impl FnMut(u32, usize)) {
+        self.recurse_symbols(0, 0, start, end, f);
+    }
+
+    fn recurse_symbols(
+        &self,
+        level: usize,
+        prefix: u32,
+        start: usize,
+        end: usize,
+        f: &mut impl FnMut(u32, usize),
+    ) {
+        if start >= end {
+            return;
+        }
+        if level == self.levels.len() {
+            f(prefix, end - start);
+            return;
+        }
+
+        let bitvec = &self.levels[level];
+        let (start0, end0) = (bitvec.rank0(start), bitvec.rank0(end));
+        self.recurse_symbols(level + 1, prefix << 1, start0, end0, f);
+
+        let zeros = self.zeros[level];
+        let (start1, end1) = (zeros + bitvec.rank1(start), zeros + bitvec.rank1(end));
+        self.recurse_symbols(level + 1, prefix << 1 | 1, start1, end1, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rank_bitvec() {
+        let bits: Vec<bool> = (0..1_000).map(|i| i % 3 == 0 || i % 7 == 0).collect();
+        let bitvec = RankBitVec::from_bits(bits.iter().copied());
+
+        let mut rank = 0;
+        for (i, &bit) in bits.iter().enumerate() {
+            assert_eq!(bitvec.rank1(i), rank);
+            assert_eq!(bitvec.get(i), bit);
+            rank += bit as usize;
+        }
+        assert_eq!(bitvec.rank1(bits.len()), rank);
+    }
+
+    #[test]
+    fn wavelet_matrix_queries() {
+        let symbols: Vec<u32> = (0..500u32).map(|i| (i * 31 + i / 7) % 13).collect();
+        let matrix = WaveletMatrix::new(symbols.clone(), 4);
+
+        for (i, &s) in symbols.iter().enumerate() {
+            assert_eq!(matrix.access(i), s);
+        }
+        for s in 0..16 {
+            for i in [0, 1, 77, 250, 500] {
+                let expected = symbols[..i].iter().filter(|&&x| x == s).count();
+                assert_eq!(matrix.rank(s, i), expected);
+            }
+        }
+
+        let mut counts = [0; 16];
+        matrix.for_each_symbol(100, 200, &mut |s, count| counts[s as usize] += count);
+        for (s, &count) in counts.iter().enumerate() {
+            let expected = symbols[100..200].iter().filter(|&&x| x == s as u32).count();
+            assert_eq!(count, expected);
+        }
+    }
+}
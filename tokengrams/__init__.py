@@ -1,7 +1,22 @@
 from .tokengrams import (
+    FmIndex,
     InMemoryIndex,
     MemmapIndex,
     ShardedMemmapIndex,
+    SparseMemmapIndex,
+    IntegrityIssue,
+    CacheStats,
+    PositionPages,
+    SamplingConfig,
+    SampleResult,
+    TokenAutomaton,
+    TokengramsError,
+    MisalignedFileError,
+    LengthMismatchError,
+    UnsortedTableError,
+    TokenOutOfVocabError,
+    InvalidMetadataError,
+    EmptyDistributionError,
 )
 
 from .utils.tokenize_hf_dataset import tokenize_hf_dataset
\ No newline at end of file
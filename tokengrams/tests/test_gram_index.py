@@ -1,7 +1,16 @@
+from concurrent.futures import ThreadPoolExecutor
 from itertools import pairwise
 from tempfile import NamedTemporaryFile
 
-from tokengrams import InMemoryIndex, MemmapIndex
+from tokengrams import (
+    FmIndex,
+    InMemoryIndex,
+    InvalidMetadataError,
+    MemmapIndex,
+    SamplingConfig,
+    TokenAutomaton,
+)
+import pytest
 from hypothesis import given, strategies as st
 
 import numpy as np
@@ -21,6 +30,61 @@ def check_gram_index(index: InMemoryIndex | MemmapIndex, tokens: list[int]):
         assert index.contains(list(b)) == (b in bigrams)
         assert index.count(list(b)) == bigrams.count(b)
 
+    # Batch lookups agree with single lookups
+    queries = [[t] for t in tokens] + [list(b) for b in bigrams] + [[], tokens]
+    assert index.batch_contains(queries) == [index.contains(q) for q in queries]
+    assert index.batch_count(queries) == [index.count(q) for q in queries]
+    for q, positions in zip(queries, index.batch_positions(queries)):
+        assert sorted(positions) == sorted(index.positions(q))
+
+    # Positions come back in pages and samples of the full set
+    query = tokens[:1]
+    positions = index.positions(query, sorted=True)
+    assert list(positions) == [i for i, t in enumerate(tokens) if t == tokens[0]]
+    assert list(index.positions(query, offset=1, limit=2, sorted=True)) == list(positions[1:3])
+    pages = list(index.iter_positions(query, page_size=2))
+    assert sorted(np.concatenate(pages)) == list(positions)
+    pages = list(index.iter_positions(query, page_size=2, sorted=True))
+    assert list(np.concatenate(pages)) == list(positions)
+    sample = index.sample_positions(query, k=2, seed=0)
+    assert len(sample) == min(2, len(positions)) and set(sample) <= set(positions)
+
+    # A sample covering every occurrence gives exact counts
+    estimates, errors = index.approx_count_next(query, sample_size=len(tokens), seed=0)
+    assert estimates == [float(c) for c in index.count_next(query)]
+    assert not any(errors)
+
+    # Seeded samples are reproducible
+    for sample in (index.sample_unsmoothed, index.sample_smoothed):
+        samples = sample([], 2, 5, 4, seed=0)
+        assert samples == sample([], 2, 5, 4, seed=0)
+        assert all(len(s) == 5 for s in samples)
+
+    # Unsmoothed sampling backs off from the context ending the corpus
+    samples, orders = index.sample_unsmoothed(tokens[-2:], 3, 4, 2, seed=0, return_orders=True)
+    assert all(len(s) == len(tokens[-2:]) + 4 for s in samples)
+    assert all(len(o) == 4 and all(1 <= order <= 3 for order in o) for o in orders)
+
+    # Detailed samples match plain samples and carry one diagnostic per sampled token
+    for sample in (index.sample_unsmoothed, index.sample_smoothed):
+        results = sample(tokens[:1], 2, 3, 2, seed=0, detailed=True)
+        assert [r.tokens for r in results] == sample(tokens[:1], 2, 3, 2, seed=0)
+        for r in results:
+            steps = len(r.tokens) - 1
+            assert len(r.log_probs) == len(r.context_lens) == len(r.context_counts) == len(r.entropies) == steps
+            assert all(lp <= 0.0 for lp in r.log_probs) and all(e >= 0.0 for e in r.entropies)
+
+    # Beam search returns the best continuations first, the best of which greedy decoding finds
+    beams = index.beam_search(tokens[:1], 2, 3, beam_width=3)
+    assert 1 <= len(beams) <= 3
+    assert [score for _, score in beams] == sorted((score for _, score in beams), reverse=True)
+    assert index.decode_greedy(tokens[:1], 2, 3) == tuple(index.beam_search(tokens[:1], 2, 3, beam_width=1)[0])
+
+    # Greedy decoding ends at the first stop token
+    config = SamplingConfig(temperature=0.0, stop_tokens=tokens)
+    for sample in (index.sample_unsmoothed, index.sample_smoothed):
+        assert [len(s) for s in sample([], 2, 5, 2, config=config)] == [1, 1]
+
 @given(
     st.lists(
         st.integers(0, 2 ** 16 - 1), min_size=1,
@@ -49,3 +113,161 @@ def test_gram_index(tokens: list[int]):
         for limit in range(1, len(tokens) + 1):
             index = InMemoryIndex.from_token_file(f.name, limit)
             check_gram_index(index, tokens[:limit])
+
+
+@given(st.binary(min_size=1))
+def test_byte_index(data: bytes):
+    tokens = list(data)
+    check_gram_index(InMemoryIndex.from_bytes(data), tokens)
+
+    with NamedTemporaryFile() as f, NamedTemporaryFile() as idx:
+        f.write(data)
+        f.flush()
+
+        index = MemmapIndex.build(f.name, idx.name, vocab=256, token_width=1)
+        check_gram_index(index, tokens)
+
+        # The token width is read back from the index metadata
+        index = MemmapIndex(f.name, idx.name)
+        check_gram_index(index, tokens)
+
+
+@given(
+    st.lists(
+        st.integers(0, 2 ** 16 - 1), min_size=1,
+    )
+)
+def test_fm_index(tokens: list[int]):
+    with NamedTemporaryFile() as f, NamedTemporaryFile() as fm:
+        InMemoryIndex(tokens).save_tokens(f.name)
+        in_memory_index = InMemoryIndex.from_token_file(f.name)
+
+        for index in [FmIndex.build(f.name, fm.name, sample_rate=3), FmIndex(fm.name)]:
+            for t in tokens:
+                assert index.count([t]) == tokens.count(t)
+
+            bigrams = list(pairwise(tokens))
+            for b in bigrams:
+                assert index.contains(list(b))
+                assert index.count(list(b)) == bigrams.count(b)
+                assert sorted(index.positions(list(b))) == sorted(in_memory_index.positions(list(b)))
+                assert index.count_next(list(b)) == in_memory_index.count_next(list(b))
+
+
+def test_concurrent_queries():
+    tokens = [(i * i + i // 3) % 7 for i in range(10_000)]
+    index = InMemoryIndex(tokens, vocab=7)
+    queries = [tokens[i:i + 3] for i in range(0, 1_000, 7)]
+    expected = index.batch_count_next(queries)
+
+    # Queries release the GIL, so threads run them side by side on one index
+    with ThreadPoolExecutor(max_workers=4) as pool:
+        results = list(pool.map(index.batch_count_next, [queries] * 8))
+    assert all(result == expected for result in results)
+
+    # Smoothed queries share the lazily filled smoothing cache
+    expected = InMemoryIndex(tokens, vocab=7).batch_get_smoothed_probs(queries)
+    with ThreadPoolExecutor(max_workers=4) as pool:
+        results = list(pool.map(index.batch_get_smoothed_probs, [queries] * 8))
+    assert all(result == expected for result in results)
+
+
+def test_save_and_load_cache():
+    tokens = [(i * i + i // 3) % 7 for i in range(1_000)]
+    index = InMemoryIndex(tokens, vocab=7)
+    index.estimate_deltas(3)
+    probs = index.get_smoothed_probs(tokens[:2])
+
+    with NamedTemporaryFile() as f:
+        index.save_cache(f.name)
+
+        loaded = InMemoryIndex(tokens, vocab=7)
+        loaded.load_cache(f.name)
+        assert loaded.get_smoothed_probs(tokens[:2]) == probs
+
+        with pytest.raises(InvalidMetadataError):
+            InMemoryIndex(tokens[1:], vocab=7).load_cache(f.name)
+
+
+def test_sampling_config():
+    config = SamplingConfig(top_k=5, stop_tokens=[0])
+    assert (config.top_k, config.stop_tokens, config.temperature) == (5, [0], 1.0)
+
+    with pytest.raises(ValueError):
+        SamplingConfig(top_p=1.5)
+    with pytest.raises(ValueError):
+        SamplingConfig(temperature=-1.0)
+
+    # The minimum count applies to the context smoothing backs off to when "e" is unseen
+    index = InMemoryIndex.from_bytes(b"abcabdabcabe")
+    config = SamplingConfig(min_count=1)
+    assert all(len(s) == 4 for s in index.sample_smoothed([ord("e")], 2, 3, 2, seed=0, config=config))
+
+
+def test_token_automaton():
+    automaton = TokenAutomaton.from_sequences([[1, 2], [1, 3, 4], [5]])
+    assert automaton.num_states == 6
+    assert automaton.allowed_tokens(0) == [1, 5]
+    state = automaton.next_state(0, 1)
+    assert automaton.allowed_tokens(state) == [2, 3]
+    assert automaton.is_accepting(automaton.next_state(state, 2))
+    assert automaton.next_state(0, 2) is None
+
+    with pytest.raises(ValueError):
+        TokenAutomaton([(0, 7, 1), (0, 7, 2)], accepting=[])
+    with pytest.raises(ValueError):
+        automaton.allowed_tokens(6)
+
+    # Generation only follows the allowed sequences and ends once one is complete
+    tokens = [1, 2, 1, 3, 4, 5, 1, 2, 6, 5]
+    index = InMemoryIndex(tokens, vocab=7)
+    config = SamplingConfig(automaton=automaton)
+    allowed = [[1, 2], [1, 3, 4], [5]]
+    for sample in index.sample_unsmoothed([5], 2, 10, 20, seed=0, config=config):
+        assert sample[1:] in allowed
+    for tokens, _ in index.beam_search([5], 2, 10, beam_width=3, config=config):
+        assert tokens[1:] in allowed
+
+    # A sequence that extends another allowed sequence can still be generated
+    config = SamplingConfig(automaton=TokenAutomaton.from_sequences([[1, 2], [1, 2, 6]]))
+    samples = index.sample_unsmoothed([5], 2, 10, 32, seed=0, config=config)
+    assert sorted({tuple(s[1:]) for s in samples}) == [(1, 2), (1, 2, 6)]
+    beams = index.beam_search([5], 2, 10, beam_width=2, config=config)
+    assert sorted(tuple(tokens[1:]) for tokens, _ in beams) == [(1, 2), (1, 2, 6)]
+
+
+def test_batch_sample():
+    tokens = [1, 2, 1, 3, 4, 5, 1, 2, 6, 5]
+    index = InMemoryIndex(tokens, vocab=7)
+    requests = [([1], 2, 5, 3), ([], 3, 4, 0), ([2, 1], 3, 6, 2)]
+
+    for smoothed, single in [(False, index.sample_unsmoothed), (True, index.sample_smoothed)]:
+        batch = index.batch_sample(requests, smoothed=smoothed, seed=5)
+        # Samples are numbered across requests, so the third request starts at seed 5 + 3
+        assert batch[0] == single([1], 2, 5, 3, seed=5)
+        assert batch[1] == []
+        assert batch[2] == single([2, 1], 3, 6, 2, seed=8)
+
+    detailed = index.batch_sample(requests, detailed=True)
+    assert [len(results) for results in detailed] == [3, 0, 2]
+    assert all(result.tokens[:2] == [2, 1] for result in detailed[2])
+
+    # Detailed results carry the orders themselves, so both flags together are rejected
+    with pytest.raises(ValueError):
+        index.batch_sample(requests, return_orders=True, detailed=True)
+    with pytest.raises(ValueError):
+        index.sample_unsmoothed([1], 2, 5, 3, return_orders=True, detailed=True)
+
+
+@given(st.lists(st.integers(0, 199), min_size=1))
+def test_small_vocab_u16(tokens: list[int]):
+    with NamedTemporaryFile() as f, NamedTemporaryFile() as idx:
+        f.write(b"".join(t.to_bytes(2, "little") for t in tokens))
+        f.flush()
+
+        # Small vocabularies are still read as u16 unless a token width is given
+        index = MemmapIndex.build(f.name, idx.name, vocab=200)
+        check_gram_index(index, tokens)
+
+        index = InMemoryIndex.from_token_file(f.name, vocab=200)
+        check_gram_index(index, tokens)
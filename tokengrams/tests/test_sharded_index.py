@@ -19,6 +19,13 @@ def check_sharded_index(index: ShardedMemmapIndex, tokens: list[int], eos_token:
             assert index.contains(list(b)) == (b in bigrams)
             assert index.count(list(b)) == bigrams.count(b)
 
+    # Batch lookups agree with single lookups, with positions in the concatenated shards
+    queries = [[t] for t in tokens[:100]] + [list(b) for b in bigrams[:100]]
+    assert index.batch_contains(queries) == [index.contains(q) for q in queries]
+    assert index.batch_count(queries) == [index.count(q) for q in queries]
+    for q, positions in zip(queries, index.batch_positions(queries)):
+        assert all(tokens[p:p + len(q)] == q for p in positions)
+
     # Check bigram samples
     for i in range(len(tokens[:20])):
         query = tokens[:i]
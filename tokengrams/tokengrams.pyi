@@ -1,15 +1,164 @@
+from typing import Iterator
+
+import numpy as np
+from numpy.typing import NDArray
+
+class TokengramsError(Exception):
+    """Base class for tokengrams errors. I/O failures are raised as `OSError` subclasses."""
+
+class MisalignedFileError(TokengramsError):
+    """A token or table file size is not a multiple of its element size."""
+
+class LengthMismatchError(TokengramsError):
+    """A suffix table does not have one entry per token in its text."""
+
+class UnsortedTableError(TokengramsError):
+    """A suffix table is not lexicographically sorted."""
+
+class TokenOutOfVocabError(TokengramsError):
+    """A token is greater than or equal to the vocabulary size."""
+
+class InvalidMetadataError(TokengramsError):
+    """Index metadata is corrupt or does not match the files it describes."""
+
+class EmptyDistributionError(TokengramsError):
+    """A next token distribution has no nonzero weights to sample from."""
+
+class IntegrityIssue:
+    """The first problem found by an index integrity check."""
+
+    kind: str
+    """One of "length_mismatch", "out_of_range", "duplicate", "unsorted",
+    "text_checksum_mismatch" or "table_checksum_mismatch"."""
+
+    rank: int | None
+    """Rank of the offending suffix table entry."""
+
+    offset: int | None
+    """Text offset stored at `rank`."""
+
+    shard: int | None
+    """Index of the shard containing the issue, for sharded indices."""
+
+class CacheStats:
+    """Hit and miss statistics of an index's `count_next` cache."""
+
+    hits: int
+    misses: int
+    size: int
+    """The number of contexts in the cache."""
+    capacity: int
+
+class SamplingConfig:
+    """Decoding options applied to each next-token distribution when sampling.
+
+    Banned tokens and tokens seen fewer than `min_count` times after the context are removed
+    first. The remaining weights are then sharpened or flattened by `temperature`, truncated to
+    the `top_k` most likely tokens, and truncated again to the smallest set of most likely tokens
+    holding at least `top_p` of the remaining mass. A sequence ends early once it emits one of
+    the `stop_tokens`, such as the end-of-document token, which is kept at its end.
+
+    With an `automaton`, each step may only emit tokens allowed by the automaton's current
+    state. A sequence the automaton accepts ends there if no transition leaves its state, and
+    otherwise may end in place of its next token, weighted by the tokens the automaton
+    disallows."""
+
+    temperature: float
+    """Zero always picks the most likely token."""
+    top_k: int | None
+    top_p: float | None
+    min_count: int
+    banned_tokens: list[int]
+    stop_tokens: list[int]
+    automaton: TokenAutomaton | None
+
+    def __init__(
+        self,
+        temperature: float = 1.0,
+        top_k: int | None = None,
+        top_p: float | None = None,
+        min_count: int = 0,
+        banned_tokens: list[int] = [],
+        stop_tokens: list[int] = [],
+        automaton: TokenAutomaton | None = None,
+    ): ...
+
+class TokenAutomaton:
+    """A deterministic finite automaton over tokens, restricting generation to the token
+    sequences it allows. Generation starts in state zero and may only emit tokens with a
+    transition out of the current state, ending once it reaches an accepting state. In an
+    accepting state that longer sequences continue from, generation may stop or continue."""
+
+    num_states: int
+
+    def __init__(
+        self,
+        transitions: list[tuple[int, int, int]],
+        accepting: list[int],
+        num_states: int | None = None,
+    ):
+        """Build an automaton from `(state, token, next_state)` transitions. The number of
+        states defaults to one more than the largest state mentioned."""
+
+    @staticmethod
+    def from_sequences(sequences: list[list[int]]) -> "TokenAutomaton":
+        """Build a trie accepting exactly the given token sequences."""
+
+    def allowed_tokens(self, state: int) -> list[int]:
+        """Return the tokens with a transition out of `state`, in increasing order."""
+
+    def next_state(self, state: int, token: int) -> int | None:
+        """Return the state reached by emitting `token` in `state`, if it is allowed."""
+
+    def is_accepting(self, state: int) -> bool: ...
+
+class SampleResult:
+    """A sampled sequence with diagnostics for each sampled token."""
+
+    tokens: list[int]
+    """The prompt followed by the sampled tokens."""
+    log_probs: list[float]
+    """Natural log-probability of each sampled token under the model, before decoding options."""
+    context_lens: list[int]
+    """Number of context tokens the distribution of each sampled token was conditioned on, the
+    longest suffix of the context followed by any token in the corpus."""
+    context_counts: list[int]
+    """Number of times each context was followed by any token in the corpus."""
+    entropies: list[float]
+    """Entropy in nats of the model distribution each token was drawn from, before decoding options."""
+
+class PositionPages:
+    """An iterator over the positions of a query in pages, each fetched from the index with
+    `positions` when it is reached."""
+
+    def __iter__(self) -> Iterator[NDArray[np.uint64]]: ...
+    def __next__(self) -> NDArray[np.uint64]: ...
+
 class InMemoryIndex:
     """An n-gram index."""
 
-    def __init__(self, tokens: list[int], vocab: int = 2**16, verbose: bool = False) -> None:
+    out_of_vocab: str
+    """How query tokens outside the vocabulary are handled. "error" (the default) raises 
+    `TokenOutOfVocabError`, while "miss" treats them as tokens that never occur in the corpus."""
+
+    def __init__(self, tokens: list[int], vocab: int = 2**16, verbose: bool = False, token_width: int | None = None) -> None:
         ...
     
     @staticmethod
-    def from_token_file(path: str, token_limit: int | None = None, vocab: int = 2**16, verbose: bool = False) -> "InMemoryIndex":
-        """Construct a `InMemoryIndex` from a file containing raw little-endian tokens."""
+    def from_bytes(data: bytes, verbose: bool = False) -> "InMemoryIndex":
+        """Construct a byte-level `InMemoryIndex` over raw bytes, such as UTF-8 text, with a
+        vocabulary of 256."""
 
-    def from_disk(self, token_path: str, index_path: str, vocab: int = 2**16) -> "InMemoryIndex":
-        """Load a pretrained index from disk."""
+    @staticmethod
+    def from_token_file(path: str, token_limit: int | None = None, vocab: int = 2**16, verbose: bool = False, token_width: int | None = None) -> "InMemoryIndex":
+        """Construct a `InMemoryIndex` from a file containing raw little-endian tokens. Tokens are
+        read as u16 unless `vocab` exceeds 2**16 or `token_width` gives their size in bytes. Raises
+        `MisalignedFileError` if the file size is not a multiple of the token width."""
+
+    @staticmethod
+    def from_disk(token_path: str, index_path: str, vocab: int | None = None) -> "InMemoryIndex":
+        """Load a pretrained index from disk. The token width and vocabulary size are read from
+        the index's `.meta` sidecar when present, otherwise `vocab` defaults to 2**16."""
 
     def save_tokens(self, path: str):
         """Save the tokens to a file."""
@@ -21,18 +170,70 @@ class InMemoryIndex:
         """Check if the index's suffix table is sorted lexicographically. 
         This is always true for valid indices."""
 
+    count_cache_capacity: int
+    """The maximum number of contexts whose `count_next` results are cached. Zero, the default,
+    disables the cache. Setting the capacity clears the cache and its statistics."""
+
+    def count_cache_stats(self) -> CacheStats:
+        """Hit and miss statistics of the `count_next` cache, which serves `count_next`, the
+        smoothed probability methods and sampling."""
+
+    def warm_count_cache(self, top_m: int, max_len: int = 2) -> None:
+        """Fill the `count_next` cache with the `top_m` most frequent contexts of at most `max_len`
+        tokens, including the empty context."""
+
     def contains(self, query: list[int]) -> bool:
         """Check if `query` has nonzero count. Faster than `count(query) > 0`."""
     
     def count(self, query: list[int]) -> int:
         """Count the number of occurrences of `query` in the index."""
 
-    def positions(self, query: list[int]) -> list[int]:
-        """Returns an unordered list of positions where `query` starts in `tokens`."""
+    def positions(
+        self,
+        query: list[int],
+        offset: int = 0,
+        limit: int | None = None,
+        sorted: bool = False,
+    ) -> NDArray[np.uint64]:
+        """Returns the positions where `query` starts in `tokens`, skipping the first `offset`
+        and keeping at most `limit`. Positions are in index order unless `sorted`, in which
+        case they are in corpus order and every page costs a pass over all matches."""
+
+    def iter_positions(
+        self, query: list[int], page_size: int = 2**20, sorted: bool = False
+    ) -> PositionPages:
+        """Iterate over the positions where `query` starts in `tokens` in arrays of at most
+        `page_size` positions, fetching each page only when it is reached. With `sorted`, every
+        position is fetched and sorted once with the first page."""
+
+    def sample_positions(
+        self, query: list[int], k: int, seed: int | None = None
+    ) -> NDArray[np.uint64]:
+        """Returns `k` positions where `query` starts in `tokens` in corpus order, drawn
+        uniformly at random without replacement, or every position if there are at most `k`."""
 
     def count_next(self, query: list[int]) -> list[int]:
         """Count the occurrences of each token directly following `query`."""
 
+    def approx_count_next(
+        self, query: list[int], sample_size: int = 1000, seed: int | None = None
+    ) -> tuple[list[float], list[float]]:
+        """Estimate the occurrences of each token directly following `query` from
+        `sample_size` of its occurrences drawn uniformly at random, returning the
+        estimates and their standard errors. Both are exact when `sample_size` covers
+        every occurrence."""
+
+    def batch_contains(self, queries: list[list[int]]) -> list[bool]:
+        """Check whether each sequence in `queries` occurs in `tokens`. Queries are looked up in
+        parallel in sorted order, so that queries sharing a prefix share its search."""
+
+    def batch_count(self, queries: list[list[int]]) -> list[int]:
+        """Count the occurrences of each sequence in `queries` like `batch_contains`."""
+
+    def batch_positions(self, queries: list[list[int]]) -> list[NDArray[np.uint64]]:
+        """Returns the unordered positions of each sequence in `queries`, looked up like
+        `batch_contains`."""
+
     def batch_count_next(self, queries: list[list[int]]) -> list[list[int]]:
         """Count the occurrences of each token that directly follows each sequence in `queries`."""
 
@@ -42,15 +243,90 @@ class InMemoryIndex:
     def batch_get_smoothed_probs(self, queries: list[list[int]]) -> list[list[float]]:
         """Compute interpolated Kneser-Ney smoothed token probability distributions using all previous tokens in each query."""
 
-    def sample_smoothed(self, query: list[int], n: int, k: int, num_samples: int) -> list[list[int]]:
+    def sample_smoothed(
+        self,
+        query: list[int],
+        n: int,
+        k: int,
+        num_samples: int,
+        seed: int | None = None,
+        config: SamplingConfig | None = None,
+        detailed: bool = False,
+    ) -> list[list[int]] | list[SampleResult]:
         """Autoregressively samples num_samples of k characters each from Kneser-Ney smoothed conditional 
         distributions based on the previous (n - 1) characters (n-gram prefix) in the sequence. If there are 
-        fewer than (n - 1) characters all available characters are used."""
+        fewer than (n - 1) characters all available characters are used, and n below 1 raises
+        `ValueError`. Given a `seed`, sample i is drawn
+        from its own random stream seeded with `seed + i`, so results do not depend on the number of threads.
+        A `config` sets decoding options and stop tokens, which can end samples before k characters.
+        Returns a list of token lists by default, and with `detailed` a `SampleResult` with
+        diagnostics for each sample instead."""
+
+    def batch_sample(
+        self,
+        requests: list[tuple[list[int], int, int, int]],
+        smoothed: bool = False,
+        seed: int | None = None,
+        config: SamplingConfig | None = None,
+        return_orders: bool = False,
+        detailed: bool = False,
+    ) -> (
+        list[list[list[int]]]
+        | list[tuple[list[list[int]], list[list[int]]]]
+        | list[list[SampleResult]]
+    ):
+        """Samples from many prompts in one parallel job. Each request is a `(query, n, k, num_samples)`
+        tuple, and a list of samples is returned for each request in order, formatted as by
+        `sample_unsmoothed`. Samples are drawn from a Kneser-Ney smoothed n-gram model if `smoothed`, and
+        otherwise from an unsmoothed one. Given a `seed`, samples are numbered consecutively across
+        requests, so a lone request draws the same samples as the single-prompt methods."""
    
-    def sample_unsmoothed(self, query: list[int], n: int, k: int, num_samples: int) -> list[list[int]]:
+    def decode_greedy(
+        self, query: list[int], n: int, k: int, config: SamplingConfig | None = None
+    ) -> tuple[list[int], float]:
+        """Extend the query by up to k characters, picking the most likely token under a Kneser-Ney
+        smoothed n-gram model at each step. Returns the sequence and the sum of the log-probabilities
+        of its new tokens. The banned tokens, minimum count and stop tokens of `config` apply."""
+
+    def beam_search(
+        self,
+        query: list[int],
+        n: int,
+        k: int,
+        beam_width: int = 4,
+        config: SamplingConfig | None = None,
+    ) -> list[tuple[list[int], float]]:
+        """Beam search for the `beam_width` most likely extensions of the query by up to k characters
+        under a Kneser-Ney smoothed n-gram model. Returns (sequence, score) pairs in decreasing order of
+        score, the sum of the log-probabilities of the new tokens. The banned tokens, minimum count and
+        stop tokens of `config` apply, and a beam ends after a stop token."""
+
+    def sample_unsmoothed(
+        self,
+        query: list[int],
+        n: int,
+        k: int,
+        num_samples: int,
+        seed: int | None = None,
+        config: SamplingConfig | None = None,
+        return_orders: bool = False,
+        detailed: bool = False,
+    ) -> list[list[int]] | tuple[list[list[int]], list[list[int]]] | list[SampleResult]:
         """Autoregressively samples num_samples of k characters each from conditional distributions based 
         on the previous (n - 1) characters (n-gram prefix) in the sequence. If there are fewer than 
-        (n - 1) characters all available characters are used."""
+        (n - 1) characters all available characters are used, and n below 1 raises
+        `ValueError`. Given a `seed`, sample i is drawn from its
+        own random stream seeded with `seed + i`, so results do not depend on the number of threads.
+        A `config` sets decoding options and stop tokens, which can end samples before k characters.
+
+        When a context has no continuations, such as one ending the corpus, sampling backs off to
+        successively shorter contexts down to the unigram distribution.
+
+        Returns a list of token lists by default. With `return_orders`, returns a `(samples, orders)`
+        pair instead, where `orders` holds the order of the n-gram each sampled token completes, from 1
+        for a unigram to n. With `detailed`, returns a `SampleResult` with diagnostics for each sample
+        instead, whose `context_lens` plus one are the same orders. Setting both flags raises
+        `ValueError`."""
 
     def estimate_deltas(self, n: int):
         """Warning: O(k**n) where k is vocabulary size, use with caution.
@@ -58,19 +334,75 @@ class InMemoryIndex:
         for models of order n and below with improved estimates over the entire index.
         https://people.eecs.berkeley.edu/~klein/cs294-5/chen_goodman.pdf, page 16."""
 
+    def save_cache(self, path: str) -> None:
+        """Save the smoothing statistics computed so far, such as those from `estimate_deltas`,
+        together with a fingerprint of the corpus."""
+
+    def load_cache(self, path: str) -> None:
+        """Load smoothing statistics saved by `save_cache`, raising `InvalidMetadataError` if
+        they were computed from a different corpus."""
+
 class MemmapIndex:
     """An n-gram index backed by a memory-mapped file."""
 
-    def __init__(self, token_path: str, index_path: str, vocab: int = 2**16) -> None:
-        """Load a prebuilt memory-mapped index from a pair of files."""
+    out_of_vocab: str
+    """How query tokens outside the vocabulary are handled. "error" (the default) raises 
+    `TokenOutOfVocabError`, while "miss" treats them as tokens that never occur in the corpus."""
+
+    bucket_depth: int | None
+    """The number of leading tokens covered by the index's lookup table, or `None` if it has none."""
+
+    has_counts: bool
+    """Whether the index has precomputed unigram and bigram counts, see `build_counts`."""
+
+    def __init__(self, token_path: str, index_path: str, vocab: int | None = None) -> None:
+        """Load a prebuilt memory-mapped index from a pair of files. The token width and vocabulary
+        size are read from the index's `.meta` sidecar when present, otherwise `vocab` defaults
+        to 2**16."""
 
     @staticmethod
-    def build(token_path: str, index_path: str, vocab: int = 2**16, verbose: bool = False) -> "MemmapIndex":
-        """Build a memory-mapped index from a token file."""
+    def build(token_path: str, index_path: str, vocab: int = 2**16, verbose: bool = False, token_width: int | None = None) -> "MemmapIndex":
+        """Build a memory-mapped index from a token file. Tokens are read as u16 unless `vocab`
+        exceeds 2**16 or `token_width` gives their size in bytes, e.g. 1 for byte-level corpora."""
+
+    def build_buckets(self, depth: int = 1) -> None:
+        """Build a lookup table from the first `depth` tokens of each suffix to its range of the
+        suffix table, and save it to `{index}.lut`. It is loaded whenever the index is opened, after
+        which searches start inside the right range and queries of at most `depth` tokens need no
+        binary search. The table holds one entry per distinct prefix."""
+
+    def build_counts(self) -> None:
+        """Count every unigram and bigram in one pass over the suffix table and save the counts to
+        `{index}.counts`. They are loaded whenever the index is opened, after which `count_next`,
+        the smoothed probability methods and sampling answer contexts of at most one token from
+        the counts. The file holds one entry per distinct bigram."""
 
     def is_sorted(self) -> bool:
         """Check if the index's suffix table is sorted lexicographically. 
         This is always true for valid indices."""
+
+    count_cache_capacity: int
+    """The maximum number of contexts whose `count_next` results are cached. Zero, the default,
+    disables the cache. Setting the capacity clears the cache and its statistics."""
+
+    def count_cache_stats(self) -> CacheStats:
+        """Hit and miss statistics of the `count_next` cache, which serves `count_next`, the
+        smoothed probability methods and sampling."""
+
+    def warm_count_cache(self, top_m: int, max_len: int = 2) -> None:
+        """Fill the `count_next` cache with the `top_m` most frequent contexts of at most `max_len`
+        tokens, including the empty context."""
+
+    def verify(self, sample: int | None = None) -> IntegrityIssue | None:
+        """Check that the suffix table is a sorted permutation of the text's offsets and that
+        both files match the checksums in the index metadata, returning the first problem found.
+        When `sample` is given only that many evenly spaced ranks are checked for range and
+        order, and the permutation and checksum checks are skipped."""
+
+    @staticmethod
+    def verify_files(token_path: str, index_path: str, vocab: int | None = None, sample: int | None = None) -> IntegrityIssue | None:
+        """Run `verify` on a pair of files without opening them as an index, so that files which
+        fail to open, for example because their lengths differ, can still be diagnosed."""
     
     def contains(self, query: list[int]) -> bool:
         """Check if `query` has nonzero count. Faster than `count(query) > 0`."""
@@ -78,24 +410,139 @@ class MemmapIndex:
     def count(self, query: list[int]) -> int:
         """Count the number of occurrences of `query` in the index."""
 
-    def positions(self, query: list[int]) -> list[int]:
-        """Returns an unordered list of positions where `query` starts in `tokens`."""
+    def positions(
+        self,
+        query: list[int],
+        offset: int = 0,
+        limit: int | None = None,
+        sorted: bool = False,
+    ) -> NDArray[np.uint64]:
+        """Returns the positions where `query` starts in `tokens`, skipping the first `offset`
+        and keeping at most `limit`. Positions are in index order unless `sorted`, in which
+        case they are in corpus order and every page costs a pass over all matches."""
+
+    def iter_positions(
+        self, query: list[int], page_size: int = 2**20, sorted: bool = False
+    ) -> PositionPages:
+        """Iterate over the positions where `query` starts in `tokens` in arrays of at most
+        `page_size` positions, fetching each page only when it is reached. With `sorted`, every
+        position is fetched and sorted once with the first page."""
+
+    def sample_positions(
+        self, query: list[int], k: int, seed: int | None = None
+    ) -> NDArray[np.uint64]:
+        """Returns `k` positions where `query` starts in `tokens` in corpus order, drawn
+        uniformly at random without replacement, or every position if there are at most `k`."""
 
     def count_next(self, query: list[int]) -> list[int]:
         """Count the occurrences of each token directly following `query`."""
 
+    def approx_count_next(
+        self, query: list[int], sample_size: int = 1000, seed: int | None = None
+    ) -> tuple[list[float], list[float]]:
+        """Estimate the occurrences of each token directly following `query` from
+        `sample_size` of its occurrences drawn uniformly at random, returning the
+        estimates and their standard errors. Both are exact when `sample_size` covers
+        every occurrence."""
+
+    def batch_contains(self, queries: list[list[int]]) -> list[bool]:
+        """Check whether each sequence in `queries` occurs in `tokens`. Queries are looked up in
+        parallel in sorted order, so that queries sharing a prefix share its search."""
+
+    def batch_count(self, queries: list[list[int]]) -> list[int]:
+        """Count the occurrences of each sequence in `queries` like `batch_contains`."""
+
+    def batch_positions(self, queries: list[list[int]]) -> list[NDArray[np.uint64]]:
+        """Returns the unordered positions of each sequence in `queries`, looked up like
+        `batch_contains`."""
+
     def batch_count_next(self, queries: list[list[int]]) -> list[list[int]]:
         """Count the occurrences of each token that directly follows each sequence in `queries`."""
 
-    def sample_smoothed(self, query: list[int], n: int, k: int, num_samples: int) -> list[list[int]]:
+    def sample_smoothed(
+        self,
+        query: list[int],
+        n: int,
+        k: int,
+        num_samples: int,
+        seed: int | None = None,
+        config: SamplingConfig | None = None,
+        detailed: bool = False,
+    ) -> list[list[int]] | list[SampleResult]:
         """Autoregressively samples num_samples of k characters each from Kneser-Ney smoothed conditional 
         distributions based on the previous (n - 1) characters (n-gram prefix) in the sequence. If there are 
-        fewer than (n - 1) characters all available characters are used."""
+        fewer than (n - 1) characters all available characters are used, and n below 1 raises
+        `ValueError`. Given a `seed`, sample i is drawn
+        from its own random stream seeded with `seed + i`, so results do not depend on the number of threads.
+        A `config` sets decoding options and stop tokens, which can end samples before k characters.
+        Returns a list of token lists by default, and with `detailed` a `SampleResult` with
+        diagnostics for each sample instead."""
+
+    def batch_sample(
+        self,
+        requests: list[tuple[list[int], int, int, int]],
+        smoothed: bool = False,
+        seed: int | None = None,
+        config: SamplingConfig | None = None,
+        return_orders: bool = False,
+        detailed: bool = False,
+    ) -> (
+        list[list[list[int]]]
+        | list[tuple[list[list[int]], list[list[int]]]]
+        | list[list[SampleResult]]
+    ):
+        """Samples from many prompts in one parallel job. Each request is a `(query, n, k, num_samples)`
+        tuple, and a list of samples is returned for each request in order, formatted as by
+        `sample_unsmoothed`. Samples are drawn from a Kneser-Ney smoothed n-gram model if `smoothed`, and
+        otherwise from an unsmoothed one. Given a `seed`, samples are numbered consecutively across
+        requests, so a lone request draws the same samples as the single-prompt methods."""
    
-    def sample_unsmoothed(self, query: list[int], n: int, k: int, num_samples: int) -> list[list[int]]:
+    def decode_greedy(
+        self, query: list[int], n: int, k: int, config: SamplingConfig | None = None
+    ) -> tuple[list[int], float]:
+        """Extend the query by up to k characters, picking the most likely token under a Kneser-Ney
+        smoothed n-gram model at each step. Returns the sequence and the sum of the log-probabilities
+        of its new tokens. The banned tokens, minimum count and stop tokens of `config` apply."""
+
+    def beam_search(
+        self,
+        query: list[int],
+        n: int,
+        k: int,
+        beam_width: int = 4,
+        config: SamplingConfig | None = None,
+    ) -> list[tuple[list[int], float]]:
+        """Beam search for the `beam_width` most likely extensions of the query by up to k characters
+        under a Kneser-Ney smoothed n-gram model. Returns (sequence, score) pairs in decreasing order of
+        score, the sum of the log-probabilities of the new tokens. The banned tokens, minimum count and
+        stop tokens of `config` apply, and a beam ends after a stop token."""
+
+    def sample_unsmoothed(
+        self,
+        query: list[int],
+        n: int,
+        k: int,
+        num_samples: int,
+        seed: int | None = None,
+        config: SamplingConfig | None = None,
+        return_orders: bool = False,
+        detailed: bool = False,
+    ) -> list[list[int]] | tuple[list[list[int]], list[list[int]]] | list[SampleResult]:
         """Autoregressively samples num_samples of k characters each from conditional distributions based 
         on the previous (n - 1) characters (n-gram prefix) in the sequence. If there are fewer than 
-        (n - 1) characters all available characters are used."""
+        (n - 1) characters all available characters are used, and n below 1 raises
+        `ValueError`. Given a `seed`, sample i is drawn from its
+        own random stream seeded with `seed + i`, so results do not depend on the number of threads.
+        A `config` sets decoding options and stop tokens, which can end samples before k characters.
+
+        When a context has no continuations, such as one ending the corpus, sampling backs off to
+        successively shorter contexts down to the unigram distribution.
+
+        Returns a list of token lists by default. With `return_orders`, returns a `(samples, orders)`
+        pair instead, where `orders` holds the order of the n-gram each sampled token completes, from 1
+        for a unigram to n. With `detailed`, returns a `SampleResult` with diagnostics for each sample
+        instead, whose `context_lens` plus one are the same orders. Setting both flags raises
+        `ValueError`."""
 
     def get_smoothed_probs(self, query: list[int]) -> list[float]:
         """Compute interpolated Kneser-Ney smoothed token probability distribution using all previous tokens in the query."""
@@ -109,19 +556,81 @@ class MemmapIndex:
         for models of order n and below with improved estimates over the entire index.
         https://people.eecs.berkeley.edu/~klein/cs294-5/chen_goodman.pdf, page 16."""
 
+    def save_cache(self, path: str) -> None:
+        """Save the smoothing statistics computed so far, such as those from `estimate_deltas`,
+        together with a fingerprint of the corpus."""
+
+    def load_cache(self, path: str) -> None:
+        """Load smoothing statistics saved by `save_cache`, raising `InvalidMetadataError` if
+        they were computed from a different corpus."""
+
 class ShardedMemmapIndex:
     """An n-gram index backed by several memory-mapped files."""
 
-    def __init__(self, paths: list[tuple[str, str]], vocab: int = 2**16) -> None:
-        """Load a prebuilt memory-mapped index from a list of pairs of files in form (token_file, index_file)."""
+    out_of_vocab: str
+    """How query tokens outside the vocabulary are handled. "error" (the default) raises 
+    `TokenOutOfVocabError`, while "miss" treats them as tokens that never occur in the corpus."""
+
+    def __init__(self, paths: list[tuple[str, str]], vocab: int | None = None) -> None:
+        """Load a prebuilt memory-mapped index from a list of pairs of files in form (token_file, index_file).
+        The token width and vocabulary size are read from the first shard's `.meta` sidecar when
+        present, otherwise `vocab` defaults to 2**16."""
 
     @staticmethod
-    def build(paths: list[tuple[str, str]], vocab: int = 2**16, verbose: bool = False) -> "ShardedMemmapIndex":
+    def build(paths: list[tuple[str, str]], vocab: int = 2**16, verbose: bool = False, token_width: int | None = None) -> "ShardedMemmapIndex":
         """Build a memory-mapped index from a token file."""
 
+    @staticmethod
+    def build_from_token_file(
+        token_path: str,
+        output_prefix: str,
+        shard_size: int,
+        eod_token: int | None = None,
+        vocab: int = 2**16,
+        verbose: bool = False,
+        token_width: int | None = None,
+    ) -> "ShardedMemmapIndex":
+        """Split a single token file into shards of roughly `shard_size` tokens and build an index 
+        for each. Shards are written to `{output_prefix}-{i:05}.bin` and `{output_prefix}-{i:05}.idx`. 
+        When `eod_token` is provided shards are split on document boundaries."""
+
+    @staticmethod
+    def split_token_file(
+        token_path: str,
+        output_prefix: str,
+        shard_size: int,
+        eod_token: int | None = None,
+        vocab: int = 2**16,
+        token_width: int | None = None,
+    ) -> list[tuple[str, str]]:
+        """Split a single token file into shards of roughly `shard_size` tokens without building 
+        their indices. Returns the (token_file, index_file) paths of each shard."""
+
     def is_sorted(self) -> bool:
         """Check if the index's suffix table is sorted lexicographically. 
         This is always true for valid indices."""
+
+    count_cache_capacity: int
+    """The maximum number of contexts whose `count_next` results are cached. Zero, the default,
+    disables the cache. Setting the capacity clears the cache and its statistics."""
+
+    def count_cache_stats(self) -> CacheStats:
+        """Hit and miss statistics of the `count_next` cache, which serves `count_next`, the
+        smoothed probability methods and sampling."""
+
+    def warm_count_cache(self, top_m: int, max_len: int = 2) -> None:
+        """Fill the `count_next` cache with the `top_m` most frequent contexts of at most `max_len`
+        tokens, including the empty context."""
+
+    def build_buckets(self, depth: int = 1) -> None:
+        """Build and save a lookup table for every shard, see `MemmapIndex.build_buckets`."""
+
+    def build_counts(self) -> None:
+        """Build and save unigram and bigram counts for every shard, see `MemmapIndex.build_counts`."""
+
+    def verify(self, sample: int | None = None) -> list[IntegrityIssue]:
+        """Run `MemmapIndex.verify` on every shard, returning the first problem found in each
+        failing shard. An empty list means every shard passed."""
     
     def contains(self, query: list[int]) -> bool:
         """Check if `query` has nonzero count. Faster than `count(query) > 0`."""
@@ -132,18 +641,350 @@ class ShardedMemmapIndex:
     def count_next(self, query: list[int]) -> list[int]:
         """Count the occurrences of each token directly following `query`."""
 
+    def approx_count_next(
+        self, query: list[int], sample_size: int = 1000, seed: int | None = None
+    ) -> tuple[list[float], list[float]]:
+        """Estimate the occurrences of each token directly following `query` from
+        `sample_size` of its occurrences drawn uniformly at random, returning the
+        estimates and their standard errors. Both are exact when `sample_size` covers
+        every occurrence."""
+
+    def batch_contains(self, queries: list[list[int]]) -> list[bool]:
+        """Check whether each sequence in `queries` occurs in `tokens`. Queries are looked up in
+        parallel in sorted order, so that queries sharing a prefix share its search."""
+
+    def batch_count(self, queries: list[list[int]]) -> list[int]:
+        """Count the occurrences of each sequence in `queries` like `batch_contains`."""
+
+    def batch_positions(self, queries: list[list[int]]) -> list[NDArray[np.uint64]]:
+        """Returns the unordered positions of each sequence in `queries`, looked up like
+        `batch_contains`.
+        Positions are offsets into the concatenation of the shards' token files, in shard
+        order."""
+
+    def batch_count_next(self, queries: list[list[int]]) -> list[list[int]]:
+        """Count the occurrences of each token that directly follows each sequence in `queries`."""
+
+    def sample_smoothed(
+        self,
+        query: list[int],
+        n: int,
+        k: int,
+        num_samples: int,
+        seed: int | None = None,
+        config: SamplingConfig | None = None,
+        detailed: bool = False,
+    ) -> list[list[int]] | list[SampleResult]:
+        """Autoregressively samples num_samples of k characters each from Kneser-Ney smoothed conditional 
+        distributions based on the previous (n - 1) characters (n-gram prefix) in the sequence. If there are 
+        fewer than (n - 1) characters all available characters are used, and n below 1 raises
+        `ValueError`. Given a `seed`, sample i is drawn
+        from its own random stream seeded with `seed + i`, so results do not depend on the number of threads.
+        A `config` sets decoding options and stop tokens, which can end samples before k characters.
+        Returns a list of token lists by default, and with `detailed` a `SampleResult` with
+        diagnostics for each sample instead."""
+
+    def batch_sample(
+        self,
+        requests: list[tuple[list[int], int, int, int]],
+        smoothed: bool = False,
+        seed: int | None = None,
+        config: SamplingConfig | None = None,
+        return_orders: bool = False,
+        detailed: bool = False,
+    ) -> (
+        list[list[list[int]]]
+        | list[tuple[list[list[int]], list[list[int]]]]
+        | list[list[SampleResult]]
+    ):
+        """Samples from many prompts in one parallel job. Each request is a `(query, n, k, num_samples)`
+        tuple, and a list of samples is returned for each request in order, formatted as by
+        `sample_unsmoothed`. Samples are drawn from a Kneser-Ney smoothed n-gram model if `smoothed`, and
+        otherwise from an unsmoothed one. Given a `seed`, samples are numbered consecutively across
+        requests, so a lone request draws the same samples as the single-prompt methods."""
+   
+    def decode_greedy(
+        self, query: list[int], n: int, k: int, config: SamplingConfig | None = None
+    ) -> tuple[list[int], float]:
+        """Extend the query by up to k characters, picking the most likely token under a Kneser-Ney
+        smoothed n-gram model at each step. Returns the sequence and the sum of the log-probabilities
+        of its new tokens. The banned tokens, minimum count and stop tokens of `config` apply."""
+
+    def beam_search(
+        self,
+        query: list[int],
+        n: int,
+        k: int,
+        beam_width: int = 4,
+        config: SamplingConfig | None = None,
+    ) -> list[tuple[list[int], float]]:
+        """Beam search for the `beam_width` most likely extensions of the query by up to k characters
+        under a Kneser-Ney smoothed n-gram model. Returns (sequence, score) pairs in decreasing order of
+        score, the sum of the log-probabilities of the new tokens. The banned tokens, minimum count and
+        stop tokens of `config` apply, and a beam ends after a stop token."""
+
+    def sample_unsmoothed(
+        self,
+        query: list[int],
+        n: int,
+        k: int,
+        num_samples: int,
+        seed: int | None = None,
+        config: SamplingConfig | None = None,
+        return_orders: bool = False,
+        detailed: bool = False,
+    ) -> list[list[int]] | tuple[list[list[int]], list[list[int]]] | list[SampleResult]:
+        """Autoregressively samples num_samples of k characters each from conditional distributions based 
+        on the previous (n - 1) characters (n-gram prefix) in the sequence. If there are fewer than 
+        (n - 1) characters all available characters are used, and n below 1 raises
+        `ValueError`. Given a `seed`, sample i is drawn from its
+        own random stream seeded with `seed + i`, so results do not depend on the number of threads.
+        A `config` sets decoding options and stop tokens, which can end samples before k characters.
+
+        When a context has no continuations, such as one ending the corpus, sampling backs off to
+        successively shorter contexts down to the unigram distribution.
+
+        Returns a list of token lists by default. With `return_orders`, returns a `(samples, orders)`
+        pair instead, where `orders` holds the order of the n-gram each sampled token completes, from 1
+        for a unigram to n. With `detailed`, returns a `SampleResult` with diagnostics for each sample
+        instead, whose `context_lens` plus one are the same orders. Setting both flags raises
+        `ValueError`."""
+
+    def get_smoothed_probs(self, query: list[int]) -> list[float]:
+        """Compute interpolated Kneser-Ney smoothed token probability distribution using all previous tokens in the query."""
+
+    def batch_get_smoothed_probs(self, queries: list[list[int]]) -> list[list[float]]:
+        """Compute interpolated Kneser-Ney smoothed token probability distributions using all previous tokens in each query."""
+    
+    def estimate_deltas(self, n: int):
+        """Warning: O(k**n) where k is vocabulary size, use with caution.
+        Improve smoothed model quality by replacing the default delta hyperparameters
+        for models of order n and below with improved estimates over the entire index.
+        https://people.eecs.berkeley.edu/~klein/cs294-5/chen_goodman.pdf, page 16."""
+
+    def save_cache(self, path: str) -> None:
+        """Save the smoothing statistics computed so far, such as those from `estimate_deltas`,
+        together with a fingerprint of the corpus."""
+
+    def load_cache(self, path: str) -> None:
+        """Load smoothing statistics saved by `save_cache`, raising `InvalidMetadataError` if
+        they were computed from a different corpus."""
+
+class SparseMemmapIndex:
+    """A memory-mapped index storing only every `step`-th suffix of a corpus, giving a table `step`
+    times smaller than a `MemmapIndex`'s. Queries of at least `step` tokens are answered with `step`
+    binary searches.
+
+    Limitation: queries and `count_next` contexts shorter than `step`, including the empty
+    context, can occur between two sampled suffixes, so the index cannot answer them from its
+    table. They raise `ValueError` unless `allow_scan` is set, in which case each one costs a
+    parallel scan of the whole corpus. Use a `MemmapIndex` when short queries or unigram counts
+    are needed."""
+
+    out_of_vocab: str
+    """How query tokens outside the vocabulary are handled. "error" (the default) raises 
+    `TokenOutOfVocabError`, while "miss" treats them as tokens that never occur in the corpus."""
+
+    step: int
+    """The distance between the corpus offsets of sampled suffixes."""
+
+    allow_scan: bool
+    """Whether queries and `count_next` contexts shorter than `step` may be answered with a scan of
+    the whole corpus. When False (the default) they raise `ValueError`."""
+
+    def __init__(self, token_path: str, index_path: str, vocab: int | None = None, step: int | None = None) -> None:
+        """Load a prebuilt sparse index from a pair of files. The token width, vocabulary size and
+        step are read from the index's `.meta` sidecar when present. Indices without a sidecar must
+        be opened with their `step`."""
+
+    @staticmethod
+    def build(token_path: str, index_path: str, step: int, vocab: int = 2**16, verbose: bool = False, token_width: int | None = None) -> "SparseMemmapIndex":
+        """Build a sparse memory-mapped index from a token file, sampling every `step`-th suffix."""
+
+    def is_sorted(self) -> bool:
+        """Check if the index's sampled suffixes are sorted lexicographically. 
+        This is always true for valid indices."""
+
+    def contains(self, query: list[int]) -> bool:
+        """Check if `query` has nonzero count."""
+
+    def count(self, query: list[int]) -> int:
+        """Count the number of occurrences of `query` in the index."""
+
+    def positions(
+        self,
+        query: list[int],
+        offset: int = 0,
+        limit: int | None = None,
+        sorted: bool = False,
+    ) -> NDArray[np.uint64]:
+        """Returns the positions where `query` starts in `tokens`, skipping the first `offset`
+        and keeping at most `limit`. Positions are in index order unless `sorted`, in which
+        case they are in corpus order and every page costs a pass over all matches."""
+
+    def iter_positions(
+        self, query: list[int], page_size: int = 2**20, sorted: bool = False
+    ) -> PositionPages:
+        """Iterate over the positions where `query` starts in `tokens` in arrays of at most
+        `page_size` positions, fetching each page only when it is reached. With `sorted`, every
+        position is fetched and sorted once with the first page."""
+
+    def count_next(self, query: list[int]) -> list[int]:
+        """Count the occurrences of each token directly following `query`. Contexts shorter than
+        `step` scan the whole corpus, see the class docstring."""
+
+    def batch_count_next(self, queries: list[list[int]]) -> list[list[int]]:
+        """Count the occurrences of each token that directly follows each sequence in `queries`.
+        Contexts shorter than `step` scan the whole corpus, see the class docstring."""
+
+class FmIndex:
+    """A compressed self-index built from a token file, replacing both the token file and the
+    suffix table of a `MemmapIndex`. The index stores the Burrows-Wheeler transform of the corpus
+    in a wavelet matrix, answering `count`, `contains` and `count_next` with one rank query per
+    query token, while `positions` walks to the nearest of every `sample_rate`-th stored offset."""
+
+    out_of_vocab: str
+    """How query tokens outside the vocabulary are handled. "error" (the default) raises 
+    `TokenOutOfVocabError`, while "miss" treats them as tokens that never occur in the corpus."""
+
+    sample_rate: int
+    """The distance between the corpus offsets stored for `positions` queries."""
+
+    def __init__(self, index_path: str) -> None:
+        """Load a prebuilt FM-index, memory mapping its arrays. The token width and vocabulary size
+        are read from the index."""
+
+    @staticmethod
+    def build(
+        token_path: str,
+        index_path: str,
+        vocab: int = 2**16,
+        sample_rate: int = 32,
+        verbose: bool = False,
+        token_width: int | None = None,
+    ) -> "FmIndex":
+        """Build an FM-index from a token file and save it to `index_path`. Larger sample rates
+        give smaller indices and slower `positions` queries. The suffix array of the reversed
+        corpus is sorted in temporary files next to the index, after which building the wavelet
+        matrix needs about 8 bytes of memory per token."""
+
+    def save(self, index_path: str) -> None:
+        """Save the index to `index_path`."""
+
+    def contains(self, query: list[int]) -> bool:
+        """Check if `query` has nonzero count."""
+
+    def count(self, query: list[int]) -> int:
+        """Count the number of occurrences of `query` in the index."""
+
+    def positions(
+        self,
+        query: list[int],
+        offset: int = 0,
+        limit: int | None = None,
+        sorted: bool = False,
+    ) -> NDArray[np.uint64]:
+        """Returns the positions where `query` starts in `tokens`, skipping the first `offset`
+        and keeping at most `limit`. Positions are in index order unless `sorted`, in which
+        case they are in corpus order and every page costs a pass over all matches."""
+
+    def iter_positions(
+        self, query: list[int], page_size: int = 2**20, sorted: bool = False
+    ) -> PositionPages:
+        """Iterate over the positions where `query` starts in `tokens` in arrays of at most
+        `page_size` positions, fetching each page only when it is reached. With `sorted`, every
+        position is fetched and sorted once with the first page."""
+
+    def count_next(self, query: list[int]) -> list[int]:
+        """Count the occurrences of each token directly following `query`."""
+
     def batch_count_next(self, queries: list[list[int]]) -> list[list[int]]:
         """Count the occurrences of each token that directly follows each sequence in `queries`."""
 
-    def sample_smoothed(self, query: list[int], n: int, k: int, num_samples: int) -> list[list[int]]:
+    def sample_smoothed(
+        self,
+        query: list[int],
+        n: int,
+        k: int,
+        num_samples: int,
+        seed: int | None = None,
+        config: SamplingConfig | None = None,
+        detailed: bool = False,
+    ) -> list[list[int]] | list[SampleResult]:
         """Autoregressively samples num_samples of k characters each from Kneser-Ney smoothed conditional 
         distributions based on the previous (n - 1) characters (n-gram prefix) in the sequence. If there are 
-        fewer than (n - 1) characters all available characters are used."""
+        fewer than (n - 1) characters all available characters are used, and n below 1 raises
+        `ValueError`. Given a `seed`, sample i is drawn
+        from its own random stream seeded with `seed + i`, so results do not depend on the number of threads.
+        A `config` sets decoding options and stop tokens, which can end samples before k characters.
+        Returns a list of token lists by default, and with `detailed` a `SampleResult` with
+        diagnostics for each sample instead."""
+
+    def batch_sample(
+        self,
+        requests: list[tuple[list[int], int, int, int]],
+        smoothed: bool = False,
+        seed: int | None = None,
+        config: SamplingConfig | None = None,
+        return_orders: bool = False,
+        detailed: bool = False,
+    ) -> (
+        list[list[list[int]]]
+        | list[tuple[list[list[int]], list[list[int]]]]
+        | list[list[SampleResult]]
+    ):
+        """Samples from many prompts in one parallel job. Each request is a `(query, n, k, num_samples)`
+        tuple, and a list of samples is returned for each request in order, formatted as by
+        `sample_unsmoothed`. Samples are drawn from a Kneser-Ney smoothed n-gram model if `smoothed`, and
+        otherwise from an unsmoothed one. Given a `seed`, samples are numbered consecutively across
+        requests, so a lone request draws the same samples as the single-prompt methods."""
    
-    def sample_unsmoothed(self, query: list[int], n: int, k: int, num_samples: int) -> list[list[int]]:
+    def decode_greedy(
+        self, query: list[int], n: int, k: int, config: SamplingConfig | None = None
+    ) -> tuple[list[int], float]:
+        """Extend the query by up to k characters, picking the most likely token under a Kneser-Ney
+        smoothed n-gram model at each step. Returns the sequence and the sum of the log-probabilities
+        of its new tokens. The banned tokens, minimum count and stop tokens of `config` apply."""
+
+    def beam_search(
+        self,
+        query: list[int],
+        n: int,
+        k: int,
+        beam_width: int = 4,
+        config: SamplingConfig | None = None,
+    ) -> list[tuple[list[int], float]]:
+        """Beam search for the `beam_width` most likely extensions of the query by up to k characters
+        under a Kneser-Ney smoothed n-gram model. Returns (sequence, score) pairs in decreasing order of
+        score, the sum of the log-probabilities of the new tokens. The banned tokens, minimum count and
+        stop tokens of `config` apply, and a beam ends after a stop token."""
+
+    def sample_unsmoothed(
+        self,
+        query: list[int],
+        n: int,
+        k: int,
+        num_samples: int,
+        seed: int | None = None,
+        config: SamplingConfig | None = None,
+        return_orders: bool = False,
+        detailed: bool = False,
+    ) -> list[list[int]] | tuple[list[list[int]], list[list[int]]] | list[SampleResult]:
         """Autoregressively samples num_samples of k characters each from conditional distributions based 
         on the previous (n - 1) characters (n-gram prefix) in the sequence. If there are fewer than 
-        (n - 1) characters all available characters are used."""
+        (n - 1) characters all available characters are used, and n below 1 raises
+        `ValueError`. Given a `seed`, sample i is drawn from its
+        own random stream seeded with `seed + i`, so results do not depend on the number of threads.
+        A `config` sets decoding options and stop tokens, which can end samples before k characters.
+
+        When a context has no continuations, such as one ending the corpus, sampling backs off to
+        successively shorter contexts down to the unigram distribution.
+
+        Returns a list of token lists by default. With `return_orders`, returns a `(samples, orders)`
+        pair instead, where `orders` holds the order of the n-gram each sampled token completes, from 1
+        for a unigram to n. With `detailed`, returns a `SampleResult` with diagnostics for each sample
+        instead, whose `context_lens` plus one are the same orders. Setting both flags raises
+        `ValueError`."""
 
     def get_smoothed_probs(self, query: list[int]) -> list[float]:
         """Compute interpolated Kneser-Ney smoothed token probability distribution using all previous tokens in the query."""